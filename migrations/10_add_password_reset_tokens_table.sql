@@ -0,0 +1,10 @@
+-- password_reset_tokens テーブルの作成
+create table password_reset_tokens (
+    token_hash text primary key,
+    user_id text not null references users(id) on delete cascade,
+    expires_at timestamptz not null,
+    used_at timestamptz,
+    created_at timestamptz not null default current_timestamp
+);
+
+create index password_reset_tokens_user_id_index on password_reset_tokens (user_id);
@@ -0,0 +1,12 @@
+-- sessions テーブルの作成
+create table sessions (
+    id uuid primary key default gen_random_uuid(),
+    user_id text not null references users(id) on delete cascade,
+    user_agent text,
+    created_at timestamptz not null default current_timestamp,
+    last_used_at timestamptz not null default current_timestamp,
+    expires_at timestamptz not null,
+    revoked_at timestamptz
+);
+
+create index sessions_user_id_index on sessions (user_id);
@@ -0,0 +1,34 @@
+-- api_keys テーブルの作成
+create type api_key_scope as enum ('read', 'upload', 'write', 'admin');
+
+create table api_keys (
+    id uuid primary key default gen_random_uuid(),
+    user_id text not null references users(id) on delete cascade,
+    "name" text not null,
+    key_hash text not null unique,
+    prefix text not null,
+    scopes api_key_scope[] not null,
+    expires_at timestamptz,
+    last_used_at timestamptz,
+    created_at timestamptz not null default current_timestamp,
+    revoked_at timestamptz
+);
+
+create index api_keys_user_id_index on api_keys (user_id);
+
+-- 既存のAPIキーをすべての権限を持つキーとして移行する
+insert into
+    api_keys (user_id, "name", key_hash, prefix, scopes)
+select
+    id,
+    'default',
+    encode(sha256(convert_to(api_key, 'UTF8')), 'hex'),
+    left(api_key, 8),
+    '{admin}'
+from
+    users;
+
+-- usersテーブルからapi_keyカラムを削除
+drop index users_api_key_index;
+
+alter table users drop column api_key;
@@ -0,0 +1,21 @@
+-- 外部IDプロバイダーのアカウントとの紐付け
+create table user_identities (
+    issuer text not null,
+    subject text not null,
+    user_id text not null references users(id) on delete cascade,
+    created_at timestamptz not null default current_timestamp,
+    primary key (issuer, subject)
+);
+
+create index user_identities_user_id_index on user_identities (user_id);
+
+-- 認可リクエストごとのstate・PKCE・nonce
+create table oidc_states (
+    "state" text primary key,
+    code_verifier text not null,
+    nonce text not null,
+    invitation_code text,
+    new_user_id text,
+    link_user_id text references users(id) on delete cascade,
+    expires_at timestamptz not null
+);
@@ -0,0 +1,33 @@
+-- TOTPの秘密鍵
+-- enabled_at が null の間は登録中
+create table user_totp (
+    user_id text primary key references users(id) on delete cascade,
+    secret text not null,
+    last_used_step bigint,
+    enabled_at timestamptz,
+    created_at timestamptz not null default current_timestamp
+);
+
+-- リカバリーコード
+create table recovery_codes (
+    code_hash text primary key,
+    user_id text not null references users(id) on delete cascade,
+    used_at timestamptz
+);
+
+create index recovery_codes_user_id_index on recovery_codes (user_id);
+
+-- パスワード認証後、二段階目の認証を待っているログイン
+create table login_challenges (
+    token_hash text primary key,
+    user_id text not null references users(id) on delete cascade,
+    user_agent text,
+    attempts integer not null default 0,
+    expires_at timestamptz not null
+);
+
+-- ロールごとのポリシー
+create table role_policies (
+    role user_role primary key,
+    require_totp boolean not null default false
+);
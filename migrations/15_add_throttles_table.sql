@@ -0,0 +1,8 @@
+-- ログイン・招待コード確認の失敗回数
+-- key は "login:ip:<IP>" や "login:account:<ID>" の形式
+create table throttles (
+    "key" text primary key,
+    failures integer not null default 0,
+    last_failure_at timestamptz not null default current_timestamp,
+    locked_until timestamptz
+);
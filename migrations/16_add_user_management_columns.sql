@@ -0,0 +1,6 @@
+-- 停止中のアカウントはセッション・APIキーでの認証ができない
+alter table users add column suspended_at timestamptz;
+
+-- ストレージ使用量の集計のため、EPUBのサイズを保存する
+-- 既存の本のサイズ(0)は get_metadata がS3のオブジェクトのサイズで更新する
+alter table books add column "size" bigint not null default 0;
@@ -0,0 +1,6 @@
+-- 招待コードの有効期限と発行日時
+alter table invitations add column expires_at timestamptz;
+alter table invitations add column created_at timestamptz not null default now();
+
+-- ユーザーごとの招待コードの発行上限(nullの場合は環境変数のデフォルト値)
+alter table users add column invitation_quota integer;
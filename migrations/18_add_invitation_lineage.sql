@@ -0,0 +1,5 @@
+-- ユーザーが登録時に使用した招待コード
+alter table users add column invitation_code text references invitations(code) on delete set null;
+alter table users add column created_at timestamptz not null default now();
+
+create index users_invitation_code_index on users (invitation_code);
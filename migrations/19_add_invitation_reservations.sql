@@ -0,0 +1,11 @@
+-- 招待コードの予約(usingの状態)はトークンを持つクライアントのみが使用できる
+create table invitation_reservations (
+    token_hash text primary key,
+    code text not null references invitations(code) on delete cascade,
+    reserved_until timestamptz not null
+);
+
+create index invitation_reservations_code_index on invitation_reservations (code);
+
+-- 既存の予約は期限切れとして扱う
+update invitations set "state" = 'unused', used_at = null where "state" = 'using';
@@ -0,0 +1,15 @@
+-- 複数回使用できる招待コードと、登録時に付与するロール・グループ
+alter table invitations add column max_uses integer not null default 1 check (max_uses >= 1);
+alter table invitations add column use_count integer not null default 0;
+alter table invitations add column "role" user_role not null default 'user';
+alter table invitations add column group_name text;
+
+update invitations set use_count = 1 where "state" = 'used';
+
+-- ユーザーの所属グループ
+create table user_groups (
+    user_id text not null references users(id) on delete cascade,
+    group_name text not null,
+    created_at timestamptz not null default now(),
+    primary key (user_id, group_name)
+);
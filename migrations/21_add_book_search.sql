@@ -0,0 +1,56 @@
+-- 日本語など空白で区切られないテキストの部分一致検索に使用する
+create extension if not exists pg_trgm;
+
+-- 検索対象(タイトル・著者・出版社・タグ)
+alter table books add column search_text text not null default '';
+alter table books add column search_vector tsvector not null default ''::tsvector;
+
+create index books_search_vector_index on books using gin (search_vector);
+create index books_search_text_index on books using gin (search_text gin_trgm_ops);
+
+-- bookの検索対象を更新する
+create function refresh_book_search(target_id text) returns void as $$
+    update books b
+    set
+        search_text = concat_ws(' ', b.name, b.creator, b.publisher, t.tags),
+        search_vector =
+            setweight(to_tsvector('simple', b.name), 'A')
+            || setweight(to_tsvector('simple', b.creator), 'B')
+            || setweight(to_tsvector('simple', coalesce(t.tags, '')), 'B')
+            || setweight(to_tsvector('simple', b.publisher), 'C')
+    from (
+        select string_agg(tag_name, ' ' order by tag_name) as tags
+        from book_tags
+        where book_id = target_id
+    ) t
+    where b.id = target_id;
+$$ language sql;
+
+create function books_search_trigger() returns trigger as $$
+begin
+    perform refresh_book_search(new.id);
+    return null;
+end
+$$ language plpgsql;
+
+create trigger books_search_update
+after insert or update of name, creator, publisher on books
+for each row execute function books_search_trigger();
+
+create function book_tags_search_trigger() returns trigger as $$
+begin
+    if tg_op = 'DELETE' then
+        perform refresh_book_search(old.book_id);
+    else
+        perform refresh_book_search(new.book_id);
+    end if;
+    return null;
+end
+$$ language plpgsql;
+
+create trigger book_tags_search_update
+after insert or delete on book_tags
+for each row execute function book_tags_search_trigger();
+
+-- 既存のbookの検索対象を作成する
+select refresh_book_search(id) from books;
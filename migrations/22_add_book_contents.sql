@@ -0,0 +1,16 @@
+-- bookの本文(spineの各文書)
+create table book_contents (
+    book_id text not null references books(id) on delete cascade,
+    "position" integer not null,
+    href text not null,
+    title text,
+    content text not null,
+    search_vector tsvector generated always as (to_tsvector('simple', content)) stored,
+    primary key (book_id, "position")
+);
+
+create index book_contents_search_vector_index on book_contents using gin (search_vector);
+create index book_contents_content_index on book_contents using gin (content gin_trgm_ops);
+
+-- 本文を索引に登録した日時(既存のbookは未登録として次回のepub2imgで登録する)
+alter table books add column contents_indexed_at timestamptz;
@@ -0,0 +1,20 @@
+-- シリーズ(EPUB3の belongs-to-collection に対応する)
+create table series (
+    id text primary key,
+    "name" text not null unique,
+    created_at timestamptz not null default now()
+);
+
+-- bookが属するシリーズと巻数
+alter table books add column series_id text references series(id) on delete set null;
+alter table books add column volume double precision;
+
+create index books_series_id_index on books (series_id, volume);
+
+-- ユーザーが読み終えたbook
+create table finished_books (
+    user_id text not null references users(id) on delete cascade,
+    book_id text not null references books(id) on delete cascade,
+    finished_at timestamptz not null default now(),
+    primary key (user_id, book_id)
+);
@@ -0,0 +1,29 @@
+-- ユーザーが作成する本のコレクション
+--
+-- private: 作成者のみ、public: 全員が閲覧可能、shared: メンバーが閲覧・編集可能
+create type collection_visibility as enum ('private', 'public', 'shared');
+
+create table collections (
+    id text primary key,
+    owner_id text not null references users(id) on delete cascade,
+    "name" text not null,
+    visibility collection_visibility not null default 'private',
+    created_at timestamptz not null default now(),
+    updated_at timestamptz not null default now(),
+    unique (owner_id, "name")
+);
+
+create table collection_books (
+    collection_id text not null references collections(id) on delete cascade,
+    book_id text not null references books(id) on delete cascade,
+    "position" integer not null,
+    primary key (collection_id, book_id)
+);
+
+create index collection_books_book_id_index on collection_books (book_id);
+
+create table collection_members (
+    collection_id text not null references collections(id) on delete cascade,
+    user_id text not null references users(id) on delete cascade,
+    primary key (collection_id, user_id)
+);
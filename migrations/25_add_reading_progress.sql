@@ -0,0 +1,15 @@
+-- ユーザーごとの本の読書位置
+--
+-- pre-paginated の本は page を、reflowable の本は cfi または percentage を記録する
+create table reading_progress (
+    user_id text not null references users(id) on delete cascade,
+    book_id text not null references books(id) on delete cascade,
+    page integer,
+    cfi text,
+    percentage double precision,
+    device text not null,
+    updated_at timestamptz not null default now(),
+    primary key (user_id, book_id)
+);
+
+create index reading_progress_user_id_updated_at_index on reading_progress (user_id, updated_at desc);
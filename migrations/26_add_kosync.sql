@@ -0,0 +1,21 @@
+-- KOReaderの進捗同期(kosync)で使用するEPUBファイルのハッシュ
+--
+-- hash はKOReaderの部分MD5(ファイルの一部のみから計算したMD5)。
+-- メタデータの書き換えでファイルが変わっても以前のハッシュで引けるよう、過去のハッシュも残す
+create table book_file_hashes (
+    book_id text not null references books(id) on delete cascade,
+    hash text not null,
+    created_at timestamptz not null default now(),
+    primary key (book_id, hash)
+);
+
+create index book_file_hashes_hash_index on book_file_hashes (hash);
+
+-- KOReaderはパスワードのMD5を送信するため、APIキーのMD5のハッシュも保存する
+alter table api_keys add column kosync_key_hash text;
+
+create index api_keys_kosync_key_hash_index on api_keys (kosync_key_hash);
+
+-- KOReaderの読書位置(XPointerまたはページ番号)と端末ID
+alter table reading_progress add column kosync_progress text;
+alter table reading_progress add column device_id text;
@@ -0,0 +1,14 @@
+-- Readium Web Publication Manifestで配信するEPUBのリソース
+--
+-- EPUBを展開したファイルをS3に保存し、リクエストのたびにEPUBを取得・展開しないようにする。
+-- path はEPUB内でのパス、key はS3のオブジェクトのキー
+create table book_resources (
+    book_id text not null references books(id) on delete cascade,
+    path text not null,
+    key text not null,
+    media_type text not null,
+    primary key (book_id, path)
+);
+
+-- EPUBのOPFから作成したReadium Web Publication Manifest(リソースを展開するまでは null)
+alter table books add column manifest jsonb;
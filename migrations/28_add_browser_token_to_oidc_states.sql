@@ -0,0 +1,7 @@
+-- 認可リクエストを開始したブラウザのCookieのハッシュ
+--
+-- コールバックで同じブラウザからのリクエストかを確認し、他人が開始した認可リクエストで
+-- ログイン・紐付けさせられないようにする。既存の認可リクエストは確認できないため削除する
+delete from oidc_states;
+
+alter table oidc_states add column browser_token_hash text not null;
@@ -2,7 +2,13 @@ use aws_sdk_s3::primitives::ByteStream;
 use epubapi::{
     db::connect_db,
     minio::get_client,
-    service::book::model::{get_books_without_layout, update_book_images, BookLayout},
+    service::book::{
+        content::extract_text,
+        model::{
+            extract_book_resources, get_unprocessed_books, replace_book_contents,
+            update_book_images, BookLayout, NewChapter,
+        },
+    },
 };
 use std::{
     env::var,
@@ -27,7 +33,7 @@ async fn main() {
     let db = connect_db().await;
 
     // 未処理のbookのkeyを取得する
-    let books = get_books_without_layout(&db)
+    let books = get_unprocessed_books(&db)
         .await
         .expect("Failed to get books");
 
@@ -88,18 +94,68 @@ async fn main() {
         .to_string();
         let content_path = Path::new(&work_dir).join(&content_path);
 
+        // spineの文書のパスを取得
+        let content_xml = read_to_string(&content_path).expect("content.opf could not found");
+        let doc = roxmltree::Document::parse(&content_xml).expect("Failed to parse content.opf");
+        let xhtml_paths = doc
+            .descendants()
+            .filter(|n| n.tag_name().name() == "itemref")
+            .map(|n| n.attribute("idref").unwrap())
+            .map(|idref| {
+                let node = doc
+                    .descendants()
+                    .find(|n| n.tag_name().name() == "item" && n.attribute("id") == Some(idref))
+                    .unwrap();
+                content_path
+                    .parent()
+                    .unwrap()
+                    .join(node.attribute("href").unwrap())
+            })
+            .collect::<Vec<_>>();
+
+        // 本文を検索用に登録する
+        let chapters = xhtml_paths
+            .iter()
+            .enumerate()
+            .filter_map(|(position, xhtml_path)| {
+                let chapter = extract_text(&read_to_string(xhtml_path).ok()?)?;
+                Some(NewChapter {
+                    position: position as i32,
+                    href: xhtml_path
+                        .strip_prefix(&work_dir)
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string(),
+                    title: chapter.title,
+                    content: chapter.content,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("indexing {} chapters", chapters.len());
+        replace_book_contents(&book.id, chapters, &db)
+            .await
+            .expect("Failed to update book contents");
+
+        // Web Publication Manifestで配信するリソースを展開する
+        let epub = std::fs::read(&file_path).expect("Failed to read epub file");
+        extract_book_resources(&book.id, &epub, &db)
+            .await
+            .expect("Failed to extract book resources");
+
+        // 画像の処理が済んでいれば終了
+        if book.layout.is_some() {
+            continue;
+        }
+
         // rendition:layout が pre-paginated であるか確認
-        let layout = roxmltree::Document::parse(
-            &read_to_string(&content_path).expect("content.opf could not found"),
-        )
-        .expect("Failed to parse content.opf")
-        .descendants()
-        .find(|n| {
-            n.tag_name().name() == "meta" && n.attribute("property") == Some("rendition:layout")
-        })
-        .map(|n| n.text().unwrap())
-        .unwrap_or("reflowable")
-        .to_string();
+        let layout = doc
+            .descendants()
+            .find(|n| {
+                n.tag_name().name() == "meta" && n.attribute("property") == Some("rendition:layout")
+            })
+            .map(|n| n.text().unwrap())
+            .unwrap_or("reflowable")
+            .to_string();
         if &layout == "reflowable" {
             // DBのみ更新して終了
             update_book_images(&book.id, BookLayout::Reflowable, Vec::new(), &db)
@@ -112,26 +168,10 @@ async fn main() {
         }
 
         // 画像ファイルのパスを取得
-        let content_xml = read_to_string(&content_path).unwrap();
-        let doc = roxmltree::Document::parse(&content_xml).unwrap();
-        let xhtml_paths = doc
-            .descendants()
-            .filter(|n| n.tag_name().name() == "itemref")
-            .map(|n| n.attribute("idref").unwrap().to_string())
-            .map(|idref| {
-                let doc = roxmltree::Document::parse(&content_xml).unwrap();
-                let node = doc
-                    .descendants()
-                    .find(|n| n.tag_name().name() == "item" && n.attribute("id") == Some(&idref))
-                    .unwrap();
-                content_path
-                    .parent()
-                    .unwrap()
-                    .join(node.attribute("href").unwrap())
-            });
         let image_paths = xhtml_paths
+            .iter()
             .flat_map(|xhtml_path| {
-                let xhtml = &read_to_string(&xhtml_path).unwrap();
+                let xhtml = &read_to_string(xhtml_path).unwrap();
                 let doc = roxmltree::Document::parse_with_options(
                     xhtml,
                     roxmltree::ParsingOptions {
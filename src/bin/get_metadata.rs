@@ -1,7 +1,15 @@
 use aws_sdk_s3::primitives::ByteStream;
 use chrono::Local;
 use epub::doc::EpubDoc;
-use epubapi::{db::connect_db, minio::get_client, service::book::model::Direction};
+use epubapi::{
+    db::connect_db,
+    minio::get_client,
+    service::{
+        book::{model::Direction, opf::series_from_opf},
+        kosync::model::{add_book_file_hash, partial_md5},
+        series::model::find_or_create_series,
+    },
+};
 use sqlx::{query, query_as};
 use std::{
     env::var,
@@ -97,6 +105,9 @@ async fn main() {
                 file.write_all(&bytes).unwrap();
             }
 
+            // KOReaderの進捗同期で使用するハッシュを計算する
+            let file_hash = partial_md5(&std::fs::read(&tmp_path).unwrap());
+
             // メタデータを取得する
             let mut metadata = EpubDoc::new(&tmp_path).unwrap();
             let direction = if metadata
@@ -158,8 +169,21 @@ async fn main() {
                 .await
                 .unwrap();
 
+            // シリーズを取得する
+            let series = metadata
+                .get_resource_str_by_path(metadata.root_file.clone())
+                .and_then(|opf| series_from_opf(&opf));
+
             // メタデータをDBに保存する
             let mut tx = db_client.begin().await.expect("transaction error.");
+            let series_id = match &series {
+                Some(series) => Some(
+                    find_or_create_series(&series.name, &mut tx)
+                        .await
+                        .expect("Failed to save series"),
+                ),
+                None => None,
+            };
             query!(
                 r#"INSERT INTO books (
                     id,
@@ -170,7 +194,10 @@ async fn main() {
                     publisher,
                     date,
                     cover_image,
-                    direction
+                    direction,
+                    size,
+                    series_id,
+                    volume
                 ) VALUES (
                     $1,
                     $2,
@@ -180,7 +207,10 @@ async fn main() {
                     $6,
                     $7,
                     $8,
-                    $9
+                    $9,
+                    $10,
+                    $11,
+                    $12
                 )"#,
                 uuid,
                 key,
@@ -191,11 +221,18 @@ async fn main() {
                 metadata.mdata("date").unwrap_or(Local::now().to_rfc3339()),
                 cover_image_key,
                 direction as _,
+                object.size().unwrap_or_default(),
+                series_id,
+                series.and_then(|series| series.volume),
             )
             .execute(&mut *tx)
             .await
             .unwrap();
 
+            add_book_file_hash(&uuid, &file_hash, &mut tx)
+                .await
+                .unwrap();
+
             println!("{}のメタデータを保存しました", key);
 
             // tagをDBに保存する
@@ -223,4 +260,61 @@ async fn main() {
             std::fs::remove_file(&tmp_path).unwrap();
         }
     }
+
+    // サイズのない本(サイズの記録を始める前に登録された本)のサイズを記録する
+    let unsized_books = query!(r#"SELECT id, key FROM books WHERE size = 0"#)
+        .fetch_all(&db_client)
+        .await
+        .unwrap();
+    for book in unsized_books {
+        let size = match minio_client
+            .head_object()
+            .bucket(epub_bucket)
+            .key(&book.key)
+            .send()
+            .await
+        {
+            Ok(output) => output.content_length().unwrap_or_default(),
+            Err(e) => {
+                println!("{}のサイズの取得に失敗しました: {}", book.key, e);
+                continue;
+            }
+        };
+        query!(r#"UPDATE books SET size = $1 WHERE id = $2"#, size, book.id)
+            .execute(&db_client)
+            .await
+            .unwrap();
+    }
+
+    // ハッシュのない本(ハッシュの記録を始める前に登録された本)のハッシュを記録する
+    let unhashed_books = query!(
+        r#"
+            SELECT id, key
+            FROM books b
+            WHERE NOT EXISTS (SELECT 1 FROM book_file_hashes h WHERE h.book_id = b.id)
+        "#
+    )
+    .fetch_all(&db_client)
+    .await
+    .unwrap();
+    for book in unhashed_books {
+        println!("{}のハッシュを計算中...", book.key);
+        let epub = match minio_client
+            .get_object()
+            .bucket(epub_bucket)
+            .key(&book.key)
+            .send()
+            .await
+        {
+            Ok(output) => output.body.collect().await.unwrap().into_bytes(),
+            Err(e) => {
+                println!("{}の取得に失敗しました: {}", book.key, e);
+                continue;
+            }
+        };
+        let mut conn = db_client.acquire().await.unwrap();
+        add_book_file_hash(&book.id, &partial_md5(&epub), &mut conn)
+            .await
+            .unwrap();
+    }
 }
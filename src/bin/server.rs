@@ -1,6 +1,9 @@
+use std::net::SocketAddr;
+
 use epubapi::{
     db::{connect_db, insert_admin_user},
     routes::init_app,
+    service::oidc::model::OidcConfig,
 };
 
 /// Main function
@@ -8,9 +11,16 @@ use epubapi::{
 /// Start axum server
 #[tokio::main]
 async fn main() {
+    // OIDCの設定に不足があれば起動しない
+    OidcConfig::validate_env().expect("Invalid OIDC configuration");
     let db = connect_db().await;
     insert_admin_user(&db).await;
     let router = init_app(&db);
     let listner = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listner, router).await.unwrap();
+    axum::serve(
+        listner,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
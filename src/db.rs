@@ -2,30 +2,39 @@ use std::env;
 
 use sqlx::{postgres::PgPoolOptions, query, Pool, Postgres};
 
-use crate::service::user::model::UserRole;
+use crate::service::{
+    api_key::model::create_admin_api_key,
+    user::model::{hash_password, UserRole},
+};
 
 /// 管理者ユーザーをデータベースに追加する
 pub async fn insert_admin_user(db: &Pool<Postgres>) {
     let admin_id = env::var("ADMIN_ID").expect("ADMIN_ID is not set");
     let admin_password = env::var("ADMIN_PASSWORD").expect("ADMIN_PASSWORD is not set");
-    if let Ok(admin_api_key) = sqlx::query!(r#"SELECT api_key FROM users WHERE id = $1"#, admin_id)
+    if sqlx::query!(r#"SELECT id FROM users WHERE id = $1"#, admin_id)
         .fetch_one(db)
         .await
+        .is_ok()
     {
-        println!("Admin user already exists: {}", admin_api_key.api_key);
+        println!("Admin user already exists");
         return;
     }
-    match query!(
-        r#"INSERT INTO users (id, password, role) VALUES ($1, $2, $3) returning api_key"#,
+    if let Err(e) = query!(
+        r#"INSERT INTO users (id, password, role) VALUES ($1, $2, $3)"#,
         admin_id,
-        admin_password,
+        hash_password(&admin_password),
         UserRole::Admin as UserRole
     )
-    .fetch_one(db)
+    .execute(db)
     .await
     {
-        Ok(row) => println!("Admin api key: {}", row.api_key),
-        Err(e) => println!("Failed to create admin user: {}", e),
+        println!("Failed to create admin user: {}", e);
+        return;
+    }
+    // APIキーはハッシュ化して保存されるため、作成時にのみ表示する
+    match create_admin_api_key(&admin_id, db).await {
+        Ok(key) => println!("Admin api key: {}", key),
+        Err(e) => println!("Failed to create admin api key: {}", e),
     }
 }
 
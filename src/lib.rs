@@ -2,3 +2,4 @@ pub mod db;
 pub mod minio;
 pub mod routes;
 pub mod service;
+pub mod throttle;
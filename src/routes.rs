@@ -5,7 +5,7 @@ use axum::{
     http::{header, Method, StatusCode},
     middleware::Next,
     response::Response,
-    routing::{delete, get, post, put},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use tower_http::cors::CorsLayer;
@@ -14,54 +14,185 @@ use utoipa::OpenApi;
 use utoipa_swagger_ui::SwaggerUi;
 
 use crate::service::{
+    admin::route::{
+        delete_user, get_invitation_tree, get_user, get_users, revoke_invitations, update_user,
+    },
+    api_key::route::{delete_api_key, get_api_keys, new_api_key, rotate_api_key},
     book::route::{
-        add_tag_to_book, delete_book, delete_tag_from_book, get_book, get_books, get_cover_image,
-        new_book, update_book,
+        add_tag_to_book, delete_book, delete_tag_from_book, finish_book, get_book,
+        get_book_manifest, get_book_resource, get_books, get_cover_image, new_book,
+        search_contents, unfinish_book, update_book,
+    },
+    collection::route::{
+        add_book_to_collection, delete_book_from_collection, delete_collection, get_collection,
+        get_collections, new_collection, update_collection,
     },
-    invitation::route::check_invitation,
+    invitation::route::{check_invitation, delete_invitation, get_invitations, new_invitations},
+    kosync::route::{kosync_auth, kosync_create_user, kosync_get_progress, kosync_update_progress},
+    oidc::route::{oidc_callback, oidc_login},
+    opds::route::{
+        opds_books, opds_download, opds_new, opds_opensearch, opds_root, opds_series,
+        opds_series_list, opds_tags,
+    },
+    progress::route::{delete_progress, get_continue_reading, get_progress, update_progress},
+    series::route::{get_next_volume, get_series, get_series_volumes, update_series},
     tag::route::{delete_tag, get_tags, new_tag, update_tag},
-    user::route::{login, new_user, show_user},
+    totp::route::{
+        disable_totp, enroll_totp, get_role_policies, login_totp, login_totp_enroll,
+        regenerate_recovery_codes, update_role_policy, verify_totp,
+    },
+    user::route::{
+        change_password, delete_session, get_sessions, issue_password_reset_token, login, logout,
+        new_user, reset_password, show_user,
+    },
 };
 
 #[derive(OpenApi)]
 #[openapi(
     paths(
         crate::service::invitation::route::check_invitation,
+        crate::service::invitation::route::get_invitations,
+        crate::service::invitation::route::new_invitations,
+        crate::service::invitation::route::delete_invitation,
         crate::service::user::route::login,
+        crate::service::totp::route::login_totp,
+        crate::service::totp::route::login_totp_enroll,
+        crate::service::oidc::route::oidc_login,
+        crate::service::oidc::route::oidc_callback,
         crate::service::user::route::new_user,
         crate::service::user::route::show_user,
+        crate::service::user::route::change_password,
+        crate::service::user::route::issue_password_reset_token,
+        crate::service::user::route::reset_password,
+        crate::service::user::route::logout,
+        crate::service::user::route::get_sessions,
+        crate::service::user::route::delete_session,
+        crate::service::totp::route::enroll_totp,
+        crate::service::totp::route::verify_totp,
+        crate::service::totp::route::disable_totp,
+        crate::service::totp::route::regenerate_recovery_codes,
+        crate::service::totp::route::get_role_policies,
+        crate::service::totp::route::update_role_policy,
+        crate::service::admin::route::get_users,
+        crate::service::admin::route::get_user,
+        crate::service::admin::route::update_user,
+        crate::service::admin::route::delete_user,
+        crate::service::admin::route::revoke_invitations,
+        crate::service::admin::route::get_invitation_tree,
+        crate::service::api_key::route::get_api_keys,
+        crate::service::api_key::route::new_api_key,
+        crate::service::api_key::route::rotate_api_key,
+        crate::service::api_key::route::delete_api_key,
         crate::service::tag::route::get_tags,
         crate::service::tag::route::new_tag,
         crate::service::tag::route::update_tag,
         crate::service::tag::route::delete_tag,
         crate::service::book::route::get_book,
         crate::service::book::route::get_books,
+        crate::service::book::route::search_contents,
         crate::service::book::route::update_book,
         crate::service::book::route::delete_book,
         crate::service::book::route::add_tag_to_book,
         crate::service::book::route::delete_tag_from_book,
+        crate::service::book::route::finish_book,
+        crate::service::book::route::unfinish_book,
+        crate::service::book::route::get_book_manifest,
+        crate::service::book::route::get_book_resource,
+        crate::service::series::route::get_series,
+        crate::service::series::route::get_series_volumes,
+        crate::service::series::route::get_next_volume,
+        crate::service::series::route::update_series,
+        crate::service::collection::route::get_collections,
+        crate::service::collection::route::new_collection,
+        crate::service::collection::route::get_collection,
+        crate::service::collection::route::update_collection,
+        crate::service::collection::route::delete_collection,
+        crate::service::collection::route::add_book_to_collection,
+        crate::service::collection::route::delete_book_from_collection,
+        crate::service::progress::route::get_continue_reading,
+        crate::service::progress::route::get_progress,
+        crate::service::progress::route::update_progress,
+        crate::service::progress::route::delete_progress,
+        crate::service::kosync::route::kosync_create_user,
+        crate::service::kosync::route::kosync_auth,
+        crate::service::kosync::route::kosync_get_progress,
+        crate::service::kosync::route::kosync_update_progress,
+        crate::service::opds::route::opds_root,
+        crate::service::opds::route::opds_new,
+        crate::service::opds::route::opds_books,
+        crate::service::opds::route::opds_tags,
+        crate::service::opds::route::opds_series_list,
+        crate::service::opds::route::opds_series,
+        crate::service::opds::route::opds_opensearch,
+        crate::service::opds::route::opds_download,
     ),
     components(
         schemas(
             crate::service::invitation::model::CheckInvitationRequest,
             crate::service::invitation::model::CheckInvitationResponse,
+            crate::service::invitation::model::Invitation,
+            crate::service::invitation::model::NewInvitationsRequest,
             crate::service::user::model::UserError,
             crate::service::user::model::User,
             crate::service::user::model::LoginRequest,
             crate::service::user::model::ShowUserRequest,
             crate::service::user::model::NewUserRequest,
             crate::service::user::model::LoginRequest,
+            crate::service::user::model::ChangePasswordRequest,
+            crate::service::user::model::PasswordResetTokenResponse,
+            crate::service::user::model::ResetPasswordRequest,
+            crate::service::user::model::Session,
+            crate::service::totp::model::TotpEnrollmentResponse,
+            crate::service::totp::model::TotpCodeRequest,
+            crate::service::totp::model::RecoveryCodesResponse,
+            crate::service::totp::model::LoginChallengeResponse,
+            crate::service::totp::model::LoginTotpEnrollRequest,
+            crate::service::totp::model::LoginTotpRequest,
+            crate::service::totp::model::RolePolicy,
+            crate::service::totp::model::UpdateRolePolicyRequest,
+            crate::service::oidc::model::OidcLoginQuery,
+            crate::service::oidc::model::OidcCallbackQuery,
+            crate::service::admin::model::ManagedUser,
+            crate::service::admin::model::UpdateUserRequest,
+            crate::service::admin::model::DeleteUserQuery,
+            crate::service::admin::model::AuditInvitation,
+            crate::service::admin::model::InvitationTreeNode,
+            crate::service::admin::model::RevokeInvitationsResponse,
+            crate::service::api_key::model::ApiKey,
+            crate::service::api_key::model::ApiKeyScope,
+            crate::service::api_key::model::NewApiKeyRequest,
+            crate::service::api_key::model::NewApiKeyResponse,
             crate::service::tag::model::Tag,
             crate::service::tag::model::NewTagRequest,
             crate::service::book::model::GetBookDetailsResponse,
             crate::service::book::model::GetBooksResponse,
+            crate::service::book::model::GetBooksPage,
             crate::service::book::model::BookQuery,
+            crate::service::book::model::ContentQuery,
+            crate::service::book::model::ContentSearchResponse,
+            crate::service::book::model::ContentMatch,
             crate::service::book::model::Visibility,
             crate::service::book::model::Direction,
             crate::service::book::model::UpdateBookRequest,
             crate::service::book::model::AddTagRequest,
             crate::service::book::model::DeleteTagRequest,
             crate::service::book::model::DeleteBookRequest,
+            crate::service::series::model::Series,
+            crate::service::series::model::SeriesVolume,
+            crate::service::series::model::UpdateSeriesRequest,
+            crate::service::collection::model::Collection,
+            crate::service::collection::model::NewCollectionRequest,
+            crate::service::collection::model::UpdateCollectionRequest,
+            crate::service::collection::model::AddCollectionBookRequest,
+            crate::service::progress::model::ReadingProgress,
+            crate::service::progress::model::UpdateProgressRequest,
+            crate::service::progress::model::ContinueReading,
+            crate::service::progress::model::ContinueReadingQuery,
+            crate::service::kosync::model::KosyncError,
+            crate::service::kosync::model::KosyncAuthResponse,
+            crate::service::kosync::model::KosyncProgress,
+            crate::service::kosync::model::UpdateKosyncProgressRequest,
+            crate::service::kosync::model::UpdateKosyncProgressResponse,
         )
     ),
     tags(
@@ -75,9 +206,45 @@ pub fn init_app(db: &sqlx::PgPool) -> Router {
     Router::new()
         .route("/", get(health))
         .route("/users", get(show_user).post(new_user))
+        .route("/users/password", put(change_password))
+        .route(
+            "/users/{user_id}/password_reset",
+            post(issue_password_reset_token),
+        )
         .route("/login", post(login))
+        .route("/login/totp", post(login_totp))
+        .route("/login/totp/enroll", post(login_totp_enroll))
+        .route("/logout", post(logout))
+        .route("/oidc/login", get(oidc_login))
+        .route("/oidc/callback", get(oidc_callback))
+        .route("/users/totp", post(enroll_totp).delete(disable_totp))
+        .route("/users/totp/verify", post(verify_totp))
+        .route(
+            "/users/totp/recovery_codes",
+            post(regenerate_recovery_codes),
+        )
+        .route("/role_policies", get(get_role_policies))
+        .route("/role_policies/{role}", put(update_role_policy))
+        .route("/sessions", get(get_sessions))
+        .route("/sessions/{session_id}", delete(delete_session))
+        .route("/password_reset", post(reset_password))
+        .route("/admin/users", get(get_users))
+        .route(
+            "/admin/users/{user_id}",
+            get(get_user).patch(update_user).delete(delete_user),
+        )
+        .route(
+            "/admin/users/{user_id}/invitations",
+            delete(revoke_invitations),
+        )
+        .route("/admin/invitations", get(get_invitation_tree))
+        .route("/api_keys", get(get_api_keys).post(new_api_key))
+        .route("/api_keys/{api_key_id}", delete(delete_api_key))
+        .route("/api_keys/{api_key_id}/rotate", post(rotate_api_key))
         .route("/epubs", post(new_book))
         .route("/check_invitation", post(check_invitation))
+        .route("/invitations", get(get_invitations).post(new_invitations))
+        .route("/invitations/{code}", delete(delete_invitation))
         .route("/tags", get(get_tags).post(new_tag))
         .route("/tags/{name}", put(update_tag).delete(delete_tag))
         .route(
@@ -90,12 +257,66 @@ pub fn init_app(db: &sqlx::PgPool) -> Router {
             "/books/{book_id}",
             get(get_book).patch(update_book).delete(delete_book),
         )
+        .route("/contents", get(search_contents))
         .route("/covers/{book_id}", get(get_cover_image))
         .route("/books/{book_id}/tags", post(add_tag_to_book))
         .route(
             "/books/{book_id}/tags/{tag_name}",
             delete(delete_tag_from_book),
         )
+        .route(
+            "/books/{book_id}/finished",
+            put(finish_book).delete(unfinish_book),
+        )
+        .route("/books/{book_id}/manifest.json", get(get_book_manifest))
+        .route("/books/{book_id}/resources/{*path}", get(get_book_resource))
+        .route(
+            "/books/{book_id}/progress",
+            get(get_progress)
+                .put(update_progress)
+                .delete(delete_progress),
+        )
+        .route("/progress", get(get_continue_reading))
+        .route("/kosync/users/create", post(kosync_create_user))
+        .route("/kosync/users/auth", get(kosync_auth))
+        .route("/kosync/syncs/progress", put(kosync_update_progress))
+        .route(
+            "/kosync/syncs/progress/{document}",
+            get(kosync_get_progress),
+        )
+        .route("/opds", get(opds_root))
+        .route("/opds/v2", get(opds_root))
+        .route("/opds/new", get(opds_new))
+        .route("/opds/v2/new", get(opds_new))
+        .route("/opds/books", get(opds_books))
+        .route("/opds/v2/books", get(opds_books))
+        .route("/opds/tags", get(opds_tags))
+        .route("/opds/v2/tags", get(opds_tags))
+        .route("/opds/series", get(opds_series_list))
+        .route("/opds/v2/series", get(opds_series_list))
+        .route("/opds/series/{series_id}", get(opds_series))
+        .route("/opds/v2/series/{series_id}", get(opds_series))
+        .route("/opds/opensearch.xml", get(opds_opensearch))
+        .route("/opds/books/{book_id}/download", get(opds_download))
+        .route("/series", get(get_series))
+        .route("/series/{series_id}", patch(update_series))
+        .route("/series/{series_id}/books", get(get_series_volumes))
+        .route("/series/{series_id}/next", get(get_next_volume))
+        .route("/collections", get(get_collections).post(new_collection))
+        .route(
+            "/collections/{collection_id}",
+            get(get_collection)
+                .patch(update_collection)
+                .delete(delete_collection),
+        )
+        .route(
+            "/collections/{collection_id}/books",
+            post(add_book_to_collection),
+        )
+        .route(
+            "/collections/{collection_id}/books/{book_id}",
+            delete(delete_book_from_collection),
+        )
         .layer(axum::middleware::from_fn(access_log_on_request))
         .layer(
             CorsLayer::new()
@@ -106,7 +327,13 @@ pub fn init_app(db: &sqlx::PgPool) -> Router {
                     header::CONTENT_TYPE,
                     header::COOKIE,
                 ])
-                .allow_methods([Method::GET, Method::POST, Method::PATCH, Method::DELETE])
+                .allow_methods([
+                    Method::GET,
+                    Method::POST,
+                    Method::PUT,
+                    Method::PATCH,
+                    Method::DELETE,
+                ])
                 .allow_origin(
                     env::var("ALLOW_ORIGINS")
                         .unwrap_or("http://localhost:3000".to_string())
@@ -1,4 +1,13 @@
+pub mod admin;
+pub mod api_key;
 pub mod book;
+pub mod collection;
 pub mod invitation;
+pub mod kosync;
+pub mod oidc;
+pub mod opds;
+pub mod progress;
+pub mod series;
 pub mod tag;
+pub mod totp;
 pub mod user;
@@ -0,0 +1,46 @@
+insert into
+    books(
+        id,
+        "key",
+        owner_id,
+        "name",
+        creator,
+        publisher,
+        "date",
+        cover_image,
+        "size"
+    )
+values
+    (
+        'user_book_id_1',
+        'user_id/user_book_id_1.epub',
+        'user_id',
+        'user_book_name_1',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'user_book_id_1.avif',
+        1024
+    ),
+    (
+        'user_book_id_2',
+        'user_id/user_book_id_2.epub',
+        'user_id',
+        'user_book_name_2',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'user_book_id_2.avif',
+        2048
+    ),
+    (
+        'other_user_book_id',
+        'other_user_id/other_user_book_id.epub',
+        'other_user_id',
+        'other_user_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'other_user_book_id.avif',
+        512
+    );
@@ -0,0 +1,5 @@
+insert into
+    invitations(code, issuer_id, expires_at)
+values
+    ('user_unused_code', 'user_id', null),
+    ('user_expired_code', 'user_id', '2020-01-01 00:00:00+00');
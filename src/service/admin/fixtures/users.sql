@@ -0,0 +1,41 @@
+insert into
+    users(id, "password", role)
+values
+    (
+        'user_id',
+        'user_password',
+        'user'
+    ),
+    (
+        'other_user_id',
+        'other_user_password',
+        'user'
+    ),
+    (
+        'admin_id',
+        'admin_password',
+        'admin'
+    );
+
+insert into
+    api_keys(user_id, "name", key_hash, prefix, scopes)
+values
+    (
+        'user_id',
+        'default',
+        encode(sha256(convert_to('user_api_key', 'UTF8')), 'hex'),
+        left('user_api_key', 8),
+        '{admin}'
+    ),
+    (
+        'admin_id',
+        'default',
+        encode(sha256(convert_to('admin_api_key', 'UTF8')), 'hex'),
+        left('admin_api_key', 8),
+        '{admin}'
+    );
+
+insert into
+    invitations(code, issuer_id)
+values
+    ('user_invitation_code', 'user_id');
@@ -0,0 +1,441 @@
+use std::env;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{minio, service::user::model::UserRole};
+
+/// 管理者向けのユーザー情報
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ManagedUser {
+    pub id: String,
+    #[schema(inline)]
+    pub role: UserRole,
+    /// アカウントの停止日時(停止されていない場合は `null`)
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub suspended_at: Option<DateTime<Utc>>,
+    /// 所有しているbookの数
+    pub book_count: i64,
+    /// 所有しているEPUBの合計サイズ(バイト)
+    pub storage_usage: i64,
+    /// 招待コードの発行上限(`null` の場合は `INVITATION_QUOTA`)
+    pub invitation_quota: Option<i32>,
+    /// 所属しているグループ
+    pub groups: Vec<String>,
+}
+
+/// `PATCH /admin/users/{user_id}` のリクエストボディ
+///
+/// 指定した項目のみを更新する
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UpdateUserRequest {
+    #[schema(inline)]
+    pub role: Option<UserRole>,
+    /// trueでアカウントを停止、falseで停止を解除する
+    pub suspended: Option<bool>,
+    /// 招待コードの発行上限
+    pub invitation_quota: Option<i32>,
+}
+
+/// `DELETE /admin/users/{user_id}` のクエリパラメータ
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct DeleteUserQuery {
+    /// bookの移譲先のユーザーID(省略時はbookとS3のオブジェクトを削除する)
+    pub transfer_to: Option<String>,
+}
+
+/// 招待コードの状態(有効期限切れを含む)
+///
+/// - `unused`: 未使用
+/// - `using`: 残りの使用可能回数がすべて登録手続き中
+/// - `used`: 使用済み
+/// - `expired`: 未使用のまま有効期限が切れた
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AuditInvitation {
+    pub code: String,
+    pub state: String,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub used_at: Option<DateTime<Utc>>,
+    /// 使用可能な回数
+    pub max_uses: i32,
+    /// 使用された回数
+    pub use_count: i32,
+    /// この招待コードで登録したユーザーのID
+    pub used_by: Vec<String>,
+}
+
+/// 招待ツリーのノード
+///
+/// `invited_by` が親ノードのユーザーIDで、`null` の場合は根
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct InvitationTreeNode {
+    pub user_id: String,
+    pub invited_by: Option<String>,
+    /// 登録時に使用した招待コード
+    pub invitation_code: Option<String>,
+    /// 根からの深さ(根は0)
+    pub depth: i32,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+    /// このユーザーが発行した招待コード
+    #[schema(inline)]
+    pub invitations: Vec<AuditInvitation>,
+}
+
+/// `DELETE /admin/users/{user_id}/invitations` のレスポンス
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RevokeInvitationsResponse {
+    /// 取り消した招待コードの数
+    pub revoked: i64,
+}
+
+/// ユーザーの一覧を取得する
+pub async fn get_users(db: &PgPool) -> Result<Vec<ManagedUser>, sqlx::Error> {
+    sqlx::query_as!(
+        ManagedUser,
+        r#"
+            SELECT
+                u.id,
+                u.role as "role: UserRole",
+                u.suspended_at,
+                count(b.id) as "book_count!",
+                coalesce(sum(b.size), 0)::bigint as "storage_usage!",
+                u.invitation_quota,
+                coalesce(
+                    (SELECT array_agg(g.group_name ORDER BY g.group_name) FROM user_groups g WHERE g.user_id = u.id),
+                    '{}'
+                ) as "groups!"
+            FROM users u
+            LEFT JOIN books b ON b.owner_id = u.id
+            GROUP BY u.id
+            ORDER BY u.id
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// ユーザーを取得する
+pub async fn get_user(user_id: &str, db: &PgPool) -> Result<ManagedUser, sqlx::Error> {
+    sqlx::query_as!(
+        ManagedUser,
+        r#"
+            SELECT
+                u.id,
+                u.role as "role: UserRole",
+                u.suspended_at,
+                count(b.id) as "book_count!",
+                coalesce(sum(b.size), 0)::bigint as "storage_usage!",
+                u.invitation_quota,
+                coalesce(
+                    (SELECT array_agg(g.group_name ORDER BY g.group_name) FROM user_groups g WHERE g.user_id = u.id),
+                    '{}'
+                ) as "groups!"
+            FROM users u
+            LEFT JOIN books b ON b.owner_id = u.id
+            WHERE u.id = $1
+            GROUP BY u.id
+        "#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// ユーザーのロール・停止状態を更新する
+///
+/// 操作している管理者自身は変更できない
+pub async fn update_user(
+    admin_id: &str,
+    user_id: &str,
+    req: UpdateUserRequest,
+    db: &PgPool,
+) -> Result<ManagedUser, String> {
+    if admin_id == user_id {
+        return Err("自分自身は変更できません".to_string());
+    }
+    if req.invitation_quota.is_some_and(|quota| quota < 0) {
+        return Err("招待コードの発行上限は0以上で指定してください".to_string());
+    }
+    sqlx::query!(
+        r#"
+            UPDATE users
+            SET
+                role = coalesce($1, role),
+                suspended_at = CASE
+                    WHEN $2::boolean IS NULL THEN suspended_at
+                    WHEN $2 THEN coalesce(suspended_at, now())
+                    ELSE NULL
+                END,
+                invitation_quota = coalesce($3, invitation_quota)
+            WHERE id = $4
+            RETURNING id
+        "#,
+        req.role as Option<UserRole>,
+        req.suspended,
+        req.invitation_quota,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("ユーザーが見つかりません")?;
+
+    get_user(user_id, db).await.map_err(|e| e.to_string())
+}
+
+/// ユーザーを削除する
+///
+/// transfer_to を指定した場合はbookと発行した招待コードをそのユーザーに移譲し、
+/// そうでなければbookとS3のオブジェクトを削除する
+pub async fn delete_user(
+    admin_id: &str,
+    user_id: &str,
+    transfer_to: Option<&str>,
+    db: &PgPool,
+) -> Result<(), String> {
+    if admin_id == user_id {
+        return Err("自分自身は削除できません".to_string());
+    }
+    if transfer_to == Some(user_id) {
+        return Err("削除するユーザーには移譲できません".to_string());
+    }
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    sqlx::query!(r#"SELECT id FROM users WHERE id = $1 FOR UPDATE"#, user_id)
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("ユーザーが見つかりません")?;
+
+    let (objects, resource_keys) = match transfer_to {
+        Some(transfer_to) => {
+            sqlx::query!(r#"SELECT id FROM users WHERE id = $1"#, transfer_to)
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or("移譲先のユーザーが見つかりません")?;
+            sqlx::query!(
+                r#"UPDATE books SET owner_id = $1 WHERE owner_id = $2"#,
+                transfer_to,
+                user_id
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| e.to_string())?;
+            sqlx::query!(
+                r#"UPDATE invitations SET issuer_id = $1 WHERE issuer_id = $2"#,
+                transfer_to,
+                user_id
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| e.to_string())?;
+            (vec![], vec![])
+        }
+        None => {
+            // book_resources は本とともに削除されるため、先にS3のキーを集めておく
+            let resource_keys = sqlx::query_scalar!(
+                r#"
+                    SELECT key
+                    FROM book_resources
+                    WHERE book_id IN (SELECT id FROM books WHERE owner_id = $1)
+                "#,
+                user_id
+            )
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(|e| e.to_string())?;
+            let books = sqlx::query!(
+                r#"DELETE FROM books WHERE owner_id = $1 RETURNING key, cover_image, images"#,
+                user_id
+            )
+            .fetch_all(&mut *transaction)
+            .await
+            .map_err(|e| e.to_string())?;
+            sqlx::query!(r#"DELETE FROM invitations WHERE issuer_id = $1"#, user_id)
+                .execute(&mut *transaction)
+                .await
+                .map_err(|e| e.to_string())?;
+            let objects = books
+                .into_iter()
+                .map(|book| (book.key, book.cover_image, book.images))
+                .collect();
+            (objects, resource_keys)
+        }
+    };
+
+    sqlx::query!(r#"DELETE FROM users WHERE id = $1"#, user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    if !objects.is_empty() {
+        delete_objects(objects, resource_keys).await;
+    }
+    Ok(())
+}
+
+/// 削除したbookのEPUB・カバー画像・ページ画像・展開したリソースをS3から削除する
+///
+/// DBからは削除済みのため、失敗した場合はログを出力するのみ
+async fn delete_objects(objects: Vec<(String, String, Vec<String>)>, resource_keys: Vec<String>) {
+    let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+    let minio_client = minio::get_client(&endpoint).await;
+    let epub_bucket = env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set");
+    let out_images_bucket = env::var("OUT_IMAGES_BUCKET").expect("OUT_IMAGES_BUCKET is not set");
+
+    let keys = objects
+        .into_iter()
+        .flat_map(|(key, cover_image, images)| {
+            [(&epub_bucket, key), (&epub_bucket, cover_image)]
+                .into_iter()
+                .chain(images.into_iter().map(|image| (&out_images_bucket, image)))
+        })
+        .chain(resource_keys.into_iter().map(|key| (&epub_bucket, key)))
+        .collect::<Vec<_>>();
+    for (bucket, key) in keys {
+        if let Err(e) = minio_client
+            .delete_object()
+            .bucket(bucket)
+            .key(&key)
+            .send()
+            .await
+        {
+            log::error!("Failed to delete object {}: {}", key, e);
+        }
+    }
+}
+
+/// 招待ツリーを取得する
+///
+/// 親ノードが子ノードより先になるよう、深さ順に返す
+pub async fn get_invitation_tree(db: &PgPool) -> Result<Vec<InvitationTreeNode>, sqlx::Error> {
+    let users = sqlx::query!(
+        r#"
+            WITH RECURSIVE tree AS (
+                SELECT u.id, NULL::text as invited_by, u.invitation_code, 0 as depth, u.created_at
+                FROM users u
+                LEFT JOIN invitations i ON i.code = u.invitation_code
+                WHERE i.code IS NULL
+                UNION ALL
+                SELECT u.id, i.issuer_id, u.invitation_code, tree.depth + 1, u.created_at
+                FROM users u
+                JOIN invitations i ON i.code = u.invitation_code
+                JOIN tree ON tree.id = i.issuer_id
+            )
+            SELECT
+                id as "id!",
+                invited_by,
+                invitation_code,
+                depth as "depth!",
+                created_at as "created_at!"
+            FROM tree
+            ORDER BY depth, created_at, id
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    let invitations = sqlx::query!(
+        r#"
+            SELECT
+                i.issuer_id,
+                i.code,
+                CASE
+                    WHEN i.state = 'used' THEN 'used'
+                    WHEN i.expires_at <= now() THEN 'expired'
+                    WHEN i.use_count + (
+                        SELECT count(*)
+                        FROM invitation_reservations r
+                        WHERE r.code = i.code AND r.reserved_until > now()
+                    ) >= i.max_uses THEN 'using'
+                    ELSE 'unused'
+                END as "state!",
+                i.max_uses,
+                i.use_count,
+                i.created_at,
+                i.expires_at,
+                i.used_at AT TIME ZONE 'UTC' as used_at,
+                coalesce(
+                    array_agg(u.id ORDER BY u.created_at) FILTER (WHERE u.id IS NOT NULL),
+                    '{}'
+                ) as "used_by!"
+            FROM invitations i
+            LEFT JOIN users u ON u.invitation_code = i.code
+            GROUP BY i.code
+            ORDER BY i.created_at, i.code
+        "#
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(users
+        .into_iter()
+        .map(|user| InvitationTreeNode {
+            invitations: invitations
+                .iter()
+                .filter(|i| i.issuer_id == user.id)
+                .map(|i| AuditInvitation {
+                    code: i.code.clone(),
+                    state: i.state.clone(),
+                    created_at: i.created_at,
+                    expires_at: i.expires_at,
+                    used_at: i.used_at,
+                    max_uses: i.max_uses,
+                    use_count: i.use_count,
+                    used_by: i.used_by.clone(),
+                })
+                .collect(),
+            user_id: user.id,
+            invited_by: user.invited_by,
+            invitation_code: user.invitation_code,
+            depth: user.depth,
+            created_at: user.created_at,
+        })
+        .collect())
+}
+
+/// ユーザーが発行した使用済みでない招待コードをすべて取り消す
+///
+/// 一度も使用されていない招待コードは削除し、使用されているものはそれ以上使用できないようにする
+pub async fn revoke_invitations(user_id: &str, db: &PgPool) -> Result<i64, sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let deleted = sqlx::query!(
+        r#"DELETE FROM invitations WHERE issuer_id = $1 AND state != 'used' AND use_count = 0"#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    let closed = sqlx::query!(
+        r#"
+            UPDATE invitations
+            SET state = 'used', max_uses = use_count
+            WHERE issuer_id = $1 AND state != 'used'
+        "#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    sqlx::query!(
+        r#"
+            DELETE FROM invitation_reservations r
+            USING invitations i
+            WHERE r.code = i.code AND i.issuer_id = $1
+        "#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok((deleted + closed) as i64)
+}
@@ -0,0 +1,418 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use sqlx::PgPool;
+
+use super::model;
+use crate::service::user::{auth::AdminUser, model::UserError};
+
+/// ユーザーの一覧を取得する
+///
+/// 管理者のみ実行可能
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::ManagedUser>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn get_users(_: AdminUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::get_users(&db).await {
+        Ok(users) => (StatusCode::OK, Json(users)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// ユーザーを取得する
+///
+/// 管理者のみ実行可能
+#[utoipa::path(
+    get,
+    path = "/admin/users/{user_id}",
+    responses(
+        (status = 200, description = "OK", body = inline(model::ManagedUser)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn get_user(
+    _: AdminUser,
+    Path(user_id): Path<String>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_user(&user_id, &db).await {
+        Ok(user) => (StatusCode::OK, Json(user)).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
+/// ユーザーのロール・停止状態を更新する
+///
+/// 停止したユーザーはセッション・APIキーでの認証とログインができなくなる。
+/// 管理者のみ実行可能
+#[utoipa::path(
+    patch,
+    path = "/admin/users/{user_id}",
+    request_body = inline(model::UpdateUserRequest),
+    responses(
+        (status = 200, description = "OK", body = inline(model::ManagedUser)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidUserOperation(String::from("自分自身は変更できません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn update_user(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<String>,
+    State(db): State<PgPool>,
+    Json(body): Json<model::UpdateUserRequest>,
+) -> impl IntoResponse {
+    match model::update_user(&admin.id, &user_id, body, &db).await {
+        Ok(user) => (StatusCode::OK, Json(user)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidUserOperation(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// ユーザーを削除する
+///
+/// transfer_toを指定するとbookをそのユーザーに移譲し、指定しなければbookとS3のオブジェクトを削除する。
+/// 管理者のみ実行可能
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{user_id}",
+    params(model::DeleteUserQuery),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidUserOperation(String::from("移譲先のユーザーが見つかりません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn delete_user(
+    AdminUser(admin): AdminUser,
+    Path(user_id): Path<String>,
+    Query(query): Query<model::DeleteUserQuery>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::delete_user(&admin.id, &user_id, query.transfer_to.as_deref(), &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidUserOperation(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// 招待ツリーを取得する
+///
+/// 誰が誰を招待したかと、各ユーザーが発行した招待コードの状態を返す。
+/// 管理者のみ実行可能
+#[utoipa::path(
+    get,
+    path = "/admin/invitations",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::InvitationTreeNode>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn get_invitation_tree(_: AdminUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::get_invitation_tree(&db).await {
+        Ok(tree) => (StatusCode::OK, Json(tree)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// ユーザーが発行した使用済みでない招待コードをすべて取り消す
+///
+/// 管理者のみ実行可能
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{user_id}/invitations",
+    responses(
+        (status = 200, description = "OK", body = inline(model::RevokeInvitationsResponse)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn revoke_invitations(
+    _: AdminUser,
+    Path(user_id): Path<String>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::revoke_invitations(&user_id, &db).await {
+        Ok(revoked) => (
+            StatusCode::OK,
+            Json(model::RevokeInvitationsResponse { revoked }),
+        )
+            .into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Method, Request},
+        Router,
+    };
+    use serde_json::{json, to_string, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
+
+    async fn send(
+        router: &Router,
+        method: Method,
+        uri: &str,
+        cookie: &str,
+        body: Option<Value>,
+    ) -> (u16, Value) {
+        let req = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header(header::COOKIE, cookie)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(match body {
+                Some(body) => Body::from(to_string(&body).unwrap()),
+                None => Body::empty(),
+            })
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status().as_u16();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    }
+
+    #[sqlx::test(fixtures("users", "books"))]
+    async fn test_admin_users(pool: PgPool) {
+        let router = init_app(&pool);
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+
+        // 管理者以外は実行できない
+        let (status, _) = send(&router, Method::GET, "/admin/users", &user_cookie, None).await;
+        assert_eq!(status, 401);
+
+        // 一覧の取得
+        let (status, users) = send(&router, Method::GET, "/admin/users", &admin_cookie, None).await;
+        assert_eq!(status, 200);
+        let user = users
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|u| u["id"] == "user_id")
+            .unwrap();
+        assert_eq!(user["book_count"], 2);
+        assert_eq!(user["storage_usage"], 3072);
+        assert_eq!(user["role"], "User");
+
+        // ロールの変更
+        let (status, user) = send(
+            &router,
+            Method::PATCH,
+            "/admin/users/other_user_id",
+            &admin_cookie,
+            Some(json!({"role": "Admin"})),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(user["role"], "Admin");
+
+        // 自分自身は変更できない
+        let (status, _) = send(
+            &router,
+            Method::PATCH,
+            "/admin/users/admin_id",
+            &admin_cookie,
+            Some(json!({"role": "User"})),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // 停止したユーザーはセッション・APIキーで認証できない
+        let (status, user) = send(
+            &router,
+            Method::PATCH,
+            "/admin/users/user_id",
+            &admin_cookie,
+            Some(json!({"suspended": true})),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(user["suspended_at"].is_string());
+        let (status, _) = send(&router, Method::GET, "/users", &user_cookie, None).await;
+        assert_eq!(status, 401);
+        let req = Request::builder()
+            .uri("/users")
+            .header("X-Api-Key", "user_api_key")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // 停止を解除すると再び認証できる
+        let (status, _) = send(
+            &router,
+            Method::PATCH,
+            "/admin/users/user_id",
+            &admin_cookie,
+            Some(json!({"suspended": false})),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let (status, _) = send(&router, Method::GET, "/users", &user_cookie, None).await;
+        assert_eq!(status, 200);
+
+        // bookを移譲して削除する
+        let (status, _) = send(
+            &router,
+            Method::DELETE,
+            "/admin/users/user_id?transfer_to=not_found",
+            &admin_cookie,
+            None,
+        )
+        .await;
+        assert_eq!(status, 400);
+        let (status, _) = send(
+            &router,
+            Method::DELETE,
+            "/admin/users/user_id?transfer_to=other_user_id",
+            &admin_cookie,
+            None,
+        )
+        .await;
+        assert_eq!(status, 204);
+        let (status, user) = send(
+            &router,
+            Method::GET,
+            "/admin/users/other_user_id",
+            &admin_cookie,
+            None,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(user["book_count"], 3);
+        let (status, _) = send(
+            &router,
+            Method::GET,
+            "/admin/users/user_id",
+            &admin_cookie,
+            None,
+        )
+        .await;
+        assert_eq!(status, 404);
+
+        // bookごと削除する
+        let (status, _) = send(
+            &router,
+            Method::DELETE,
+            "/admin/users/other_user_id",
+            &admin_cookie,
+            None,
+        )
+        .await;
+        assert_eq!(status, 204);
+        let count = sqlx::query_scalar!(r#"SELECT count(*) as "count!" FROM books"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[sqlx::test(fixtures("users", "invitations"))]
+    async fn test_invitation_tree(pool: PgPool) {
+        let router = init_app(&pool);
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+
+        // 招待コードでユーザーを登録する
+        let (_, reservation) = send(
+            &router,
+            Method::POST,
+            "/check_invitation",
+            "",
+            Some(json!({"invitation_code": "user_invitation_code"})),
+        )
+        .await;
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!({
+                    "id": "invited_user_id",
+                    "password": "Test1234",
+                    "invitation_code": "user_invitation_code",
+                    "reservation_token": reservation["reservation_token"]
+                }))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+
+        let (status, tree) = send(
+            &router,
+            Method::GET,
+            "/admin/invitations",
+            &admin_cookie,
+            None,
+        )
+        .await;
+        assert_eq!(status, 200);
+        let nodes = tree.as_array().unwrap();
+        let invited = nodes
+            .iter()
+            .find(|n| n["user_id"] == "invited_user_id")
+            .unwrap();
+        assert_eq!(invited["invited_by"], "user_id");
+        assert_eq!(invited["invitation_code"], "user_invitation_code");
+        assert_eq!(invited["depth"], 1);
+        let user = nodes.iter().find(|n| n["user_id"] == "user_id").unwrap();
+        assert_eq!(user["depth"], 0);
+        let states = user["invitations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| (i["code"].as_str().unwrap(), i["state"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert!(states.contains(&("user_invitation_code", "used")));
+        assert!(states.contains(&("user_unused_code", "unused")));
+        assert!(states.contains(&("user_expired_code", "expired")));
+        let used = user["invitations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|i| i["code"] == "user_invitation_code")
+            .unwrap();
+        assert_eq!(used["used_by"], json!(["invited_user_id"]));
+
+        // 使用済みでない招待コードをすべて取り消す
+        let (status, res) = send(
+            &router,
+            Method::DELETE,
+            "/admin/users/user_id/invitations",
+            &admin_cookie,
+            None,
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(res["revoked"], 2);
+        let codes =
+            sqlx::query_scalar!(r#"SELECT code FROM invitations WHERE issuer_id = 'user_id'"#)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(codes, vec!["user_invitation_code".to_string()]);
+    }
+}
@@ -0,0 +1,33 @@
+insert into
+    users(id, "password", role)
+values
+    (
+        'user_id',
+        'user_password',
+        'user'
+    ),
+    (
+        'other_user_id',
+        'other_user_password',
+        'user'
+    );
+
+insert into
+    api_keys(user_id, "name", key_hash, prefix, scopes, expires_at)
+values
+    (
+        'user_id',
+        'default',
+        encode(sha256(convert_to('user_api_key', 'UTF8')), 'hex'),
+        left('user_api_key', 8),
+        '{admin}',
+        null
+    ),
+    (
+        'user_id',
+        'expired',
+        encode(sha256(convert_to('expired_api_key', 'UTF8')), 'hex'),
+        left('expired_api_key', 8),
+        '{read}',
+        current_timestamp - interval '1 day'
+    );
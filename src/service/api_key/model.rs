@@ -0,0 +1,327 @@
+use chrono::{DateTime, Utc};
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::service::user::model::UserRole;
+
+/// APIキーの権限
+///
+/// - `read`: 閲覧のみ
+/// - `upload`: bookのアップロードのみ
+/// - `write`: 閲覧・アップロード・更新
+/// - `admin`: アカウントのすべての操作(APIキーやセッションの管理を含む)
+#[derive(Serialize, Deserialize, Debug, sqlx::Type, ToSchema, PartialEq, Clone, Copy)]
+#[sqlx(type_name = "api_key_scope", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    Read,
+    Upload,
+    Write,
+    Admin,
+}
+
+impl ApiKeyScope {
+    /// このスコープで `required` の操作ができるかを返す
+    pub fn allows(self, required: ApiKeyScope) -> bool {
+        match self {
+            Self::Admin => true,
+            Self::Write => required != Self::Admin,
+            Self::Read | Self::Upload => self == required,
+        }
+    }
+}
+
+/// 有効なAPIキーの所有者
+pub struct ApiKeyOwner {
+    pub user_id: String,
+    pub role: UserRole,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+/// APIキーの情報(キー自体は含まない)
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ApiKey {
+    #[schema(value_type = String)]
+    pub id: Uuid,
+    pub name: String,
+    /// キーの先頭8文字
+    pub prefix: String,
+    #[schema(inline)]
+    pub scopes: Vec<ApiKeyScope>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// `POST /api_keys` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NewApiKeyRequest {
+    pub name: String,
+    #[schema(inline)]
+    pub scopes: Vec<ApiKeyScope>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// `POST /api_keys` のレスポンス
+///
+/// キーはこのレスポンスでのみ返される
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NewApiKeyResponse {
+    #[schema(inline)]
+    #[serde(flatten)]
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+/// APIキーをハッシュ化する
+pub fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+/// KOReaderがパスワードとして送信するAPIキーのMD5をハッシュ化する
+///
+/// kosyncの認証でMD5のままAPIキーを照合するために使用する
+pub fn hash_kosync_key(key: &str) -> String {
+    hash_api_key(&format!("{:x}", Md5::digest(key.as_bytes())))
+}
+
+/// 新しいAPIキーを生成する
+fn generate_api_key() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// APIキーを作成する
+pub async fn create_api_key(
+    user_id: &str,
+    req: NewApiKeyRequest,
+    db: &PgPool,
+) -> Result<NewApiKeyResponse, String> {
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err("名前は1文字以上100文字以下で入力してください".to_string());
+    }
+    if req.scopes.is_empty() {
+        return Err("スコープを1つ以上指定してください".to_string());
+    }
+    if req
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= Utc::now())
+    {
+        return Err("有効期限は未来の日時を指定してください".to_string());
+    }
+
+    let key = generate_api_key();
+    let api_key = sqlx::query_as!(
+        ApiKey,
+        r#"
+            INSERT INTO api_keys (user_id, name, key_hash, prefix, scopes, expires_at, kosync_key_hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING
+                id,
+                name,
+                prefix,
+                scopes as "scopes: Vec<ApiKeyScope>",
+                expires_at,
+                last_used_at,
+                created_at
+        "#,
+        user_id,
+        name,
+        hash_api_key(&key),
+        &key[..8],
+        &req.scopes as &[ApiKeyScope],
+        req.expires_at,
+        hash_kosync_key(&key)
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(NewApiKeyResponse { api_key, key })
+}
+
+/// 有効なAPIキーの一覧を取得する
+pub async fn get_api_keys(user_id: &str, db: &PgPool) -> Result<Vec<ApiKey>, sqlx::Error> {
+    sqlx::query_as!(
+        ApiKey,
+        r#"
+            SELECT
+                id,
+                name,
+                prefix,
+                scopes as "scopes: Vec<ApiKeyScope>",
+                expires_at,
+                last_used_at,
+                created_at
+            FROM api_keys
+            WHERE
+                user_id = $1
+                AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > now())
+            ORDER BY created_at
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// APIキーを再生成する
+///
+/// 名前・スコープ・有効期限は引き継ぎ、それまでのキーは使用できなくなる
+pub async fn rotate_api_key(
+    user_id: &str,
+    api_key_id: Uuid,
+    db: &PgPool,
+) -> Result<NewApiKeyResponse, sqlx::Error> {
+    let key = generate_api_key();
+    let api_key = sqlx::query_as!(
+        ApiKey,
+        r#"
+            UPDATE api_keys
+            SET key_hash = $1, prefix = $2, kosync_key_hash = $5, last_used_at = NULL
+            WHERE
+                id = $3
+                AND user_id = $4
+                AND revoked_at IS NULL
+                AND (expires_at IS NULL OR expires_at > now())
+            RETURNING
+                id,
+                name,
+                prefix,
+                scopes as "scopes: Vec<ApiKeyScope>",
+                expires_at,
+                last_used_at,
+                created_at
+        "#,
+        hash_api_key(&key),
+        &key[..8],
+        api_key_id,
+        user_id,
+        hash_kosync_key(&key)
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(NewApiKeyResponse { api_key, key })
+}
+
+/// APIキーを無効にする
+///
+/// 該当するキーがなければ `sqlx::Error::RowNotFound` を返す
+pub async fn revoke_api_key(
+    user_id: &str,
+    api_key_id: Uuid,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            UPDATE api_keys
+            SET revoked_at = now()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            RETURNING id
+        "#,
+        api_key_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+/// APIキーを受け取って、有効なキーであればその所有者を返す
+///
+/// 失効・期限切れのキーは使用できない。使用したキーの最終使用日時を更新する
+pub async fn find_api_key_owner(key: &str, db: &PgPool) -> Option<ApiKeyOwner> {
+    sqlx::query_as!(
+        ApiKeyOwner,
+        r#"
+            WITH k AS (
+                UPDATE api_keys
+                SET last_used_at = now()
+                WHERE
+                    key_hash = $1
+                    AND revoked_at IS NULL
+                    AND (expires_at IS NULL OR expires_at > now())
+                RETURNING user_id, scopes
+            )
+            SELECT
+                u.id as "user_id!",
+                u.role as "role!: UserRole",
+                k.scopes as "scopes!: Vec<ApiKeyScope>"
+            FROM k
+            JOIN users u ON u.id = k.user_id
+            WHERE u.suspended_at IS NULL
+        "#,
+        hash_api_key(key)
+    )
+    .fetch_optional(db)
+    .await
+    .ok()
+    .flatten()
+}
+
+/// kosyncの認証情報を受け取って、有効なキーであればその所有者を返す
+///
+/// `key` はAPIキーそのもの、またはKOReaderが送信するAPIキーのMD5のどちらでもよい。
+/// 使用したキーの最終使用日時を更新する
+pub async fn find_kosync_key_owner(user_id: &str, key: &str, db: &PgPool) -> Option<ApiKeyOwner> {
+    sqlx::query_as!(
+        ApiKeyOwner,
+        r#"
+            WITH k AS (
+                UPDATE api_keys
+                SET last_used_at = now()
+                WHERE
+                    user_id = $1
+                    AND (key_hash = $2 OR kosync_key_hash = $2)
+                    AND revoked_at IS NULL
+                    AND (expires_at IS NULL OR expires_at > now())
+                RETURNING user_id, scopes
+            )
+            SELECT
+                u.id as "user_id!",
+                u.role as "role!: UserRole",
+                k.scopes as "scopes!: Vec<ApiKeyScope>"
+            FROM k
+            JOIN users u ON u.id = k.user_id
+            WHERE u.suspended_at IS NULL
+            LIMIT 1
+        "#,
+        user_id,
+        hash_api_key(&key.to_lowercase())
+    )
+    .fetch_optional(db)
+    .await
+    .ok()
+    .flatten()
+}
+
+/// すべての権限を持つAPIキーを作成してキーを返す
+///
+/// 管理者ユーザーの初期化に使用する
+pub async fn create_admin_api_key(user_id: &str, db: &PgPool) -> Result<String, sqlx::Error> {
+    let key = generate_api_key();
+    sqlx::query!(
+        r#"
+            INSERT INTO api_keys (user_id, name, key_hash, prefix, scopes, kosync_key_hash)
+            VALUES ($1, 'default', $2, $3, $4, $5)
+        "#,
+        user_id,
+        hash_api_key(&key),
+        &key[..8],
+        &[ApiKeyScope::Admin] as &[ApiKeyScope],
+        hash_kosync_key(&key)
+    )
+    .execute(db)
+    .await?;
+    Ok(key)
+}
@@ -0,0 +1,310 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::model;
+use crate::service::user::{auth::AccountUser, model::UserError};
+
+/// APIキーの一覧を取得する
+///
+/// キー自体は返さず、先頭8文字のみを返す
+#[utoipa::path(
+    get,
+    path = "/api_keys",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::ApiKey>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_api_keys(
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_api_keys(&user.id, &db).await {
+        Ok(api_keys) => (StatusCode::OK, Json(api_keys)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// APIキーを作成する
+///
+/// キーはこのレスポンスでのみ返される
+#[utoipa::path(
+    post,
+    path = "/api_keys",
+    request_body = inline(model::NewApiKeyRequest),
+    responses(
+        (status = 201, description = "Created", body = inline(model::NewApiKeyResponse)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidApiKey(String::from("スコープを1つ以上指定してください")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn new_api_key(
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::NewApiKeyRequest>,
+) -> impl IntoResponse {
+    match model::create_api_key(&user.id, body, &db).await {
+        Ok(api_key) => (StatusCode::CREATED, Json(api_key)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(UserError::InvalidApiKey(e))).into_response(),
+    }
+}
+
+/// APIキーを再生成する
+///
+/// それまでのキーは使用できなくなり、新しいキーはこのレスポンスでのみ返される
+#[utoipa::path(
+    post,
+    path = "/api_keys/{api_key_id}/rotate",
+    responses(
+        (status = 200, description = "OK", body = inline(model::NewApiKeyResponse)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn rotate_api_key(
+    AccountUser(user): AccountUser,
+    Path(api_key_id): Path<Uuid>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::rotate_api_key(&user.id, api_key_id, &db).await {
+        Ok(api_key) => (StatusCode::OK, Json(api_key)).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
+/// APIキーを無効にする
+#[utoipa::path(
+    delete,
+    path = "/api_keys/{api_key_id}",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn delete_api_key(
+    AccountUser(user): AccountUser,
+    Path(api_key_id): Path<Uuid>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::revoke_api_key(&user.id, api_key_id, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Method, Request},
+    };
+    use serde_json::{json, to_string, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_api_keys(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_token = token_cookie_from_user_id("user_id", &pool).await;
+        let other_token = token_cookie_from_user_id("other_user_id", &pool).await;
+
+        // APIキー作成 認証されていない場合
+        let req = Request::builder()
+            .uri("/api_keys")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!({"name": "reader", "scopes": ["read"]})).unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // APIキー作成 スコープが空の場合
+        let req = Request::builder()
+            .uri("/api_keys")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::COOKIE, &user_token)
+            .body(Body::from(
+                to_string(&json!({"name": "reader", "scopes": []})).unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+
+        // APIキー作成 成功した場合
+        let req = Request::builder()
+            .uri("/api_keys")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::COOKIE, &user_token)
+            .body(Body::from(
+                to_string(&json!({"name": "reader", "scopes": ["read"]})).unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 201);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let created: Value = serde_json::from_slice(&bytes).unwrap();
+        let key = created["key"].as_str().unwrap().to_string();
+        let id = created["id"].as_str().unwrap().to_string();
+        assert_eq!(created["prefix"], key[..8]);
+
+        // キーはハッシュ化して保存される
+        let stored = sqlx::query_scalar!(
+            r#"SELECT count(*) as "count!" FROM api_keys WHERE key_hash = $1"#,
+            key
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(stored, 0);
+
+        // readスコープのキーで閲覧できる
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header("X-Api-Key", &key)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        // readスコープのキーでは更新できない
+        let req = Request::builder()
+            .uri("/tags")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header("X-Api-Key", &key)
+            .body(Body::from(to_string(&json!({"name": "tag"})).unwrap()))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // readスコープのキーではAPIキーを管理できない
+        let req = Request::builder()
+            .uri("/api_keys")
+            .method(Method::GET)
+            .header("X-Api-Key", &key)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // APIキー一覧 キー自体は含まず、使用日時が記録されている
+        let req = Request::builder()
+            .uri("/api_keys")
+            .method(Method::GET)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let api_keys: Vec<Value> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(api_keys.len(), 2);
+        let reader = api_keys.iter().find(|k| k["id"] == id).unwrap();
+        assert!(reader.get("key").is_none());
+        assert!(!reader["last_used_at"].is_null());
+
+        // APIキー再生成 他のユーザーのキーの場合
+        let req = Request::builder()
+            .uri(format!("/api_keys/{}/rotate", id))
+            .method(Method::POST)
+            .header(header::COOKIE, &other_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        // APIキー再生成 成功した場合
+        let req = Request::builder()
+            .uri(format!("/api_keys/{}/rotate", id))
+            .method(Method::POST)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let rotated: Value = serde_json::from_slice(&bytes).unwrap();
+        let new_key = rotated["key"].as_str().unwrap().to_string();
+        assert_ne!(new_key, key);
+        assert_eq!(rotated["name"], "reader");
+
+        // 再生成前のキーは使用できない
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header("X-Api-Key", &key)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // APIキー削除 他のユーザーのキーの場合
+        let req = Request::builder()
+            .uri(format!("/api_keys/{}", id))
+            .method(Method::DELETE)
+            .header(header::COOKIE, &other_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        // APIキー削除 成功した場合
+        let req = Request::builder()
+            .uri(format!("/api_keys/{}", id))
+            .method(Method::DELETE)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+
+        // 削除したキーは使用できない
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header("X-Api-Key", &new_key)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_expired_api_key(pool: PgPool) {
+        let router = init_app(&pool);
+
+        // 期限切れのキーは使用できない
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header("X-Api-Key", "expired_api_key")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // 期限内のキーは使用できる
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header("X-Api-Key", "user_api_key")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+    }
+}
@@ -1,2 +1,6 @@
+pub mod content;
+pub mod manifest;
 pub mod model;
+pub mod opf;
 pub mod route;
+pub mod search;
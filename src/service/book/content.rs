@@ -0,0 +1,103 @@
+/// spineの文書から取り出した本文
+#[derive(Debug, PartialEq)]
+pub struct ChapterText {
+    /// 最初の見出し、なければ `<title>` の内容
+    pub title: Option<String>,
+    pub content: String,
+}
+
+/// 本文として扱わない要素
+const IGNORED_ELEMENTS: [&str; 4] = ["head", "script", "style", "rt"];
+
+/// 見出しとして扱う要素
+const HEADING_ELEMENTS: [&str; 6] = ["h1", "h2", "h3", "h4", "h5", "h6"];
+
+/// XHTMLから本文を取り出す
+///
+/// ルビ(`<rt>`)は読みを本文に混ぜないよう除外し、空白は1文字にまとめる。
+/// XHTMLとして解釈できない場合は `None` を返す
+pub fn extract_text(xhtml: &str) -> Option<ChapterText> {
+    let doc = roxmltree::Document::parse_with_options(
+        xhtml,
+        roxmltree::ParsingOptions {
+            allow_dtd: true,
+            nodes_limit: u32::MAX,
+        },
+    )
+    .ok()?;
+
+    let text_of = |node: roxmltree::Node| {
+        normalize_whitespace(
+            &node
+                .descendants()
+                .filter(|n| n.is_text() && !is_ignored(*n, node))
+                .filter_map(|n| n.text())
+                .collect::<String>(),
+        )
+    };
+
+    let title = doc
+        .descendants()
+        .find(|n| HEADING_ELEMENTS.contains(&n.tag_name().name()))
+        .or_else(|| doc.descendants().find(|n| n.tag_name().name() == "title"))
+        .map(text_of)
+        .filter(|title| !title.is_empty());
+    let content = doc
+        .descendants()
+        .find(|n| n.tag_name().name() == "body")
+        .map(text_of)
+        .unwrap_or_default();
+
+    Some(ChapterText { title, content })
+}
+
+/// `root` の中で、本文から除外する要素の中にあるか
+fn is_ignored(node: roxmltree::Node, root: roxmltree::Node) -> bool {
+    node.ancestors()
+        .take_while(|n| *n != root)
+        .any(|n| IGNORED_ELEMENTS.contains(&n.tag_name().name()))
+}
+
+/// 連続する空白を1文字にまとめ、前後の空白を取り除く
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_text() {
+        let xhtml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>第一章</title><style>p { margin: 0; }</style></head>
+<body>
+  <h1>一</h1>
+  <p><ruby>吾輩<rt>わがはい</rt></ruby>は猫である。</p>
+  <p>名前は
+     まだ無い。</p>
+</body>
+</html>"#;
+        assert_eq!(
+            extract_text(xhtml),
+            Some(ChapterText {
+                title: Some("一".to_string()),
+                content: "一 吾輩は猫である。 名前は まだ無い。".to_string(),
+            })
+        );
+
+        let xhtml =
+            r#"<html><head><title>Cover</title></head><body><img src="cover.jpg"/></body></html>"#;
+        assert_eq!(
+            extract_text(xhtml),
+            Some(ChapterText {
+                title: Some("Cover".to_string()),
+                content: "".to_string(),
+            })
+        );
+
+        assert_eq!(extract_text("<html><body>"), None);
+    }
+}
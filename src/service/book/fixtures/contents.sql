@@ -0,0 +1,8 @@
+insert into
+    book_contents(book_id, "position", href, title, content)
+values
+    ('user_public_book_id', 0, 'OEBPS/cover.xhtml', 'Cover', ''),
+    ('user_public_book_id', 1, 'OEBPS/chapter1.xhtml', '一', '吾輩は猫である。名前はまだ無い。'),
+    ('user_private_book_id', 2, 'OEBPS/chapter2.xhtml', '二', 'どこで生れたかとんと見当がつかぬ。猫の名前はまだ無い。'),
+    ('admin_public_book_id', 0, 'OEBPS/text.xhtml', 'Chapter 1', 'It was a bright cold day in April.'),
+    ('admin_private_book_id', 0, 'OEBPS/text.xhtml', null, '名前のない猫の話');
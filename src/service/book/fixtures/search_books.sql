@@ -0,0 +1,59 @@
+insert into
+    books(
+        id,
+        "key",
+        owner_id,
+        "name",
+        creator,
+        publisher,
+        "date",
+        cover_image,
+        visibility,
+        layout,
+        images
+    )
+values
+    (
+        'cat_book_id',
+        'cat_book_key',
+        'user_id',
+        '吾輩は猫である',
+        '夏目漱石',
+        '青空文庫',
+        '1905-10-01',
+        'book_cover_image',
+        'public',
+        'pre-paginated',
+        '{}'
+    ),
+    (
+        'rust_book_id',
+        'rust_book_key',
+        'user_id',
+        'The Rust Programming Language',
+        'Steve Klabnik',
+        'No Starch Press',
+        '2018-08-06',
+        'book_cover_image',
+        'public',
+        'pre-paginated',
+        '{}'
+    ),
+    (
+        'rust_draft_book_id',
+        'rust_draft_book_key',
+        'user_id',
+        'Rust in Action',
+        'Tim McNamara',
+        'Manning',
+        '2021-08-10',
+        'book_cover_image',
+        'public',
+        'pre-paginated',
+        '{}'
+    );
+
+insert into
+    book_tags(book_id, tag_name)
+values
+    ('rust_draft_book_id', 'additional_tag');
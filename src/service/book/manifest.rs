@@ -0,0 +1,698 @@
+use std::io::{Cursor, Read, Seek};
+
+use serde_json::{json, Map, Value};
+use zip::ZipArchive;
+
+use super::{
+    model::{Book, Direction},
+    opf::rootfile_path,
+};
+
+/// Readium Web Publication Manifestのメディアタイプ
+pub const WEBPUB_MANIFEST_TYPE: &str = "application/webpub+json";
+
+/// Dublin Coreの名前空間
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+
+/// EPUBの `epub:type` などの名前空間
+const OPS_NAMESPACE: &str = "http://www.idpf.org/2007/ops";
+
+const PARSING_OPTIONS: roxmltree::ParsingOptions = roxmltree::ParsingOptions {
+    allow_dtd: true,
+    nodes_limit: u32::MAX,
+};
+
+/// OPFのmanifestの項目
+struct ManifestItem {
+    id: String,
+    /// EPUB内でのパス
+    path: String,
+    media_type: String,
+    properties: Vec<String>,
+}
+
+/// EPUBのファイルを文字列として読み込む
+fn read_string<R: Read + Seek>(archive: &mut ZipArchive<R>, path: &str) -> Result<String, String> {
+    let mut text = String::new();
+    archive
+        .by_name(path)
+        .map_err(|_| format!("{}が見つかりません", path))?
+        .read_to_string(&mut text)
+        .map_err(|e| e.to_string())?;
+    Ok(text)
+}
+
+/// `%XX` の形式でエンコードされた文字列をデコードする
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Ok(byte) = std::str::from_utf8(&bytes[i + 1..i + 3])
+                .map_err(|_| ())
+                .and_then(|hex| u8::from_str_radix(hex, 16).map_err(|_| ()))
+            {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// EPUB内でのパスをURLのパスとしてエンコードする
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// `base_dir` からの相対URLをEPUB内でのパスとフラグメントに解決する
+fn resolve_href(base_dir: &str, href: &str) -> (String, Option<String>) {
+    let (href, fragment) = match href.split_once('#') {
+        Some((href, fragment)) => (href, Some(fragment.to_string())),
+        None => (href, None),
+    };
+    let mut segments = base_dir
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>();
+    let decoded = percent_decode(href);
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    (segments.join("/"), fragment)
+}
+
+/// パスのディレクトリ部分
+fn parent_dir(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(dir, _)| dir)
+}
+
+/// OPFのmanifestの項目を取得する
+fn manifest_items(doc: &roxmltree::Document, opf_dir: &str) -> Vec<ManifestItem> {
+    doc.descendants()
+        .filter(|n| n.tag_name().name() == "item")
+        .filter_map(|n| {
+            Some(ManifestItem {
+                id: n.attribute("id")?.to_string(),
+                path: resolve_href(opf_dir, n.attribute("href")?).0,
+                media_type: n.attribute("media-type")?.to_string(),
+                properties: n
+                    .attribute("properties")
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// APIで配信するリソースのリンク
+fn resource_link(base_href: &str, path: &str, fragment: Option<&str>) -> String {
+    let fragment = fragment.map(|f| format!("#{}", f)).unwrap_or_default();
+    format!("{}/resources/{}{}", base_href, encode_path(path), fragment)
+}
+
+/// EPUBからReadium Web Publication Manifestを作成する
+///
+/// リソースのリンクは `{base_href}/resources/{EPUB内でのパス}` になる。
+/// spineの `linear="no"` の文書はreadingOrderではなくresourcesに含める
+pub fn build_manifest(epub: &[u8], base_href: &str) -> Result<Value, String> {
+    let mut archive = ZipArchive::new(Cursor::new(epub)).map_err(|e| e.to_string())?;
+    let opf_path = rootfile_path(&mut archive)?;
+    let opf = read_string(&mut archive, &opf_path)?;
+    let doc = roxmltree::Document::parse_with_options(&opf, PARSING_OPTIONS)
+        .map_err(|e| e.to_string())?;
+    let opf_dir = parent_dir(&opf_path);
+    let items = manifest_items(&doc, opf_dir);
+    let item_by_id = |id: &str| items.iter().find(|item| item.id == id);
+
+    // メタデータ
+    let metadata_node = doc
+        .descendants()
+        .find(|n| n.tag_name().name() == "metadata")
+        .ok_or("metadataが見つかりません")?;
+    let dc = |name: &'static str| {
+        metadata_node
+            .children()
+            .filter(move |n| n.tag_name().namespace() == Some(DC_NAMESPACE))
+            .filter(move |n| n.tag_name().name() == name)
+            .filter_map(|n| n.text())
+            .map(|text| text.trim().to_string())
+            .filter(|text| !text.is_empty())
+    };
+    let meta_property = |property: &str| {
+        metadata_node
+            .children()
+            .find(|n| n.tag_name().name() == "meta" && n.attribute("property") == Some(property))
+            .and_then(|n| n.text())
+            .map(|text| text.trim().to_string())
+    };
+    let unique_identifier = doc
+        .root_element()
+        .attribute("unique-identifier")
+        .and_then(|id| {
+            metadata_node
+                .children()
+                .find(|n| n.attribute("id") == Some(id))
+                .and_then(|n| n.text())
+        })
+        .map(|text| text.trim().to_string())
+        .or_else(|| dc("identifier").next());
+
+    let spine = doc
+        .descendants()
+        .find(|n| n.tag_name().name() == "spine")
+        .ok_or("spineが見つかりません")?;
+    let reading_progression = match spine.attribute("page-progression-direction") {
+        Some("rtl") => "rtl",
+        Some("ltr") => "ltr",
+        _ => "auto",
+    };
+
+    let mut rendition = Map::new();
+    rendition.insert(
+        "layout".to_string(),
+        json!(match meta_property("rendition:layout").as_deref() {
+            Some("pre-paginated") => "fixed",
+            _ => "reflowable",
+        }),
+    );
+    if let Some(orientation) = meta_property("rendition:orientation") {
+        rendition.insert("orientation".to_string(), json!(orientation));
+    }
+    if let Some(spread) = meta_property("rendition:spread") {
+        // EPUBの `portrait` はReadiumの `both` に相当する
+        let spread = if spread == "portrait" {
+            "both".to_string()
+        } else {
+            spread
+        };
+        rendition.insert("spread".to_string(), json!(spread));
+    }
+    if let Some(flow) = meta_property("rendition:flow") {
+        let overflow = match flow.as_str() {
+            "paginated" => "paginated",
+            "scrolled-continuous" | "scrolled-doc" => "scrolled",
+            _ => "auto",
+        };
+        rendition.insert("overflow".to_string(), json!(overflow));
+    }
+
+    let mut metadata = json!({
+        "@type": "http://schema.org/Book",
+        "conformsTo": "https://readium.org/webpub-manifest/profiles/epub",
+        "title": dc("title").next().unwrap_or_default(),
+        "author": dc("creator").map(|name| json!({ "name": name })).collect::<Vec<_>>(),
+        "language": dc("language").collect::<Vec<_>>(),
+        "readingProgression": reading_progression,
+        "rendition": rendition,
+    });
+    if let Some(identifier) = unique_identifier {
+        metadata["identifier"] = json!(identifier);
+    }
+    if let Some(publisher) = dc("publisher").next() {
+        metadata["publisher"] = json!(publisher);
+    }
+    if let Some(date) = dc("date").next() {
+        metadata["published"] = json!(date);
+    }
+    if let Some(modified) = meta_property("dcterms:modified") {
+        metadata["modified"] = json!(modified);
+    }
+
+    // readingOrder
+    let mut reading_order = vec![];
+    let mut in_reading_order = vec![];
+    for itemref in spine
+        .children()
+        .filter(|n| n.tag_name().name() == "itemref")
+    {
+        let Some(item) = itemref.attribute("idref").and_then(item_by_id) else {
+            continue;
+        };
+        if itemref.attribute("linear") == Some("no") {
+            continue;
+        }
+        let mut link = json!({
+            "href": resource_link(base_href, &item.path, None),
+            "type": item.media_type,
+        });
+        let page = itemref
+            .attribute("properties")
+            .unwrap_or_default()
+            .split_whitespace()
+            .find_map(|property| match property {
+                "page-spread-left" | "rendition:page-spread-left" => Some("left"),
+                "page-spread-right" | "rendition:page-spread-right" => Some("right"),
+                "rendition:page-spread-center" => Some("center"),
+                _ => None,
+            });
+        if let Some(page) = page {
+            link["properties"] = json!({ "page": page });
+        }
+        reading_order.push(link);
+        in_reading_order.push(item.id.as_str());
+    }
+
+    // resources
+    // EPUB2では `<meta name="cover" content="{id}">` でカバー画像を指定する
+    let epub2_cover = metadata_node
+        .children()
+        .find(|n| n.tag_name().name() == "meta" && n.attribute("name") == Some("cover"))
+        .and_then(|n| n.attribute("content"));
+    let resources = items
+        .iter()
+        .filter(|item| !in_reading_order.contains(&item.id.as_str()))
+        .map(|item| {
+            let mut link = json!({
+                "href": resource_link(base_href, &item.path, None),
+                "type": item.media_type,
+            });
+            if item.properties.iter().any(|p| p == "cover-image")
+                || epub2_cover == Some(item.id.as_str())
+            {
+                link["rel"] = json!("cover");
+            } else if item.properties.iter().any(|p| p == "nav") {
+                link["rel"] = json!("contents");
+            }
+            link
+        })
+        .collect::<Vec<_>>();
+
+    // toc
+    let nav = items
+        .iter()
+        .find(|item| item.properties.iter().any(|p| p == "nav"));
+    let ncx = spine.attribute("toc").and_then(item_by_id).or_else(|| {
+        items
+            .iter()
+            .find(|item| item.media_type == "application/x-dtbncx+xml")
+    });
+    let toc = match (nav, ncx) {
+        (Some(nav), _) => {
+            let xhtml = read_string(&mut archive, &nav.path)?;
+            toc_from_nav(&xhtml, parent_dir(&nav.path), base_href)
+        }
+        (None, Some(ncx)) => {
+            let xml = read_string(&mut archive, &ncx.path)?;
+            toc_from_ncx(&xml, parent_dir(&ncx.path), base_href)
+        }
+        (None, None) => vec![],
+    };
+
+    Ok(json!({
+        "@context": "https://readium.org/webpub-manifest/context.jsonld",
+        "metadata": metadata,
+        "links": [{
+            "rel": "self",
+            "href": format!("{}/manifest.json", base_href),
+            "type": WEBPUB_MANIFEST_TYPE,
+        }],
+        "readingOrder": reading_order,
+        "resources": resources,
+        "toc": toc,
+    }))
+}
+
+/// 空白をまとめたテキスト
+fn text_of(node: roxmltree::Node) -> String {
+    node.descendants()
+        .filter(|n| n.is_text())
+        .filter_map(|n| n.text())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// EPUB3のナビゲーション文書から目次を取得する
+///
+/// `epub:type="toc"` の `<nav>` を優先し、なければ最初の `<nav>` を使う
+fn toc_from_nav(xhtml: &str, nav_dir: &str, base_href: &str) -> Vec<Value> {
+    let Ok(doc) = roxmltree::Document::parse_with_options(xhtml, PARSING_OPTIONS) else {
+        return vec![];
+    };
+    let navs = doc
+        .descendants()
+        .filter(|n| n.tag_name().name() == "nav")
+        .collect::<Vec<_>>();
+    let Some(nav) = navs
+        .iter()
+        .find(|n| n.attribute((OPS_NAMESPACE, "type")) == Some("toc"))
+        .or(navs.first())
+    else {
+        return vec![];
+    };
+    let Some(ol) = nav.children().find(|n| n.tag_name().name() == "ol") else {
+        return vec![];
+    };
+
+    fn items(ol: roxmltree::Node, nav_dir: &str, base_href: &str) -> Vec<Value> {
+        ol.children()
+            .filter(|n| n.tag_name().name() == "li")
+            .filter_map(|li| {
+                let label = li
+                    .children()
+                    .find(|n| matches!(n.tag_name().name(), "a" | "span"))?;
+                let mut link = json!({ "title": text_of(label) });
+                if let Some(href) = label.attribute("href") {
+                    let (path, fragment) = resolve_href(nav_dir, href);
+                    link["href"] = json!(resource_link(base_href, &path, fragment.as_deref()));
+                }
+                if let Some(child) = li.children().find(|n| n.tag_name().name() == "ol") {
+                    let children = items(child, nav_dir, base_href);
+                    if !children.is_empty() {
+                        link["children"] = json!(children);
+                    }
+                }
+                // リンクのない見出しは子の項目がある場合のみ含める
+                (link.get("href").is_some() || link.get("children").is_some()).then_some(link)
+            })
+            .collect()
+    }
+    items(ol, nav_dir, base_href)
+}
+
+/// EPUB2のNCXから目次を取得する
+fn toc_from_ncx(xml: &str, ncx_dir: &str, base_href: &str) -> Vec<Value> {
+    let Ok(doc) = roxmltree::Document::parse_with_options(xml, PARSING_OPTIONS) else {
+        return vec![];
+    };
+    let Some(nav_map) = doc.descendants().find(|n| n.tag_name().name() == "navMap") else {
+        return vec![];
+    };
+
+    fn points(parent: roxmltree::Node, ncx_dir: &str, base_href: &str) -> Vec<Value> {
+        parent
+            .children()
+            .filter(|n| n.tag_name().name() == "navPoint")
+            .filter_map(|point| {
+                let title = point
+                    .children()
+                    .find(|n| n.tag_name().name() == "navLabel")
+                    .map(text_of)
+                    .unwrap_or_default();
+                let src = point
+                    .children()
+                    .find(|n| n.tag_name().name() == "content")?
+                    .attribute("src")?;
+                let (path, fragment) = resolve_href(ncx_dir, src);
+                let mut link = json!({
+                    "href": resource_link(base_href, &path, fragment.as_deref()),
+                    "title": title,
+                });
+                let children = points(point, ncx_dir, base_href);
+                if !children.is_empty() {
+                    link["children"] = json!(children);
+                }
+                Some(link)
+            })
+            .collect()
+    }
+    points(nav_map, ncx_dir, base_href)
+}
+
+/// EPUBのmanifestに含まれ、EPUB内に存在するリソースのパスとメディアタイプを取得する
+pub fn resource_items(epub: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(epub)).map_err(|e| e.to_string())?;
+    let opf_path = rootfile_path(&mut archive)?;
+    let opf = read_string(&mut archive, &opf_path)?;
+    let doc = roxmltree::Document::parse_with_options(&opf, PARSING_OPTIONS)
+        .map_err(|e| e.to_string())?;
+    let mut items = manifest_items(&doc, parent_dir(&opf_path))
+        .into_iter()
+        .filter(|item| archive.index_for_name(&item.path).is_some())
+        .map(|item| (item.path, item.media_type))
+        .collect::<Vec<_>>();
+    items.sort();
+    items.dedup_by(|a, b| a.0 == b.0);
+    Ok(items)
+}
+
+/// 保存したmanifestのメタデータを本の情報で上書きする
+///
+/// 本の情報は `PATCH /books/{book_id}` で変更できるため、EPUBより本の情報を優先する
+pub fn apply_book_metadata(manifest: &mut Value, book: &Book) {
+    let metadata = &mut manifest["metadata"];
+    metadata["title"] = json!(book.name);
+    let has_creator = metadata["author"]
+        .as_array()
+        .is_some_and(|authors| authors.iter().any(|author| author["name"] == book.creator));
+    if !book.creator.is_empty() && !has_creator {
+        metadata["author"] = json!([{ "name": book.creator }]);
+    }
+    if !book.publisher.is_empty() {
+        metadata["publisher"] = json!(book.publisher);
+    }
+    if !book.date.is_empty() {
+        metadata["published"] = json!(book.date);
+    }
+    metadata["readingProgression"] = json!(match book.direction {
+        Direction::Ltr => "ltr",
+        Direction::Rtl => "rtl",
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+    use super::*;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="isbn">urn:isbn:9780000000000</dc:identifier>
+    <dc:identifier id="uid">urn:uuid:1</dc:identifier>
+    <dc:title>吾輩は猫である</dc:title>
+    <dc:creator>夏目漱石</dc:creator>
+    <dc:language>ja</dc:language>
+    <dc:publisher>青空文庫</dc:publisher>
+    <dc:date>1905-10-01</dc:date>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+    <meta property="rendition:layout">pre-paginated</meta>
+    <meta property="rendition:spread">landscape</meta>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="cover" href="images/cover%20image.jpg" media-type="image/jpeg" properties="cover-image"/>
+    <item id="page1" href="text/page1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="page2" href="text/page2.xhtml" media-type="application/xhtml+xml"/>
+    <item id="style" href="../style.css" media-type="text/css"/>
+  </manifest>
+  <spine page-progression-direction="rtl">
+    <itemref idref="nav" linear="no"/>
+    <itemref idref="page1" properties="page-spread-left"/>
+    <itemref idref="page2" properties="rendition:page-spread-right"/>
+  </spine>
+</package>"#;
+
+    const NAV: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE html>
+<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops">
+<body>
+  <nav epub:type="landmarks"><ol><li><a href="text/page1.xhtml">表紙</a></li></ol></nav>
+  <nav epub:type="toc">
+    <ol>
+      <li><a href="text/page1.xhtml">一</a></li>
+      <li>
+        <span>二</span>
+        <ol><li><a href="text/page2.xhtml#p1">
+          二の一
+        </a></li></ol>
+      </li>
+    </ol>
+  </nav>
+</body>
+</html>"#;
+
+    fn epub(files: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        writer.start_file("mimetype", stored).unwrap();
+        writer.write_all(b"application/epub+zip").unwrap();
+        writer
+            .start_file("META-INF/container.xml", SimpleFileOptions::default())
+            .unwrap();
+        writer
+            .write_all(
+                br#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#,
+            )
+            .unwrap();
+        for (name, content) in files {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_resolve_href() {
+        assert_eq!(
+            resolve_href("OEBPS", "text/a%20b.xhtml#p1"),
+            ("OEBPS/text/a b.xhtml".to_string(), Some("p1".to_string()))
+        );
+        assert_eq!(
+            resolve_href("OEBPS/text", "../style.css"),
+            ("OEBPS/style.css".to_string(), None)
+        );
+        assert_eq!(encode_path("OEBPS/a b.jpg"), "OEBPS/a%20b.jpg");
+    }
+
+    #[test]
+    fn test_build_manifest() {
+        let epub = epub(&[("OEBPS/content.opf", OPF), ("OEBPS/nav.xhtml", NAV)]);
+        let manifest = build_manifest(&epub, "/books/book_id").unwrap();
+
+        let metadata = &manifest["metadata"];
+        assert_eq!(metadata["title"], "吾輩は猫である");
+        assert_eq!(metadata["identifier"], "urn:uuid:1");
+        assert_eq!(metadata["author"], json!([{"name": "夏目漱石"}]));
+        assert_eq!(metadata["language"], json!(["ja"]));
+        assert_eq!(metadata["publisher"], "青空文庫");
+        assert_eq!(metadata["published"], "1905-10-01");
+        assert_eq!(metadata["modified"], "2024-01-01T00:00:00Z");
+        assert_eq!(metadata["readingProgression"], "rtl");
+        assert_eq!(
+            metadata["rendition"],
+            json!({"layout": "fixed", "spread": "landscape"})
+        );
+        assert_eq!(manifest["links"][0]["href"], "/books/book_id/manifest.json");
+
+        assert_eq!(
+            manifest["readingOrder"],
+            json!([
+                {
+                    "href": "/books/book_id/resources/OEBPS/text/page1.xhtml",
+                    "type": "application/xhtml+xml",
+                    "properties": {"page": "left"}
+                },
+                {
+                    "href": "/books/book_id/resources/OEBPS/text/page2.xhtml",
+                    "type": "application/xhtml+xml",
+                    "properties": {"page": "right"}
+                }
+            ])
+        );
+        assert_eq!(
+            manifest["resources"],
+            json!([
+                {
+                    "href": "/books/book_id/resources/OEBPS/nav.xhtml",
+                    "type": "application/xhtml+xml",
+                    "rel": "contents"
+                },
+                {
+                    "href": "/books/book_id/resources/OEBPS/images/cover%20image.jpg",
+                    "type": "image/jpeg",
+                    "rel": "cover"
+                },
+                {
+                    "href": "/books/book_id/resources/style.css",
+                    "type": "text/css"
+                }
+            ])
+        );
+        assert_eq!(
+            manifest["toc"],
+            json!([
+                {
+                    "title": "一",
+                    "href": "/books/book_id/resources/OEBPS/text/page1.xhtml"
+                },
+                {
+                    "title": "二",
+                    "children": [{
+                        "title": "二の一",
+                        "href": "/books/book_id/resources/OEBPS/text/page2.xhtml#p1"
+                    }]
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_build_manifest_ncx() {
+        let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>ncx_book</dc:title>
+    <meta name="cover" content="cover"/>
+  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+    <item id="cover" href="cover.jpg" media-type="image/jpeg"/>
+    <item id="page1" href="page1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine toc="ncx"><itemref idref="page1"/></spine>
+</package>"#;
+        let ncx = r#"<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+  <navMap>
+    <navPoint id="p1"><navLabel><text>はじめに</text></navLabel><content src="page1.xhtml"/>
+      <navPoint id="p2"><navLabel><text>節</text></navLabel><content src="page1.xhtml#s1"/></navPoint>
+    </navPoint>
+  </navMap>
+</ncx>"#;
+        let epub = epub(&[("OEBPS/content.opf", opf), ("OEBPS/toc.ncx", ncx)]);
+        let manifest = build_manifest(&epub, "/books/book_id").unwrap();
+
+        assert_eq!(manifest["metadata"]["readingProgression"], "auto");
+        assert_eq!(
+            manifest["metadata"]["rendition"],
+            json!({"layout": "reflowable"})
+        );
+        assert_eq!(manifest["resources"][1]["rel"], "cover");
+        assert_eq!(
+            manifest["toc"],
+            json!([{
+                "href": "/books/book_id/resources/OEBPS/page1.xhtml",
+                "title": "はじめに",
+                "children": [{
+                    "href": "/books/book_id/resources/OEBPS/page1.xhtml#s1",
+                    "title": "節"
+                }]
+            }])
+        );
+    }
+
+    #[test]
+    fn test_resource_items() {
+        let epub = epub(&[
+            ("OEBPS/content.opf", OPF),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/secret.txt", "secret"),
+        ]);
+        // manifestにないファイルと、manifestにあるがEPUBにないファイルは含まない
+        assert_eq!(
+            resource_items(&epub).unwrap(),
+            vec![(
+                "OEBPS/nav.xhtml".to_string(),
+                "application/xhtml+xml".to_string()
+            )]
+        );
+    }
+}
@@ -1,13 +1,35 @@
-use std::{env, time::Duration};
+use std::{
+    env,
+    io::{Cursor, Read},
+    time::Duration,
+};
 
-use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::{presigning::PresigningConfig, primitives::ByteStream};
 use axum::extract::Multipart;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use futures::future::join_all;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use sqlx::{types::chrono::NaiveDateTime, PgPool};
+use serde_json::Value;
+use sqlx::{
+    types::chrono::{NaiveDateTime, Utc},
+    PgConnection, PgPool, Postgres, QueryBuilder,
+};
 use utoipa::{IntoParams, ToSchema};
+use zip::ZipArchive;
 
-use crate::{minio, service::user::model::is_admin};
+use super::{
+    manifest::{apply_book_metadata, build_manifest, resource_items},
+    opf::{update_epub, OpfMetadata},
+    search::{escape_like, snippet, SearchQuery},
+};
+use crate::{
+    minio,
+    service::{
+        kosync::model::{add_book_file_hash, partial_md5},
+        series::model::find_or_create_series,
+    },
+};
 
 #[derive(Serialize, Deserialize, Debug, sqlx::Type, ToSchema, PartialEq, Clone, Copy)]
 #[sqlx(type_name = "visibility", rename_all = "lowercase")]
@@ -64,8 +86,139 @@ pub struct GetBooksResponse {
     #[schema(value_type = String, format = Date)]
     pub created_at: NaiveDateTime,
     pub tags: Vec<String>,
+    /// キーワードに一致した箇所を `<mark>` で囲んだ抜粋(キーワードがない場合は `null`)
+    pub snippet: Option<String>,
 }
 
+#[derive(ToSchema, Serialize, Deserialize)]
+pub struct GetBooksPage {
+    #[schema(inline)]
+    pub books: Vec<GetBooksResponse>,
+    /// 検索条件に一致する本の数
+    pub total: i64,
+    /// 次のページを取得するためのカーソル(次のページがない場合は `null`)
+    pub next_cursor: Option<String>,
+    /// 前のページを取得するためのカーソル(前のページがない場合は `null`)
+    pub prev_cursor: Option<String>,
+    /// 検索条件に一致する本に付いたタグごとの本の数(`facets=true` の場合のみ)
+    #[schema(inline)]
+    pub facets: Option<Vec<TagFacet>>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize, sqlx::FromRow)]
+pub struct TagFacet {
+    pub tag_name: String,
+    pub count: i64,
+}
+
+/// 本の一覧のページの境界
+///
+/// 並び順のキー(関連度・並び替えの項目・登録日時・ID)と、境界より前後どちらを取得するかを持つ
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BookCursor {
+    pub rank: f32,
+    pub key: String,
+    pub created_at: NaiveDateTime,
+    pub id: String,
+    pub backward: bool,
+}
+
+impl BookCursor {
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, String> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or_else(|| "カーソルが不正です".to_string())
+    }
+}
+
+/// 本の一覧の1行
+#[derive(sqlx::FromRow)]
+struct BookRow {
+    id: String,
+    owner_id: String,
+    name: String,
+    creator: String,
+    publisher: String,
+    date: String,
+    cover_image: String,
+    created_at: NaiveDateTime,
+    visibility: Visibility,
+    tags: Vec<String>,
+    rank: f32,
+    key: String,
+}
+
+/// 本の一覧の並び替えの項目
+///
+/// - `relevance`: キーワードの関連度(キーワードがない場合は登録日時)
+/// - `name`: タイトル
+/// - `creator`: 著者
+/// - `date`: 出版日
+/// - `created_at`: 登録日時
+#[derive(Serialize, Deserialize, Debug, ToSchema, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BookSort {
+    #[default]
+    Relevance,
+    Name,
+    Creator,
+    Date,
+    CreatedAt,
+}
+
+impl BookSort {
+    /// 関連度の次に並べる項目の式
+    fn key_column(&self) -> &'static str {
+        match self {
+            BookSort::Name => "b.name",
+            BookSort::Creator => "b.creator",
+            BookSort::Date => "b.date",
+            BookSort::Relevance | BookSort::CreatedAt => "''::text",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// 複数のタグで絞り込む場合の条件
+///
+/// - `all`: すべてのタグが付いた本
+/// - `any`: いずれかのタグが付いた本
+#[derive(Serialize, Deserialize, Debug, ToSchema, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMatch {
+    #[default]
+    All,
+    Any,
+}
+
+/// カンマ区切りのタグの一覧を分割する
+fn split_tags(tags: Option<&str>) -> Vec<String> {
+    tags.unwrap_or_default()
+        .split(',')
+        .map(|tag| tag.trim().to_string())
+        .filter(|tag| !tag.is_empty())
+        .collect()
+}
+
+/// 本の一覧・本文の検索結果の1ページあたりの件数のデフォルト値
+const DEFAULT_PAGE_SIZE: u32 = 24;
+
+/// 本の一覧の1ページあたりの件数の最大値
+const MAX_PAGE_SIZE: u32 = 100;
+
 #[derive(ToSchema, Serialize, Deserialize)]
 pub struct GetBookDetailsResponse {
     pub id: String,
@@ -86,14 +239,60 @@ pub struct GetBookDetailsResponse {
     #[schema(inline)]
     pub layout: Option<BookLayout>,
     pub images: Vec<String>,
+    pub series_id: Option<String>,
+    pub series_name: Option<String>,
+    /// シリーズの巻数
+    pub volume: Option<f64>,
 }
 
-#[derive(Serialize, Deserialize, ToSchema, IntoParams, Clone)]
+#[derive(Serialize, Deserialize, ToSchema, IntoParams, Clone, Default)]
 #[into_params(style = Form, parameter_in = Query)]
 pub struct BookQuery {
+    /// ページ番号(1始まり、`cursor` を指定した場合は無視する)
     pub page: Option<u32>,
+    /// 1ページあたりの件数(デフォルト: 24、最大: 100)
+    pub limit: Option<u32>,
+    /// 前のレスポンスの `next_cursor` または `prev_cursor`
+    pub cursor: Option<String>,
+    /// タイトル・著者・出版社・タグでの検索キーワード
+    ///
+    /// `"..."` で語句を、`-語` で除外する語を指定できる
     pub keyword: Option<String>,
     pub tag: Option<String>,
+    /// 絞り込むタグ(カンマ区切り)
+    pub tags: Option<String>,
+    /// `tags` のすべて(`all`)といずれか(`any`)のどちらに一致する本を取得するか(デフォルト: `all`)
+    #[schema(inline)]
+    #[param(inline)]
+    pub tag_match: Option<TagMatch>,
+    /// 除外するタグ(カンマ区切り)
+    pub exclude_tags: Option<String>,
+    pub owner_id: Option<String>,
+    #[schema(inline)]
+    #[param(inline)]
+    pub visibility: Option<Visibility>,
+    #[schema(inline)]
+    #[param(inline)]
+    pub direction: Option<Direction>,
+    #[schema(inline)]
+    #[param(inline)]
+    pub layout: Option<BookLayout>,
+    /// 出版日の範囲の開始(`2020-01-01` の形式)
+    pub date_from: Option<String>,
+    /// 出版日の範囲の終了(`2020` のように省略した場合はその年・月のすべてを含む)
+    pub date_to: Option<String>,
+    /// 並び替えの項目(デフォルト: `relevance`)
+    #[schema(inline)]
+    #[param(inline)]
+    pub sort: Option<BookSort>,
+    /// 並び順(デフォルト: `desc`)
+    #[schema(inline)]
+    #[param(inline)]
+    pub order: Option<SortOrder>,
+    /// 絞り込むコレクションのID(閲覧できるコレクションのみ)
+    pub collection_id: Option<String>,
+    /// `true` の場合はタグごとの本の数を返す
+    pub facets: Option<bool>,
 }
 
 pub struct Epub {
@@ -110,10 +309,80 @@ pub struct DeleteTagRequest {
     pub tag_name: String,
 }
 
-#[derive(ToSchema, Serialize, Deserialize)]
+/// 本の更新のリクエスト
+///
+/// 指定した項目のみを更新する
+#[derive(ToSchema, Serialize, Deserialize, Default)]
 pub struct UpdateBookRequest {
     #[schema(inline)]
-    pub visibility: Visibility,
+    pub visibility: Option<Visibility>,
+    pub name: Option<String>,
+    pub creator: Option<String>,
+    pub publisher: Option<String>,
+    /// 出版日(`2020`・`2020-01`・`2020-01-01`・`2020-01-01T00:00:00Z` の形式、空文字列で削除)
+    pub date: Option<String>,
+    #[schema(inline)]
+    pub direction: Option<Direction>,
+    /// シリーズ名(なければ作成する、空文字列でシリーズから外す)
+    pub series: Option<String>,
+    /// シリーズの巻数(シリーズを変更して巻数を指定しない場合は未設定にする)
+    pub volume: Option<f64>,
+    /// `true` の場合は保存されているEPUBファイルのメタデータも書き換える
+    pub update_epub: Option<bool>,
+}
+
+/// タイトル・著者・出版社の最大文字数
+const MAX_METADATA_LENGTH: usize = 255;
+
+impl UpdateBookRequest {
+    /// 前後の空白を取り除き、値を検証する
+    fn validate(mut self) -> Result<Self, String> {
+        for (label, value) in [
+            ("タイトル", &mut self.name),
+            ("著者", &mut self.creator),
+            ("出版社", &mut self.publisher),
+        ] {
+            let Some(value) = value else {
+                continue;
+            };
+            *value = value.trim().to_string();
+            if value.chars().count() > MAX_METADATA_LENGTH {
+                return Err(format!(
+                    "{}は{}文字以内で指定してください",
+                    label, MAX_METADATA_LENGTH
+                ));
+            }
+        }
+        if self.name.as_deref() == Some("") {
+            return Err("タイトルを指定してください".to_string());
+        }
+        if let Some(series) = &mut self.series {
+            *series = series.trim().to_string();
+            if series.chars().count() > MAX_METADATA_LENGTH {
+                return Err(format!(
+                    "シリーズ名は{}文字以内で指定してください",
+                    MAX_METADATA_LENGTH
+                ));
+            }
+        }
+        if self
+            .volume
+            .is_some_and(|volume| !volume.is_finite() || volume < 0.0)
+        {
+            return Err("巻数は0以上で指定してください".to_string());
+        }
+        if let Some(date) = &mut self.date {
+            *date = date.trim().to_string();
+            let date_regex = Regex::new(
+                r#"^\d{4}(-\d{2}(-\d{2}(T\d{2}:\d{2}(:\d{2}(\.\d+)?)?(Z|[+-]\d{2}:\d{2})?)?)?)?$"#,
+            )
+            .unwrap();
+            if !date.is_empty() && !date_regex.is_match(date) {
+                return Err("出版日はYYYY-MM-DDの形式で指定してください".to_string());
+            }
+        }
+        Ok(self)
+    }
 }
 
 #[derive(ToSchema, Serialize, Deserialize)]
@@ -121,122 +390,358 @@ pub struct DeleteBookRequest {
     pub key: String,
 }
 
+/// epub2imgで処理が必要なbook
+///
+/// `layout` が `None` の場合は画像の処理も必要
 #[derive(Serialize, Deserialize)]
-pub struct BookIdAndKey {
+pub struct UnprocessedBook {
     pub id: String,
     pub key: String,
+    pub layout: Option<BookLayout>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(style = Form, parameter_in = Query)]
+pub struct ContentQuery {
+    /// 本文での検索キーワード
+    ///
+    /// `"..."` で語句を、`-語` で除外する語を指定できる
+    pub keyword: String,
+    /// ページ番号(1始まり、1ページあたり24冊)
+    pub page: Option<u32>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize)]
+pub struct ContentSearchResponse {
+    pub book_id: String,
+    pub name: String,
+    pub creator: String,
+    pub cover_image: String,
+    /// 一致した章(spine内の順)
+    #[schema(inline)]
+    pub chapters: Vec<ContentMatch>,
+}
+
+#[derive(ToSchema, Serialize, Deserialize)]
+pub struct ContentMatch {
+    /// spine内の位置(0始まり)
+    pub position: i32,
+    /// 章の文書のEPUB内でのパス
+    pub href: String,
+    pub title: Option<String>,
+    /// キーワードに一致した箇所を `<mark>` で囲んだ前後の本文
+    pub snippet: Option<String>,
+}
+
+/// 本文に登録する章
+pub struct NewChapter {
+    /// spine内の位置(0始まり)
+    pub position: i32,
+    pub href: String,
+    pub title: Option<String>,
+    pub content: String,
+}
+
+/// 本文検索で1冊あたりに返す章の数
+const MAX_CHAPTERS_PER_BOOK: usize = 5;
+
+/// 本の一覧の絞り込み条件
+///
+/// 件数・一覧・タグごとの数で同じ条件を使うため、`WHERE` 句の組み立てをまとめる
+struct BookFilter<'a> {
+    user_id: &'a str,
+    keyword: &'a str,
+    include_patterns: Vec<String>,
+    exclude_patterns: Vec<String>,
+    tags: Vec<String>,
+    match_any: bool,
+    exclude_tags: Vec<String>,
+    owner_id: Option<&'a str>,
+    visibility: Option<Visibility>,
+    direction: Option<Direction>,
+    layout: Option<BookLayout>,
+    date_from: Option<&'a str>,
+    date_to: Option<&'a str>,
+    collection_id: Option<&'a str>,
+}
+
+impl BookFilter<'_> {
+    /// `books b` に対する絞り込み条件を `WHERE` 句として追加する
+    fn push_where(&self, qb: &mut QueryBuilder<'_, Postgres>) {
+        qb.push(" WHERE (b.owner_id = ")
+            .push_bind(self.user_id.to_string())
+            .push(" OR b.visibility = 'public')");
+        if !self.include_patterns.is_empty() {
+            qb.push(" AND (b.search_vector @@ websearch_to_tsquery('simple', ")
+                .push_bind(self.keyword.to_string())
+                .push(") OR NOT EXISTS (SELECT 1 FROM unnest(")
+                .push_bind(self.include_patterns.clone())
+                .push("::text[]) AS pattern WHERE b.search_text NOT ILIKE pattern))");
+        }
+        if !self.exclude_patterns.is_empty() {
+            qb.push(" AND NOT EXISTS (SELECT 1 FROM unnest(")
+                .push_bind(self.exclude_patterns.clone())
+                .push("::text[]) AS pattern WHERE b.search_text ILIKE pattern)");
+        }
+        if !self.tags.is_empty() {
+            if self.match_any {
+                qb.push(" AND EXISTS (SELECT 1 FROM book_tags bt WHERE bt.book_id = b.id AND bt.tag_name = ANY(")
+                    .push_bind(self.tags.clone())
+                    .push("))");
+            } else {
+                qb.push(" AND NOT EXISTS (SELECT 1 FROM unnest(")
+                    .push_bind(self.tags.clone())
+                    .push("::text[]) AS t(tag_name) WHERE NOT EXISTS (SELECT 1 FROM book_tags bt WHERE bt.book_id = b.id AND bt.tag_name = t.tag_name))");
+            }
+        }
+        if !self.exclude_tags.is_empty() {
+            qb.push(" AND NOT EXISTS (SELECT 1 FROM book_tags bt WHERE bt.book_id = b.id AND bt.tag_name = ANY(")
+                .push_bind(self.exclude_tags.clone())
+                .push("))");
+        }
+        if let Some(owner_id) = self.owner_id {
+            qb.push(" AND b.owner_id = ")
+                .push_bind(owner_id.to_string());
+        }
+        if let Some(visibility) = self.visibility {
+            qb.push(" AND b.visibility = ").push_bind(visibility);
+        }
+        if let Some(direction) = self.direction {
+            qb.push(" AND b.direction = ").push_bind(direction);
+        }
+        if let Some(layout) = self.layout {
+            qb.push(" AND b.layout = ").push_bind(layout);
+        }
+        if let Some(date_from) = self.date_from {
+            qb.push(" AND b.date >= ").push_bind(date_from.to_string());
+        }
+        if let Some(date_to) = self.date_to {
+            // 指定した桁数までで比較し、`2020` なら2020年の本も含める
+            qb.push(" AND left(b.date, ")
+                .push_bind(date_to.chars().count() as i32)
+                .push(") <= ")
+                .push_bind(date_to.to_string());
+        }
+        if let Some(collection_id) = self.collection_id {
+            qb.push(
+                " AND EXISTS (SELECT 1 FROM collection_books cb JOIN collections c ON c.id = cb.collection_id WHERE cb.collection_id = ",
+            )
+            .push_bind(collection_id.to_string())
+            .push(" AND cb.book_id = b.id AND (c.owner_id = ")
+            .push_bind(self.user_id.to_string())
+            .push(" OR c.visibility = 'public' OR (c.visibility = 'shared' AND EXISTS (SELECT 1 FROM collection_members m WHERE m.collection_id = c.id AND m.user_id = ")
+            .push_bind(self.user_id.to_string())
+            .push("))))");
+        }
+    }
 }
 
 /// 本を検索して取得
+///
+/// キーワードは全文検索と、空白で区切られない日本語向けの部分一致検索のどちらかで一致すればよく、
+/// 並び替えの項目が `relevance` でキーワードがある場合は関連度の高い順に並べる。
+/// 同じ順位の本は登録日時・IDの順に並べ、カーソルがあればその前後から取得する
 pub async fn get_books(
     user_id: &str,
     query: BookQuery,
+    cursor: Option<BookCursor>,
     db: &PgPool,
-) -> Result<Vec<GetBooksResponse>, sqlx::Error> {
+) -> Result<GetBooksPage, sqlx::Error> {
+    let keyword = query.keyword.unwrap_or_default();
+    let search = SearchQuery::parse(&keyword);
+    let mut tags = split_tags(query.tags.as_deref());
+    tags.extend(query.tag.filter(|tag| !tag.is_empty()));
+    let filter = BookFilter {
+        user_id,
+        keyword: &keyword,
+        include_patterns: search
+            .include
+            .iter()
+            .map(|term| format!("%{}%", escape_like(term)))
+            .collect(),
+        exclude_patterns: search
+            .exclude
+            .iter()
+            .map(|term| format!("%{}%", escape_like(term)))
+            .collect(),
+        tags,
+        match_any: query.tag_match.unwrap_or_default() == TagMatch::Any,
+        exclude_tags: split_tags(query.exclude_tags.as_deref()),
+        owner_id: query.owner_id.as_deref(),
+        visibility: query.visibility,
+        direction: query.direction,
+        layout: query.layout,
+        date_from: query.date_from.as_deref(),
+        date_to: query.date_to.as_deref(),
+        collection_id: query.collection_id.as_deref(),
+    };
+    let sort = query.sort.unwrap_or_default();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE) as i64;
+    // カーソルがない場合のみページ番号を使う
+    let offset = match cursor {
+        Some(_) => 0,
+        None => (query.page.unwrap_or(1).max(1) as i64 - 1) * limit,
+    };
+    let backward = cursor.as_ref().is_some_and(|c| c.backward);
+    // 前のページを取得する場合は逆順に取得してから並べ直す
+    let ascending = (query.order.unwrap_or_default() == SortOrder::Asc) != backward;
+
+    // 検索条件に一致する本の数
+    let mut qb = QueryBuilder::new("SELECT count(*) FROM books b");
+    filter.push_where(&mut qb);
+    let total = qb.build_query_scalar::<i64>().fetch_one(db).await?;
+
+    // 検索条件に一致する本のタグごとの数
+    let facets = if query.facets.unwrap_or(false) {
+        let mut qb = QueryBuilder::new(
+            "SELECT bt.tag_name, count(*) AS count FROM books b JOIN book_tags bt ON bt.book_id = b.id",
+        );
+        filter.push_where(&mut qb);
+        qb.push(" GROUP BY bt.tag_name ORDER BY count(*) DESC, bt.tag_name");
+        Some(qb.build_query_as::<TagFacet>().fetch_all(db).await?)
+    } else {
+        None
+    };
+
     // データベースから本の情報を取得
-    let books = match sqlx::query_as!(
-        Book,
+    // 次(前)のページがあるかを確認するため、1件多く取得する
+    let mut qb = QueryBuilder::new(
         r#"
-            SELECT
-                b.id as id,
-                b.key as key,
-                b.owner_id as owner_id,
-                b.name as name,
-                b.creator as creator,
-                b.publisher as publisher,
-                b.date as date,
-                b.cover_image as cover_image,
-                b.created_at as created_at,
-                b.visibility as "visibility: _",
-                b.direction as "direction: _",
-                b.layout as "layout: _",
-                b.images as images
-            FROM books b
-            LEFT JOIN book_tags bt
-                ON b.id = bt.book_id
-            LEFT JOIN tags t
-                ON bt.tag_name = t.name
-            WHERE
-                (
-                    b.owner_id = $1
-                    OR b.visibility = 'public'
-                ) AND (
-                    b.name ILIKE $2
-                    OR b.creator ILIKE $2
-                ) AND (
-                    $3 = ''
-                    OR EXISTS (
-                        SELECT 1
-                        FROM book_tags bt
-                        WHERE bt.book_id = b.id
-                        AND bt.tag_name = $3
-                    )
-                )
-            GROUP BY b.id
-            ORDER BY created_at DESC
-            LIMIT 24 OFFSET $4
+            SELECT b.*
+            FROM (
+                SELECT
+                    b.id,
+                    b.owner_id,
+                    b.name,
+                    b.creator,
+                    b.publisher,
+                    b.date,
+                    b.cover_image,
+                    b.created_at,
+                    b.visibility,
+                    coalesce(
+                        (SELECT array_agg(bt.tag_name ORDER BY bt.tag_name) FROM book_tags bt WHERE bt.book_id = b.id),
+                        '{}'
+                    ) as tags,
         "#,
-        user_id,
-        query
-            .keyword
-            .map(|k| format!("%{}%", k))
-            .unwrap_or("%%".to_string()),
-        query.tag.unwrap_or("".to_string()),
-        ((query.page.unwrap_or(1) - 1) * 24) as i32,
-    )
-    .fetch_all(db)
-    .await
-    {
-        Ok(books) => books,
-        Err(e) => {
-            panic!("{}", e.to_string());
-        }
+    );
+    if sort == BookSort::Relevance && !search.include.is_empty() {
+        qb.push("(ts_rank(b.search_vector, websearch_to_tsquery('simple', ")
+            .push_bind(keyword.clone())
+            .push(")) + similarity(b.search_text, ")
+            .push_bind(search.include.join(" "))
+            .push("))::real as rank,");
+    } else {
+        qb.push("0::real as rank,");
+    }
+    qb.push(format!(" {} as key FROM books b", sort.key_column()));
+    filter.push_where(&mut qb);
+    qb.push(") b");
+    if let Some(cursor) = &cursor {
+        qb.push(" WHERE (b.rank, b.key, b.created_at, b.id) ")
+            .push(if ascending { ">" } else { "<" })
+            .push(" (")
+            .push_bind(cursor.rank)
+            .push(", ")
+            .push_bind(cursor.key.clone())
+            .push(", ")
+            .push_bind(cursor.created_at)
+            .push(", ")
+            .push_bind(cursor.id.clone())
+            .push(")");
+    }
+    let direction = if ascending { "ASC" } else { "DESC" };
+    qb.push(format!(
+        " ORDER BY b.rank {direction}, b.key {direction}, b.created_at {direction}, b.id {direction}"
+    ));
+    qb.push(" LIMIT ")
+        .push_bind(limit + 1)
+        .push(" OFFSET ")
+        .push_bind(offset);
+    let mut books = qb.build_query_as::<BookRow>().fetch_all(db).await?;
+
+    // 取得しきれなかった本があるか
+    let has_more = books.len() > limit as usize;
+    books.truncate(limit as usize);
+    if backward {
+        books.reverse();
+    }
+    let cursor_of = |book: Option<&_>, backward: bool| {
+        book.map(|book: &BookRow| {
+            BookCursor {
+                rank: book.rank,
+                key: book.key.clone(),
+                created_at: book.created_at,
+                id: book.id.clone(),
+                backward,
+            }
+            .encode()
+        })
+    };
+    let (has_prev, has_next) = if backward {
+        (has_more, true)
+    } else {
+        (cursor.is_some() || offset > 0, has_more)
     };
+    let prev_cursor = cursor_of(books.first().filter(|_| has_prev), true);
+    let next_cursor = cursor_of(books.last().filter(|_| has_next), false);
 
-    let response = books
-        .iter()
-        .map(|book| GetBooksResponse {
-            id: book.id.clone(),
-            owner_id: book.owner_id.clone(),
-            name: book.name.clone(),
-            creator: book.creator.clone(),
-            publisher: book.publisher.clone(),
-            date: book.date.clone(),
-            cover_image: book.cover_image.clone(),
-            visibility: book.visibility,
-            created_at: book.created_at,
-            tags: vec![],
+    let books = books
+        .into_iter()
+        .map(|book| {
+            let snippet = snippet(
+                &[
+                    book.name.as_str(),
+                    book.creator.as_str(),
+                    book.publisher.as_str(),
+                    &book.tags.join(" "),
+                ]
+                .join(" / "),
+                &search.include,
+            );
+            GetBooksResponse {
+                id: book.id,
+                owner_id: book.owner_id,
+                name: book.name,
+                creator: book.creator,
+                publisher: book.publisher,
+                date: book.date,
+                cover_image: book.cover_image,
+                visibility: book.visibility,
+                created_at: book.created_at,
+                tags: book.tags,
+                snippet,
+            }
         })
         .collect::<Vec<_>>();
 
-    Ok(response)
+    Ok(GetBooksPage {
+        books,
+        total,
+        next_cursor,
+        prev_cursor,
+        facets,
+    })
 }
 
 /// 本の詳細を取得
 pub async fn get_book_details(
     book_id: &str,
     user_id: &str,
+    is_admin: bool,
     db: &PgPool,
 ) -> Result<GetBookDetailsResponse, sqlx::Error> {
-    // データベースから本の情報を取得
-    let book = sqlx::query_as!(
-        Book,
+    let book = get_available_book(book_id, user_id, is_admin, db).await?;
+
+    // シリーズを取得
+    let series = sqlx::query!(
         r#"
-            SELECT
-                b.id as id,
-                b.key as key,
-                b.owner_id as owner_id,
-                b.name as name,
-                b.creator as creator,
-                b.publisher as publisher,
-                b.date as date,
-                b.cover_image as cover_image,
-                b.created_at as created_at,
-                b.visibility as "visibility: _",
-                b.direction as "direction: _",
-                b.layout as "layout: _",
-                b.images as images
+            SELECT b.series_id, s.name as "series_name?", b.volume
             FROM books b
+            LEFT JOIN series s ON s.id = b.series_id
             WHERE b.id = $1
         "#,
         book_id
@@ -244,11 +749,6 @@ pub async fn get_book_details(
     .fetch_one(db)
     .await?;
 
-    // 権限があるか確認
-    if !is_available(&book, user_id, db).await {
-        return Err(sqlx::Error::RowNotFound);
-    }
-
     // MinIOから署名付きURLを取得してBookResponseを作成
     let endpoint = env::var("PUBLIC_S3_ENDPOINT").expect("PUBLIC_S3_ENDPOINT is not set");
     let minio_client = minio::get_client(&endpoint).await;
@@ -288,14 +788,59 @@ pub async fn get_book_details(
         epub_url: presigned_epub_url,
         layout: book.layout,
         images: presigned_image_urls,
+        series_id: series.series_id,
+        series_name: series.series_name,
+        volume: series.volume,
     })
 }
 
+/// 閲覧できる本を取得する
+///
+/// 閲覧できない場合は `RowNotFound` を返す
+pub async fn get_available_book(
+    book_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Book, sqlx::Error> {
+    let book = sqlx::query_as!(
+        Book,
+        r#"
+            SELECT
+                b.id as id,
+                b.key as key,
+                b.owner_id as owner_id,
+                b.name as name,
+                b.creator as creator,
+                b.publisher as publisher,
+                b.date as date,
+                b.cover_image as cover_image,
+                b.created_at as created_at,
+                b.visibility as "visibility: _",
+                b.direction as "direction: _",
+                b.layout as "layout: _",
+                b.images as images
+            FROM books b
+            WHERE b.id = $1
+        "#,
+        book_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    // 権限があるか確認
+    if !is_available(&book, user_id, is_admin) {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    Ok(book)
+}
+
 /// タグを追加する
 pub async fn add_tag(
     book_id: &str,
     tag_name: &str,
     user_id: &str,
+    is_admin: bool,
     db: &PgPool,
 ) -> Result<(), sqlx::Error> {
     let book = sqlx::query!(
@@ -309,7 +854,7 @@ pub async fn add_tag(
     .fetch_one(db)
     .await?;
 
-    if book.owner_id != user_id && !is_admin(db, user_id).await {
+    if book.owner_id != user_id && !is_admin {
         return Err(sqlx::Error::RowNotFound);
     }
 
@@ -331,6 +876,7 @@ pub async fn delete_tag_from_book(
     book_id: &str,
     tag_name: &str,
     user_id: &str,
+    is_admin: bool,
     db: &PgPool,
 ) -> Result<(), sqlx::Error> {
     let book = sqlx::query!(
@@ -344,7 +890,7 @@ pub async fn delete_tag_from_book(
     .fetch_one(db)
     .await?;
 
-    if book.owner_id != user_id && !is_admin(db, user_id).await {
+    if book.owner_id != user_id && !is_admin {
         return Err(sqlx::Error::RowNotFound);
     }
 
@@ -363,43 +909,442 @@ pub async fn delete_tag_from_book(
 }
 
 /// 本を更新する
+///
+/// 所有者と管理者のみ更新できる。
+/// EPUBファイルの書き換えに失敗した場合はデータベースも更新しない
 pub async fn update_book(
     book_id: &str,
     user_id: &str,
+    is_admin: bool,
     req: UpdateBookRequest,
     db: &PgPool,
-) -> Result<(), sqlx::Error> {
+) -> Result<(), String> {
+    let req = req.validate()?;
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
     let book = sqlx::query!(
         r#"
-            SELECT owner_id
+            SELECT owner_id, key, size
             FROM books
             WHERE id = $1
+            FOR UPDATE
         "#,
         book_id
     )
-    .fetch_one(db)
-    .await?;
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("本が見つかりません")?;
 
-    if book.owner_id != user_id && !is_admin(db, user_id).await {
-        return Err(sqlx::Error::RowNotFound);
+    if book.owner_id != user_id && !is_admin {
+        return Err("本が見つかりません".to_string());
     }
 
+    // 書き換えたEPUBはデータベースを更新してから保存するため、先に書き換えておく
+    let epub = if req.update_epub.unwrap_or(false) {
+        let metadata = OpfMetadata {
+            title: req.name.clone(),
+            creator: req.creator.clone(),
+            publisher: req.publisher.clone(),
+            date: req.date.clone(),
+            direction: req.direction,
+            modified: Some(Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()),
+        };
+        Some(update_epub(&fetch_epub(&book.key).await?, &metadata)?)
+    } else {
+        None
+    };
+
+    let series_id = match req.series.as_deref() {
+        Some("") | None => None,
+        Some(series) => Some(
+            find_or_create_series(series, &mut transaction)
+                .await
+                .map_err(|e| e.to_string())?,
+        ),
+    };
+
     sqlx::query!(
         r#"
             UPDATE books
-            SET visibility = $1
-            WHERE id = $2
+            SET
+                visibility = coalesce($1, visibility),
+                name = coalesce($2, name),
+                creator = coalesce($3, creator),
+                publisher = coalesce($4, publisher),
+                date = coalesce($5, date),
+                direction = coalesce($6, direction),
+                series_id = CASE WHEN $7 THEN $8 ELSE series_id END,
+                volume = CASE WHEN $7 THEN $9 ELSE coalesce($9, volume) END,
+                size = coalesce($10, size)
+            WHERE id = $11
         "#,
-        req.visibility as Visibility,
+        req.visibility as Option<Visibility>,
+        req.name,
+        req.creator,
+        req.publisher,
+        req.date,
+        req.direction as Option<Direction>,
+        req.series.is_some(),
+        series_id,
+        req.volume,
+        epub.as_ref().map(|epub| epub.len() as i64),
         book_id
     )
-    .execute(db)
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let Some(epub) = epub else {
+        return transaction.commit().await.map_err(|e| e.to_string());
+    };
+    // 書き換える前のファイルを持つKOReaderとも同期できるよう、以前のハッシュも残す
+    let file_hash = partial_md5(&epub);
+    let hash_added = add_book_file_hash(book_id, &file_hash, &mut transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    // 保存に失敗した場合は、書き換えたEPUBのハッシュとサイズを元に戻す
+    if let Err(e) = put_epub(&book.key, epub).await {
+        let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+        if hash_added {
+            sqlx::query!(
+                "DELETE FROM book_file_hashes WHERE book_id = $1 AND hash = $2",
+                book_id,
+                file_hash
+            )
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| e.to_string())?;
+        }
+        sqlx::query!(
+            "UPDATE books SET size = $1 WHERE id = $2",
+            book.size,
+            book_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+        transaction.commit().await.map_err(|e| e.to_string())?;
+        return Err(e);
+    }
+    // 展開済みのリソースは書き換える前のEPUBのものなので、次に取得するときに展開し直す
+    clear_book_resources(book_id, db).await
+}
+
+/// 展開したリソースとmanifestを削除する
+///
+/// 削除したリソースは、次にmanifestかリソースを取得するときにEPUBから展開し直す
+async fn clear_book_resources(book_id: &str, db: &PgPool) -> Result<(), String> {
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    // 展開中のリクエストが削除したオブジェクトを参照しないよう、本の行をロックしておく
+    sqlx::query!("SELECT id FROM books WHERE id = $1 FOR UPDATE", book_id)
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    let keys = sqlx::query_scalar!(
+        "DELETE FROM book_resources WHERE book_id = $1 RETURNING key",
+        book_id
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+    sqlx::query!("UPDATE books SET manifest = NULL WHERE id = $1", book_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+    let epub_bucket = env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set");
+    let minio_client = minio::get_client(&endpoint).await;
+    for key in keys {
+        if let Err(e) = minio_client
+            .delete_object()
+            .bucket(&epub_bucket)
+            .key(&key)
+            .send()
+            .await
+        {
+            log::error!("Failed to delete object {}: {}", key, e);
+        }
+    }
+    transaction.commit().await.map_err(|e| e.to_string())
+}
+
+/// S3に保存されているEPUBファイルを取得する
+pub async fn fetch_epub(key: &str) -> Result<Vec<u8>, String> {
+    let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+    let epub_bucket = env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set");
+    let minio_client = minio::get_client(&endpoint).await;
+    Ok(minio_client
+        .get_object()
+        .bucket(&epub_bucket)
+        .key(key)
+        .send()
+        .await
+        .map_err(|e| format!("EPUBファイルの取得に失敗しました: {}", e))?
+        .body
+        .collect()
+        .await
+        .map_err(|e| format!("EPUBファイルの取得に失敗しました: {}", e))?
+        .into_bytes()
+        .to_vec())
+}
+
+/// EPUBファイルをS3に保存する
+async fn put_epub(key: &str, epub: Vec<u8>) -> Result<(), String> {
+    let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+    let epub_bucket = env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set");
+    let minio_client = minio::get_client(&endpoint).await;
+    minio_client
+        .put_object()
+        .bucket(&epub_bucket)
+        .key(key)
+        .content_type("application/epub+zip")
+        .body(ByteStream::from(epub))
+        .send()
+        .await
+        .map_err(|e| format!("EPUBファイルの保存に失敗しました: {}", e))?;
+    Ok(())
+}
+
+/// EPUBのリソースを保存するS3のオブジェクトのキー
+fn resource_key(book_id: &str, path: &str) -> String {
+    format!("resources/{}/{}", book_id, path)
+}
+
+/// EPUBのリソースを展開してS3に保存し、Readium Web Publication Manifestとともに記録する
+///
+/// 記録したmanifestを返す。すでに展開済みであれば展開せずに記録済みのmanifestを返す
+pub async fn extract_book_resources(
+    book_id: &str,
+    epub: &[u8],
+    db: &PgPool,
+) -> Result<Value, String> {
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    if let Some(manifest) = lock_manifest(book_id, &mut transaction).await? {
+        return Ok(manifest);
+    }
+    let manifest = store_book_resources(book_id, epub, &mut transaction).await?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+    Ok(manifest)
+}
+
+/// 記録したmanifestを取得する
+///
+/// リソースを展開していなければ、S3からEPUBを取得して展開する
+async fn stored_manifest(book: &Book, db: &PgPool) -> Result<Value, String> {
+    let manifest = sqlx::query_scalar!("SELECT manifest FROM books WHERE id = $1", book.id)
+        .fetch_one(db)
+        .await
+        .map_err(|e| e.to_string())?;
+    if let Some(manifest) = manifest {
+        return Ok(manifest);
+    }
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    if let Some(manifest) = lock_manifest(&book.id, &mut transaction).await? {
+        return Ok(manifest);
+    }
+    let epub = fetch_epub(&book.key).await?;
+    let manifest = store_book_resources(&book.id, &epub, &mut transaction).await?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+    Ok(manifest)
+}
+
+/// 本の行をロックして、記録したmanifestを取得する
+///
+/// 同じ本を同時に展開しないよう、展開する前にロックを取ってから展開済みか確認する
+async fn lock_manifest(book_id: &str, conn: &mut PgConnection) -> Result<Option<Value>, String> {
+    sqlx::query_scalar!(
+        "SELECT manifest FROM books WHERE id = $1 FOR UPDATE",
+        book_id
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// EPUBのリソースをS3に保存し、manifestとともに記録する
+async fn store_book_resources(
+    book_id: &str,
+    epub: &[u8],
+    conn: &mut PgConnection,
+) -> Result<Value, String> {
+    let manifest = build_manifest(epub, &format!("/books/{}", book_id))?;
+    let items = resource_items(epub)?;
+
+    let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+    let epub_bucket = env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set");
+    let minio_client = minio::get_client(&endpoint).await;
+    let mut archive = ZipArchive::new(Cursor::new(epub)).map_err(|e| e.to_string())?;
+    let mut keys = vec![];
+    for (path, media_type) in &items {
+        let mut bytes = vec![];
+        archive
+            .by_name(path)
+            .map_err(|e| e.to_string())?
+            .read_to_end(&mut bytes)
+            .map_err(|e| e.to_string())?;
+        let key = resource_key(book_id, path);
+        minio_client
+            .put_object()
+            .bucket(&epub_bucket)
+            .key(&key)
+            .content_type(media_type)
+            .body(ByteStream::from(bytes))
+            .send()
+            .await
+            .map_err(|e| format!("リソースの保存に失敗しました: {}", e))?;
+        keys.push(key);
+    }
+    let (paths, media_types): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+
+    sqlx::query!("DELETE FROM book_resources WHERE book_id = $1", book_id)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+    sqlx::query!(
+        r#"
+            INSERT INTO book_resources (book_id, path, key, media_type)
+            SELECT $1, r.path, r.key, r.media_type
+            FROM unnest($2::text[], $3::text[], $4::text[]) AS r(path, key, media_type)
+        "#,
+        book_id,
+        &paths,
+        &keys,
+        &media_types
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    sqlx::query!(
+        "UPDATE books SET manifest = $1 WHERE id = $2",
+        manifest,
+        book_id
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(manifest)
+}
+
+/// 本のReadium Web Publication Manifestを取得する
+///
+/// 記録したmanifestのメタデータを本の情報で上書きして返す。
+/// リソースを展開していなければ、EPUBを取得して展開する
+pub async fn get_book_manifest(book: &Book, db: &PgPool) -> Result<Value, String> {
+    let mut manifest = stored_manifest(book, db).await?;
+    apply_book_metadata(&mut manifest, book);
+    Ok(manifest)
+}
+
+/// 本のEPUBに含まれるリソースを取得する
+///
+/// `path` はEPUB内でのパス。manifestにない場合は `None` を返す。
+/// リソースを展開していなければ、EPUBを取得して展開する
+pub async fn get_book_resource(
+    book: &Book,
+    path: &str,
+    db: &PgPool,
+) -> Result<Option<(Vec<u8>, String)>, String> {
+    stored_manifest(book, db).await?;
+    let Some(resource) = sqlx::query!(
+        r#"
+            SELECT key, media_type
+            FROM book_resources
+            WHERE book_id = $1
+            AND path = $2
+        "#,
+        book.id,
+        path
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    else {
+        return Ok(None);
+    };
+
+    let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+    let epub_bucket = env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set");
+    let minio_client = minio::get_client(&endpoint).await;
+    let bytes = minio_client
+        .get_object()
+        .bucket(&epub_bucket)
+        .key(&resource.key)
+        .send()
+        .await
+        .map_err(|e| format!("リソースの取得に失敗しました: {}", e))?
+        .body
+        .collect()
+        .await
+        .map_err(|e| format!("リソースの取得に失敗しました: {}", e))?
+        .into_bytes()
+        .to_vec();
+    Ok(Some((bytes, resource.media_type)))
+}
+
+/// 本を読み終えたかどうかを記録する
+///
+/// 閲覧できない本の場合は `RowNotFound` を返す
+pub async fn set_finished(
+    book_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    finished: bool,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            SELECT id
+            FROM books
+            WHERE id = $1
+            AND ($3 OR owner_id = $2 OR visibility = 'public')
+        "#,
+        book_id,
+        user_id,
+        is_admin
+    )
+    .fetch_one(db)
     .await?;
+
+    if finished {
+        sqlx::query!(
+            r#"
+                INSERT INTO finished_books (user_id, book_id)
+                VALUES ($1, $2)
+                ON CONFLICT DO NOTHING
+            "#,
+            user_id,
+            book_id
+        )
+        .execute(db)
+        .await?;
+    } else {
+        sqlx::query!(
+            r#"
+                DELETE FROM finished_books
+                WHERE user_id = $1
+                AND book_id = $2
+            "#,
+            user_id,
+            book_id
+        )
+        .execute(db)
+        .await?;
+    }
     Ok(())
 }
 
 /// 本を削除する
-pub async fn delete_book(book_id: &str, user_id: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+pub async fn delete_book(
+    book_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
     let book = sqlx::query!(
         r#"
             SELECT owner_id, key
@@ -411,20 +1356,27 @@ pub async fn delete_book(book_id: &str, user_id: &str, db: &PgPool) -> Result<()
     .fetch_one(db)
     .await?;
 
-    if book.owner_id != user_id && !is_admin(db, user_id).await {
+    if book.owner_id != user_id && !is_admin {
         return Err(sqlx::Error::RowNotFound);
     }
 
+    let resource_keys =
+        sqlx::query_scalar!("SELECT key FROM book_resources WHERE book_id = $1", book_id)
+            .fetch_all(db)
+            .await?;
+
     let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
     let minio_client = minio::get_client(&endpoint).await;
-    if let Err(e) = minio_client
-        .delete_object()
-        .bucket(env::var("EPUB_BUCKET").unwrap())
-        .key(book.key)
-        .send()
-        .await
-    {
-        log::error!("Failed to delete object: {}", e);
+    for key in std::iter::once(book.key).chain(resource_keys) {
+        if let Err(e) = minio_client
+            .delete_object()
+            .bucket(env::var("EPUB_BUCKET").unwrap())
+            .key(key)
+            .send()
+            .await
+        {
+            log::error!("Failed to delete object: {}", e);
+        }
     }
 
     sqlx::query!(
@@ -439,17 +1391,21 @@ pub async fn delete_book(book_id: &str, user_id: &str, db: &PgPool) -> Result<()
     Ok(())
 }
 
-/// Layoutの登録がない本を取得する
+/// Layout・本文の登録またはリソースの展開がない本を取得する
 ///
 /// エンドユーザーには公開しないため、認証は不要
-pub async fn get_books_without_layout(db: &PgPool) -> Result<Vec<BookIdAndKey>, sqlx::Error> {
-    let keys = sqlx::query_as!(
-        BookIdAndKey,
-        r#"SELECT id, key FROM books WHERE layout isnull"#
+pub async fn get_unprocessed_books(db: &PgPool) -> Result<Vec<UnprocessedBook>, sqlx::Error> {
+    let books = sqlx::query_as!(
+        UnprocessedBook,
+        r#"
+            SELECT id, key, layout as "layout: _"
+            FROM books
+            WHERE layout isnull OR contents_indexed_at isnull OR manifest isnull
+        "#
     )
     .fetch_all(db)
     .await?;
-    Ok(keys)
+    Ok(books)
 }
 
 /// 本の画像を更新する
@@ -476,10 +1432,161 @@ pub async fn update_book_images(
     Ok(())
 }
 
-pub async fn is_available(book: &Book, user_id: &str, db: &PgPool) -> bool {
-    if is_admin(db, user_id).await {
-        return true;
+/// 本の本文を登録する
+///
+/// 登録済みの本文はすべて置き換える。
+/// エンドユーザーには公開しないため、認証は不要
+pub async fn replace_book_contents(
+    book_id: &str,
+    chapters: Vec<NewChapter>,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+    sqlx::query!("DELETE FROM book_contents WHERE book_id = $1", book_id)
+        .execute(&mut *tx)
+        .await?;
+    let (positions, (hrefs, (titles, contents))): (Vec<_>, (Vec<_>, (Vec<_>, Vec<_>))) = chapters
+        .into_iter()
+        .map(|chapter| {
+            (
+                chapter.position,
+                (chapter.href, (chapter.title, chapter.content)),
+            )
+        })
+        .unzip();
+    sqlx::query!(
+        r#"
+            INSERT INTO book_contents (book_id, "position", href, title, content)
+            SELECT $1, c."position", c.href, c.title, c.content
+            FROM unnest($2::int[], $3::text[], $4::text[], $5::text[]) AS c("position", href, title, content)
+        "#,
+        book_id,
+        &positions,
+        &hrefs,
+        &titles as &[Option<String>],
+        &contents,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "UPDATE books SET contents_indexed_at = now() WHERE id = $1",
+        book_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// 本文を検索して、一致した章を本ごとにまとめて取得する
+///
+/// 閲覧できる本は `is_available` と同じく、自分の本・公開されている本(管理者はすべての本)
+pub async fn search_book_contents(
+    user_id: &str,
+    is_admin: bool,
+    query: ContentQuery,
+    db: &PgPool,
+) -> Result<Vec<ContentSearchResponse>, sqlx::Error> {
+    let search = SearchQuery::parse(&query.keyword);
+    if search.include.is_empty() {
+        return Ok(vec![]);
+    }
+    let include_patterns = search
+        .include
+        .iter()
+        .map(|term| format!("%{}%", escape_like(term)))
+        .collect::<Vec<_>>();
+    let exclude_patterns = search
+        .exclude
+        .iter()
+        .map(|term| format!("%{}%", escape_like(term)))
+        .collect::<Vec<_>>();
+
+    let rows = sqlx::query!(
+        r#"
+            WITH matched AS (
+                SELECT
+                    c.book_id,
+                    c."position",
+                    c.href,
+                    c.title,
+                    c.content,
+                    ts_rank(c.search_vector, websearch_to_tsquery('simple', $2)) AS "rank"
+                FROM book_contents c
+                JOIN books b ON b.id = c.book_id
+                WHERE
+                    (
+                        b.owner_id = $1
+                        OR b.visibility = 'public'
+                        OR $5
+                    ) AND (
+                        c.search_vector @@ websearch_to_tsquery('simple', $2)
+                        OR NOT EXISTS (
+                            SELECT 1
+                            FROM unnest($3::text[]) AS pattern
+                            WHERE c.content NOT ILIKE pattern
+                        )
+                    ) AND NOT EXISTS (
+                        SELECT 1
+                        FROM unnest($4::text[]) AS pattern
+                        WHERE c.content ILIKE pattern
+                    )
+            ), ranked_books AS (
+                SELECT book_id, max("rank") AS "rank"
+                FROM matched
+                GROUP BY book_id
+                ORDER BY max("rank") DESC, book_id
+                LIMIT $7 OFFSET $6
+            )
+            SELECT
+                b.id,
+                b.name,
+                b.creator,
+                b.cover_image,
+                m."position",
+                m.href,
+                m.title,
+                m.content
+            FROM ranked_books r
+            JOIN books b ON b.id = r.book_id
+            JOIN matched m ON m.book_id = r.book_id
+            ORDER BY r."rank" DESC, b.id, m."position"
+        "#,
+        user_id,
+        query.keyword,
+        &include_patterns,
+        &exclude_patterns,
+        is_admin,
+        (i64::from(query.page.unwrap_or(1).max(1)) - 1) * i64::from(DEFAULT_PAGE_SIZE),
+        i64::from(DEFAULT_PAGE_SIZE),
+    )
+    .fetch_all(db)
+    .await?;
+
+    let mut response: Vec<ContentSearchResponse> = vec![];
+    for row in rows {
+        if response.last().map(|book| &book.book_id) != Some(&row.id) {
+            response.push(ContentSearchResponse {
+                book_id: row.id,
+                name: row.name,
+                creator: row.creator,
+                cover_image: row.cover_image,
+                chapters: vec![],
+            });
+        }
+        let book = response.last_mut().unwrap();
+        if book.chapters.len() < MAX_CHAPTERS_PER_BOOK {
+            book.chapters.push(ContentMatch {
+                position: row.position,
+                href: row.href,
+                title: row.title,
+                snippet: snippet(&row.content, &search.include),
+            });
+        }
     }
 
-    book.owner_id == user_id || book.visibility == Visibility::Public
+    Ok(response)
+}
+
+pub fn is_available(book: &Book, user_id: &str, is_admin: bool) -> bool {
+    is_admin || book.owner_id == user_id || book.visibility == Visibility::Public
 }
@@ -0,0 +1,495 @@
+use std::io::{Cursor, Read, Seek, Write};
+
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use super::model::Direction;
+
+/// Dublin Coreの名前空間
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+
+/// OPFに書き込むメタデータ
+///
+/// `None` の項目は変更しない
+#[derive(Debug, Default)]
+pub struct OpfMetadata {
+    pub title: Option<String>,
+    pub creator: Option<String>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub direction: Option<Direction>,
+    /// `dcterms:modified` に書き込む最終更新日時(EPUB3のみ)
+    pub modified: Option<String>,
+}
+
+/// XMLの特殊文字をエスケープする
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// 開始タグに書かれている要素の名前(接頭辞を含む)
+fn qualified_name<'a>(opf: &'a str, node: roxmltree::Node) -> &'a str {
+    let start = node.range().start + 1;
+    let end = opf[start..]
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .map_or(opf.len(), |len| start + len);
+    &opf[start..end]
+}
+
+/// OPFのメタデータを書き換える
+///
+/// 元の書式を保つため、変更する箇所のみを置き換える。
+/// 要素がなければ `<metadata>` の末尾に追加する
+pub fn update_opf(opf: &str, metadata: &OpfMetadata) -> Result<String, String> {
+    let doc = roxmltree::Document::parse_with_options(
+        opf,
+        roxmltree::ParsingOptions {
+            allow_dtd: true,
+            nodes_limit: u32::MAX,
+        },
+    )
+    .map_err(|e| e.to_string())?;
+
+    // (置き換える範囲, 置き換える文字列)
+    let mut edits: Vec<(std::ops::Range<usize>, String)> = vec![];
+    let metadata_node = doc
+        .descendants()
+        .find(|n| n.tag_name().name() == "metadata")
+        .ok_or("metadataが見つかりません")?;
+    // 追加する要素は最後の要素の直後に置く
+    let metadata_end = metadata_node
+        .children()
+        .filter(|n| n.is_element())
+        .last()
+        .ok_or("metadataが空です")?
+        .range()
+        .end;
+    let dc_prefix = metadata_node
+        .lookup_prefix(DC_NAMESPACE)
+        .unwrap_or("dc")
+        .to_string();
+    for (name, value) in [
+        ("title", &metadata.title),
+        ("creator", &metadata.creator),
+        ("publisher", &metadata.publisher),
+        ("date", &metadata.date),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        let element = metadata_node.descendants().find(|n| {
+            n.tag_name().name() == name && n.tag_name().namespace() == Some(DC_NAMESPACE)
+        });
+        match element {
+            Some(element) => {
+                let range = match (element.first_child(), element.last_child()) {
+                    (Some(first), Some(last)) => first.range().start..last.range().end,
+                    _ => {
+                        // 空要素は開始タグと終了タグに置き換える
+                        edits.push((
+                            element.range(),
+                            format!(
+                                "<{prefix}:{name}>{}</{prefix}:{name}>",
+                                escape_xml(value),
+                                prefix = dc_prefix
+                            ),
+                        ));
+                        continue;
+                    }
+                };
+                edits.push((range, escape_xml(value)));
+            }
+            None => edits.push((
+                metadata_end..metadata_end,
+                format!(
+                    "\n    <{prefix}:{name}>{}</{prefix}:{name}>",
+                    escape_xml(value),
+                    prefix = dc_prefix
+                ),
+            )),
+        }
+    }
+
+    if let Some(modified) = &metadata.modified {
+        let element = metadata_node.children().find(|n| {
+            n.tag_name().name() == "meta" && n.attribute("property") == Some("dcterms:modified")
+        });
+        match element {
+            Some(element) => match (element.first_child(), element.last_child()) {
+                (Some(first), Some(last)) => {
+                    edits.push((first.range().start..last.range().end, escape_xml(modified)))
+                }
+                _ => edits.push((
+                    element.range(),
+                    format!(
+                        "<{name} property=\"dcterms:modified\">{}</{name}>",
+                        escape_xml(modified),
+                        name = qualified_name(opf, element)
+                    ),
+                )),
+            },
+            // EPUB2には `dcterms:modified` がないため追加しない
+            None if doc
+                .root_element()
+                .attribute("version")
+                .is_some_and(|version| version.starts_with('3')) =>
+            {
+                // `<metadata>` と同じ接頭辞を使う
+                let prefix = qualified_name(opf, metadata_node)
+                    .strip_suffix("metadata")
+                    .unwrap_or_default();
+                edits.push((
+                    metadata_end..metadata_end,
+                    format!(
+                        "\n    <{prefix}meta property=\"dcterms:modified\">{}</{prefix}meta>",
+                        escape_xml(modified)
+                    ),
+                ));
+            }
+            None => {}
+        }
+    }
+
+    if let Some(direction) = metadata.direction {
+        let value = match direction {
+            Direction::Ltr => "ltr",
+            Direction::Rtl => "rtl",
+        };
+        let spine = doc
+            .descendants()
+            .find(|n| n.tag_name().name() == "spine")
+            .ok_or("spineが見つかりません")?;
+        match spine
+            .attributes()
+            .find(|a| a.name() == "page-progression-direction")
+        {
+            Some(attribute) => edits.push((attribute.range_value(), value.to_string())),
+            None => {
+                // `<spine`(接頭辞があれば `<opf:spine`)の直後に属性を追加する
+                let position = spine.range().start + 1 + qualified_name(opf, spine).len();
+                edits.push((
+                    position..position,
+                    format!(" page-progression-direction=\"{}\"", value),
+                ));
+            }
+        }
+    }
+
+    // 後ろから置き換えて、前の範囲がずれないようにする
+    edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut opf = opf.to_string();
+    for (range, text) in edits {
+        opf.replace_range(range, &text);
+    }
+    Ok(opf)
+}
+
+/// container.xml から rootfile(OPF)のEPUB内でのパスを取得する
+pub fn rootfile_path<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<String, String> {
+    let mut container = String::new();
+    archive
+        .by_name("META-INF/container.xml")
+        .map_err(|_| "container.xmlが見つかりません")?
+        .read_to_string(&mut container)
+        .map_err(|e| e.to_string())?;
+    Ok(roxmltree::Document::parse(&container)
+        .map_err(|e| e.to_string())?
+        .descendants()
+        .find(|n| n.tag_name().name() == "rootfile")
+        .and_then(|n| n.attribute("full-path"))
+        .ok_or("rootfileが見つかりません")?
+        .to_string())
+}
+
+/// EPUBのOPFのメタデータを書き換える
+///
+/// OPF以外のファイルは圧縮したまま複製する
+pub fn update_epub(epub: &[u8], metadata: &OpfMetadata) -> Result<Vec<u8>, String> {
+    let mut archive = ZipArchive::new(Cursor::new(epub)).map_err(|e| e.to_string())?;
+
+    let opf_path = rootfile_path(&mut archive)?;
+    let mut opf = String::new();
+    archive
+        .by_name(&opf_path)
+        .map_err(|_| "OPFが見つかりません")?
+        .read_to_string(&mut opf)
+        .map_err(|e| e.to_string())?;
+    let opf = update_opf(&opf, metadata)?;
+
+    let mut writer = ZipWriter::new(Cursor::new(vec![]));
+    for index in 0..archive.len() {
+        let file = archive.by_index_raw(index).map_err(|e| e.to_string())?;
+        if file.name() == opf_path {
+            writer
+                .start_file(
+                    file.name(),
+                    SimpleFileOptions::default().compression_method(CompressionMethod::Deflated),
+                )
+                .map_err(|e| e.to_string())?;
+            writer
+                .write_all(opf.as_bytes())
+                .map_err(|e| e.to_string())?;
+        } else {
+            writer.raw_copy_file(file).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(writer.finish().map_err(|e| e.to_string())?.into_inner())
+}
+
+/// OPFから取得したシリーズ
+#[derive(Debug, PartialEq)]
+pub struct OpfSeries {
+    pub name: String,
+    pub volume: Option<f64>,
+}
+
+/// OPFからシリーズと巻数を取得する
+///
+/// EPUB3の `belongs-to-collection` と `group-position` を優先し、
+/// なければCalibreの `calibre:series` と `calibre:series_index` を使う。
+/// `collection-type` が `series` 以外のコレクションは無視する
+pub fn series_from_opf(opf: &str) -> Option<OpfSeries> {
+    let doc = roxmltree::Document::parse_with_options(
+        opf,
+        roxmltree::ParsingOptions {
+            allow_dtd: true,
+            nodes_limit: u32::MAX,
+        },
+    )
+    .ok()?;
+    let metas = doc
+        .descendants()
+        .filter(|n| n.tag_name().name() == "meta")
+        .collect::<Vec<_>>();
+    // `refines` で指定された要素を補足するメタデータの値
+    let refinement = |id: &str, property: &str| {
+        metas
+            .iter()
+            .find(|n| {
+                n.attribute("refines") == Some(&format!("#{}", id))
+                    && n.attribute("property") == Some(property)
+            })
+            .and_then(|n| n.text())
+            .map(|text| text.trim().to_string())
+    };
+
+    let collection = metas
+        .iter()
+        .filter(|n| n.attribute("property") == Some("belongs-to-collection"))
+        .filter_map(|n| {
+            let name = n.text()?.trim().to_string();
+            let id = n.attribute("id");
+            let collection_type = id.and_then(|id| refinement(id, "collection-type"));
+            if name.is_empty() || collection_type.is_some_and(|t| t != "series") {
+                return None;
+            }
+            let volume = id
+                .and_then(|id| refinement(id, "group-position"))
+                .and_then(|position| position.parse().ok());
+            Some(OpfSeries { name, volume })
+        })
+        .next();
+    if collection.is_some() {
+        return collection;
+    }
+
+    let calibre = |name: &str| {
+        metas
+            .iter()
+            .find(|n| n.attribute("name") == Some(name))
+            .and_then(|n| n.attribute("content"))
+            .map(|content| content.trim().to_string())
+    };
+    let name = calibre("calibre:series").filter(|name| !name.is_empty())?;
+    let volume = calibre("calibre:series_index").and_then(|index| index.parse().ok());
+    Some(OpfSeries { name, volume })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:1</dc:identifier>
+    <dc:title>image_book</dc:title>
+    <dc:creator>unknown</dc:creator>
+    <dc:date/>
+  </metadata>
+  <manifest/>
+  <spine page-progression-direction="ltr">
+    <itemref idref="page1"/>
+  </spine>
+</package>"#;
+
+    #[test]
+    fn test_update_opf() {
+        let opf = update_opf(
+            OPF,
+            &OpfMetadata {
+                title: Some("吾輩は猫である".to_string()),
+                publisher: Some("青空文庫 & 出版".to_string()),
+                date: Some("1905-10-01".to_string()),
+                direction: Some(Direction::Rtl),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(opf.contains("<dc:title>吾輩は猫である</dc:title>"));
+        assert!(opf.contains("<dc:creator>unknown</dc:creator>"));
+        assert!(opf.contains("<dc:publisher>青空文庫 &amp; 出版</dc:publisher>\n  </metadata>"));
+        assert!(opf.contains("<dc:date>1905-10-01</dc:date>"));
+        assert!(opf.contains(r#"<spine page-progression-direction="rtl">"#));
+        assert!(roxmltree::Document::parse(&opf).is_ok());
+
+        // spineに方向がなければ追加する
+        let opf = update_opf(
+            &OPF.replace(r#" page-progression-direction="ltr""#, ""),
+            &OpfMetadata {
+                direction: Some(Direction::Rtl),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(opf.contains(r#"<spine page-progression-direction="rtl">"#));
+
+        // 接頭辞の付いたspineにも方向を追加する
+        let prefixed = r#"<?xml version="1.0" encoding="UTF-8"?>
+<opf:package xmlns:opf="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+  <opf:metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>image_book</dc:title>
+  </opf:metadata>
+  <opf:spine toc="ncx">
+    <opf:itemref idref="page1"/>
+  </opf:spine>
+</opf:package>"#;
+        let opf = update_opf(
+            prefixed,
+            &OpfMetadata {
+                direction: Some(Direction::Rtl),
+                modified: Some("2026-10-17T00:00:00Z".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(opf.contains(r#"<opf:spine page-progression-direction="rtl" toc="ncx">"#));
+        // EPUB2には最終更新日時を追加しない
+        assert!(!opf.contains("dcterms:modified"));
+        assert!(roxmltree::Document::parse(&opf).is_ok());
+    }
+
+    #[test]
+    fn test_update_opf_modified() {
+        // 最終更新日時がなければ追加する
+        let opf = update_opf(
+            OPF,
+            &OpfMetadata {
+                modified: Some("2026-10-17T00:00:00Z".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(opf.contains(
+            "<meta property=\"dcterms:modified\">2026-10-17T00:00:00Z</meta>\n  </metadata>"
+        ));
+
+        // 最終更新日時があれば書き換える
+        let opf = update_opf(
+            &opf,
+            &OpfMetadata {
+                modified: Some("2026-10-18T00:00:00Z".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(opf.contains(r#"<meta property="dcterms:modified">2026-10-18T00:00:00Z</meta>"#));
+        assert!(!opf.contains("2026-10-17T00:00:00Z"));
+        assert!(roxmltree::Document::parse(&opf).is_ok());
+    }
+
+    #[test]
+    fn test_update_epub() {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        writer.start_file("mimetype", stored).unwrap();
+        writer.write_all(b"application/epub+zip").unwrap();
+        writer
+            .start_file("META-INF/container.xml", SimpleFileOptions::default())
+            .unwrap();
+        writer
+            .write_all(
+                br#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#,
+            )
+            .unwrap();
+        writer
+            .start_file("OEBPS/content.opf", SimpleFileOptions::default())
+            .unwrap();
+        writer.write_all(OPF.as_bytes()).unwrap();
+        let epub = writer.finish().unwrap().into_inner();
+
+        let epub = update_epub(
+            &epub,
+            &OpfMetadata {
+                creator: Some("夏目漱石".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut archive = ZipArchive::new(Cursor::new(epub)).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        assert_eq!(
+            archive.by_index(0).unwrap().compression(),
+            CompressionMethod::Stored
+        );
+        let mut opf = String::new();
+        archive
+            .by_name("OEBPS/content.opf")
+            .unwrap()
+            .read_to_string(&mut opf)
+            .unwrap();
+        assert!(opf.contains("<dc:creator>夏目漱石</dc:creator>"));
+    }
+
+    #[test]
+    fn test_series_from_opf() {
+        let opf = r##"<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>第2巻</dc:title>
+    <meta property="belongs-to-collection" id="set">全集</meta>
+    <meta refines="#set" property="collection-type">set</meta>
+    <meta property="belongs-to-collection" id="c01">ある漫画</meta>
+    <meta refines="#c01" property="collection-type">series</meta>
+    <meta refines="#c01" property="group-position">2</meta>
+  </metadata>
+</package>"##;
+        assert_eq!(
+            series_from_opf(opf),
+            Some(OpfSeries {
+                name: "ある漫画".to_string(),
+                volume: Some(2.0),
+            })
+        );
+
+        let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0">
+  <metadata>
+    <meta name="calibre:series" content="ある漫画"/>
+    <meta name="calibre:series_index" content="1.5"/>
+  </metadata>
+</package>"#;
+        assert_eq!(
+            series_from_opf(opf),
+            Some(OpfSeries {
+                name: "ある漫画".to_string(),
+                volume: Some(1.5),
+            })
+        );
+
+        assert_eq!(series_from_opf(OPF), None);
+    }
+}
@@ -1,6 +1,6 @@
 use std::env;
 
-use super::model;
+use super::{manifest::WEBPUB_MANIFEST_TYPE, model};
 use aws_sdk_s3::{
     operation::create_multipart_upload::CreateMultipartUploadOutput,
     primitives::ByteStream,
@@ -8,8 +8,11 @@ use aws_sdk_s3::{
 };
 use axum::{
     extract::{Multipart, Path, Query, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    http::{
+        header::{CONTENT_SECURITY_POLICY, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
     Json,
 };
 use sqlx::PgPool;
@@ -17,40 +20,81 @@ use uuid::Uuid;
 
 use crate::{
     minio,
-    service::user::model::{get_user_id_by_api_key, user_id_from_header, UserError},
+    service::user::{
+        auth::{AuthUser, UploadUser},
+        model::UserError,
+    },
 };
 
 /// 閲覧可能なbook一覧を取得する
 ///
 /// page: ページ番号
 ///
+/// limit: 1ページあたりの件数
+///
+/// cursor: 前のレスポンスの `next_cursor` または `prev_cursor`
+///
 /// keyword: タイトル・著者名での検索キーワード
+///
+/// tags, tag_match, exclude_tags, owner_id, visibility, direction, layout, date_from, date_to: 絞り込みの条件
+///
+/// sort, order: 並び替えの項目と並び順
+///
+/// facets: `true` の場合はタグごとの本の数を返す
 #[utoipa::path(
     get,
     path = "/books",
     params(model::BookQuery),
     responses(
-        (status = 200, description = "OK", body = inline(Vec<model::GetBooksResponse>)),
+        (status = 200, description = "OK", body = inline(model::GetBooksPage)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidBookQuery(String::from("カーソルが不正です")))),
         (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
     )
 )]
 pub async fn get_books(
-    headers: HeaderMap,
+    user: AuthUser,
     query: Query<model::BookQuery>,
     State(db): State<PgPool>,
 ) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => {
+    let cursor = match query.cursor.as_deref().map(model::BookCursor::decode) {
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(e)) => {
             return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("missing user id"))),
+                StatusCode::BAD_REQUEST,
+                Json(UserError::InvalidBookQuery(e)),
             )
                 .into_response()
         }
+        None => None,
+    };
+    let books = match model::get_books(&user.id, query.0, cursor, &db).await {
+        Ok(books) => books,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     };
 
-    let books = match model::get_books(&user_id, query.0, &db).await {
+    (StatusCode::OK, Json(books)).into_response()
+}
+
+/// 本文を検索して、一致した章と前後の本文を取得する
+///
+/// keyword: 本文での検索キーワード
+///
+/// page: ページ番号
+#[utoipa::path(
+    get,
+    path = "/contents",
+    params(model::ContentQuery),
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::ContentSearchResponse>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn search_contents(
+    user: AuthUser,
+    query: Query<model::ContentQuery>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    let books = match model::search_book_contents(&user.id, user.is_admin(), query.0, &db).await {
         Ok(books) => books,
         Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     };
@@ -71,21 +115,10 @@ pub async fn get_books(
 )]
 pub async fn get_book(
     Path(book_id): Path<String>,
-    headers: HeaderMap,
+    user: AuthUser,
     State(db): State<PgPool>,
 ) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("missing user id"))),
-            )
-                .into_response()
-        }
-    };
-
-    let book = match model::get_book_details(&book_id, &user_id, &db).await {
+    let book = match model::get_book_details(&book_id, &user.id, user.is_admin(), &db).await {
         Ok(book) => book,
         Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     };
@@ -95,32 +128,9 @@ pub async fn get_book(
 
 /// bookを新規作成する
 ///
-/// cookieではなく、ヘッダーにX-Api-Keyを設定する必要がある
-pub async fn new_book(
-    headers: HeaderMap,
-    State(db): State<PgPool>,
-    mut multipart: Multipart,
-) -> impl IntoResponse {
-    // APIキーの確認
-    let api_key = headers.get("X-Api-Key");
-    if api_key.is_none() {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(UserError::Unauthorized(String::from("missing api key"))),
-        )
-            .into_response();
-    }
-    let api_key = api_key.unwrap().to_str().unwrap();
-    let user_id = match get_user_id_by_api_key(api_key, &db).await {
-        Ok(id) => id,
-        Err(_) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("incorrect api key"))),
-            )
-                .into_response()
-        }
-    };
+/// APIキーの場合はuploadスコープが必要
+pub async fn new_book(UploadUser(user): UploadUser, mut multipart: Multipart) -> impl IntoResponse {
+    let user_id = user.id;
 
     let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
     let client = minio::get_client(&endpoint).await;
@@ -187,22 +197,11 @@ pub async fn new_book(
 )]
 pub async fn add_tag_to_book(
     Path(book_id): Path<String>,
-    headers: HeaderMap,
+    user: AuthUser,
     State(db): State<PgPool>,
     Json(req): Json<model::AddTagRequest>,
 ) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("missing user id"))),
-            )
-                .into_response()
-        }
-    };
-
-    match model::add_tag(&book_id, &req.tag_name, &user_id, &db).await {
+    match model::add_tag(&book_id, &req.tag_name, &user.id, user.is_admin(), &db).await {
         Ok(_) => (StatusCode::NO_CONTENT).into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     }
@@ -219,55 +218,89 @@ pub async fn add_tag_to_book(
 )]
 pub async fn delete_tag_from_book(
     Path((book_id, tag_name)): Path<(String, String)>,
-    headers: HeaderMap,
+    user: AuthUser,
     State(db): State<PgPool>,
 ) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("missing user id"))),
-            )
-                .into_response()
-        }
-    };
-
-    match model::delete_tag_from_book(&book_id, &tag_name, &user_id, &db).await {
+    match model::delete_tag_from_book(&book_id, &tag_name, &user.id, user.is_admin(), &db).await {
         Ok(_) => (StatusCode::NO_CONTENT).into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     }
 }
 
 /// bookを更新する
+///
+/// 指定した項目のみを更新する。所有者と管理者のみ実行可能
 #[utoipa::path(
     patch,
     path = "/books/{book_id}",
     request_body = inline(model::UpdateBookRequest),
     responses(
         (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidBookRequest(String::from("タイトルを指定してください")))),
         (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
     )
 )]
 pub async fn update_book(
     Path(book_id): Path<String>,
-    headers: HeaderMap,
+    user: AuthUser,
     State(db): State<PgPool>,
     Json(req): Json<model::UpdateBookRequest>,
 ) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("missing user id"))),
-            )
-                .into_response()
-        }
-    };
+    match model::update_book(&book_id, &user.id, user.is_admin(), req, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidBookRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// bookを読み終えたことを記録する
+#[utoipa::path(
+    put,
+    path = "/books/{book_id}/finished",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn finish_book(
+    Path(book_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    set_finished(&book_id, &user, true, &db).await
+}
+
+/// bookを読み終えた記録を削除する
+#[utoipa::path(
+    delete,
+    path = "/books/{book_id}/finished",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn unfinish_book(
+    Path(book_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    set_finished(&book_id, &user, false, &db).await
+}
 
-    match model::update_book(&book_id, &user_id, req, &db).await {
+async fn set_finished(
+    book_id: &str,
+    user: &AuthUser,
+    finished: bool,
+    db: &PgPool,
+) -> axum::response::Response {
+    match model::set_finished(book_id, &user.id, user.is_admin(), finished, db).await {
         Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND).into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     }
 }
@@ -283,40 +316,108 @@ pub async fn update_book(
 )]
 pub async fn delete_book(
     Path(book_id): Path<String>,
-    headers: HeaderMap,
+    user: AuthUser,
     State(db): State<PgPool>,
 ) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("missing user id"))),
-            )
-                .into_response()
-        }
-    };
-
-    match model::delete_book(&book_id, &user_id, &db).await {
+    match model::delete_book(&book_id, &user.id, user.is_admin(), &db).await {
         Ok(_) => (StatusCode::NO_CONTENT).into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     }
 }
 
-/// カバー画像を取得する
-pub async fn get_cover_image(
+/// bookのReadium Web Publication Manifestを取得する
+///
+/// EPUBのOPFから作成し、リソースは `/books/{book_id}/resources/{path}` から配信する。
+/// EPUBは初回のみ展開し、以降は展開したリソースを使う
+#[utoipa::path(
+    get,
+    path = "/books/{book_id}/manifest.json",
+    responses(
+        (status = 200, description = "OK", content_type = "application/webpub+json", body = Object),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn get_book_manifest(
     Path(book_id): Path<String>,
-    headers: HeaderMap,
+    user: AuthUser,
     State(db): State<PgPool>,
 ) -> impl IntoResponse {
-    if (user_id_from_header(&headers, &db).await).is_none() {
-        return (
-            StatusCode::UNAUTHORIZED,
-            Json(UserError::Unauthorized(String::from("missing user id"))),
-        )
-            .into_response();
+    let book = match model::get_available_book(&book_id, &user.id, user.is_admin(), &db).await {
+        Ok(book) => book,
+        Err(sqlx::Error::RowNotFound) => return (StatusCode::NOT_FOUND).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    let manifest = match model::get_book_manifest(&book, &db).await {
+        Ok(manifest) => manifest,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, WEBPUB_MANIFEST_TYPE)],
+        manifest.to_string(),
+    )
+        .into_response()
+}
+
+/// bookのEPUBに含まれるリソースを取得する
+///
+/// path: EPUB内でのパス。OPFのmanifestにあるファイルのみ取得できる
+#[utoipa::path(
+    get,
+    path = "/books/{book_id}/resources/{path}",
+    responses(
+        (status = 200, description = "OK", body = Vec<u8>),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn get_book_resource(
+    Path((book_id, path)): Path<(String, String)>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    let book = match model::get_available_book(&book_id, &user.id, user.is_admin(), &db).await {
+        Ok(book) => book,
+        Err(sqlx::Error::RowNotFound) => return (StatusCode::NOT_FOUND).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    let resource = match model::get_book_resource(&book, &path, &db).await {
+        Ok(resource) => resource,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+
+    match resource {
+        Some((bytes, media_type)) => resource_response(bytes, &media_type),
+        None => (StatusCode::NOT_FOUND).into_response(),
     }
+}
 
+/// EPUBのリソースに付けるContent-Security-Policy
+///
+/// EPUBの文書はAPIと同じオリジンで配信されるため、スクリプトを実行させない。
+/// 文書の表示に必要な同じオリジンの画像・スタイル・フォント・音声と動画のみ読み込める
+const RESOURCE_CSP: &str = "sandbox; default-src 'none'; img-src 'self' data:; style-src 'self' 'unsafe-inline'; font-src 'self' data:; media-src 'self'";
+
+/// EPUBのリソースのレスポンス
+///
+/// Content-TypeはEPUBで宣言されたものを使うため、ブラウザに推測させない
+fn resource_response(bytes: Vec<u8>, media_type: &str) -> Response {
+    (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, media_type),
+            (CONTENT_SECURITY_POLICY, RESOURCE_CSP),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+        ],
+        bytes,
+    )
+        .into_response()
+}
+
+/// カバー画像を取得する
+pub async fn get_cover_image(Path(book_id): Path<String>, _: AuthUser) -> impl IntoResponse {
     let book_id = book_id.clone().replace(".avif", "");
     let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
     let minio_client = minio::get_client(&endpoint).await;
@@ -340,7 +441,11 @@ pub async fn get_cover_image(
 
 #[cfg(test)]
 mod tests {
-    use std::{env, str::from_utf8};
+    use std::{
+        env,
+        io::{Cursor, Write},
+        str::from_utf8,
+    };
 
     use aws_sdk_s3::primitives::ByteStream;
     use axum::{
@@ -351,11 +456,20 @@ mod tests {
         multipart::{MultipartForm, Part},
         TestServer,
     };
+    use serde_json::{json, Value};
     use sqlx::PgPool;
     use tokio::sync::OnceCell;
     use tower::ServiceExt;
+    use zip::{write::SimpleFileOptions, ZipWriter};
 
-    use crate::{minio, routes::init_app, service::user::model::token_cookie_from_user_id};
+    use crate::{
+        minio,
+        routes::init_app,
+        service::{
+            book::model::{Direction, Visibility},
+            user::model::token_cookie_from_user_id,
+        },
+    };
 
     static INIT_IMAGES: OnceCell<Vec<String>> = OnceCell::const_new();
 
@@ -383,7 +497,7 @@ mod tests {
     #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
     async fn test_get_books(pool: PgPool) {
         let router = init_app(&pool);
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
 
         // GET /books (no query)
         let req = Request::builder()
@@ -423,8 +537,9 @@ mod tests {
         let res = router.clone().oneshot(req).await.unwrap();
         assert_eq!(res.status(), 200);
         let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
-        let text = from_utf8(&bytes).unwrap();
-        assert_eq!(text, r#"[]"#);
+        let page: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(page["books"], json!([]));
+        assert_eq!(page["total"], 2);
 
         // GET /books (with api key)
         let req = Request::builder()
@@ -442,13 +557,441 @@ mod tests {
         assert!(!text.contains(r#""id":"admin_private_book_id""#));
     }
 
+    /// Book一覧のページングのテスト
+    #[sqlx::test(fixtures("users", "tags", "book_with_tags", "search_books"))]
+    async fn test_get_books_pagination(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let get_page = |query: &str| {
+            let router = router.clone();
+            let req = Request::builder()
+                .uri(format!("/books?{}", query))
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let res = router.oneshot(req).await.unwrap();
+                let status = res.status();
+                let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<Value>(&bytes).unwrap())
+            }
+        };
+        let ids = |page: &Value| -> Vec<String> {
+            page["books"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|book| book["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // すべて取得した場合の並び順
+        let (status, all) = get_page("").await;
+        assert_eq!(status, 200);
+        assert_eq!(all["total"], 7);
+        assert_eq!(all["next_cursor"], Value::Null);
+        assert_eq!(all["prev_cursor"], Value::Null);
+        let all_ids = ids(&all);
+        assert_eq!(all_ids.len(), 7);
+
+        // カーソルで順に取得
+        let (_, first) = get_page("limit=4").await;
+        assert_eq!(ids(&first), all_ids[..4]);
+        assert_eq!(first["total"], 7);
+        assert_eq!(first["prev_cursor"], Value::Null);
+        let next_cursor = first["next_cursor"].as_str().unwrap();
+        let (_, second) = get_page(&format!("limit=4&cursor={}", next_cursor)).await;
+        assert_eq!(ids(&second), all_ids[4..]);
+        assert_eq!(second["next_cursor"], Value::Null);
+
+        // 前のページに戻る
+        let prev_cursor = second["prev_cursor"].as_str().unwrap();
+        let (_, back) = get_page(&format!("limit=4&cursor={}", prev_cursor)).await;
+        assert_eq!(ids(&back), all_ids[..4]);
+        assert_eq!(back["prev_cursor"], Value::Null);
+        assert!(back["next_cursor"].is_string());
+
+        // ページ番号
+        let (_, page) = get_page("limit=4&page=2").await;
+        assert_eq!(ids(&page), all_ids[4..]);
+        assert!(page["prev_cursor"].is_string());
+        let (_, page) = get_page("limit=4&page=0").await;
+        assert_eq!(ids(&page), all_ids[..4]);
+
+        // 件数の上限
+        let (_, page) = get_page("limit=0").await;
+        assert_eq!(ids(&page).len(), 1);
+
+        // 不正なカーソル
+        let (status, error) = get_page("cursor=invalid").await;
+        assert_eq!(status, 400);
+        assert_eq!(error, json!({"invalid book query": "カーソルが不正です"}));
+    }
+
+    /// Book一覧の並び替えと絞り込みのテスト
+    #[sqlx::test(fixtures("users", "tags", "book_with_tags", "search_books"))]
+    async fn test_get_books_sort_and_filter(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let get_page = |query: &str| {
+            let router = router.clone();
+            let req = Request::builder()
+                .uri(format!("/books?{}", query))
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let res = router.oneshot(req).await.unwrap();
+                assert_eq!(res.status(), 200);
+                let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<Value>(&bytes).unwrap()
+            }
+        };
+        let ids = |page: &Value| -> Vec<String> {
+            page["books"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|book| book["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // タイトルで並び替え
+        let page = get_page("tags=test_tag&sort=name&order=asc").await;
+        assert_eq!(
+            ids(&page),
+            vec![
+                "admin_public_book_id",
+                "user_private_book_id",
+                "user_public_book_id"
+            ]
+        );
+        let page = get_page("tags=test_tag&sort=name").await;
+        assert_eq!(
+            ids(&page),
+            vec![
+                "user_public_book_id",
+                "user_private_book_id",
+                "admin_public_book_id"
+            ]
+        );
+
+        // 並び替えた状態でカーソルで取得
+        let first = get_page("tags=test_tag&sort=name&order=asc&limit=2").await;
+        assert_eq!(
+            ids(&first),
+            vec!["admin_public_book_id", "user_private_book_id"]
+        );
+        let next_cursor = first["next_cursor"].as_str().unwrap();
+        let second = get_page(&format!(
+            "tags=test_tag&sort=name&order=asc&limit=2&cursor={}",
+            next_cursor
+        ))
+        .await;
+        assert_eq!(ids(&second), vec!["user_public_book_id"]);
+        let prev_cursor = second["prev_cursor"].as_str().unwrap();
+        let back = get_page(&format!(
+            "tags=test_tag&sort=name&order=asc&limit=2&cursor={}",
+            prev_cursor
+        ))
+        .await;
+        assert_eq!(ids(&back), ids(&first));
+
+        // 出版日で並び替え
+        let page = get_page("keyword=-book&sort=date&order=asc").await;
+        assert_eq!(
+            ids(&page),
+            vec!["cat_book_id", "rust_book_id", "rust_draft_book_id"]
+        );
+
+        // 複数のタグで絞り込み
+        let page = get_page("tags=test_tag,additional_tag&tag_match=any").await;
+        assert_eq!(page["total"], 4);
+        let page = get_page("tags=test_tag,additional_tag").await;
+        assert_eq!(page["total"], 0);
+        let page = get_page("tag=test_tag&exclude_tags=additional_tag").await;
+        assert_eq!(page["total"], 3);
+        let page = get_page("exclude_tags=test_tag").await;
+        assert_eq!(page["total"], 4);
+        assert!(!ids(&page).contains(&"user_public_book_id".to_string()));
+
+        // 所有者・公開範囲・方向・レイアウトで絞り込み
+        let page = get_page("owner_id=admin_id").await;
+        assert_eq!(ids(&page), vec!["admin_public_book_id"]);
+        let page = get_page("visibility=private").await;
+        assert_eq!(ids(&page), vec!["user_private_book_id"]);
+        let page = get_page("direction=rtl").await;
+        assert_eq!(page["total"], 0);
+        let page = get_page("layout=PrePaginated").await;
+        assert_eq!(page["total"], 7);
+
+        // 出版日の範囲で絞り込み
+        let page = get_page("date_from=2000-01-01&date_to=2018").await;
+        assert_eq!(ids(&page), vec!["rust_book_id"]);
+        let page = get_page("date_from=2018-08-06&date_to=2099").await;
+        assert_eq!(ids(&page), vec!["rust_draft_book_id", "rust_book_id"]);
+    }
+
+    /// Book一覧のタグとタグごとの数のテスト
+    #[sqlx::test(fixtures("users", "tags", "book_with_tags", "search_books"))]
+    async fn test_get_books_tag_facets(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let get_page = |query: &str| {
+            let router = router.clone();
+            let req = Request::builder()
+                .uri(format!("/books?{}", query))
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let res = router.oneshot(req).await.unwrap();
+                assert_eq!(res.status(), 200);
+                let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                serde_json::from_slice::<Value>(&bytes).unwrap()
+            }
+        };
+
+        // 一覧の各本にタグが含まれる
+        let page = get_page("owner_id=user_id&visibility=private").await;
+        assert_eq!(page["books"][0]["tags"], json!(["test_tag"]));
+        assert_eq!(page["facets"], Value::Null);
+
+        // 閲覧できない本のタグは数えない
+        let page = get_page("facets=true").await;
+        assert_eq!(
+            page["facets"],
+            json!([
+                {"tag_name": "test_tag", "count": 3},
+                {"tag_name": "additional_tag", "count": 1}
+            ])
+        );
+
+        // 検索条件に一致する本のみを数える
+        let page = get_page("facets=true&keyword=rust").await;
+        assert_eq!(
+            page["facets"],
+            json!([{"tag_name": "additional_tag", "count": 1}])
+        );
+    }
+
+    /// Book検索のテスト
+    #[sqlx::test(fixtures("users", "tags", "search_books"))]
+    async fn test_search_books(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+
+        let search = |keyword: &str| {
+            let url =
+                reqwest::Url::parse_with_params("http://localhost/books", &[("keyword", keyword)])
+                    .unwrap();
+            Request::builder()
+                .uri(format!("{}?{}", url.path(), url.query().unwrap()))
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let ids = |page: &Value| -> Vec<String> {
+            page["books"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|book| book["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        // 分かち書きされない日本語の部分一致
+        let res = router.clone().oneshot(search("猫")).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books), vec!["cat_book_id"]);
+        assert_eq!(
+            books["books"][0]["snippet"],
+            "吾輩は<mark>猫</mark>である / 夏目漱石 / 青空文庫 / "
+        );
+
+        // 出版社での検索
+        let res = router.clone().oneshot(search("starch")).await.unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books), vec!["rust_book_id"]);
+
+        // タグでの検索
+        let res = router.clone().oneshot(search("additional")).await.unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books), vec!["rust_draft_book_id"]);
+
+        // 除外
+        let res = router
+            .clone()
+            .oneshot(search("rust -manning"))
+            .await
+            .unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books), vec!["rust_book_id"]);
+
+        // 語句
+        let res = router
+            .clone()
+            .oneshot(search(r#""rust in action""#))
+            .await
+            .unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books), vec!["rust_draft_book_id"]);
+        assert_eq!(
+            books["books"][0]["snippet"],
+            "<mark>Rust in Action</mark> / Tim McNamara / Manning / additional_tag"
+        );
+
+        // 関連度順(タイトルに一致する本が先)
+        let res = router.clone().oneshot(search("rust")).await.unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books).len(), 2);
+        assert_eq!(books["total"], 2);
+        assert!(books["books"][0]["snippet"]
+            .as_str()
+            .unwrap()
+            .contains("<mark>Rust</mark>"));
+    }
+
+    /// 本文検索のテスト
+    #[sqlx::test(fixtures("users", "tags", "book_with_tags", "contents"))]
+    async fn test_search_contents(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+
+        let search = |keyword: &str, cookie: &str| {
+            let url = reqwest::Url::parse_with_params(
+                "http://localhost/contents",
+                &[("keyword", keyword)],
+            )
+            .unwrap();
+            Request::builder()
+                .uri(format!("{}?{}", url.path(), url.query().unwrap()))
+                .header(header::COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let ids = |books: &Value| -> Vec<String> {
+            let mut ids = books
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|book| book["book_id"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+
+        // 自分の本と公開されている本のみ
+        let res = router
+            .clone()
+            .oneshot(search("名前", &user_cookie))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            ids(&books),
+            vec!["user_private_book_id", "user_public_book_id"]
+        );
+        let public_book = books
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|book| book["book_id"] == "user_public_book_id")
+            .unwrap();
+        assert_eq!(
+            public_book["chapters"],
+            json!([{
+                "position": 1,
+                "href": "OEBPS/chapter1.xhtml",
+                "title": "一",
+                "snippet": "吾輩は猫である。<mark>名前</mark>はまだ無い。",
+            }])
+        );
+
+        // 管理者はすべての本
+        let res = router
+            .clone()
+            .oneshot(search("名前", &admin_cookie))
+            .await
+            .unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            ids(&books),
+            vec![
+                "admin_private_book_id",
+                "user_private_book_id",
+                "user_public_book_id"
+            ]
+        );
+
+        // 除外と語句
+        let res = router
+            .clone()
+            .oneshot(search("名前 -生れた", &user_cookie))
+            .await
+            .unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books), vec!["user_public_book_id"]);
+
+        let res = router
+            .clone()
+            .oneshot(search(r#""cold day""#, &user_cookie))
+            .await
+            .unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let books: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(ids(&books), vec!["admin_public_book_id"]);
+        assert_eq!(
+            books[0]["chapters"][0]["snippet"],
+            "It was a bright <mark>cold day</mark> in April."
+        );
+
+        // キーワードがない場合は空
+        let res = router
+            .clone()
+            .oneshot(search("-猫", &user_cookie))
+            .await
+            .unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(from_utf8(&bytes).unwrap(), "[]");
+
+        // ページ番号0は1ページ目として扱う
+        for (page, expected) in [("0", 2), ("1", 2), ("2", 0), ("4294967295", 0)] {
+            let req = Request::builder()
+                .uri(format!(
+                    "/contents?keyword=%E5%90%8D%E5%89%8D&page={}",
+                    page
+                ))
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap();
+            let res = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), 200);
+            let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let books: Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(books.as_array().unwrap().len(), expected, "page={}", page);
+        }
+    }
+
     /// Book詳細取得のテスト
     #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
     async fn test_get_book_details(pool: PgPool) {
         let images = INIT_IMAGES.get_or_init(put_images_to_minio).await;
 
         let router = init_app(&pool);
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
 
         // GET /books/{book_id}
         let req = Request::builder()
@@ -475,6 +1018,108 @@ mod tests {
         assert_eq!(res.status(), 500);
     }
 
+    /// 閲覧できない本のWeb Publication Manifestのテスト
+    #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
+    async fn test_get_book_manifest_not_found(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+
+        for uri in [
+            "/books/admin_private_book_id/manifest.json",
+            "/books/admin_private_book_id/resources/OEBPS/content.opf",
+            "/books/unknown_book_id/manifest.json",
+        ] {
+            let req = Request::builder()
+                .uri(uri)
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap();
+            let res = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), 404, "{}", uri);
+        }
+
+        // 認証なし
+        let req = Request::builder()
+            .uri("/books/user_public_book_id/manifest.json")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+    }
+
+    /// 展開済みの本のWeb Publication Manifestのテスト
+    #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
+    async fn test_get_book_manifest(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        sqlx::query!(
+            r#"UPDATE books SET manifest = $1, direction = 'rtl' WHERE id = 'user_public_book_id'"#,
+            json!({
+                "metadata": {
+                    "title": "epub_title",
+                    "author": [{"name": "epub_creator"}],
+                    "readingProgression": "auto"
+                },
+                "readingOrder": []
+            })
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // GET /books/{book_id}/manifest.json (本の情報で上書きする)
+        let req = Request::builder()
+            .uri("/books/user_public_book_id/manifest.json")
+            .header(header::COOKIE, &user_cookie)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/webpub+json"
+        );
+        let manifest: Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(
+            manifest["metadata"],
+            json!({
+                "title": "user_public_book_name",
+                "author": [{"name": "book_creator"}],
+                "publisher": "book_publisher",
+                "published": "book_date",
+                "readingProgression": "rtl"
+            })
+        );
+
+        // GET /books/{book_id}/resources/{path} (展開したリソースにない)
+        let req = Request::builder()
+            .uri("/books/user_public_book_id/resources/OEBPS/content.opf")
+            .header(header::COOKIE, &user_cookie)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+    }
+
+    /// EPUBのリソースのレスポンスのテスト
+    #[test]
+    fn test_resource_response() {
+        let res = super::resource_response(
+            b"<html><script>alert(1)</script></html>".to_vec(),
+            "application/xhtml+xml",
+        );
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/xhtml+xml");
+        let csp = res.headers()[header::CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap();
+        assert!(csp.starts_with("sandbox;"));
+        assert!(csp.contains("default-src 'none'"));
+        assert!(!csp.contains("script-src"));
+        assert_eq!(res.headers()[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+    }
+
     /// Book新規作成のテスト
     #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
     async fn test_new_book(pool: PgPool) {
@@ -496,7 +1141,7 @@ mod tests {
     #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
     async fn test_add_tag_to_book(pool: PgPool) {
         let router = init_app(&pool);
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
 
         // POST /books/{book_id}/tags
         let req = Request::builder()
@@ -532,7 +1177,7 @@ mod tests {
         assert_eq!(res.status(), 500);
 
         // POST /books/{book_id}/tags with admin user
-        let admin_cookie = token_cookie_from_user_id("admin_id");
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
         let req = Request::builder()
             .uri("/books/user_private_book_id/tags")
             .method("POST")
@@ -548,7 +1193,7 @@ mod tests {
     #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
     async fn test_delete_tag_from_book(pool: PgPool) {
         let router = init_app(&pool);
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
 
         // DELETE /books/{book_id}/tags
         let req = Request::builder()
@@ -581,7 +1226,7 @@ mod tests {
         assert_eq!(res.status(), 500);
 
         // DELETE /books/{book_id}/tags with admin user
-        let admin_cookie = token_cookie_from_user_id("admin_id");
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
         let req = Request::builder()
             .uri("/books/user_private_book_id/tags/test_tag")
             .method("DELETE")
@@ -596,7 +1241,7 @@ mod tests {
     #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
     async fn test_update_book(pool: PgPool) {
         let router = init_app(&pool);
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
 
         // PATCH /books/{book_id}
         let req = Request::builder()
@@ -618,10 +1263,53 @@ mod tests {
             .body(Body::from(r#"{"visibility":"private"}"#))
             .unwrap();
         let res = router.clone().oneshot(req).await.unwrap();
-        assert_eq!(res.status(), 500);
+        assert_eq!(res.status(), 400);
+
+        // PATCH /books/{book_id} with metadata
+        let req = Request::builder()
+            .uri("/books/user_public_book_id")
+            .method("PATCH")
+            .header(header::COOKIE, &user_cookie)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"name":" 吾輩は猫である ","creator":"夏目漱石","date":"1905-10","direction":"rtl"}"#,
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let book = sqlx::query!(
+            r#"SELECT name, creator, publisher, date, direction as "direction: Direction", visibility as "visibility: Visibility", search_text FROM books WHERE id = 'user_public_book_id'"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(book.name, "吾輩は猫である");
+        assert_eq!(book.creator, "夏目漱石");
+        assert_eq!(book.publisher, "book_publisher");
+        assert_eq!(book.date, "1905-10");
+        assert_eq!(book.direction, Direction::Rtl);
+        assert_eq!(book.visibility, Visibility::Private);
+        assert!(book.search_text.contains("吾輩は猫である"));
+
+        // PATCH /books/{book_id} with invalid metadata
+        for body in [
+            r#"{"name":"  "}"#,
+            r#"{"date":"10/01/1905"}"#,
+            &format!(r#"{{"creator":"{}"}}"#, "a".repeat(256)),
+        ] {
+            let req = Request::builder()
+                .uri("/books/user_public_book_id")
+                .method("PATCH")
+                .header(header::COOKIE, &user_cookie)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let res = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), 400);
+        }
 
         // PATCH /books/{book_id} with admin user
-        let admin_cookie = token_cookie_from_user_id("admin_id");
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
         let req = Request::builder()
             .uri("/books/user_private_book_id")
             .method("PATCH")
@@ -633,11 +1321,121 @@ mod tests {
         assert_eq!(res.status(), 204);
     }
 
+    /// EPUBを書き換えたときに展開したリソースを展開し直すテスト
+    #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
+    async fn test_update_book_epub(pool: PgPool) {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        for (name, content) in [
+            ("mimetype", "application/epub+zip"),
+            (
+                "META-INF/container.xml",
+                r#"<?xml version="1.0"?>
+<container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
+  <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+</container>"#,
+            ),
+            (
+                "OEBPS/content.opf",
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:1</dc:identifier>
+    <dc:title>epub_title</dc:title>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+  </metadata>
+  <manifest>
+    <item id="page1" href="text/page1.xhtml" media-type="application/xhtml+xml"/>
+  </manifest>
+  <spine>
+    <itemref idref="page1"/>
+  </spine>
+</package>"#,
+            ),
+            ("OEBPS/text/page1.xhtml", "<html><body>page1</body></html>"),
+        ] {
+            writer
+                .start_file(name, SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        let epub = writer.finish().unwrap().into_inner();
+        let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+        minio::get_client(&endpoint)
+            .await
+            .put_object()
+            .bucket(env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set"))
+            .key("user_public_book_key")
+            .body(ByteStream::from(epub))
+            .send()
+            .await
+            .unwrap();
+
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let get_manifest = || {
+            Request::builder()
+                .uri("/books/user_public_book_id/manifest.json")
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let get_resource = || {
+            Request::builder()
+                .uri("/books/user_public_book_id/resources/OEBPS/text/page1.xhtml")
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // GET /books/{book_id}/manifest.json (EPUBを展開する)
+        let res = router.clone().oneshot(get_manifest()).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let manifest: Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(manifest["metadata"]["modified"], "2024-01-01T00:00:00Z");
+
+        // PATCH /books/{book_id} with update_epub
+        let req = Request::builder()
+            .uri("/books/user_public_book_id")
+            .method("PATCH")
+            .header(header::COOKIE, &user_cookie)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"name":"吾輩は猫である","update_epub":true}"#,
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let manifest =
+            sqlx::query_scalar!("SELECT manifest FROM books WHERE id = 'user_public_book_id'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert!(manifest.is_none());
+
+        // GET /books/{book_id}/resources/{path} (書き換えたEPUBを展開し直す)
+        let res = router.clone().oneshot(get_resource()).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            from_utf8(&bytes).unwrap(),
+            "<html><body>page1</body></html>"
+        );
+
+        // GET /books/{book_id}/manifest.json (書き換えたEPUBのメタデータ)
+        let res = router.clone().oneshot(get_manifest()).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let manifest: Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(manifest["metadata"]["title"], "吾輩は猫である");
+        assert_ne!(manifest["metadata"]["modified"], "2024-01-01T00:00:00Z");
+    }
+
     /// Bookの削除のテスト
     #[sqlx::test(fixtures("users", "tags", "book_with_tags"))]
     async fn test_delete_book(pool: PgPool) {
         let router = init_app(&pool);
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
 
         // DELETE /books/{book_id}
         let req = Request::builder()
@@ -660,7 +1458,7 @@ mod tests {
         assert_eq!(res.status(), 500);
 
         // DELETE /books/{book_id} with admin user
-        let admin_cookie = token_cookie_from_user_id("admin_id");
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
         let req = Request::builder()
             .uri("/books/user_private_book_id")
             .method("DELETE")
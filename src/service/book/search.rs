@@ -0,0 +1,170 @@
+/// 検索キーワードを解析した結果
+///
+/// - 空白区切りの語はすべてを含むbookに一致する
+/// - `"..."` で囲んだ語句は空白を含めてひとまとまりとして扱う
+/// - `-` で始まる語・語句を含むbookは除外する
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl SearchQuery {
+    pub fn parse(keyword: &str) -> Self {
+        let mut query = Self::default();
+        let mut chars = keyword.chars().peekable();
+        loop {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            let Some(&first) = chars.peek() else {
+                break;
+            };
+            let negated = first == '-';
+            if negated {
+                chars.next();
+            }
+            let term: String = if chars.next_if_eq(&'"').is_some() {
+                chars.by_ref().take_while(|&c| c != '"').collect()
+            } else {
+                let mut term = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    term.push(c);
+                }
+                term
+            };
+            let term = term.trim().to_string();
+            if term.is_empty() {
+                continue;
+            }
+            if negated {
+                query.exclude.push(term);
+            } else {
+                query.include.push(term);
+            }
+        }
+        query
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+}
+
+/// LIKEのパターンで特別な意味を持つ文字をエスケープする
+pub fn escape_like(term: &str) -> String {
+    term.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// HTMLの特殊文字をエスケープする
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// スニペットに含める、一致した語の前後の文字数
+const SNIPPET_CONTEXT_CHARS: usize = 40;
+
+/// 一致した語を `<mark>` で囲んだスニペットを返す
+///
+/// 大文字・小文字を区別せずに最初に一致した箇所の前後を切り出す。
+/// `<mark>` 以外のHTMLの特殊文字はエスケープする
+pub fn snippet(text: &str, terms: &[String]) -> Option<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let lower: Vec<char> = chars.iter().map(|c| fold_case(*c)).collect();
+    let terms: Vec<Vec<char>> = terms
+        .iter()
+        .map(|t| t.chars().map(fold_case).collect::<Vec<_>>())
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    // 一致した範囲(開始, 終了)の一覧
+    let mut matches: Vec<(usize, usize)> = vec![];
+    let mut i = 0;
+    while i < lower.len() {
+        match terms
+            .iter()
+            .filter(|t| lower[i..].starts_with(t))
+            .map(|t| t.len())
+            .max()
+        {
+            Some(len) => {
+                matches.push((i, i + len));
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+    let &(first_start, _) = matches.first()?;
+
+    let start = first_start.saturating_sub(SNIPPET_CONTEXT_CHARS);
+    let end = (first_start + SNIPPET_CONTEXT_CHARS * 2).min(chars.len());
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut cursor = start;
+    for &(match_start, match_end) in matches.iter().filter(|&&(s, e)| s >= start && e <= end) {
+        snippet.push_str(&escape_html(
+            &chars[cursor..match_start].iter().collect::<String>(),
+        ));
+        snippet.push_str("<mark>");
+        snippet.push_str(&escape_html(
+            &chars[match_start..match_end].iter().collect::<String>(),
+        ));
+        snippet.push_str("</mark>");
+        cursor = match_end;
+    }
+    snippet.push_str(&escape_html(&chars[cursor..end].iter().collect::<String>()));
+    if end < chars.len() {
+        snippet.push('…');
+    }
+    Some(snippet)
+}
+
+/// 大文字・小文字を区別しないための変換
+///
+/// 文字数が変わらないよう、1文字の小文字になる場合のみ変換する
+fn fold_case(c: char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(l), None) => l,
+        _ => c,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            SearchQuery::parse(r#" rust "hello world" -draft -"old edition" 吾輩"#),
+            SearchQuery {
+                include: vec![
+                    "rust".to_string(),
+                    "hello world".to_string(),
+                    "吾輩".to_string()
+                ],
+                exclude: vec!["draft".to_string(), "old edition".to_string()],
+            }
+        );
+        assert!(SearchQuery::parse(r#"  - "" "#).is_empty());
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(
+            snippet("吾輩は猫である 夏目漱石", &["猫".to_string()]),
+            Some("吾輩は<mark>猫</mark>である 夏目漱石".to_string())
+        );
+        assert_eq!(
+            snippet("The <Rust> Book", &["rust".to_string()]),
+            Some("The &lt;<mark>Rust</mark>&gt; Book".to_string())
+        );
+        assert_eq!(snippet("The Rust Book", &["go".to_string()]), None);
+    }
+}
@@ -0,0 +1,75 @@
+insert into
+    books(
+        id,
+        "key",
+        owner_id,
+        "name",
+        creator,
+        publisher,
+        "date",
+        cover_image,
+        visibility,
+        layout,
+        images
+    )
+values
+    (
+        'user_book_id',
+        'user_book_key',
+        'user_id',
+        'user_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'reflowable',
+        '{}'
+    ),
+    (
+        'public_book_id',
+        'public_book_key',
+        'test_user_id',
+        'public_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'public',
+        'reflowable',
+        '{}'
+    ),
+    (
+        'private_book_id',
+        'private_book_key',
+        'test_user_id',
+        'private_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'reflowable',
+        '{}'
+    );
+
+insert into
+    collections(id, owner_id, "name", visibility)
+values
+    ('private_collection_id', 'test_user_id', 'あとで読む', 'private'),
+    ('public_collection_id', 'test_user_id', 'おすすめ', 'public'),
+    ('shared_collection_id', 'test_user_id', '研究', 'shared');
+
+insert into
+    collection_books(collection_id, book_id, "position")
+values
+    ('private_collection_id', 'private_book_id', 1),
+    ('public_collection_id', 'private_book_id', 1),
+    ('public_collection_id', 'public_book_id', 2),
+    ('shared_collection_id', 'public_book_id', 1),
+    ('shared_collection_id', 'private_book_id', 2);
+
+insert into
+    collection_members(collection_id, user_id)
+values
+    ('shared_collection_id', 'user_id');
@@ -0,0 +1,584 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// コレクションの公開範囲
+///
+/// - `private`: 作成者のみ
+/// - `public`: すべてのユーザーが閲覧可能
+/// - `shared`: メンバーが閲覧・本の追加と削除が可能
+#[derive(Serialize, Deserialize, Debug, sqlx::Type, ToSchema, PartialEq, Clone, Copy)]
+#[sqlx(type_name = "collection_visibility", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum CollectionVisibility {
+    Private,
+    Public,
+    Shared,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Collection {
+    pub id: String,
+    pub owner_id: String,
+    pub name: String,
+    #[schema(inline)]
+    pub visibility: CollectionVisibility,
+    /// `shared` の場合に閲覧・編集できるユーザー
+    pub members: Vec<String>,
+    /// 閲覧できる本のID(並び順)
+    pub book_ids: Vec<String>,
+    /// 本の追加・削除・並べ替えができるかどうか
+    pub editable: bool,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+    #[schema(value_type = String, format = DateTime)]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// `POST /collections` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NewCollectionRequest {
+    pub name: String,
+    #[schema(inline)]
+    pub visibility: Option<CollectionVisibility>,
+    #[serde(default)]
+    pub members: Vec<String>,
+    #[serde(default)]
+    pub book_ids: Vec<String>,
+}
+
+/// `PATCH /collections/{collection_id}` のリクエストボディ
+///
+/// 指定した項目のみを更新する
+#[derive(Serialize, Deserialize, ToSchema, Default)]
+pub struct UpdateCollectionRequest {
+    pub name: Option<String>,
+    #[schema(inline)]
+    pub visibility: Option<CollectionVisibility>,
+    pub members: Option<Vec<String>>,
+    /// 本のID(並び順、すべて置き換える)
+    pub book_ids: Option<Vec<String>>,
+}
+
+/// `POST /collections/{collection_id}/books` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AddCollectionBookRequest {
+    pub book_id: String,
+}
+
+/// コレクション名の最大文字数
+const MAX_COLLECTION_NAME_LENGTH: usize = 100;
+
+/// コレクション名を検証する
+fn validate_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("コレクション名を指定してください".to_string());
+    }
+    if name.chars().count() > MAX_COLLECTION_NAME_LENGTH {
+        return Err(format!(
+            "コレクション名は{}文字以内で指定してください",
+            MAX_COLLECTION_NAME_LENGTH
+        ));
+    }
+    Ok(name.to_string())
+}
+
+/// コレクションの作成者かどうかを確認し、行をロックする
+async fn lock_owned(
+    collection_id: &str,
+    user_id: &str,
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    sqlx::query!(
+        r#"
+            SELECT id
+            FROM collections
+            WHERE id = $1
+            AND owner_id = $2
+            FOR UPDATE
+        "#,
+        collection_id,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("コレクションが見つかりません")?;
+    Ok(())
+}
+
+/// コレクションの本を編集できるかどうかを確認し、行をロックする
+async fn lock_editable(
+    collection_id: &str,
+    user_id: &str,
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    sqlx::query!(
+        r#"
+            SELECT c.id
+            FROM collections c
+            WHERE c.id = $1
+            AND (
+                c.owner_id = $2
+                OR (
+                    c.visibility = 'shared'
+                    AND EXISTS (
+                        SELECT 1
+                        FROM collection_members m
+                        WHERE m.collection_id = c.id
+                        AND m.user_id = $2
+                    )
+                )
+            )
+            FOR UPDATE OF c
+        "#,
+        collection_id,
+        user_id
+    )
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("コレクションが見つかりません")?;
+    Ok(())
+}
+
+/// 指定した本がすべて閲覧できるかどうかを確認する
+async fn check_books_available(
+    user_id: &str,
+    book_ids: &[String],
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    let available = sqlx::query_scalar!(
+        r#"
+            SELECT count(*) as "count!"
+            FROM books b
+            WHERE b.id = ANY($1)
+            AND (
+                b.owner_id = $2
+                OR b.visibility = 'public'
+            )
+        "#,
+        book_ids,
+        user_id
+    )
+    .fetch_one(conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    if available != book_ids.len() as i64 {
+        return Err("本が見つかりません".to_string());
+    }
+    Ok(())
+}
+
+/// コレクションの本をすべて置き換える
+///
+/// 閲覧できない本は追加できない。
+/// 編集するユーザーが閲覧できない本(作成者の非公開の本など)は削除・並べ替えできないため、元の位置に残す
+async fn replace_books(
+    collection_id: &str,
+    user_id: &str,
+    book_ids: &[String],
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    let mut unique_ids: Vec<String> = vec![];
+    for book_id in book_ids {
+        if !unique_ids.contains(book_id) {
+            unique_ids.push(book_id.clone());
+        }
+    }
+    check_books_available(user_id, &unique_ids, &mut *conn).await?;
+
+    // 閲覧できる本の位置に指定された本を順に入れ、余った本は末尾に追加する
+    let current = sqlx::query!(
+        r#"
+            SELECT
+                cb.book_id,
+                (b.owner_id = $2 OR b.visibility = 'public') as "visible!"
+            FROM collection_books cb
+            JOIN books b ON b.id = cb.book_id
+            WHERE cb.collection_id = $1
+            ORDER BY cb."position"
+        "#,
+        collection_id,
+        user_id
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    let mut new_ids = unique_ids.into_iter();
+    let mut ordered_ids = vec![];
+    for book in current {
+        if !book.visible {
+            ordered_ids.push(book.book_id);
+        } else if let Some(book_id) = new_ids.next() {
+            ordered_ids.push(book_id);
+        }
+    }
+    ordered_ids.extend(new_ids);
+
+    sqlx::query!(
+        r#"DELETE FROM collection_books WHERE collection_id = $1"#,
+        collection_id
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    sqlx::query!(
+        r#"
+            INSERT INTO collection_books (collection_id, book_id, "position")
+            SELECT $1, book_id, "position"::integer
+            FROM unnest($2::text[]) WITH ORDINALITY AS t(book_id, "position")
+        "#,
+        collection_id,
+        &ordered_ids
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// `shared` のメンバーをすべて置き換える
+async fn replace_members(
+    collection_id: &str,
+    owner_id: &str,
+    members: &[String],
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    let members = members
+        .iter()
+        .filter(|member| *member != owner_id)
+        .cloned()
+        .collect::<Vec<_>>();
+    let existing = sqlx::query_scalar!(
+        r#"SELECT count(*) as "count!" FROM users WHERE id = ANY($1)"#,
+        &members
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    let mut unique_members = members.clone();
+    unique_members.sort();
+    unique_members.dedup();
+    if existing != unique_members.len() as i64 {
+        return Err("メンバーのユーザーが見つかりません".to_string());
+    }
+
+    sqlx::query!(
+        r#"DELETE FROM collection_members WHERE collection_id = $1"#,
+        collection_id
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    sqlx::query!(
+        r#"
+            INSERT INTO collection_members (collection_id, user_id)
+            SELECT $1, unnest($2::text[])
+        "#,
+        collection_id,
+        &unique_members
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// コレクションの1行
+struct CollectionRow {
+    id: String,
+    owner_id: String,
+    name: String,
+    visibility: CollectionVisibility,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    members: Vec<String>,
+    book_ids: Vec<String>,
+}
+
+impl CollectionRow {
+    fn into_collection(self, user_id: &str) -> Collection {
+        let editable = self.owner_id == user_id
+            || (self.visibility == CollectionVisibility::Shared
+                && self.members.iter().any(|member| member == user_id));
+        Collection {
+            id: self.id,
+            owner_id: self.owner_id,
+            name: self.name,
+            visibility: self.visibility,
+            members: self.members,
+            book_ids: self.book_ids,
+            editable,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+/// 閲覧できるコレクションを取得する
+///
+/// `collection_id` が `None` の場合はすべて取得し、
+/// 自分のコレクション、共有されたコレクション、公開されたコレクションの順に並べる。
+/// 本はコレクションの並び順で、閲覧できるものだけを返す
+async fn fetch_collections(
+    collection_id: Option<&str>,
+    user_id: &str,
+    db: &PgPool,
+) -> Result<Vec<Collection>, sqlx::Error> {
+    let rows = sqlx::query_as!(
+        CollectionRow,
+        r#"
+            SELECT
+                c.id,
+                c.owner_id,
+                c.name,
+                c.visibility as "visibility: CollectionVisibility",
+                c.created_at,
+                c.updated_at,
+                coalesce(
+                    (SELECT array_agg(m.user_id ORDER BY m.user_id) FROM collection_members m WHERE m.collection_id = c.id),
+                    '{}'
+                ) as "members!",
+                coalesce(
+                    (
+                        SELECT array_agg(cb.book_id ORDER BY cb."position")
+                        FROM collection_books cb
+                        JOIN books b ON b.id = cb.book_id
+                        WHERE cb.collection_id = c.id
+                        AND (
+                            b.owner_id = $2
+                            OR b.visibility = 'public'
+                        )
+                    ),
+                    '{}'
+                ) as "book_ids!"
+            FROM collections c
+            LEFT JOIN collection_members m ON m.collection_id = c.id AND m.user_id = $2
+            WHERE
+                ($1::text IS NULL OR c.id = $1)
+                AND (
+                    c.owner_id = $2
+                    OR c.visibility = 'public'
+                    OR (c.visibility = 'shared' AND m.user_id IS NOT NULL)
+                )
+            ORDER BY
+                c.owner_id = $2 DESC,
+                m.user_id IS NOT NULL DESC,
+                c.name,
+                c.id
+        "#,
+        collection_id,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| row.into_collection(user_id))
+        .collect())
+}
+
+/// 閲覧できるコレクションの一覧を取得する
+///
+/// 自分のコレクション、共有されたコレクション、公開されたコレクションの順に並べる
+pub async fn get_collections(user_id: &str, db: &PgPool) -> Result<Vec<Collection>, sqlx::Error> {
+    fetch_collections(None, user_id, db).await
+}
+
+/// コレクションを取得する
+///
+/// 閲覧できない場合は `RowNotFound` を返す。
+/// 本はコレクションの並び順で、閲覧できるものだけを返す
+pub async fn get_collection(
+    collection_id: &str,
+    user_id: &str,
+    db: &PgPool,
+) -> Result<Collection, sqlx::Error> {
+    fetch_collections(Some(collection_id), user_id, db)
+        .await?
+        .pop()
+        .ok_or(sqlx::Error::RowNotFound)
+}
+
+/// 名前の重複による失敗をエラーメッセージに変換する
+fn name_conflict_error(e: sqlx::Error) -> String {
+    match e.as_database_error() {
+        Some(db_error) if db_error.is_unique_violation() => {
+            "同じ名前のコレクションがあります".to_string()
+        }
+        _ => e.to_string(),
+    }
+}
+
+/// コレクションを作成する
+pub async fn create_collection(
+    user_id: &str,
+    req: NewCollectionRequest,
+    db: &PgPool,
+) -> Result<Collection, String> {
+    let name = validate_name(&req.name)?;
+    let id = Uuid::new_v4().to_string();
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    sqlx::query!(
+        r#"
+            INSERT INTO collections (id, owner_id, name, visibility)
+            VALUES ($1, $2, $3, $4)
+        "#,
+        id,
+        user_id,
+        name,
+        req.visibility.unwrap_or(CollectionVisibility::Private) as CollectionVisibility
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(name_conflict_error)?;
+    replace_members(&id, user_id, &req.members, &mut transaction).await?;
+    replace_books(&id, user_id, &req.book_ids, &mut transaction).await?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    get_collection(&id, user_id, db)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// コレクションを更新する
+///
+/// 名前・公開範囲・メンバーは作成者のみ、本は `shared` のメンバーも更新できる
+pub async fn update_collection(
+    collection_id: &str,
+    user_id: &str,
+    req: UpdateCollectionRequest,
+    db: &PgPool,
+) -> Result<Collection, String> {
+    let name = req.name.as_deref().map(validate_name).transpose()?;
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    if name.is_some() || req.visibility.is_some() || req.members.is_some() {
+        lock_owned(collection_id, user_id, &mut transaction).await?;
+    } else {
+        lock_editable(collection_id, user_id, &mut transaction).await?;
+    }
+
+    sqlx::query!(
+        r#"
+            UPDATE collections
+            SET
+                name = coalesce($1, name),
+                visibility = coalesce($2, visibility),
+                updated_at = now()
+            WHERE id = $3
+        "#,
+        name,
+        req.visibility as Option<CollectionVisibility>,
+        collection_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(name_conflict_error)?;
+    if let Some(members) = &req.members {
+        replace_members(collection_id, user_id, members, &mut transaction).await?;
+    }
+    if let Some(book_ids) = &req.book_ids {
+        replace_books(collection_id, user_id, book_ids, &mut transaction).await?;
+    }
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    get_collection(collection_id, user_id, db)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// コレクションの末尾に本を追加する
+pub async fn add_book(
+    collection_id: &str,
+    user_id: &str,
+    book_id: &str,
+    db: &PgPool,
+) -> Result<(), String> {
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    lock_editable(collection_id, user_id, &mut transaction).await?;
+    check_books_available(user_id, &[book_id.to_string()], &mut transaction).await?;
+    sqlx::query!(
+        r#"
+            INSERT INTO collection_books (collection_id, book_id, "position")
+            SELECT $1, $2, coalesce(max("position"), 0) + 1
+            FROM collection_books
+            WHERE collection_id = $1
+            ON CONFLICT DO NOTHING
+        "#,
+        collection_id,
+        book_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+    sqlx::query!(
+        r#"UPDATE collections SET updated_at = now() WHERE id = $1"#,
+        collection_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// コレクションから本を削除する
+pub async fn remove_book(
+    collection_id: &str,
+    user_id: &str,
+    book_id: &str,
+    db: &PgPool,
+) -> Result<(), String> {
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    lock_editable(collection_id, user_id, &mut transaction).await?;
+    sqlx::query!(
+        r#"
+            DELETE FROM collection_books
+            WHERE collection_id = $1
+            AND book_id = $2
+        "#,
+        collection_id,
+        book_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+    sqlx::query!(
+        r#"UPDATE collections SET updated_at = now() WHERE id = $1"#,
+        collection_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// コレクションを削除する
+pub async fn delete_collection(
+    collection_id: &str,
+    user_id: &str,
+    db: &PgPool,
+) -> Result<(), String> {
+    sqlx::query!(
+        r#"
+            DELETE FROM collections
+            WHERE id = $1
+            AND owner_id = $2
+            RETURNING id
+        "#,
+        collection_id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("コレクションが見つかりません")?;
+    Ok(())
+}
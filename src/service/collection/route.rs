@@ -0,0 +1,616 @@
+use super::model;
+use crate::service::user::{auth::AuthUser, model::UserError};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use sqlx::PgPool;
+
+/// 閲覧できるコレクションの一覧を取得する
+///
+/// 自分のコレクション、共有されたコレクション、公開されたコレクションの順に並べる
+#[utoipa::path(
+    get,
+    path = "/collections",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::Collection>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_collections(user: AuthUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::get_collections(&user.id, &db).await {
+        Ok(collections) => (StatusCode::OK, Json(collections)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// コレクションを作成する
+#[utoipa::path(
+    post,
+    path = "/collections",
+    request_body = inline(model::NewCollectionRequest),
+    responses(
+        (status = 201, description = "Created", body = inline(model::Collection)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidCollectionRequest(String::from("同じ名前のコレクションがあります")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn new_collection(
+    user: AuthUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::NewCollectionRequest>,
+) -> impl IntoResponse {
+    match model::create_collection(&user.id, body, &db).await {
+        Ok(collection) => (StatusCode::CREATED, Json(collection)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidCollectionRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// コレクションを取得する
+///
+/// collection_id: コレクションのID
+#[utoipa::path(
+    get,
+    path = "/collections/{collection_id}",
+    responses(
+        (status = 200, description = "OK", body = inline(model::Collection)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn get_collection(
+    Path(collection_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_collection(&collection_id, &user.id, &db).await {
+        Ok(collection) => (StatusCode::OK, Json(collection)).into_response(),
+        Err(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// コレクションを更新する
+///
+/// 名前・公開範囲・メンバーは作成者のみ、本の並びは `shared` のメンバーも更新できる
+#[utoipa::path(
+    patch,
+    path = "/collections/{collection_id}",
+    request_body = inline(model::UpdateCollectionRequest),
+    responses(
+        (status = 200, description = "OK", body = inline(model::Collection)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidCollectionRequest(String::from("コレクションが見つかりません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn update_collection(
+    Path(collection_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::UpdateCollectionRequest>,
+) -> impl IntoResponse {
+    match model::update_collection(&collection_id, &user.id, body, &db).await {
+        Ok(collection) => (StatusCode::OK, Json(collection)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidCollectionRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// コレクションを削除する
+#[utoipa::path(
+    delete,
+    path = "/collections/{collection_id}",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidCollectionRequest(String::from("コレクションが見つかりません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn delete_collection(
+    Path(collection_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::delete_collection(&collection_id, &user.id, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidCollectionRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// コレクションの末尾に本を追加する
+#[utoipa::path(
+    post,
+    path = "/collections/{collection_id}/books",
+    request_body = inline(model::AddCollectionBookRequest),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidCollectionRequest(String::from("本が見つかりません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn add_book_to_collection(
+    Path(collection_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::AddCollectionBookRequest>,
+) -> impl IntoResponse {
+    match model::add_book(&collection_id, &user.id, &body.book_id, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidCollectionRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// コレクションから本を削除する
+#[utoipa::path(
+    delete,
+    path = "/collections/{collection_id}/books/{book_id}",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidCollectionRequest(String::from("コレクションが見つかりません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn delete_book_from_collection(
+    Path((collection_id, book_id)): Path<(String, String)>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::remove_book(&collection_id, &user.id, &book_id, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidCollectionRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
+
+    fn request(method: &str, uri: &str, cookie: &str, body: Option<Value>) -> Request<Body> {
+        let builder = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header(header::COOKIE, cookie);
+        match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(Body::empty()).unwrap(),
+        }
+    }
+
+    async fn send(router: &axum::Router, req: Request<Body>) -> (u16, Option<Value>) {
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status().as_u16();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice::<Value>(&bytes).ok())
+    }
+
+    /// コレクションの閲覧のテスト
+    #[sqlx::test(fixtures("users", "collections"))]
+    async fn test_get_collections(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+
+        // GET /collections (共有されたもの、公開されたものの順)
+        let (status, collections) =
+            send(&router, request("GET", "/collections", &user_cookie, None)).await;
+        assert_eq!(status, 200);
+        let collections = collections.unwrap();
+        let ids = collections
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c["id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["shared_collection_id", "public_collection_id"]);
+        assert_eq!(collections[0]["editable"], true);
+        assert_eq!(collections[1]["editable"], false);
+
+        // GET /collections/{collection_id} (閲覧できない本は含まない)
+        let (status, collection) = send(
+            &router,
+            request(
+                "GET",
+                "/collections/public_collection_id",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(collection.unwrap()["book_ids"], json!(["public_book_id"]));
+
+        // GET /collections/{collection_id} (非公開のコレクション)
+        let (status, _) = send(
+            &router,
+            request(
+                "GET",
+                "/collections/private_collection_id",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 404);
+
+        // GET /collections/{collection_id} (共有されていないユーザー)
+        let (status, _) = send(
+            &router,
+            request(
+                "GET",
+                "/collections/shared_collection_id",
+                &admin_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 404);
+
+        // GET /books?collection_id=...
+        let (status, page) = send(
+            &router,
+            request(
+                "GET",
+                "/books?collection_id=shared_collection_id",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let page = page.unwrap();
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["books"][0]["id"], "public_book_id");
+
+        // GET /books?collection_id=...&keyword=... (キーワードとの組み合わせ)
+        let (status, page) = send(
+            &router,
+            request(
+                "GET",
+                "/books?collection_id=shared_collection_id&keyword=public_book&facets=true",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let page = page.unwrap();
+        assert_eq!(page["total"], 1);
+        assert_eq!(page["books"].as_array().unwrap().len(), 1);
+        let (_, page) = send(
+            &router,
+            request(
+                "GET",
+                "/books?collection_id=shared_collection_id&keyword=user_book",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        let page = page.unwrap();
+        assert_eq!(page["total"], 0);
+        assert_eq!(page["books"], json!([]));
+        let (_, page) = send(
+            &router,
+            request(
+                "GET",
+                "/books?collection_id=shared_collection_id",
+                &admin_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(page.unwrap()["total"], 0);
+    }
+
+    /// コレクションの作成・編集・削除のテスト
+    #[sqlx::test(fixtures("users", "collections"))]
+    async fn test_edit_collections(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let owner_cookie = token_cookie_from_user_id("test_user_id", &pool).await;
+
+        // POST /collections
+        let (status, collection) = send(
+            &router,
+            request(
+                "POST",
+                "/collections",
+                &user_cookie,
+                Some(
+                    json!({"name": " お気に入り ", "book_ids": ["user_book_id", "public_book_id"]}),
+                ),
+            ),
+        )
+        .await;
+        assert_eq!(status, 201);
+        let collection = collection.unwrap();
+        assert_eq!(collection["name"], "お気に入り");
+        assert_eq!(collection["visibility"], "private");
+        assert_eq!(
+            collection["book_ids"],
+            json!(["user_book_id", "public_book_id"])
+        );
+        let collection_id = collection["id"].as_str().unwrap().to_string();
+
+        // POST /collections (同じ名前)
+        let (status, _) = send(
+            &router,
+            request(
+                "POST",
+                "/collections",
+                &user_cookie,
+                Some(json!({"name": "お気に入り"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // POST /collections (閲覧できない本)
+        let (status, _) = send(
+            &router,
+            request(
+                "POST",
+                "/collections",
+                &user_cookie,
+                Some(json!({"name": "読みたい", "book_ids": ["private_book_id"]})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // PATCH /collections/{collection_id} (並べ替え)
+        let (status, collection) = send(
+            &router,
+            request(
+                "PATCH",
+                &format!("/collections/{}", collection_id),
+                &user_cookie,
+                Some(json!({"book_ids": ["public_book_id", "user_book_id"]})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            collection.unwrap()["book_ids"],
+            json!(["public_book_id", "user_book_id"])
+        );
+
+        // POST /collections/{collection_id}/books (共有されたコレクション)
+        let (status, _) = send(
+            &router,
+            request(
+                "POST",
+                "/collections/shared_collection_id/books",
+                &user_cookie,
+                Some(json!({"book_id": "user_book_id"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+        let (_, collection) = send(
+            &router,
+            request(
+                "GET",
+                "/collections/shared_collection_id",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(
+            collection.unwrap()["book_ids"],
+            json!(["public_book_id", "user_book_id"])
+        );
+
+        // DELETE /collections/{collection_id}/books/{book_id}
+        let (status, _) = send(
+            &router,
+            request(
+                "DELETE",
+                "/collections/shared_collection_id/books/public_book_id",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+
+        // POST /collections/{collection_id}/books (公開されたコレクション)
+        let (status, _) = send(
+            &router,
+            request(
+                "POST",
+                "/collections/public_collection_id/books",
+                &user_cookie,
+                Some(json!({"book_id": "user_book_id"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // PATCH /collections/{collection_id} (作成者以外は名前を変更できない)
+        let (status, _) = send(
+            &router,
+            request(
+                "PATCH",
+                "/collections/shared_collection_id",
+                &user_cookie,
+                Some(json!({"name": "研究資料"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // PATCH /collections/{collection_id} (同じ名前)
+        let (status, error) = send(
+            &router,
+            request(
+                "PATCH",
+                "/collections/shared_collection_id",
+                &owner_cookie,
+                Some(json!({"name": "おすすめ"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+        assert_eq!(
+            error.unwrap(),
+            json!({"invalid collection request": "同じ名前のコレクションがあります"})
+        );
+
+        // PATCH /collections/{collection_id} (共有の解除)
+        let (status, collection) = send(
+            &router,
+            request(
+                "PATCH",
+                "/collections/shared_collection_id",
+                &owner_cookie,
+                Some(json!({"visibility": "private", "members": []})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(collection.unwrap()["members"], json!([]));
+        let (status, _) = send(
+            &router,
+            request(
+                "GET",
+                "/collections/shared_collection_id",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 404);
+
+        // DELETE /collections/{collection_id}
+        let (status, _) = send(
+            &router,
+            request(
+                "DELETE",
+                "/collections/public_collection_id",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+        let (status, _) = send(
+            &router,
+            request(
+                "DELETE",
+                &format!("/collections/{}", collection_id),
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+    }
+
+    /// 共有されたコレクションの本を作成者以外が置き換えるテスト
+    #[sqlx::test(fixtures("users", "collections"))]
+    async fn test_replace_shared_collection_books(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let owner_cookie = token_cookie_from_user_id("test_user_id", &pool).await;
+
+        // PATCH /collections/{collection_id} (メンバーが閲覧できない本は残す)
+        let (status, collection) = send(
+            &router,
+            request(
+                "PATCH",
+                "/collections/shared_collection_id",
+                &user_cookie,
+                Some(json!({"book_ids": ["user_book_id", "public_book_id"]})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            collection.unwrap()["book_ids"],
+            json!(["user_book_id", "public_book_id"])
+        );
+        let (_, collection) = send(
+            &router,
+            request(
+                "GET",
+                "/collections/shared_collection_id",
+                &owner_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(
+            collection.unwrap()["book_ids"],
+            json!(["private_book_id", "public_book_id"])
+        );
+        let book_ids = sqlx::query_scalar!(
+            r#"SELECT book_id FROM collection_books WHERE collection_id = 'shared_collection_id' ORDER BY "position""#
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            book_ids,
+            vec!["user_book_id", "private_book_id", "public_book_id"]
+        );
+
+        // PATCH /collections/{collection_id} (すべて削除)
+        let (status, collection) = send(
+            &router,
+            request(
+                "PATCH",
+                "/collections/shared_collection_id",
+                &user_cookie,
+                Some(json!({"book_ids": []})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(collection.unwrap()["book_ids"], json!([]));
+        let (_, collection) = send(
+            &router,
+            request(
+                "GET",
+                "/collections/shared_collection_id",
+                &owner_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(collection.unwrap()["book_ids"], json!(["private_book_id"]));
+    }
+}
@@ -0,0 +1,9 @@
+insert into
+  users (id, password, role, invitation_quota)
+values
+  (
+    'user_id',
+    'user_password',
+    'user',
+    2
+  );
@@ -1,9 +1,15 @@
 use core::fmt;
 
-use chrono::{Duration, Utc};
+use std::env;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
 use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::service::user::model::UserRole;
 
 /// DBのEnumとして定義したInvitationStateを使う
 #[derive(Debug, PartialEq, sqlx::Type)]
@@ -31,76 +37,429 @@ pub struct CheckInvitationRequest {
 }
 
 /// `POST /check_invitation` のレスポンス
+///
+/// 招待コードが使用可能な場合は `unused` と予約トークンを返す。
+/// 予約トークンは `POST /users` に指定する
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct CheckInvitationResponse {
     pub state: String,
+    /// 残りの使用可能回数
+    pub remaining_uses: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reservation_token: Option<String>,
+    /// 予約の有効期限
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub reserved_until: Option<DateTime<Utc>>,
+}
+
+/// 招待コードの予約の有効期間(秒)
+///
+/// `INVITATION_RESERVATION_SECONDS` で変更できる
+fn reservation_seconds() -> f64 {
+    env::var("INVITATION_RESERVATION_SECONDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(300.0)
 }
 
-/// 招待コードのDBクエリの結果
-#[derive(PartialEq)]
-struct InvitationStateQueryResult {
-    pub state: InvitationState,
-    pub used_at: Option<chrono::NaiveDateTime>,
+/// 予約トークンのハッシュを返す
+fn hash_reservation_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
-/// 招待コードの状態を確認する
+/// 招待コードの状態を確認し、使用可能であれば予約する
+///
+/// 存在しないコードや期限切れのコードは、存在を推測されないよう使用済みのコードと同じ結果を返す。
+/// 招待コードの行をロックしてから予約するため、
+/// 同時に確認しても残りの使用可能回数を超えて予約されることはない
 pub async fn check_invitation_state(
     db: &PgPool,
     invitation_code: &str,
 ) -> Result<CheckInvitationResponse, sqlx::Error> {
-    // 招待コードの状態を取得
-    let invitation_state_query_result = sqlx::query_as!(
-        InvitationStateQueryResult,
-        r#"SELECT state as "state: InvitationState", used_at FROM invitations WHERE code = $1"#,
+    let mut transaction = db.begin().await?;
+    let invitation = sqlx::query!(
+        r#"
+            SELECT state as "state: InvitationState", max_uses, use_count
+            FROM invitations
+            WHERE
+                code = $1
+                AND (state = 'used' OR expires_at IS NULL OR expires_at > now())
+            FOR UPDATE
+        "#,
+        invitation_code
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let Some(invitation) = invitation.filter(|i| i.state != InvitationState::Used) else {
+        return Ok(CheckInvitationResponse {
+            state: InvitationState::Used.to_string(),
+            remaining_uses: 0,
+            reservation_token: None,
+            reserved_until: None,
+        });
+    };
+    let remaining_uses = invitation.max_uses - invitation.use_count;
+
+    // 期限切れの予約を削除して、有効な予約の数を数える
+    sqlx::query!(
+        r#"DELETE FROM invitation_reservations WHERE code = $1 AND reserved_until <= now()"#,
         invitation_code
     )
-    .fetch_one(db)
+    .execute(&mut *transaction)
     .await?;
+    let reserved = sqlx::query_scalar!(
+        r#"SELECT count(*)::integer as "count!" FROM invitation_reservations WHERE code = $1"#,
+        invitation_code
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    if reserved >= remaining_uses {
+        transaction.commit().await?;
+        return Ok(CheckInvitationResponse {
+            state: InvitationState::Using.to_string(),
+            remaining_uses,
+            reservation_token: None,
+            reserved_until: None,
+        });
+    }
 
-    match invitation_state_query_result.state {
-        InvitationState::Unused => {
-            // 招待コードの状態が未使用の場合
-            // 招待コードの状態を using に更新
-            sqlx::query!(
-                r#"UPDATE invitations SET state = 'using', used_at = $1 WHERE code = $2"#,
-                Utc::now().naive_utc(),
-                invitation_code
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let reserved_until = sqlx::query_scalar!(
+        r#"
+            INSERT INTO invitation_reservations (token_hash, code, reserved_until)
+            VALUES ($1, $2, now() + make_interval(secs => $3))
+            RETURNING reserved_until
+        "#,
+        hash_reservation_token(&token),
+        invitation_code,
+        reservation_seconds()
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(CheckInvitationResponse {
+        state: InvitationState::Unused.to_string(),
+        remaining_uses,
+        reservation_token: Some(token),
+        reserved_until: Some(reserved_until),
+    })
+}
+
+/// 招待コードの使用で新しいユーザーに付与するもの
+pub struct Redemption {
+    pub role: UserRole,
+    pub group_name: Option<String>,
+}
+
+/// 予約トークンで招待コードを1回使用する
+///
+/// 予約は削除され、使用回数が上限に達すると使用済みになる。
+/// 予約トークンが一致しないか予約の期限が切れている場合は `None` を返す
+pub async fn redeem_reservation(
+    conn: &mut PgConnection,
+    invitation_code: &str,
+    reservation_token: &str,
+) -> Result<Option<Redemption>, sqlx::Error> {
+    sqlx::query_as!(
+        Redemption,
+        r#"
+            WITH r AS (
+                DELETE FROM invitation_reservations
+                WHERE token_hash = $2 AND code = $1 AND reserved_until > now()
+                RETURNING code
             )
-            .execute(db)
-            .await?;
-            Ok(CheckInvitationResponse {
-                state: InvitationState::Unused.to_string(),
-            })
-        }
-        InvitationState::Using => {
-            // 招待コードの状態が使用中の場合
-            if invitation_state_query_result.used_at.is_none()
-                || invitation_state_query_result.used_at.unwrap() + Duration::minutes(5)
-                    < Utc::now().naive_utc()
-            {
-                // 使用中の招待コードが5分以上経過している場合
-                // 招待コードの状態を unused に更新
-                sqlx::query!(
-                    r#"UPDATE invitations SET state = 'unused', used_at = null WHERE code = $1"#,
-                    invitation_code
-                )
-                .execute(db)
-                .await?;
-                Ok(CheckInvitationResponse {
-                    state: InvitationState::Unused.to_string(),
-                })
-            } else {
-                // 使用中の招待コードが5分以内の場合
-                Ok(CheckInvitationResponse {
-                    state: InvitationState::Using.to_string(),
-                })
-            }
+            UPDATE invitations i
+            SET
+                use_count = i.use_count + 1,
+                state = CASE WHEN i.use_count + 1 >= i.max_uses THEN 'used' ELSE 'unused' END::invitation_state,
+                used_at = now()
+            FROM r
+            WHERE
+                i.code = r.code
+                AND i.state != 'used'
+                AND (i.expires_at IS NULL OR i.expires_at > now())
+            RETURNING i.role as "role: UserRole", i.group_name
+        "#,
+        invitation_code,
+        hash_reservation_token(reservation_token)
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+/// 予約なしで招待コードを1回使用する
+///
+/// 他のクライアントの有効な予約の分は使用できない
+pub async fn redeem_without_reservation(
+    conn: &mut PgConnection,
+    invitation_code: &str,
+) -> Result<Option<Redemption>, sqlx::Error> {
+    sqlx::query_as!(
+        Redemption,
+        r#"
+            UPDATE invitations i
+            SET
+                use_count = i.use_count + 1,
+                state = CASE WHEN i.use_count + 1 >= i.max_uses THEN 'used' ELSE 'unused' END::invitation_state,
+                used_at = now()
+            WHERE
+                i.code = $1
+                AND i.state != 'used'
+                AND (i.expires_at IS NULL OR i.expires_at > now())
+                AND i.use_count + (
+                    SELECT count(*)
+                    FROM invitation_reservations r
+                    WHERE r.code = i.code AND r.reserved_until > now()
+                ) < i.max_uses
+            RETURNING i.role as "role: UserRole", i.group_name
+        "#,
+        invitation_code
+    )
+    .fetch_optional(conn)
+    .await
+}
+
+/// ユーザーをグループに追加する
+pub async fn add_user_to_group(
+    conn: &mut PgConnection,
+    user_id: &str,
+    group_name: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"INSERT INTO user_groups (user_id, group_name) VALUES ($1, $2) ON CONFLICT DO NOTHING"#,
+        user_id,
+        group_name
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// 招待コードの情報
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Invitation {
+    pub code: String,
+    /// `unused`・`used` のいずれか
+    pub state: String,
+    /// 使用可能な回数
+    pub max_uses: i32,
+    /// 使用された回数
+    pub use_count: i32,
+    /// 登録したユーザーに付与するロール
+    #[schema(inline)]
+    pub role: UserRole,
+    /// 登録したユーザーを追加するグループ
+    pub group_name: Option<String>,
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+}
+
+/// `POST /invitations` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct NewInvitationsRequest {
+    /// 発行する数(デフォルト: 1)
+    pub count: Option<i64>,
+    /// 有効期限(日)。省略時は無期限
+    pub expires_in_days: Option<i32>,
+    /// 1つの招待コードを使用できる回数(デフォルト: 1)
+    pub max_uses: Option<i32>,
+    /// 登録したユーザーに付与するロール(管理者のみ指定可能、デフォルト: `User`)
+    #[schema(inline)]
+    pub role: Option<UserRole>,
+    /// 登録したユーザーを追加するグループ(管理者のみ指定可能)
+    pub group_name: Option<String>,
+}
+
+/// ユーザーごとの招待コードの発行上限のデフォルト値
+///
+/// `INVITATION_QUOTA` で変更できる
+pub fn default_invitation_quota() -> i32 {
+    env::var("INVITATION_QUOTA")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5)
+}
+
+/// 発行した招待コードの一覧を取得する
+pub async fn get_invitations(user_id: &str, db: &PgPool) -> Result<Vec<Invitation>, sqlx::Error> {
+    sqlx::query_as!(
+        Invitation,
+        r#"
+            SELECT
+                code,
+                state::text as "state!",
+                max_uses,
+                use_count,
+                role as "role: UserRole",
+                group_name,
+                expires_at,
+                created_at
+            FROM invitations
+            WHERE issuer_id = $1
+            ORDER BY created_at DESC, code
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// 招待コードを発行する
+///
+/// 管理者以外は、使用済みもしくは有効期限内の招待コードの使用可能回数の合計が
+/// 発行上限を超えない範囲でのみ発行できる
+pub async fn create_invitations(
+    user_id: &str,
+    req: NewInvitationsRequest,
+    db: &PgPool,
+) -> Result<Vec<Invitation>, String> {
+    let count = req.count.unwrap_or(1);
+    if !(1..=100).contains(&count) {
+        return Err("発行数は1以上100以下で指定してください".to_string());
+    }
+    if req.expires_in_days.is_some_and(|days| days < 1) {
+        return Err("有効期限は1日以上で指定してください".to_string());
+    }
+    let max_uses = req.max_uses.unwrap_or(1);
+    if max_uses < 1 {
+        return Err("使用可能な回数は1以上で指定してください".to_string());
+    }
+    if req.group_name.as_deref().is_some_and(str::is_empty) {
+        return Err("グループ名を指定してください".to_string());
+    }
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    // 同時に発行して上限を超えないよう、ユーザーの行をロックする
+    let user = sqlx::query!(
+        r#"SELECT role as "role: UserRole", invitation_quota FROM users WHERE id = $1 FOR UPDATE"#,
+        user_id
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if user.role != UserRole::Admin {
+        if req.role.is_some_and(|role| role != UserRole::User) || req.group_name.is_some() {
+            return Err("ロール・グループは管理者のみ指定できます".to_string());
         }
-        InvitationState::Used => {
-            // 招待コードの状態が使用済みの場合
-            Ok(CheckInvitationResponse {
-                state: InvitationState::Used.to_string(),
-            })
+        // 有効期限切れの招待コードは使用された回数のみを数える
+        let issued = sqlx::query_scalar!(
+            r#"
+                SELECT coalesce(sum(
+                    CASE
+                        WHEN state != 'used' AND expires_at <= now() THEN use_count
+                        ELSE max_uses
+                    END
+                ), 0)::bigint as "count!"
+                FROM invitations
+                WHERE issuer_id = $1
+            "#,
+            user_id
+        )
+        .fetch_one(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+        let quota = user
+            .invitation_quota
+            .unwrap_or_else(default_invitation_quota) as i64;
+        if issued + count * max_uses as i64 > quota {
+            return Err(format!(
+                "招待コードの発行上限({})を超えています(発行済み: {})",
+                quota, issued
+            ));
         }
     }
+
+    let codes = (0..count)
+        .map(|_| Uuid::new_v4().simple().to_string())
+        .collect::<Vec<_>>();
+    let invitations = sqlx::query_as!(
+        Invitation,
+        r#"
+            INSERT INTO invitations (code, issuer_id, expires_at, max_uses, role, group_name)
+            SELECT code, $2, now() + make_interval(days => $3), $4, $5, $6
+            FROM unnest($1::text[]) AS code
+            RETURNING
+                code,
+                state::text as "state!",
+                max_uses,
+                use_count,
+                role as "role: UserRole",
+                group_name,
+                expires_at,
+                created_at
+        "#,
+        &codes,
+        user_id,
+        req.expires_in_days,
+        max_uses,
+        req.role.unwrap_or(UserRole::User) as UserRole,
+        req.group_name
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    Ok(invitations)
+}
+
+/// 使用済みでない招待コードを取り消す
+///
+/// 一度も使用されていない場合は削除し、使用されている場合はそれ以上使用できないようにする。
+/// 管理者はすべてのユーザーの招待コードを取り消せる
+pub async fn revoke_invitation(
+    user_id: &str,
+    is_admin: bool,
+    code: &str,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = db.begin().await?;
+    let revoked = sqlx::query!(
+        r#"
+            DELETE FROM invitations
+            WHERE
+                code = $1
+                AND (issuer_id = $2 OR $3)
+                AND use_count = 0
+                AND state != 'used'
+            RETURNING code
+        "#,
+        code,
+        user_id,
+        is_admin
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if revoked.is_none() {
+        sqlx::query!(
+            r#"
+                UPDATE invitations
+                SET state = 'used', max_uses = use_count
+                WHERE
+                    code = $1
+                    AND (issuer_id = $2 OR $3)
+                    AND state != 'used'
+                RETURNING code
+            "#,
+            code,
+            user_id,
+            is_admin
+        )
+        .fetch_optional(&mut *transaction)
+        .await?
+        .ok_or(sqlx::Error::RowNotFound)?;
+        sqlx::query!(
+            r#"DELETE FROM invitation_reservations WHERE code = $1"#,
+            code
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+    Ok(())
 }
@@ -1,22 +1,114 @@
-use super::model;
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use super::model::{self, InvitationState};
+use crate::{
+    service::user::{auth::AuthUser, model::UserError},
+    throttle::{self, too_many_requests, ClientIp, ThrottleKey},
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
 use sqlx::PgPool;
 
+/// 招待コードの状態を確認する
+///
+/// 存在しないコードや期限切れのコードは使用済みのコードと同じく `used` を返す。
+/// 使用できないコードの確認が続いたIPアドレスは一定時間確認できない
 #[utoipa::path(
     post,
     path = "/check_invitation",
     request_body = inline(model::CheckInvitationRequest),
     responses(
         (status = 200, description = "OK", body = inline(model::CheckInvitationResponse)),
-        (status = 404, description = "Not Found"),
+        (status = 429, description = "Too Many Requests", body = inline(UserError), example = json!(UserError::TooManyRequests(String::from("60秒後に再試行してください")))),
     )
 )]
 pub async fn check_invitation(
+    ClientIp(ip): ClientIp,
     State(db): State<PgPool>,
     Json(body): Json<model::CheckInvitationRequest>,
 ) -> impl IntoResponse {
-    match model::check_invitation_state(&db, &body.invitation_code).await {
-        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+    let keys = [ThrottleKey::InvitationIp(&ip)];
+    match throttle::retry_after(&keys, &db).await {
+        Ok(Some(retry_after)) => return too_many_requests(retry_after),
+        Ok(None) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+
+    let response = match model::check_invitation_state(&db, &body.invitation_code).await {
+        Ok(response) => response,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    if response.state == InvitationState::Used.to_string() {
+        if let Err(e) = throttle::record_failure(&keys, &db).await {
+            log::error!("Failed to record invitation check failure: {}", e);
+        }
+    }
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+/// 発行した招待コードの一覧を取得する
+#[utoipa::path(
+    get,
+    path = "/invitations",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::Invitation>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_invitations(user: AuthUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::get_invitations(&user.id, &db).await {
+        Ok(invitations) => (StatusCode::OK, Json(invitations)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// 招待コードを発行する
+///
+/// 管理者以外はユーザーごとの発行上限まで発行できる
+#[utoipa::path(
+    post,
+    path = "/invitations",
+    request_body = inline(model::NewInvitationsRequest),
+    responses(
+        (status = 201, description = "Created", body = inline(Vec<model::Invitation>)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidInvitationRequest(String::from("招待コードの発行上限(5)を超えています(発行済み: 5)")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn new_invitations(
+    user: AuthUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::NewInvitationsRequest>,
+) -> impl IntoResponse {
+    match model::create_invitations(&user.id, body, &db).await {
+        Ok(invitations) => (StatusCode::CREATED, Json(invitations)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidInvitationRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// 未使用の招待コードを取り消す
+#[utoipa::path(
+    delete,
+    path = "/invitations/{code}",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn delete_invitation(
+    user: AuthUser,
+    Path(code): Path<String>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::revoke_invitation(&user.id, user.is_admin(), &code, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
         Err(_) => (StatusCode::NOT_FOUND).into_response(),
     }
 }
@@ -25,14 +117,14 @@ pub async fn check_invitation(
 mod tests {
     use std::str::from_utf8;
 
-    use crate::routes::init_app;
+    use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
 
     use super::*;
     use axum::{
         body::{to_bytes, Body},
         http::{header, Method, Request},
     };
-    use serde_json::{json, to_string};
+    use serde_json::{json, to_string, Value};
     use tower::ServiceExt;
 
     #[sqlx::test(fixtures("invitations"))]
@@ -54,7 +146,9 @@ mod tests {
             ))
             .unwrap();
         let res = router.clone().oneshot(req).await.unwrap();
-        assert_eq!(res.status(), 404);
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let not_found = serde_json::from_slice::<Value>(&bytes).unwrap();
 
         // POST /check_invitation (unused)
         let req = Request::builder()
@@ -73,16 +167,10 @@ mod tests {
         let res = router.clone().oneshot(req).await.unwrap();
         assert_eq!(res.status(), 200);
         let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
-        let text = from_utf8(&bytes).unwrap();
-        assert_eq!(
-            text,
-            to_string(&json!(
-                {
-                    "state": "unused"
-                }
-            ))
-            .unwrap()
-        );
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["state"], "unused");
+        assert!(body["reservation_token"].is_string());
+        assert!(body["reserved_until"].is_string());
 
         // POST /check_invitation (using)
         let req = Request::builder()
@@ -103,13 +191,13 @@ mod tests {
         let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
         let text = from_utf8(&bytes).unwrap();
         assert_eq!(
-            text,
-            to_string(&json!(
+            serde_json::from_str::<Value>(text).unwrap(),
+            json!(
                 {
-                    "state": "using"
+                    "state": "using",
+                    "remaining_uses": 1
                 }
-            ))
-            .unwrap()
+            )
         );
 
         // POST /check_invitation (used)
@@ -131,13 +219,297 @@ mod tests {
         let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
         let text = from_utf8(&bytes).unwrap();
         assert_eq!(
-            text,
-            to_string(&json!(
+            serde_json::from_str::<Value>(text).unwrap(),
+            json!(
                 {
-                    "state": "used"
+                    "state": "used",
+                    "remaining_uses": 0
                 }
-            ))
-            .unwrap()
+            )
         );
+        // 存在しないコードと使用済みのコードは区別できない
+        assert_eq!(serde_json::from_str::<Value>(text).unwrap(), not_found);
+    }
+
+    #[sqlx::test(fixtures("invitations"))]
+    async fn test_check_invitation_throttle(pool: PgPool) {
+        let router = init_app(&pool);
+        let check = |code: &'static str| {
+            Request::builder()
+                .uri("/check_invitation")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    to_string(&json!(
+                        {
+                            "invitation_code": code
+                        }
+                    ))
+                    .unwrap(),
+                ))
+                .unwrap()
+        };
+
+        // 存在しないコードの確認を繰り返すと制限される
+        for _ in 0..5 {
+            let res = router.clone().oneshot(check("not_found")).await.unwrap();
+            assert_eq!(res.status(), 200);
+        }
+        let res = router
+            .clone()
+            .oneshot(check("unused_test_code"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 429);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+    }
+
+    #[sqlx::test(fixtures("invitations", "users"))]
+    async fn test_invitations(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+        let new_invitations = |cookie: &str, body: Value| {
+            Request::builder()
+                .uri("/invitations")
+                .method(Method::POST)
+                .header(header::COOKIE, cookie)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(to_string(&body).unwrap()))
+                .unwrap()
+        };
+        let delete_invitation = |cookie: &str, code: &str| {
+            Request::builder()
+                .uri(format!("/invitations/{}", code))
+                .method(Method::DELETE)
+                .header(header::COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // 発行上限まで発行できる
+        let res = router
+            .clone()
+            .oneshot(new_invitations(&user_cookie, json!({"count": 2})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let invitations: Vec<model::Invitation> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(invitations.len(), 2);
+        assert!(invitations.iter().all(|i| i.state == "unused"));
+
+        // 発行上限を超える場合
+        let res = router
+            .clone()
+            .oneshot(new_invitations(&user_cookie, json!({})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 400);
+
+        // 取り消すと再び発行できる
+        let res = router
+            .clone()
+            .oneshot(delete_invitation(&user_cookie, &invitations[0].code))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 204);
+        let res = router
+            .clone()
+            .oneshot(delete_invitation(&user_cookie, &invitations[0].code))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+        let res = router
+            .clone()
+            .oneshot(new_invitations(&user_cookie, json!({"expires_in_days": 7})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let invitations: Vec<model::Invitation> = serde_json::from_slice(&bytes).unwrap();
+        assert!(invitations[0].expires_at.is_some());
+
+        // 有効期限が切れた招待コードは使用できず、発行上限にも含まれない
+        sqlx::query!(
+            r#"UPDATE invitations SET expires_at = now() - interval '1 day' WHERE code = $1"#,
+            invitations[0].code
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let req = Request::builder()
+            .uri("/check_invitation")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!({"invitation_code": invitations[0].code})).unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["state"], "used");
+        let res = router
+            .clone()
+            .oneshot(new_invitations(&user_cookie, json!({})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+
+        // 一覧の取得
+        let req = Request::builder()
+            .uri("/invitations")
+            .header(header::COOKIE, &user_cookie)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let invitations: Vec<model::Invitation> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(invitations.len(), 3);
+
+        // 使用済みの招待コードは取り消せない
+        let res = router
+            .clone()
+            .oneshot(delete_invitation(&admin_cookie, "used_test_code"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 404);
+
+        // 管理者は他のユーザーの招待コードも取り消せる
+        let res = router
+            .clone()
+            .oneshot(delete_invitation(&admin_cookie, &invitations[0].code))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 204);
+
+        // 管理者は上限なく発行できる
+        let res = router
+            .clone()
+            .oneshot(new_invitations(&admin_cookie, json!({"count": 20})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+    }
+
+    #[sqlx::test(fixtures("invitations", "users"))]
+    async fn test_multi_use_invitation(pool: PgPool) {
+        let router = init_app(&pool);
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let new_invitations = |cookie: &str, body: Value| {
+            Request::builder()
+                .uri("/invitations")
+                .method(Method::POST)
+                .header(header::COOKIE, cookie)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(to_string(&body).unwrap()))
+                .unwrap()
+        };
+        let check = |code: &str| {
+            Request::builder()
+                .uri("/check_invitation")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    to_string(&json!({ "invitation_code": code })).unwrap(),
+                ))
+                .unwrap()
+        };
+
+        // 管理者以外はロール・グループを指定できない
+        let res = router
+            .clone()
+            .oneshot(new_invitations(&user_cookie, json!({"role": "Admin"})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 400);
+        // 管理者以外は使用可能な回数の合計が発行上限を超えない範囲で発行できる
+        let res = router
+            .clone()
+            .oneshot(new_invitations(&user_cookie, json!({"max_uses": 3})))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 400);
+
+        // 3回使用でき、管理者ロールとグループを付与する招待コード
+        let res = router
+            .clone()
+            .oneshot(new_invitations(
+                &admin_cookie,
+                json!({"max_uses": 3, "role": "Admin", "group_name": "team"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 201);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let invitations: Vec<model::Invitation> = serde_json::from_slice(&bytes).unwrap();
+        let code = invitations[0].code.clone();
+
+        // 使用可能な回数まで予約できる
+        let mut tokens = vec![];
+        for _ in 0..4 {
+            let res = router.clone().oneshot(check(&code)).await.unwrap();
+            assert_eq!(res.status(), 200);
+            let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let body: Value = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(body["remaining_uses"], 3);
+            if let Some(token) = body["reservation_token"].as_str() {
+                tokens.push(token.to_string());
+            } else {
+                assert_eq!(body["state"], "using");
+            }
+        }
+        assert_eq!(tokens.len(), 3);
+
+        // 登録したユーザーにはロールとグループが付与される
+        for (i, token) in tokens.iter().take(2).enumerate() {
+            let req = Request::builder()
+                .uri("/users")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    to_string(&json!({
+                        "id": format!("team_user_{}", i),
+                        "password": "Test1234",
+                        "invitation_code": code,
+                        "reservation_token": token
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap();
+            let res = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), 204);
+        }
+        let users = sqlx::query!(
+            r#"
+                SELECT u.id, u.role::text as "role!", g.group_name
+                FROM users u
+                JOIN user_groups g ON g.user_id = u.id
+                WHERE u.invitation_code = $1
+                ORDER BY u.id
+            "#,
+            code
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users
+            .iter()
+            .all(|u| u.role == "admin" && u.group_name == "team"));
+
+        // 残りの使用可能回数
+        sqlx::query!(r#"DELETE FROM invitation_reservations"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let res = router.clone().oneshot(check(&code)).await.unwrap();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["state"], "unused");
+        assert_eq!(body["remaining_uses"], 1);
     }
 }
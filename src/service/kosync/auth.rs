@@ -0,0 +1,78 @@
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{request::Parts, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::PgPool;
+
+use super::model::{KosyncError, ERROR_UNAUTHORIZED_USER};
+use crate::service::{
+    api_key::model::{find_kosync_key_owner, ApiKeyScope},
+    user::auth::AuthUser,
+};
+
+/// kosyncで認証済みのユーザー
+///
+/// `X-Auth-User` にユーザーID、`X-Auth-Key` にAPIキーまたはそのMD5を指定する。
+/// KOReaderではパスワードにAPIキーを入力すればよい。
+/// GET・HEADは `read`、それ以外は `write` スコープが必要
+pub struct KosyncUser(pub AuthUser);
+
+/// kosyncの認証に失敗した場合のエラー
+///
+/// 常に401と `KosyncError` を返す
+#[derive(Debug)]
+pub struct KosyncAuthError;
+
+impl IntoResponse for KosyncAuthError {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(KosyncError {
+                code: ERROR_UNAUTHORIZED_USER,
+                message: "Unauthorized".to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+impl<S> FromRequestParts<S> for KosyncUser
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = KosyncAuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let header = |name: &str| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .ok_or(KosyncAuthError)
+        };
+        let user_id = header("X-Auth-User")?;
+        let key = header("X-Auth-Key")?;
+        let owner = find_kosync_key_owner(user_id, key, &PgPool::from_ref(state))
+            .await
+            .ok_or(KosyncAuthError)?;
+        let user = AuthUser {
+            id: owner.user_id,
+            role: owner.role,
+            session_id: None,
+            scopes: Some(owner.scopes),
+        };
+        let scope = match parts.method {
+            Method::GET | Method::HEAD => ApiKeyScope::Read,
+            _ => ApiKeyScope::Write,
+        };
+        if !user.allows(scope) {
+            return Err(KosyncAuthError);
+        }
+        Ok(Self(user))
+    }
+}
@@ -0,0 +1,61 @@
+insert into
+    books(
+        id,
+        "key",
+        owner_id,
+        "name",
+        creator,
+        publisher,
+        "date",
+        cover_image,
+        visibility,
+        layout,
+        images
+    )
+values
+    (
+        'novel_book_id',
+        'novel_book_key',
+        'user_id',
+        'novel_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'reflowable',
+        '{}'
+    ),
+    (
+        'comic_book_id',
+        'comic_book_key',
+        'test_user_id',
+        'comic_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'public',
+        'pre-paginated',
+        '{page_1, page_2, page_3}'
+    ),
+    (
+        'private_book_id',
+        'private_book_key',
+        'test_user_id',
+        'private_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'reflowable',
+        '{}'
+    );
+
+insert into
+    book_file_hashes(book_id, hash)
+values
+    ('novel_book_id', '0123456789abcdef0123456789abcdef'),
+    ('comic_book_id', 'fedcba9876543210fedcba9876543210'),
+    ('private_book_id', '00000000000000000000000000000000');
@@ -0,0 +1,291 @@
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use utoipa::ToSchema;
+
+use crate::service::book::model::BookLayout;
+
+/// kosyncのエラーコード
+///
+/// KOReaderのkosyncサーバーと同じコードを使う
+pub const ERROR_INTERNAL: u32 = 2000;
+pub const ERROR_UNAUTHORIZED_USER: u32 = 2001;
+pub const ERROR_INVALID_FIELDS: u32 = 2003;
+pub const ERROR_DOCUMENT_FIELD_MISSING: u32 = 2004;
+
+/// kosyncのエラーレスポンス
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
+pub struct KosyncError {
+    pub code: u32,
+    pub message: String,
+}
+
+/// `GET /kosync/users/auth` のレスポンス
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct KosyncAuthResponse {
+    /// 常に `OK`
+    pub authorized: String,
+}
+
+/// kosyncの読書位置
+///
+/// 記録がない場合はすべての項目を省略する
+#[derive(Serialize, Deserialize, ToSchema, Default)]
+pub struct KosyncProgress {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<String>,
+    /// KOReaderのXPointerまたはページ番号(Webの読書位置の場合は空文字列)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub progress: Option<String>,
+    /// 読んだ割合(0.0〜1.0)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// 記録した日時(UNIX時間の秒)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+/// `PUT /kosync/syncs/progress` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UpdateKosyncProgressRequest {
+    /// EPUBファイルの部分MD5
+    pub document: String,
+    /// KOReaderのXPointerまたはページ番号
+    pub progress: String,
+    /// 読んだ割合(0.0〜1.0)
+    pub percentage: f64,
+    pub device: String,
+    pub device_id: Option<String>,
+}
+
+/// `PUT /kosync/syncs/progress` のレスポンス
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UpdateKosyncProgressResponse {
+    pub document: String,
+    /// 記録した日時(UNIX時間の秒)
+    pub timestamp: i64,
+}
+
+/// ハッシュに対応する本
+struct HashedBook {
+    id: String,
+    layout: Option<BookLayout>,
+    page_count: i32,
+}
+
+/// 端末名の最大文字数
+const MAX_DEVICE_LENGTH: usize = 100;
+
+/// XPointerの最大文字数
+const MAX_PROGRESS_LENGTH: usize = 1024;
+
+/// KOReaderと同じ方法でファイルの部分MD5を計算する
+///
+/// 先頭と、1024バイトを4倍ずつずらした位置から1024バイトずつを読み込んでMD5を計算する
+pub fn partial_md5(data: &[u8]) -> String {
+    let mut hasher = Md5::new();
+    for i in -1..=10 {
+        // KOReaderはLuaJITの `bit.lshift(1024, -2)` を使うため、最初の位置は0になる
+        let offset = if i < 0 { 0 } else { 1024usize << (2 * i) };
+        if offset >= data.len() {
+            break;
+        }
+        hasher.update(&data[offset..(offset + 1024).min(data.len())]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// 本のファイルのハッシュを記録する
+///
+/// 記録済みのハッシュの場合は `false` を返す
+pub async fn add_book_file_hash(
+    book_id: &str,
+    hash: &str,
+    conn: &mut PgConnection,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+            INSERT INTO book_file_hashes (book_id, hash)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
+        "#,
+        book_id,
+        hash
+    )
+    .execute(conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// ハッシュから閲覧できる本を取得する
+///
+/// 同じファイルの本が複数ある場合は自分の本を優先する
+async fn find_book_by_hash(
+    document: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Option<HashedBook>, sqlx::Error> {
+    sqlx::query_as!(
+        HashedBook,
+        r#"
+            SELECT
+                b.id,
+                b.layout as "layout: BookLayout",
+                cardinality(b.images) as "page_count!"
+            FROM book_file_hashes h
+            JOIN books b ON b.id = h.book_id
+            WHERE h.hash = $1
+            AND ($3 OR b.owner_id = $2 OR b.visibility = 'public')
+            ORDER BY b.owner_id = $2 DESC, h.created_at DESC, b.id
+            LIMIT 1
+        "#,
+        document.to_lowercase(),
+        user_id,
+        is_admin
+    )
+    .fetch_optional(db)
+    .await
+}
+
+/// ハッシュに対応する本の読書位置を取得する
+///
+/// 本や記録がない場合はすべての項目が `None` の読書位置を返す
+pub async fn get_progress(
+    document: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<KosyncProgress, sqlx::Error> {
+    let Some(book) = find_book_by_hash(document, user_id, is_admin, db).await? else {
+        return Ok(KosyncProgress::default());
+    };
+    let progress = sqlx::query!(
+        r#"
+            SELECT kosync_progress, page, percentage, device, device_id, updated_at
+            FROM reading_progress
+            WHERE user_id = $1
+            AND book_id = $2
+        "#,
+        user_id,
+        book.id
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(match progress {
+        Some(progress) => KosyncProgress {
+            document: Some(document.to_string()),
+            progress: Some(progress.kosync_progress.unwrap_or_default()),
+            percentage: Some(progress.percentage.unwrap_or(
+                // Webで記録したpre-paginatedの本の読書位置はページ番号から割合を求める
+                match (book.layout, progress.page) {
+                    (Some(BookLayout::PrePaginated), Some(page)) if book.page_count > 1 => {
+                        page as f64 / (book.page_count - 1) as f64
+                    }
+                    _ => 0.0,
+                },
+            )),
+            device: Some(progress.device),
+            device_id: progress.device_id,
+            timestamp: Some(progress.updated_at.timestamp()),
+        },
+        None => KosyncProgress::default(),
+    })
+}
+
+/// KOReaderの読書位置を記録する
+///
+/// pre-paginatedの本は読んだ割合からページ番号を求めて記録する。
+/// KOReaderは日時を送信しないため、常にサーバーの現在日時で上書きする
+pub async fn update_progress(
+    user_id: &str,
+    is_admin: bool,
+    req: UpdateKosyncProgressRequest,
+    db: &PgPool,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let device = req.device.trim();
+    if req.document.is_empty()
+        || device.is_empty()
+        || device.chars().count() > MAX_DEVICE_LENGTH
+        || req.progress.chars().count() > MAX_PROGRESS_LENGTH
+        || !(0.0..=1.0).contains(&req.percentage)
+    {
+        return Err("Invalid request".to_string());
+    }
+    let Some(book) = find_book_by_hash(&req.document, user_id, is_admin, db)
+        .await
+        .map_err(|e| e.to_string())?
+    else {
+        return Ok(None);
+    };
+    let page = match book.layout {
+        Some(BookLayout::PrePaginated) => {
+            Some((req.percentage * (book.page_count - 1).max(0) as f64).round() as i32)
+        }
+        _ => None,
+    };
+
+    let updated_at = sqlx::query_scalar!(
+        r#"
+            INSERT INTO reading_progress (
+                user_id,
+                book_id,
+                page,
+                percentage,
+                device,
+                kosync_progress,
+                device_id
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (user_id, book_id) DO UPDATE
+            SET
+                page = excluded.page,
+                cfi = NULL,
+                percentage = excluded.percentage,
+                device = excluded.device,
+                kosync_progress = excluded.kosync_progress,
+                device_id = excluded.device_id,
+                updated_at = now()
+            RETURNING updated_at
+        "#,
+        user_id,
+        book.id,
+        page,
+        req.percentage,
+        device,
+        req.progress,
+        req.device_id
+    )
+    .fetch_one(db)
+    .await
+    .map_err(|e| e.to_string())?;
+    Ok(Some(updated_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use md5::{Digest, Md5};
+
+    use super::partial_md5;
+
+    /// 部分MD5のテスト
+    #[test]
+    fn test_partial_md5() {
+        // 1024バイト以下のファイルは全体のMD5と同じ
+        assert_eq!(partial_md5(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(partial_md5(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+
+        // 先頭・1024・4096バイト目から1024バイトずつ読み込む
+        let data = (0..5000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let mut sample = data[..1024].to_vec();
+        sample.extend_from_slice(&data[1024..2048]);
+        sample.extend_from_slice(&data[4096..]);
+        assert_eq!(partial_md5(&data), format!("{:x}", Md5::digest(&sample)));
+    }
+}
@@ -0,0 +1,376 @@
+use super::{auth::KosyncUser, model};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use sqlx::PgPool;
+
+/// kosyncのユーザー登録
+///
+/// 既存のアカウントとAPIキーを使用するため、常に失敗する
+#[utoipa::path(
+    post,
+    path = "/kosync/users/create",
+    responses(
+        (status = 403, description = "Forbidden", body = inline(model::KosyncError)),
+    )
+)]
+pub async fn kosync_create_user() -> impl IntoResponse {
+    (
+        StatusCode::FORBIDDEN,
+        Json(model::KosyncError {
+            code: model::ERROR_INVALID_FIELDS,
+            message: "Registration is disabled. Log in with your user ID and an API key."
+                .to_string(),
+        }),
+    )
+}
+
+/// kosyncの認証
+///
+/// `X-Auth-User` にユーザーID、`X-Auth-Key` にAPIキーまたはそのMD5を指定する
+#[utoipa::path(
+    get,
+    path = "/kosync/users/auth",
+    responses(
+        (status = 200, description = "OK", body = inline(model::KosyncAuthResponse)),
+        (status = 401, description = "Unauthorized", body = inline(model::KosyncError)),
+    )
+)]
+pub async fn kosync_auth(_: KosyncUser) -> impl IntoResponse {
+    Json(model::KosyncAuthResponse {
+        authorized: "OK".to_string(),
+    })
+}
+
+/// kosyncの読書位置を取得する
+///
+/// document: EPUBファイルの部分MD5
+///
+/// 本や記録がない場合は空のオブジェクトを返す
+#[utoipa::path(
+    get,
+    path = "/kosync/syncs/progress/{document}",
+    responses(
+        (status = 200, description = "OK", body = inline(model::KosyncProgress)),
+        (status = 401, description = "Unauthorized", body = inline(model::KosyncError)),
+    )
+)]
+pub async fn kosync_get_progress(
+    Path(document): Path<String>,
+    user: KosyncUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_progress(&document, &user.0.id, user.0.is_admin(), &db).await {
+        Ok(progress) => (StatusCode::OK, Json(progress)).into_response(),
+        Err(_) => internal_error().into_response(),
+    }
+}
+
+/// kosyncの読書位置を記録する
+///
+/// Webの読書位置と共有し、常に最新の読書位置として上書きする
+#[utoipa::path(
+    put,
+    path = "/kosync/syncs/progress",
+    request_body = inline(model::UpdateKosyncProgressRequest),
+    responses(
+        (status = 200, description = "OK", body = inline(model::UpdateKosyncProgressResponse)),
+        (status = 401, description = "Unauthorized", body = inline(model::KosyncError)),
+        (status = 403, description = "Forbidden", body = inline(model::KosyncError)),
+        (status = 404, description = "Not Found", body = inline(model::KosyncError)),
+    )
+)]
+pub async fn kosync_update_progress(
+    user: KosyncUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::UpdateKosyncProgressRequest>,
+) -> impl IntoResponse {
+    let document = body.document.clone();
+    match model::update_progress(&user.0.id, user.0.is_admin(), body, &db).await {
+        Ok(Some(updated_at)) => (
+            StatusCode::OK,
+            Json(model::UpdateKosyncProgressResponse {
+                document,
+                timestamp: updated_at.timestamp(),
+            }),
+        )
+            .into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(model::KosyncError {
+                code: model::ERROR_DOCUMENT_FIELD_MISSING,
+                message: "Document not found".to_string(),
+            }),
+        )
+            .into_response(),
+        Err(e) => (
+            StatusCode::FORBIDDEN,
+            Json(model::KosyncError {
+                code: model::ERROR_INVALID_FIELDS,
+                message: e,
+            }),
+        )
+            .into_response(),
+    }
+}
+
+fn internal_error() -> (StatusCode, Json<model::KosyncError>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(model::KosyncError {
+            code: model::ERROR_INTERNAL,
+            message: "Internal server error".to_string(),
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use md5::{Digest, Md5};
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::{
+        routes::init_app,
+        service::{
+            api_key::model::ApiKeyScope,
+            api_key::model::{create_api_key, NewApiKeyRequest},
+            user::model::token_cookie_from_user_id,
+        },
+    };
+
+    fn request(
+        method: &str,
+        uri: &str,
+        user: &str,
+        key: &str,
+        body: Option<Value>,
+    ) -> Request<Body> {
+        let builder = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header(header::ACCEPT, "application/vnd.koreader.v1+json")
+            .header("X-Auth-User", user)
+            .header("X-Auth-Key", key);
+        match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(Body::empty()).unwrap(),
+        }
+    }
+
+    async fn send(router: &axum::Router, req: Request<Body>) -> (u16, Value) {
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status().as_u16();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice::<Value>(&bytes).unwrap())
+    }
+
+    /// kosyncの認証のテスト
+    #[sqlx::test(fixtures("users", "kosync"))]
+    async fn test_kosync_auth(pool: PgPool) {
+        let router = init_app(&pool);
+        let api_key = create_api_key(
+            "user_id",
+            NewApiKeyRequest {
+                name: "koreader".to_string(),
+                scopes: vec![ApiKeyScope::Read],
+                expires_at: None,
+            },
+            &pool,
+        )
+        .await
+        .unwrap()
+        .key;
+        let md5_key = format!("{:x}", Md5::digest(api_key.as_bytes()));
+
+        // GET /kosync/users/auth (APIキーのMD5)
+        let (status, body) = send(
+            &router,
+            request("GET", "/kosync/users/auth", "user_id", &md5_key, None),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({"authorized": "OK"}));
+
+        // GET /kosync/users/auth (APIキー)
+        let (status, _) = send(
+            &router,
+            request("GET", "/kosync/users/auth", "user_id", &api_key, None),
+        )
+        .await;
+        assert_eq!(status, 200);
+
+        // GET /kosync/users/auth (他のユーザーのID)
+        let (status, body) = send(
+            &router,
+            request("GET", "/kosync/users/auth", "test_user_id", &md5_key, None),
+        )
+        .await;
+        assert_eq!(status, 401);
+        assert_eq!(body["code"], 2001);
+
+        // PUT /kosync/syncs/progress (readスコープのみ)
+        let (status, _) = send(
+            &router,
+            request(
+                "PUT",
+                "/kosync/syncs/progress",
+                "user_id",
+                &md5_key,
+                Some(json!({
+                    "document": "0123456789abcdef0123456789abcdef",
+                    "progress": "/body/DocFragment[2]/body/p[1]/text().0",
+                    "percentage": 0.5,
+                    "device": "Kobo",
+                    "device_id": "kobo_device_id"
+                })),
+            ),
+        )
+        .await;
+        assert_eq!(status, 401);
+
+        // POST /kosync/users/create
+        let req = Request::builder()
+            .uri("/kosync/users/create")
+            .method("POST")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({"username": "new", "password": "new"}).to_string(),
+            ))
+            .unwrap();
+        let (status, _) = send(&router, req).await;
+        assert_eq!(status, 403);
+    }
+
+    /// kosyncの読書位置の同期のテスト
+    #[sqlx::test(fixtures("users", "kosync"))]
+    async fn test_kosync_progress(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let key = "user_api_key";
+
+        // GET /kosync/syncs/progress/{document} (記録がない)
+        let (status, body) = send(
+            &router,
+            request(
+                "GET",
+                "/kosync/syncs/progress/0123456789abcdef0123456789abcdef",
+                "user_id",
+                key,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({}));
+
+        // PUT /kosync/syncs/progress
+        let (status, body) = send(
+            &router,
+            request(
+                "PUT",
+                "/kosync/syncs/progress",
+                "user_id",
+                key,
+                Some(json!({
+                    "document": "0123456789ABCDEF0123456789ABCDEF",
+                    "progress": "/body/DocFragment[2]/body/p[1]/text().0",
+                    "percentage": 0.5,
+                    "device": "Kobo",
+                    "device_id": "kobo_device_id"
+                })),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["document"], "0123456789ABCDEF0123456789ABCDEF");
+        assert!(body["timestamp"].is_i64());
+
+        // Webの読書位置と共有される
+        let req = Request::builder()
+            .uri("/books/novel_book_id/progress")
+            .header(header::COOKIE, &user_cookie)
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = send(&router, req).await;
+        assert_eq!(status, 200);
+        assert_eq!(body["percentage"], 0.5);
+        assert_eq!(body["device"], "Kobo");
+
+        // GET /kosync/syncs/progress/{document}
+        let (status, body) = send(
+            &router,
+            request(
+                "GET",
+                "/kosync/syncs/progress/0123456789abcdef0123456789abcdef",
+                "user_id",
+                key,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(body["progress"], "/body/DocFragment[2]/body/p[1]/text().0");
+        assert_eq!(body["percentage"], 0.5);
+        assert_eq!(body["device_id"], "kobo_device_id");
+
+        // Webで記録したpre-paginatedの本の読書位置
+        let req = Request::builder()
+            .uri("/books/comic_book_id/progress")
+            .method("PUT")
+            .header(header::COOKIE, &user_cookie)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({"page": 1, "device": "phone"}).to_string(),
+            ))
+            .unwrap();
+        let (status, _) = send(&router, req).await;
+        assert_eq!(status, 200);
+        let (_, body) = send(
+            &router,
+            request(
+                "GET",
+                "/kosync/syncs/progress/fedcba9876543210fedcba9876543210",
+                "user_id",
+                key,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(body["progress"], "");
+        assert_eq!(body["percentage"], 0.5);
+        assert_eq!(body["device"], "phone");
+
+        // PUT /kosync/syncs/progress (閲覧できない本)
+        let (status, body) = send(
+            &router,
+            request(
+                "PUT",
+                "/kosync/syncs/progress",
+                "user_id",
+                key,
+                Some(json!({
+                    "document": "00000000000000000000000000000000",
+                    "progress": "/body/DocFragment[2]/body/p[1]/text().0",
+                    "percentage": 0.5,
+                    "device": "Kobo",
+                    "device_id": "kobo_device_id"
+                })),
+            ),
+        )
+        .await;
+        assert_eq!(status, 404);
+        assert_eq!(body["code"], 2004);
+    }
+}
@@ -0,0 +1,8 @@
+insert into
+    users(id, "password", role)
+values
+    (
+        'used_id',
+        'Test1234',
+        'user'
+    );
@@ -0,0 +1,404 @@
+use std::{collections::HashMap, env};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
+
+use crate::service::{
+    invitation::model::{add_user_to_group, redeem_without_reservation},
+    user::model::{hash_password, validate_user_id, UserRole},
+};
+
+/// 認可リクエストの有効期限(分)
+pub const STATE_TTL_MINUTES: i32 = 10;
+
+/// OIDCの設定
+///
+/// `OIDC_ISSUER` が設定されていない場合、OIDCログインは無効
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub scopes: String,
+    /// 招待コードなしでアカウントを作成できるクレーム(名前, 値)
+    pub allowed_claim: Option<(String, String)>,
+    /// ログイン後のリダイレクト先
+    pub post_login_redirect: String,
+}
+
+/// `OIDC_ISSUER` を設定した場合に必須の環境変数
+const REQUIRED_VARS: [&str; 3] = ["OIDC_CLIENT_ID", "OIDC_CLIENT_SECRET", "OIDC_REDIRECT_URI"];
+
+impl OidcConfig {
+    /// 環境変数から設定を読み込む
+    ///
+    /// 必須の環境変数が不足している場合は、ログを出力してOIDCログインを無効にする
+    pub fn from_env() -> Option<Self> {
+        let issuer = env::var("OIDC_ISSUER").ok()?;
+        let [client_id, client_secret, redirect_uri] = match required_vars() {
+            Ok(vars) => vars,
+            Err(e) => {
+                log::error!("OIDC login is disabled: {}", e);
+                return None;
+            }
+        };
+        Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret,
+            redirect_uri,
+            scopes: env::var("OIDC_SCOPES").unwrap_or("openid profile email".to_string()),
+            allowed_claim: env::var("OIDC_ALLOWED_CLAIM").ok().and_then(|claim| {
+                claim
+                    .split_once('=')
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+            }),
+            post_login_redirect: env::var("OIDC_POST_LOGIN_REDIRECT").unwrap_or("/".to_string()),
+        })
+    }
+
+    /// 起動時に環境変数を検証する
+    ///
+    /// `OIDC_ISSUER` を設定しているのに必須の環境変数が不足している場合はエラーを返す
+    pub fn validate_env() -> Result<(), String> {
+        if env::var("OIDC_ISSUER").is_err() {
+            return Ok(());
+        }
+        required_vars().map(|_| ())
+    }
+}
+
+/// 必須の環境変数を読み込む
+fn required_vars() -> Result<[String; 3], String> {
+    let missing = REQUIRED_VARS
+        .into_iter()
+        .filter(|name| env::var(name).is_err())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        return Err(format!(
+            "OIDC_ISSUER is set but {} is not set",
+            missing.join(", ")
+        ));
+    }
+    Ok(REQUIRED_VARS.map(|name| env::var(name).unwrap_or_default()))
+}
+
+/// `GET /oidc/login` のクエリパラメータ
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct OidcLoginQuery {
+    /// 新規アカウント作成時に使用する招待コード
+    pub invitation_code: Option<String>,
+    /// 新規アカウント作成時のユーザーID(省略時は `preferred_username` クレーム)
+    pub user_id: Option<String>,
+}
+
+/// `GET /oidc/callback` のクエリパラメータ
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: String,
+    pub error: Option<String>,
+}
+
+/// Discoveryで取得するIDプロバイダーの情報
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: Option<String>,
+}
+
+/// トークンエンドポイントのレスポンス
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// IDトークンのクレーム
+#[derive(Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    preferred_username: Option<String>,
+    #[serde(flatten)]
+    extra: HashMap<String, Value>,
+}
+
+/// IDプロバイダーの情報を取得する
+async fn discover(config: &OidcConfig) -> Result<ProviderMetadata, String> {
+    reqwest::get(format!(
+        "{}/.well-known/openid-configuration",
+        config.issuer
+    ))
+    .await
+    .and_then(|res| res.error_for_status())
+    .map_err(|e| e.to_string())?
+    .json()
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// PKCEのcode_challenge(S256)を返す
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+/// ブラウザのCookieに設定するトークンのハッシュを返す
+fn hash_browser_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// 認可リクエストを作成して、IDプロバイダーの認可エンドポイントのURLと、
+/// 認可リクエストを開始したブラウザのCookieに設定するトークンを返す
+///
+/// link_user_id を指定すると、ログイン後にそのユーザーへ外部アカウントを紐付ける
+pub async fn begin_login(
+    config: &OidcConfig,
+    query: OidcLoginQuery,
+    link_user_id: Option<&str>,
+    db: &PgPool,
+) -> Result<(String, String), String> {
+    let metadata = discover(config).await?;
+    let state = Uuid::new_v4().simple().to_string();
+    let browser_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let nonce = Uuid::new_v4().simple().to_string();
+    let code_verifier = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+
+    sqlx::query!(
+        r#"
+            INSERT INTO oidc_states (state, code_verifier, nonce, invitation_code, new_user_id, link_user_id, browser_token_hash, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, now() + make_interval(mins => $8))
+        "#,
+        state,
+        code_verifier,
+        nonce,
+        query.invitation_code,
+        query.user_id,
+        link_user_id,
+        hash_browser_token(&browser_token),
+        STATE_TTL_MINUTES
+    )
+    .execute(db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let url = Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("scope", config.scopes.as_str()),
+            ("state", state.as_str()),
+            ("nonce", nonce.as_str()),
+            ("code_challenge", code_challenge(&code_verifier).as_str()),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| e.to_string())?;
+    Ok((url.to_string(), browser_token))
+}
+
+/// 認可コードをIDトークンに交換して検証する
+async fn exchange_code(
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    code: &str,
+    code_verifier: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, String> {
+    let token: TokenResponse = reqwest::Client::new()
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_uri.as_str()),
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+            ("code_verifier", code_verifier),
+        ])
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| e.to_string())?
+        .json()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let header = jsonwebtoken::decode_header(&token.id_token).map_err(|e| e.to_string())?;
+    let key = match header.alg {
+        // HMAC系はクライアントシークレットで署名される
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            DecodingKey::from_secret(config.client_secret.as_bytes())
+        }
+        _ => {
+            let jwks_uri = metadata
+                .jwks_uri
+                .as_ref()
+                .ok_or("jwks_uri is not provided")?;
+            let jwks: JwkSet = reqwest::get(jwks_uri)
+                .await
+                .and_then(|res| res.error_for_status())
+                .map_err(|e| e.to_string())?
+                .json()
+                .await
+                .map_err(|e| e.to_string())?;
+            let jwk = match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }
+            .ok_or("signing key not found")?;
+            DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?
+        }
+    };
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&config.client_id]);
+    let claims = jsonwebtoken::decode::<IdTokenClaims>(&token.id_token, &key, &validation)
+        .map_err(|e| e.to_string())?
+        .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err("nonce mismatch".to_string());
+    }
+    Ok(claims)
+}
+
+/// クレームが許可リストの条件を満たすかを返す
+fn has_allowed_claim(config: &OidcConfig, claims: &IdTokenClaims) -> bool {
+    let Some((name, value)) = &config.allowed_claim else {
+        return false;
+    };
+    match claims.extra.get(name) {
+        Some(Value::String(s)) => s == value,
+        Some(Value::Array(values)) => values.iter().any(|v| v.as_str() == Some(value)),
+        Some(Value::Bool(b)) => b.to_string() == *value,
+        _ => false,
+    }
+}
+
+/// 認可レスポンスを検証して、ログインするユーザーのIDを返す
+///
+/// `browser_token` は認可リクエストを開始したブラウザのCookieの値で、一致しない場合は失敗する。
+/// 外部アカウントが紐付いていない場合、紐付け先のユーザーが指定されていればそのユーザーに紐付け、
+/// そうでなければ招待コードもしくは許可されたクレームを確認して新しいユーザーを作成する
+pub async fn complete_login(
+    config: &OidcConfig,
+    query: OidcCallbackQuery,
+    browser_token: Option<&str>,
+    db: &PgPool,
+) -> Result<String, String> {
+    // stateは一度しか使用できない
+    let state = sqlx::query!(
+        r#"
+            DELETE FROM oidc_states
+            WHERE state = $1
+            RETURNING code_verifier, nonce, invitation_code, new_user_id, link_user_id, browser_token_hash, expires_at > now() as "valid!"
+        "#,
+        query.state
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    .filter(|state| state.valid)
+    .filter(|state| {
+        browser_token.is_some_and(|token| hash_browser_token(token) == state.browser_token_hash)
+    })
+    .ok_or("無効なstateです")?;
+
+    if let Some(error) = query.error {
+        return Err(error);
+    }
+    let code = query.code.ok_or("認可コードがありません")?;
+
+    let metadata = discover(config).await?;
+    let claims =
+        exchange_code(config, &metadata, &code, &state.code_verifier, &state.nonce).await?;
+
+    // 紐付け済みの場合
+    if let Some(user_id) = sqlx::query_scalar!(
+        r#"SELECT user_id FROM user_identities WHERE issuer = $1 AND subject = $2"#,
+        metadata.issuer,
+        claims.sub
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    {
+        return Ok(user_id);
+    }
+
+    // 既存のユーザーに紐付ける場合
+    if let Some(user_id) = state.link_user_id {
+        sqlx::query!(
+            r#"INSERT INTO user_identities (issuer, subject, user_id) VALUES ($1, $2, $3)"#,
+            metadata.issuer,
+            claims.sub,
+            user_id
+        )
+        .execute(db)
+        .await
+        .map_err(|e| e.to_string())?;
+        return Ok(user_id);
+    }
+
+    // 新しいユーザーを作成する場合
+    let allowed = has_allowed_claim(config, &claims);
+    if !allowed && state.invitation_code.is_none() {
+        return Err("招待コードが必要です".to_string());
+    }
+    let user_id = state
+        .new_user_id
+        .or(claims.preferred_username.clone())
+        .ok_or("ユーザーIDを指定してください")?;
+    validate_user_id(&user_id)?;
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    let redemption = match (&state.invitation_code, allowed) {
+        (Some(invitation_code), false) => Some(
+            redeem_without_reservation(&mut transaction, invitation_code)
+                .await
+                .map_err(|e| e.to_string())?
+                .ok_or("招待コードが見つかりませんでした")?,
+        ),
+        _ => None,
+    };
+    // パスワードでのログインはできないように、推測できない値を設定する
+    sqlx::query!(
+        r#"INSERT INTO users (id, password, role, invitation_code) VALUES ($1, $2, $3, $4)"#,
+        user_id,
+        hash_password(&Uuid::new_v4().to_string()),
+        redemption
+            .as_ref()
+            .map_or(UserRole::User, |redemption| redemption.role) as UserRole,
+        state.invitation_code.filter(|_| redemption.is_some())
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|_| "そのIDは使用できません".to_string())?;
+    if let Some(group_name) = redemption.and_then(|redemption| redemption.group_name) {
+        add_user_to_group(&mut transaction, &user_id, &group_name)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    sqlx::query!(
+        r#"INSERT INTO user_identities (issuer, subject, user_id) VALUES ($1, $2, $3)"#,
+        metadata.issuer,
+        claims.sub,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    Ok(user_id)
+}
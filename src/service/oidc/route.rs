@@ -0,0 +1,471 @@
+use std::env;
+
+use axum::{
+    extract::{Query, State},
+    http::{
+        header::{COOKIE, LOCATION, SET_COOKIE},
+        HeaderMap, StatusCode,
+    },
+    response::{AppendHeaders, IntoResponse, Redirect},
+    Json,
+};
+use cookie::Cookie;
+use sqlx::PgPool;
+
+use super::model::{self, OidcConfig};
+use crate::service::{
+    totp::model::{self as totp, SecondFactor},
+    user::{
+        auth::{AccountUser, AuthError},
+        model::{create_session, UserError},
+        route::{token_cookie, user_agent},
+    },
+};
+
+/// 認可リクエストを開始したブラウザを確認するCookieの名前
+const STATE_COOKIE: &str = "oidc_state";
+
+/// 認可リクエストを開始したブラウザを確認するCookie
+///
+/// IDプロバイダーからのリダイレクトでも送信されるよう `SameSite=Lax` にする
+fn state_cookie(token: &str, max_age: i64) -> String {
+    format!(
+        "{}={};Domain={};Max-Age={};Path=/;Secure;HttpOnly;SameSite=Lax",
+        STATE_COOKIE,
+        token,
+        env::var("DOMAIN").unwrap_or("localhost".to_string()),
+        max_age
+    )
+}
+
+/// リクエストのCookieから認可リクエストを開始したブラウザのトークンを取り出す
+fn state_cookie_value(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .flat_map(Cookie::split_parse)
+        .filter_map(|cookie| cookie.ok())
+        .find(|cookie| cookie.name() == STATE_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+}
+
+/// OIDCでログインする
+///
+/// IDプロバイダーの認可エンドポイントへリダイレクトする。
+/// ログイン済みの場合は、ログイン後に外部アカウントを現在のユーザーに紐付ける
+#[utoipa::path(
+    get,
+    path = "/oidc/login",
+    params(model::OidcLoginQuery),
+    responses(
+        (status = 303, description = "IDプロバイダーへリダイレクト"),
+        (status = 404, description = "OIDCが設定されていない"),
+    )
+)]
+pub async fn oidc_login(
+    user: Result<AccountUser, AuthError>,
+    Query(query): Query<model::OidcLoginQuery>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    let Some(config) = OidcConfig::from_env() else {
+        return (StatusCode::NOT_FOUND).into_response();
+    };
+    let link_user_id = user.ok().map(|AccountUser(user)| user.id);
+
+    match model::begin_login(&config, query, link_user_id.as_deref(), &db).await {
+        Ok((url, browser_token)) => (
+            AppendHeaders([(
+                SET_COOKIE,
+                state_cookie(&browser_token, model::STATE_TTL_MINUTES as i64 * 60),
+            )]),
+            Redirect::to(&url),
+        )
+            .into_response(),
+        Err(e) => {
+            log::error!("Failed to begin oidc login: {}", e);
+            (StatusCode::BAD_GATEWAY).into_response()
+        }
+    }
+}
+
+/// OIDCのリダイレクト先
+///
+/// ログインに成功するとCookieを設定して `OIDC_POST_LOGIN_REDIRECT` へリダイレクトする。
+/// TOTPが必要な場合はCookieを設定せず、`POST /login` と同じチャレンジを
+/// `#challenge_token={token}&enrollment_required={bool}` としてフラグメントに付けてリダイレクトする
+#[utoipa::path(
+    get,
+    path = "/oidc/callback",
+    params(model::OidcCallbackQuery),
+    responses(
+        (status = 303, description = "ログイン成功、もしくはTOTPが必要"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::OidcLoginFailed(String::from("招待コードが必要です")))),
+        (status = 404, description = "OIDCが設定されていない"),
+    )
+)]
+pub async fn oidc_callback(
+    headers: HeaderMap,
+    Query(query): Query<model::OidcCallbackQuery>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    let Some(config) = OidcConfig::from_env() else {
+        return (StatusCode::NOT_FOUND).into_response();
+    };
+
+    let browser_token = state_cookie_value(&headers);
+    let user_id = match model::complete_login(&config, query, browser_token.as_deref(), &db).await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, Json(UserError::OidcLoginFailed(e))).into_response()
+        }
+    };
+
+    // パスワードでのログインと同じく、TOTPが必要な場合はセッションを作成しない
+    let second_factor = match totp::second_factor(&user_id, &db).await {
+        Ok(second_factor) => second_factor,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    if second_factor != SecondFactor::None {
+        return match totp::create_challenge(&user_id, user_agent(&headers), &db).await {
+            Ok(challenge_token) => (
+                StatusCode::SEE_OTHER,
+                AppendHeaders([
+                    (SET_COOKIE, state_cookie("", 0)),
+                    (
+                        LOCATION,
+                        format!(
+                            "{}#challenge_token={}&enrollment_required={}",
+                            config.post_login_redirect,
+                            challenge_token,
+                            second_factor == SecondFactor::Enroll
+                        ),
+                    ),
+                ]),
+            )
+                .into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+        };
+    }
+
+    let jwt = match create_session(&user_id, user_agent(&headers), &db).await {
+        Ok(jwt) => jwt,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+
+    (
+        StatusCode::SEE_OTHER,
+        AppendHeaders([
+            (SET_COOKIE, token_cookie(&jwt, 3600 * 24 * 30)),
+            (SET_COOKIE, state_cookie("", 0)),
+            (LOCATION, config.post_login_redirect),
+        ]),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, env};
+
+    use axum::{
+        body::Body,
+        extract::State,
+        http::{header, Method, Request},
+        routing::{get, post},
+        Form, Json, Router,
+    };
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use chrono::Utc;
+    use jsonwebtoken::{EncodingKey, Header};
+    use reqwest::Url;
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::{
+        routes::init_app,
+        service::{oidc::model::code_challenge, user::model::token_cookie_from_user_id},
+    };
+
+    const CLIENT_ID: &str = "epubapi";
+    const CLIENT_SECRET: &str = "mock_client_secret";
+
+    /// テスト用のIDプロバイダーを起動して、issuerを返す
+    ///
+    /// 認可コードにはIDトークンに含めるクレームをJSONでエンコードしたものを使用する
+    async fn start_mock_idp() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        async fn discovery(State(issuer): State<String>) -> Json<Value> {
+            Json(json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+            }))
+        }
+
+        async fn token(
+            State(issuer): State<String>,
+            Form(form): Form<HashMap<String, String>>,
+        ) -> Result<Json<Value>, axum::http::StatusCode> {
+            let mut claims: Value =
+                serde_json::from_slice(&URL_SAFE_NO_PAD.decode(&form["code"]).unwrap()).unwrap();
+            // PKCEとクライアント認証の確認
+            if form["client_id"] != CLIENT_ID
+                || form["client_secret"] != CLIENT_SECRET
+                || claims["code_challenge"] != code_challenge(&form["code_verifier"])
+            {
+                return Err(axum::http::StatusCode::BAD_REQUEST);
+            }
+            claims["iss"] = json!(issuer);
+            claims["aud"] = json!(CLIENT_ID);
+            claims["exp"] = json!(Utc::now().timestamp() + 300);
+            let id_token = jsonwebtoken::encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(CLIENT_SECRET.as_bytes()),
+            )
+            .unwrap();
+            Ok(Json(json!({
+                "access_token": "mock_access_token",
+                "token_type": "Bearer",
+                "id_token": id_token,
+            })))
+        }
+
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/token", post(token))
+            .with_state(issuer.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        issuer
+    }
+
+    /// `/oidc/login` を実行して、認可エンドポイントへのクエリパラメータと、
+    /// 認可リクエストを開始したブラウザのCookieを返す
+    async fn authorize(
+        router: &Router,
+        uri: &str,
+        cookie: Option<&str>,
+    ) -> (HashMap<String, String>, String) {
+        let mut req = Request::builder().uri(uri).method(Method::GET);
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, cookie);
+        }
+        let res = router
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 303);
+        let state_cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(state_cookie.contains("HttpOnly"));
+        assert!(state_cookie.contains("SameSite=Lax"));
+        let state_cookie = state_cookie.split(';').next().unwrap().to_string();
+        let location = res.headers()[header::LOCATION].to_str().unwrap();
+        let params = Url::parse(location)
+            .unwrap()
+            .query_pairs()
+            .into_owned()
+            .collect();
+        (params, state_cookie)
+    }
+
+    /// IDプロバイダーからのリダイレクトを再現する
+    async fn callback(
+        router: &Router,
+        params: &HashMap<String, String>,
+        state_cookie: Option<&str>,
+        claims: Value,
+    ) -> axum::response::Response {
+        let mut claims = claims;
+        claims["nonce"] = json!(params["nonce"]);
+        claims["code_challenge"] = json!(params["code_challenge"]);
+        let code = URL_SAFE_NO_PAD.encode(claims.to_string());
+        let mut req = Request::builder()
+            .uri(format!(
+                "/oidc/callback?code={}&state={}",
+                code, params["state"]
+            ))
+            .method(Method::GET);
+        if let Some(state_cookie) = state_cookie {
+            req = req.header(header::COOKIE, state_cookie);
+        }
+        router
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(fixtures("users", "invitations"))]
+    async fn test_oidc_login(pool: PgPool) {
+        let issuer = start_mock_idp().await;
+        env::set_var("OIDC_ISSUER", &issuer);
+        env::set_var("OIDC_CLIENT_ID", CLIENT_ID);
+        env::set_var("OIDC_CLIENT_SECRET", CLIENT_SECRET);
+        env::set_var("OIDC_REDIRECT_URI", "http://localhost/oidc/callback");
+        env::set_var("OIDC_ALLOWED_CLAIM", "groups=epubapi");
+        let router = init_app(&pool);
+
+        // 招待コードも許可されたクレームもない場合
+        let (params, state_cookie) =
+            authorize(&router, "/oidc/login?user_id=no_invitation", None).await;
+        assert_eq!(params["code_challenge_method"], "S256");
+        let res = callback(
+            &router,
+            &params,
+            Some(&state_cookie),
+            json!({"sub": "subject-0"}),
+        )
+        .await;
+        assert_eq!(res.status(), 400);
+
+        // 招待コードでアカウントを作成する場合
+        let (params, state_cookie) = authorize(
+            &router,
+            "/oidc/login?invitation_code=unused_test_code&user_id=oidc_user",
+            None,
+        )
+        .await;
+        let res = callback(
+            &router,
+            &params,
+            Some(&state_cookie),
+            json!({"sub": "subject-1"}),
+        )
+        .await;
+        assert_eq!(res.status(), 303);
+        assert!(res.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .contains("token="));
+        let state = sqlx::query_scalar!(
+            r#"SELECT state::text as "state!" FROM invitations WHERE code = 'unused_test_code'"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(state, "used");
+
+        // 同じstateは再利用できない
+        let res = callback(
+            &router,
+            &params,
+            Some(&state_cookie),
+            json!({"sub": "subject-1"}),
+        )
+        .await;
+        assert_eq!(res.status(), 400);
+
+        // 紐付け済みの外部アカウントでログインする場合
+        let (params, state_cookie) = authorize(&router, "/oidc/login", None).await;
+        let res = callback(
+            &router,
+            &params,
+            Some(&state_cookie),
+            json!({"sub": "subject-1"}),
+        )
+        .await;
+        assert_eq!(res.status(), 303);
+
+        // 許可されたクレームでアカウントを作成する場合
+        let (params, state_cookie) = authorize(&router, "/oidc/login", None).await;
+        let res = callback(
+            &router,
+            &params,
+            Some(&state_cookie),
+            json!({"sub": "subject-2", "preferred_username": "claim_user", "groups": ["epubapi"]}),
+        )
+        .await;
+        assert_eq!(res.status(), 303);
+
+        // ログイン済みのユーザーに紐付ける場合
+        let cookie = token_cookie_from_user_id("used_id", &pool).await;
+        let (params, state_cookie) = authorize(&router, "/oidc/login", Some(&cookie)).await;
+        let res = callback(
+            &router,
+            &params,
+            Some(&state_cookie),
+            json!({"sub": "subject-3"}),
+        )
+        .await;
+        assert_eq!(res.status(), 303);
+
+        // 他のブラウザで開始された認可リクエストでは紐付けない
+        let cookie = token_cookie_from_user_id("oidc_user", &pool).await;
+        let (params, _) = authorize(&router, "/oidc/login", Some(&cookie)).await;
+        let res = callback(&router, &params, None, json!({"sub": "subject-4"})).await;
+        assert_eq!(res.status(), 400);
+        let (params, _) = authorize(&router, "/oidc/login", Some(&cookie)).await;
+        let (_, other_cookie) = authorize(&router, "/oidc/login", None).await;
+        let res = callback(
+            &router,
+            &params,
+            Some(&other_cookie),
+            json!({"sub": "subject-4"}),
+        )
+        .await;
+        assert_eq!(res.status(), 400);
+
+        let identities = sqlx::query!(
+            r#"SELECT subject, user_id FROM user_identities WHERE issuer = $1 ORDER BY subject"#,
+            issuer
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|row| (row.subject, row.user_id))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            identities,
+            vec![
+                ("subject-1".to_string(), "oidc_user".to_string()),
+                ("subject-2".to_string(), "claim_user".to_string()),
+                ("subject-3".to_string(), "used_id".to_string()),
+            ]
+        );
+
+        // ロールのポリシーでTOTPが必要な場合はセッションを作成せずにチャレンジを返す
+        sqlx::query!("INSERT INTO role_policies (role, require_totp) VALUES ('user', true)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (params, state_cookie) = authorize(&router, "/oidc/login", None).await;
+        let res = callback(
+            &router,
+            &params,
+            Some(&state_cookie),
+            json!({"sub": "subject-1"}),
+        )
+        .await;
+        assert_eq!(res.status(), 303);
+        assert!(!res
+            .headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .any(|cookie| cookie.to_str().unwrap().starts_with("token=")));
+        let location = res.headers()[header::LOCATION].to_str().unwrap();
+        let (_, fragment) = location.split_once('#').unwrap();
+        let fragment = fragment
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(fragment["enrollment_required"], "true");
+
+        // チャレンジはPOST /login/totp/enrollで使用できる
+        let req = Request::builder()
+            .uri("/login/totp/enroll")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                json!({"challenge_token": fragment["challenge_token"]}).to_string(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+    }
+}
@@ -0,0 +1,90 @@
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{
+        header::{AUTHORIZATION, WWW_AUTHENTICATE},
+        request::Parts,
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sqlx::PgPool;
+
+use crate::service::{
+    api_key::model::{find_api_key_owner, ApiKeyScope},
+    user::{auth::AuthUser, model::UserError},
+};
+
+/// OPDSのフィードを閲覧できるユーザー
+///
+/// `Authorization: Basic` の場合はユーザー名にユーザーID、パスワードにAPIキーを指定する。
+/// それ以外は `AuthUser` と同じ認証情報を使用する。
+/// いずれの場合もAPIキーには `read` スコープが必要
+pub struct OpdsUser(pub AuthUser);
+
+/// OPDSの認証に失敗した場合のエラー
+///
+/// 電子書籍リーダーがBasic認証のダイアログを表示できるよう、`WWW-Authenticate` を付けて401を返す
+#[derive(Debug)]
+pub struct OpdsAuthError(&'static str);
+
+impl IntoResponse for OpdsAuthError {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(
+                WWW_AUTHENTICATE,
+                "Basic realm=\"epubapi\", charset=\"UTF-8\"",
+            )],
+            Json(UserError::Unauthorized(self.0.to_string())),
+        )
+            .into_response()
+    }
+}
+
+impl<S> FromRequestParts<S> for OpdsUser
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = OpdsAuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let basic = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .map(str::trim);
+        let Some(basic) = basic else {
+            return AuthUser::from_request_parts(parts, state)
+                .await
+                .map(Self)
+                .map_err(|_| OpdsAuthError("missing credentials"));
+        };
+
+        let credentials = STANDARD
+            .decode(basic)
+            .ok()
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .ok_or(OpdsAuthError("invalid authorization header"))?;
+        let (user_id, key) = credentials
+            .split_once(':')
+            .ok_or(OpdsAuthError("invalid authorization header"))?;
+        let owner = find_api_key_owner(key, &PgPool::from_ref(state))
+            .await
+            .filter(|owner| owner.user_id == user_id)
+            .ok_or(OpdsAuthError("invalid api key"))?;
+        let user = AuthUser {
+            id: owner.user_id,
+            role: owner.role,
+            session_id: None,
+            scopes: Some(owner.scopes),
+        };
+        if !user.allows(ApiKeyScope::Read) {
+            return Err(OpdsAuthError("insufficient scope"));
+        }
+        Ok(Self(user))
+    }
+}
@@ -0,0 +1,81 @@
+insert into
+    series(id, "name")
+values
+    ('opds_series_id', '漫画');
+
+insert into
+    books(
+        id,
+        "key",
+        owner_id,
+        "name",
+        creator,
+        publisher,
+        "date",
+        cover_image,
+        visibility,
+        layout,
+        images,
+        series_id,
+        volume,
+        created_at
+    )
+values
+    (
+        'opds_book_1_id',
+        'opds_book_1_key',
+        'user_id',
+        '漫画 1',
+        'book_creator',
+        'book_publisher',
+        '2020-01-01',
+        'opds_book_1_cover.avif',
+        'private',
+        'pre-paginated',
+        '{}',
+        'opds_series_id',
+        1,
+        '2024-01-01 00:00:00'
+    ),
+    (
+        'opds_book_2_id',
+        'opds_book_2_key',
+        'test_user_id',
+        '漫画 <2> & 続き',
+        'book_creator',
+        'book_publisher',
+        '2020-02-01',
+        'opds_book_2_cover.avif',
+        'public',
+        'pre-paginated',
+        '{}',
+        'opds_series_id',
+        2,
+        '2024-01-02 00:00:00'
+    ),
+    (
+        'opds_private_book_id',
+        'opds_private_book_key',
+        'test_user_id',
+        '非公開の本',
+        'book_creator',
+        'book_publisher',
+        '2020-03-01',
+        'opds_private_book_cover.avif',
+        'private',
+        'reflowable',
+        '{}',
+        NULL,
+        NULL,
+        '2024-01-03 00:00:00'
+    );
+
+insert into
+    tags("name")
+values
+    ('漫画');
+
+insert into
+    book_tags(book_id, tag_name)
+values
+    ('opds_book_2_id', '漫画');
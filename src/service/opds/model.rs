@@ -0,0 +1,674 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use utoipa::IntoParams;
+
+use crate::service::{
+    book::model::{get_books, BookCursor, BookQuery, BookSort, GetBooksPage, SortOrder},
+    series::model::{get_series, get_series_volumes},
+    tag::model::get_tags,
+};
+
+/// OPDS 1.2のナビゲーションフィードのメディアタイプ
+pub const ATOM_NAVIGATION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+
+/// OPDS 1.2の購入(acquisition)フィードのメディアタイプ
+pub const ATOM_ACQUISITION_TYPE: &str =
+    "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+/// OPDS 2.0のメディアタイプ
+pub const OPDS2_TYPE: &str = "application/opds+json";
+
+/// OpenSearchの説明文書のメディアタイプ
+pub const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+
+/// フィードの1ページあたりの本の数
+const FEED_PAGE_SIZE: u32 = 24;
+
+/// フィードの形式
+#[derive(Clone, Copy, PartialEq)]
+pub enum OpdsFormat {
+    /// OPDS 1.2 (Atom)
+    Atom,
+    /// OPDS 2.0 (JSON)
+    Json,
+}
+
+impl OpdsFormat {
+    /// フィードのパスの接頭辞
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Atom => "/opds",
+            Self::Json => "/opds/v2",
+        }
+    }
+
+    /// フィードの種類ごとのメディアタイプ
+    fn media_type(self, kind: FeedKind) -> &'static str {
+        match (self, kind) {
+            (Self::Atom, FeedKind::Navigation) => ATOM_NAVIGATION_TYPE,
+            (Self::Atom, FeedKind::Acquisition) => ATOM_ACQUISITION_TYPE,
+            (Self::Json, _) => OPDS2_TYPE,
+        }
+    }
+}
+
+/// フィードの種類
+#[derive(Clone, Copy, PartialEq)]
+pub enum FeedKind {
+    Navigation,
+    Acquisition,
+}
+
+/// `GET /opds/books` のクエリ
+#[derive(Deserialize, IntoParams)]
+#[into_params(style = Form, parameter_in = Query)]
+pub struct OpdsBooksQuery {
+    /// タイトル・著者・出版社・タグでの検索キーワード
+    pub keyword: Option<String>,
+    pub tag: Option<String>,
+    /// 前のフィードの `next` または `previous` のリンクのカーソル
+    pub cursor: Option<String>,
+}
+
+/// フィードのリンク
+pub struct Link {
+    pub rel: String,
+    pub href: String,
+    pub media_type: String,
+    pub title: Option<String>,
+}
+
+/// ナビゲーションフィードの項目
+pub struct NavigationEntry {
+    pub id: String,
+    pub title: String,
+    pub href: String,
+    pub kind: FeedKind,
+    /// 項目の説明(本の数など)
+    pub content: Option<String>,
+}
+
+/// 購入フィードの本
+pub struct Publication {
+    pub id: String,
+    pub title: String,
+    pub creator: String,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub tags: Vec<String>,
+    pub series: Option<(String, Option<f64>)>,
+    pub cover_image: String,
+    pub updated: Option<NaiveDateTime>,
+}
+
+/// OPDSのフィード
+///
+/// 形式によらない中間表現で、`to_atom` と `to_json` で出力する
+pub struct Feed {
+    pub format: OpdsFormat,
+    pub kind: FeedKind,
+    pub id: String,
+    pub title: String,
+    pub self_href: String,
+    pub links: Vec<Link>,
+    pub total: Option<i64>,
+    pub navigation: Vec<NavigationEntry>,
+    pub publications: Vec<Publication>,
+}
+
+impl Feed {
+    fn new(format: OpdsFormat, kind: FeedKind, id: &str, title: &str, path: &str) -> Self {
+        let prefix = format.prefix();
+        let mut links = vec![
+            Link {
+                rel: "start".to_string(),
+                href: prefix.to_string(),
+                media_type: format.media_type(FeedKind::Navigation).to_string(),
+                title: None,
+            },
+            match format {
+                OpdsFormat::Atom => Link {
+                    rel: "search".to_string(),
+                    href: format!("{}/opensearch.xml", prefix),
+                    media_type: OPENSEARCH_TYPE.to_string(),
+                    title: None,
+                },
+                OpdsFormat::Json => Link {
+                    rel: "search".to_string(),
+                    href: format!("{}/books{{?keyword}}", prefix),
+                    media_type: OPDS2_TYPE.to_string(),
+                    title: None,
+                },
+            },
+        ];
+        if format == OpdsFormat::Atom {
+            // OPDS 1.2のクライアント向けに、OpenSearchを使わない検索のリンクも追加する
+            links.push(Link {
+                rel: "search".to_string(),
+                href: format!("{}/books?keyword={{searchTerms}}", prefix),
+                media_type: ATOM_ACQUISITION_TYPE.to_string(),
+                title: None,
+            });
+        }
+        Self {
+            format,
+            kind,
+            id: format!("urn:epubapi:{}", id),
+            title: title.to_string(),
+            self_href: format!("{}{}", prefix, path),
+            links,
+            total: None,
+            navigation: vec![],
+            publications: vec![],
+        }
+    }
+
+    /// カーソルで前後のページのリンクを追加する
+    fn add_page_links(&mut self, path: &str, page: &GetBooksPage) {
+        for (rel, cursor) in [("next", &page.next_cursor), ("previous", &page.prev_cursor)] {
+            if let Some(cursor) = cursor {
+                let separator = if path.contains('?') { '&' } else { '?' };
+                self.links.push(Link {
+                    rel: rel.to_string(),
+                    href: format!(
+                        "{}{}{}cursor={}",
+                        self.format.prefix(),
+                        path,
+                        separator,
+                        cursor
+                    ),
+                    media_type: self.format.media_type(FeedKind::Acquisition).to_string(),
+                    title: None,
+                });
+            }
+        }
+        self.total = Some(page.total);
+        self.publications = page
+            .books
+            .iter()
+            .map(|book| Publication {
+                id: book.id.clone(),
+                title: book.name.clone(),
+                creator: book.creator.clone(),
+                publisher: Some(book.publisher.clone()).filter(|p| !p.is_empty()),
+                date: Some(book.date.clone()).filter(|d| !d.is_empty()),
+                tags: book.tags.clone(),
+                series: None,
+                cover_image: book.cover_image.clone(),
+                updated: Some(book.created_at),
+            })
+            .collect();
+    }
+
+    /// フィードのメディアタイプ
+    pub fn media_type(&self) -> &'static str {
+        self.format.media_type(self.kind)
+    }
+
+    /// 形式に合わせてフィードを出力する
+    pub fn render(&self) -> String {
+        match self.format {
+            OpdsFormat::Atom => self.to_atom(),
+            OpdsFormat::Json => self.to_json().to_string(),
+        }
+    }
+
+    /// OPDS 1.2 (Atom) で出力する
+    pub fn to_atom(&self) -> String {
+        let updated = format_date(Utc::now());
+        let mut xml = String::from(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/terms/" xmlns:opds="http://opds-spec.org/2010/catalog" xmlns:opensearch="http://a9.com/-/spec/opensearch/1.1/">
+"#,
+        );
+        xml.push_str(&format!("<id>{}</id>\n", escape_xml(&self.id)));
+        xml.push_str(&format!("<title>{}</title>\n", escape_xml(&self.title)));
+        xml.push_str(&format!("<updated>{}</updated>\n", updated));
+        xml.push_str("<author><name>epubapi</name></author>\n");
+        xml.push_str(&atom_link("self", &self.self_href, self.media_type(), None));
+        for link in &self.links {
+            xml.push_str(&atom_link(
+                &link.rel,
+                &link.href,
+                &link.media_type,
+                link.title.as_deref(),
+            ));
+        }
+        if let Some(total) = self.total {
+            xml.push_str(&format!(
+                "<opensearch:totalResults>{}</opensearch:totalResults>\n",
+                total
+            ));
+            xml.push_str(&format!(
+                "<opensearch:itemsPerPage>{}</opensearch:itemsPerPage>\n",
+                FEED_PAGE_SIZE
+            ));
+        }
+
+        for entry in &self.navigation {
+            xml.push_str("<entry>\n");
+            xml.push_str(&format!("<title>{}</title>\n", escape_xml(&entry.title)));
+            xml.push_str(&format!("<id>urn:epubapi:{}</id>\n", escape_xml(&entry.id)));
+            xml.push_str(&format!("<updated>{}</updated>\n", updated));
+            if let Some(content) = &entry.content {
+                xml.push_str(&format!(
+                    "<content type=\"text\">{}</content>\n",
+                    escape_xml(content)
+                ));
+            }
+            xml.push_str(&atom_link(
+                "subsection",
+                &entry.href,
+                self.format.media_type(entry.kind),
+                None,
+            ));
+            xml.push_str("</entry>\n");
+        }
+
+        for publication in &self.publications {
+            xml.push_str("<entry>\n");
+            xml.push_str(&format!(
+                "<title>{}</title>\n",
+                escape_xml(&publication.title)
+            ));
+            xml.push_str(&format!(
+                "<id>urn:epubapi:book:{}</id>\n",
+                escape_xml(&publication.id)
+            ));
+            xml.push_str(&format!(
+                "<updated>{}</updated>\n",
+                publication
+                    .updated
+                    .map_or(updated.clone(), |u| format_date(u.and_utc()))
+            ));
+            if !publication.creator.is_empty() {
+                xml.push_str(&format!(
+                    "<author><name>{}</name></author>\n",
+                    escape_xml(&publication.creator)
+                ));
+            }
+            if let Some(publisher) = &publication.publisher {
+                xml.push_str(&format!(
+                    "<dc:publisher>{}</dc:publisher>\n",
+                    escape_xml(publisher)
+                ));
+            }
+            if let Some(date) = &publication.date {
+                xml.push_str(&format!("<dc:issued>{}</dc:issued>\n", escape_xml(date)));
+            }
+            for tag in &publication.tags {
+                xml.push_str(&format!(
+                    "<category term=\"{}\" label=\"{}\"/>\n",
+                    escape_xml(tag),
+                    escape_xml(tag)
+                ));
+            }
+            for rel in [
+                "http://opds-spec.org/image",
+                "http://opds-spec.org/image/thumbnail",
+            ] {
+                xml.push_str(&atom_link(
+                    rel,
+                    &cover_href(&publication.cover_image),
+                    "image/avif",
+                    None,
+                ));
+            }
+            xml.push_str(&atom_link(
+                "http://opds-spec.org/acquisition",
+                &download_href(&publication.id),
+                "application/epub+zip",
+                None,
+            ));
+            xml.push_str("</entry>\n");
+        }
+        xml.push_str("</feed>\n");
+        xml
+    }
+
+    /// OPDS 2.0 (JSON) で出力する
+    pub fn to_json(&self) -> Value {
+        let mut links = vec![json!({
+            "rel": "self",
+            "href": self.self_href,
+            "type": OPDS2_TYPE,
+        })];
+        links.extend(self.links.iter().map(|link| {
+            let mut value = json!({
+                "rel": link.rel,
+                "href": link.href,
+                "type": link.media_type,
+            });
+            if link.href.contains('{') {
+                value["templated"] = json!(true);
+            }
+            if let Some(title) = &link.title {
+                value["title"] = json!(title);
+            }
+            value
+        }));
+
+        let mut metadata = json!({ "title": self.title });
+        if let Some(total) = self.total {
+            metadata["numberOfItems"] = json!(total);
+            metadata["itemsPerPage"] = json!(FEED_PAGE_SIZE);
+        }
+        let mut feed = json!({
+            "metadata": metadata,
+            "links": links,
+        });
+
+        if self.kind == FeedKind::Navigation {
+            feed["navigation"] = self
+                .navigation
+                .iter()
+                .map(|entry| {
+                    json!({
+                        "href": entry.href,
+                        "title": entry.title,
+                        "type": OPDS2_TYPE,
+                        "rel": "subsection",
+                    })
+                })
+                .collect();
+        } else {
+            feed["publications"] = self
+                .publications
+                .iter()
+                .map(|publication| {
+                    let mut metadata = json!({
+                        "@type": "http://schema.org/Book",
+                        "identifier": format!("urn:epubapi:book:{}", publication.id),
+                        "title": publication.title,
+                        "author": if publication.creator.is_empty() {
+                            json!([])
+                        } else {
+                            json!([{ "name": publication.creator }])
+                        },
+                        "subject": publication.tags,
+                    });
+                    if let Some(publisher) = &publication.publisher {
+                        metadata["publisher"] = json!(publisher);
+                    }
+                    if let Some(date) = &publication.date {
+                        metadata["published"] = json!(date);
+                    }
+                    if let Some(updated) = publication.updated {
+                        metadata["modified"] = json!(format_date(updated.and_utc()));
+                    }
+                    if let Some((name, position)) = &publication.series {
+                        let mut series = json!({ "name": name });
+                        if let Some(position) = position {
+                            series["position"] = json!(position);
+                        }
+                        metadata["belongsTo"] = json!({ "series": series });
+                    }
+                    json!({
+                        "metadata": metadata,
+                        "links": [{
+                            "rel": "http://opds-spec.org/acquisition",
+                            "href": download_href(&publication.id),
+                            "type": "application/epub+zip",
+                        }],
+                        "images": [{
+                            "href": cover_href(&publication.cover_image),
+                            "type": "image/avif",
+                        }],
+                    })
+                })
+                .collect();
+        }
+        feed
+    }
+}
+
+/// Atomのリンク要素
+fn atom_link(rel: &str, href: &str, media_type: &str, title: Option<&str>) -> String {
+    let title = title
+        .map(|title| format!(" title=\"{}\"", escape_xml(title)))
+        .unwrap_or_default();
+    format!(
+        "<link rel=\"{}\" href=\"{}\" type=\"{}\"{}/>\n",
+        escape_xml(rel),
+        escape_xml(href),
+        escape_xml(media_type),
+        title
+    )
+}
+
+/// XMLの特殊文字をエスケープする
+pub fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// URLのクエリの値をエンコードする
+fn encode_query(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn cover_href(cover_image: &str) -> String {
+    format!("/covers/{}", cover_image)
+}
+
+fn download_href(book_id: &str) -> String {
+    format!("/opds/books/{}/download", book_id)
+}
+
+/// ルートのナビゲーションフィード
+pub fn root_feed(format: OpdsFormat) -> Feed {
+    let prefix = format.prefix();
+    let mut feed = Feed::new(format, FeedKind::Navigation, "root", "epubapi", "");
+    feed.navigation = vec![
+        NavigationEntry {
+            id: "new".to_string(),
+            title: "新着".to_string(),
+            href: format!("{}/new", prefix),
+            kind: FeedKind::Acquisition,
+            content: Some("新しく追加された本".to_string()),
+        },
+        NavigationEntry {
+            id: "tags".to_string(),
+            title: "タグ".to_string(),
+            href: format!("{}/tags", prefix),
+            kind: FeedKind::Navigation,
+            content: Some("タグごとの本".to_string()),
+        },
+        NavigationEntry {
+            id: "series".to_string(),
+            title: "シリーズ".to_string(),
+            href: format!("{}/series", prefix),
+            kind: FeedKind::Navigation,
+            content: Some("シリーズごとの本".to_string()),
+        },
+    ];
+    feed
+}
+
+/// 閲覧できる本の購入フィード
+///
+/// キーワードかタグがある場合は検索結果、ない場合は新着順のフィードになる
+pub async fn books_feed(
+    format: OpdsFormat,
+    user_id: &str,
+    query: OpdsBooksQuery,
+    db: &PgPool,
+) -> Result<Feed, String> {
+    let cursor = query
+        .cursor
+        .as_deref()
+        .map(BookCursor::decode)
+        .transpose()?;
+    let keyword = query.keyword.filter(|k| !k.trim().is_empty());
+    let tag = query.tag.filter(|t| !t.is_empty());
+    let (id, title, path) = match (&keyword, &tag) {
+        (Some(keyword), _) => (
+            format!("search:{}", keyword),
+            format!("「{}」の検索結果", keyword),
+            format!("/books?keyword={}", encode_query(keyword)),
+        ),
+        (None, Some(tag)) => (
+            format!("tag:{}", tag),
+            tag.clone(),
+            format!("/books?tag={}", encode_query(tag)),
+        ),
+        (None, None) => ("new".to_string(), "新着".to_string(), "/new".to_string()),
+    };
+
+    // キーワードで検索する場合のみ関連度順にする
+    let sort = if keyword.is_some() {
+        BookSort::Relevance
+    } else {
+        BookSort::CreatedAt
+    };
+    let page = get_books(
+        user_id,
+        BookQuery {
+            limit: Some(FEED_PAGE_SIZE),
+            keyword,
+            tag,
+            sort: Some(sort),
+            order: Some(SortOrder::Desc),
+            ..Default::default()
+        },
+        cursor,
+        db,
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let mut feed = Feed::new(format, FeedKind::Acquisition, &id, &title, &path);
+    feed.add_page_links(&path, &page);
+    Ok(feed)
+}
+
+/// タグごとのナビゲーションフィード
+pub async fn tags_feed(
+    format: OpdsFormat,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Feed, String> {
+    let tags = get_tags(db, user_id, is_admin)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut feed = Feed::new(format, FeedKind::Navigation, "tags", "タグ", "/tags");
+    feed.navigation = tags
+        .into_iter()
+        .filter(|tag| tag.book_count > 0)
+        .map(|tag| NavigationEntry {
+            id: format!("tag:{}", tag.name),
+            href: format!("{}/books?tag={}", format.prefix(), encode_query(&tag.name)),
+            content: Some(format!("{}冊", tag.book_count)),
+            title: tag.name,
+            kind: FeedKind::Acquisition,
+        })
+        .collect();
+    Ok(feed)
+}
+
+/// シリーズごとのナビゲーションフィード
+pub async fn series_list_feed(
+    format: OpdsFormat,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Feed, String> {
+    let series = get_series(user_id, is_admin, db)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut feed = Feed::new(
+        format,
+        FeedKind::Navigation,
+        "series",
+        "シリーズ",
+        "/series",
+    );
+    feed.navigation = series
+        .into_iter()
+        .map(|series| NavigationEntry {
+            id: format!("series:{}", series.id),
+            href: format!("{}/series/{}", format.prefix(), series.id),
+            content: Some(format!("{}冊", series.volume_count)),
+            title: series.name,
+            kind: FeedKind::Acquisition,
+        })
+        .collect();
+    Ok(feed)
+}
+
+/// シリーズの巻を巻数の順に並べた購入フィード
+///
+/// 閲覧できる巻がない場合は `RowNotFound` を返す
+pub async fn series_feed(
+    format: OpdsFormat,
+    series_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Feed, sqlx::Error> {
+    let name = sqlx::query_scalar!(r#"SELECT name FROM series WHERE id = $1"#, series_id)
+        .fetch_one(db)
+        .await?;
+    let volumes = get_series_volumes(series_id, user_id, is_admin, db).await?;
+    if volumes.is_empty() {
+        return Err(sqlx::Error::RowNotFound);
+    }
+    let mut feed = Feed::new(
+        format,
+        FeedKind::Acquisition,
+        &format!("series:{}", series_id),
+        &name,
+        &format!("/series/{}", series_id),
+    );
+    feed.total = Some(volumes.len() as i64);
+    feed.publications = volumes
+        .into_iter()
+        .map(|volume| Publication {
+            id: volume.book_id,
+            title: volume.name,
+            creator: volume.creator,
+            publisher: None,
+            date: None,
+            tags: vec![],
+            series: Some((name.clone(), volume.volume)),
+            cover_image: volume.cover_image,
+            updated: None,
+        })
+        .collect();
+    Ok(feed)
+}
+
+/// OpenSearchの説明文書
+pub fn opensearch_description() -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+<ShortName>epubapi</ShortName>
+<Description>タイトル・著者・出版社・タグで本を検索</Description>
+<InputEncoding>UTF-8</InputEncoding>
+<OutputEncoding>UTF-8</OutputEncoding>
+<Url type="{}" template="/opds/books?keyword={{searchTerms}}"/>
+</OpenSearchDescription>
+"#,
+        escape_xml(ATOM_ACQUISITION_TYPE)
+    )
+}
@@ -0,0 +1,434 @@
+use std::env;
+
+use super::{
+    auth::OpdsUser,
+    model::{self, Feed, OpdsFormat},
+};
+use crate::{
+    minio,
+    service::{book::model::get_available_book, user::model::UserError},
+};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE},
+        StatusCode, Uri,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::PgPool;
+
+/// パスからフィードの形式を判定する
+///
+/// `/opds/v2` から始まる場合はOPDS 2.0、それ以外はOPDS 1.2
+fn format_from_uri(uri: &Uri) -> OpdsFormat {
+    if uri.path().starts_with("/opds/v2") {
+        OpdsFormat::Json
+    } else {
+        OpdsFormat::Atom
+    }
+}
+
+fn feed_response(feed: Feed) -> Response {
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, feed.media_type())],
+        feed.render(),
+    )
+        .into_response()
+}
+
+/// OPDSカタログのルート
+///
+/// 新着・タグ・シリーズのフィードへのリンクを返す。
+/// `/opds/v2` の場合はOPDS 2.0 (JSON) で返す
+#[utoipa::path(
+    get,
+    path = "/opds",
+    responses(
+        (status = 200, description = "OK", content_type = "application/atom+xml;profile=opds-catalog;kind=navigation", body = String),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn opds_root(uri: Uri, _: OpdsUser) -> impl IntoResponse {
+    feed_response(model::root_feed(format_from_uri(&uri)))
+}
+
+/// 新着の本のフィード
+///
+/// `/opds/v2/new` の場合はOPDS 2.0 (JSON) で返す
+#[utoipa::path(
+    get,
+    path = "/opds/new",
+    params(model::OpdsBooksQuery),
+    responses(
+        (status = 200, description = "OK", content_type = "application/atom+xml;profile=opds-catalog;kind=acquisition", body = String),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidBookQuery(String::from("カーソルが不正です")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn opds_new(
+    uri: Uri,
+    user: OpdsUser,
+    Query(query): Query<model::OpdsBooksQuery>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    let query = model::OpdsBooksQuery {
+        keyword: None,
+        tag: None,
+        cursor: query.cursor,
+    };
+    books_response(&uri, &user, query, &db).await
+}
+
+/// 本を検索するフィード
+///
+/// キーワードかタグで絞り込む。
+/// `/opds/v2/books` の場合はOPDS 2.0 (JSON) で返す
+#[utoipa::path(
+    get,
+    path = "/opds/books",
+    params(model::OpdsBooksQuery),
+    responses(
+        (status = 200, description = "OK", content_type = "application/atom+xml;profile=opds-catalog;kind=acquisition", body = String),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidBookQuery(String::from("カーソルが不正です")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn opds_books(
+    uri: Uri,
+    user: OpdsUser,
+    Query(query): Query<model::OpdsBooksQuery>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    books_response(&uri, &user, query, &db).await
+}
+
+async fn books_response(
+    uri: &Uri,
+    user: &OpdsUser,
+    query: model::OpdsBooksQuery,
+    db: &PgPool,
+) -> Response {
+    match model::books_feed(format_from_uri(uri), &user.0.id, query, db).await {
+        Ok(feed) => feed_response(feed),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidBookQuery(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// タグのフィード
+///
+/// `/opds/v2/tags` の場合はOPDS 2.0 (JSON) で返す
+#[utoipa::path(
+    get,
+    path = "/opds/tags",
+    responses(
+        (status = 200, description = "OK", content_type = "application/atom+xml;profile=opds-catalog;kind=navigation", body = String),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn opds_tags(uri: Uri, user: OpdsUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::tags_feed(format_from_uri(&uri), &user.0.id, user.0.is_admin(), &db).await {
+        Ok(feed) => feed_response(feed),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// シリーズのフィード
+///
+/// `/opds/v2/series` の場合はOPDS 2.0 (JSON) で返す
+#[utoipa::path(
+    get,
+    path = "/opds/series",
+    responses(
+        (status = 200, description = "OK", content_type = "application/atom+xml;profile=opds-catalog;kind=navigation", body = String),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn opds_series_list(
+    uri: Uri,
+    user: OpdsUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::series_list_feed(format_from_uri(&uri), &user.0.id, user.0.is_admin(), &db).await {
+        Ok(feed) => feed_response(feed),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// シリーズの巻を巻数の順に並べたフィード
+///
+/// series_id: シリーズのID
+///
+/// `/opds/v2/series/{series_id}` の場合はOPDS 2.0 (JSON) で返す
+#[utoipa::path(
+    get,
+    path = "/opds/series/{series_id}",
+    responses(
+        (status = 200, description = "OK", content_type = "application/atom+xml;profile=opds-catalog;kind=acquisition", body = String),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn opds_series(
+    uri: Uri,
+    Path(series_id): Path<String>,
+    user: OpdsUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::series_feed(
+        format_from_uri(&uri),
+        &series_id,
+        &user.0.id,
+        user.0.is_admin(),
+        &db,
+    )
+    .await
+    {
+        Ok(feed) => feed_response(feed),
+        Err(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// OpenSearchの説明文書
+#[utoipa::path(
+    get,
+    path = "/opds/opensearch.xml",
+    responses(
+        (status = 200, description = "OK", content_type = "application/opensearchdescription+xml", body = String),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn opds_opensearch(_: OpdsUser) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, model::OPENSEARCH_TYPE)],
+        model::opensearch_description(),
+    )
+}
+
+/// EPUBファイルをダウンロードする
+///
+/// 署名付きURLへのリダイレクトでは認証ヘッダーを送り直す電子書籍リーダーがあるため、APIから直接返す
+#[utoipa::path(
+    get,
+    path = "/opds/books/{book_id}/download",
+    responses(
+        (status = 200, description = "OK", content_type = "application/epub+zip", body = Vec<u8>),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn opds_download(
+    Path(book_id): Path<String>,
+    user: OpdsUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    let book = match get_available_book(&book_id, &user.0.id, user.0.is_admin(), &db).await {
+        Ok(book) => book,
+        Err(sqlx::Error::RowNotFound) => return (StatusCode::NOT_FOUND).into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    let endpoint = env::var("S3_ENDPOINT").expect("S3_ENDPOINT is not set");
+    let epub_bucket = env::var("EPUB_BUCKET").expect("EPUB_BUCKET is not set");
+    let minio_client = minio::get_client(&endpoint).await;
+    let object = match minio_client
+        .get_object()
+        .bucket(&epub_bucket)
+        .key(&book.key)
+        .send()
+        .await
+    {
+        Ok(object) => object,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    let stream = futures::stream::unfold(object.body, |mut body| async move {
+        body.next().await.map(|chunk| (chunk, body))
+    });
+    (
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, "application/epub+zip".to_string()),
+            (
+                CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}.epub\"", book.id),
+            ),
+        ],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::Value;
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::routes::init_app;
+
+    fn basic(user_id: &str, key: &str) -> String {
+        format!("Basic {}", STANDARD.encode(format!("{}:{}", user_id, key)))
+    }
+
+    async fn get(router: &axum::Router, uri: &str, authorization: &str) -> (u16, String, String) {
+        let req = Request::builder()
+            .uri(uri)
+            .header(header::AUTHORIZATION, authorization)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status().as_u16();
+        let content_type = res
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|v| v.to_str().unwrap().to_string())
+            .unwrap_or_default();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (
+            status,
+            content_type,
+            String::from_utf8(bytes.to_vec()).unwrap(),
+        )
+    }
+
+    /// OPDSの認証のテスト
+    #[sqlx::test(fixtures("users", "opds"))]
+    async fn test_opds_auth(pool: PgPool) {
+        let router = init_app(&pool);
+
+        // Basic認証
+        let (status, content_type, _) =
+            get(&router, "/opds", &basic("user_id", "user_api_key")).await;
+        assert_eq!(status, 200);
+        assert!(content_type.contains("kind=navigation"));
+
+        // APIキーとユーザーIDが一致しない
+        let req = Request::builder()
+            .uri("/opds")
+            .header(header::AUTHORIZATION, basic("test_user_id", "user_api_key"))
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+        assert!(res.headers().contains_key(header::WWW_AUTHENTICATE));
+
+        // Bearer
+        let (status, _, _) = get(&router, "/opds", "Bearer user_api_key").await;
+        assert_eq!(status, 200);
+    }
+
+    /// OPDS 1.2のフィードのテスト
+    #[sqlx::test(fixtures("users", "opds"))]
+    async fn test_opds_atom(pool: PgPool) {
+        let router = init_app(&pool);
+        let auth = basic("user_id", "user_api_key");
+
+        // ルート
+        let (_, _, feed) = get(&router, "/opds", &auth).await;
+        let doc = roxmltree::Document::parse(&feed).unwrap();
+        let hrefs = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("link") && n.attribute("rel") == Some("subsection"))
+            .map(|n| n.attribute("href").unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(hrefs, vec!["/opds/new", "/opds/tags", "/opds/series"]);
+
+        // 新着(閲覧できない本は含まない)
+        let (status, content_type, feed) = get(&router, "/opds/new", &auth).await;
+        assert_eq!(status, 200);
+        assert!(content_type.contains("kind=acquisition"));
+        let doc = roxmltree::Document::parse(&feed).unwrap();
+        let titles = doc
+            .descendants()
+            .filter(|n| n.has_tag_name("entry"))
+            .map(|n| {
+                n.children()
+                    .find(|c| c.has_tag_name("title"))
+                    .unwrap()
+                    .text()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(titles, vec!["漫画 <2> & 続き", "漫画 1"]);
+        let acquisition = doc
+            .descendants()
+            .find(|n| n.attribute("rel") == Some("http://opds-spec.org/acquisition"))
+            .unwrap();
+        assert_eq!(
+            acquisition.attribute("href"),
+            Some("/opds/books/opds_book_2_id/download")
+        );
+
+        // タグ
+        let (_, _, feed) = get(&router, "/opds/tags", &auth).await;
+        assert!(feed.contains("href=\"/opds/books?tag=%E6%BC%AB%E7%94%BB\""));
+        let (_, _, feed) = get(&router, "/opds/books?tag=%E6%BC%AB%E7%94%BB", &auth).await;
+        assert!(feed.contains("<opensearch:totalResults>1</opensearch:totalResults>"));
+
+        // 検索
+        let (_, _, feed) = get(&router, "/opds/books?keyword=%E7%B6%9A%E3%81%8D", &auth).await;
+        assert!(feed.contains("<opensearch:totalResults>1</opensearch:totalResults>"));
+        let (status, content_type, feed) = get(&router, "/opds/opensearch.xml", &auth).await;
+        assert_eq!(status, 200);
+        assert_eq!(content_type, "application/opensearchdescription+xml");
+        assert!(feed.contains("template=\"/opds/books?keyword={searchTerms}\""));
+
+        // シリーズ
+        let (_, _, feed) = get(&router, "/opds/series", &auth).await;
+        assert!(feed.contains("href=\"/opds/series/opds_series_id\""));
+        let (status, _, _) = get(&router, "/opds/series/opds_series_id", &auth).await;
+        assert_eq!(status, 200);
+
+        // 閲覧できない本のダウンロード
+        let (status, _, _) = get(&router, "/opds/books/opds_private_book_id/download", &auth).await;
+        assert_eq!(status, 404);
+    }
+
+    /// OPDS 2.0のフィードのテスト
+    #[sqlx::test(fixtures("users", "opds"))]
+    async fn test_opds_json(pool: PgPool) {
+        let router = init_app(&pool);
+        let auth = basic("user_id", "user_api_key");
+
+        let (status, content_type, feed) = get(&router, "/opds/v2", &auth).await;
+        assert_eq!(status, 200);
+        assert_eq!(content_type, "application/opds+json");
+        let feed: Value = serde_json::from_str(&feed).unwrap();
+        assert_eq!(feed["navigation"][0]["href"], "/opds/v2/new");
+        let search = feed["links"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|link| link["rel"] == "search")
+            .unwrap();
+        assert_eq!(search["href"], "/opds/v2/books{?keyword}");
+        assert_eq!(search["templated"], true);
+
+        let (_, _, feed) = get(&router, "/opds/v2/series/opds_series_id", &auth).await;
+        let feed: Value = serde_json::from_str(&feed).unwrap();
+        let publications = feed["publications"].as_array().unwrap();
+        assert_eq!(publications.len(), 2);
+        assert_eq!(publications[0]["metadata"]["title"], "漫画 1");
+        assert_eq!(
+            publications[0]["metadata"]["belongsTo"]["series"]["position"],
+            1.0
+        );
+        assert_eq!(
+            publications[0]["links"][0]["href"],
+            "/opds/books/opds_book_1_id/download"
+        );
+    }
+}
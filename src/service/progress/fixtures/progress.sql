@@ -0,0 +1,54 @@
+insert into
+    books(
+        id,
+        "key",
+        owner_id,
+        "name",
+        creator,
+        publisher,
+        "date",
+        cover_image,
+        visibility,
+        layout,
+        images
+    )
+values
+    (
+        'comic_book_id',
+        'comic_book_key',
+        'user_id',
+        'comic_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'pre-paginated',
+        '{page_1, page_2, page_3}'
+    ),
+    (
+        'novel_book_id',
+        'novel_book_key',
+        'test_user_id',
+        'novel_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'public',
+        'reflowable',
+        '{}'
+    ),
+    (
+        'private_book_id',
+        'private_book_key',
+        'test_user_id',
+        'private_book_name',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'reflowable',
+        '{}'
+    );
@@ -0,0 +1,297 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::service::book::model::BookLayout;
+
+/// 本の読書位置
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ReadingProgress {
+    pub book_id: String,
+    /// pre-paginated の本のページ番号(0始まり)
+    pub page: Option<i32>,
+    /// reflowable の本のEPUB CFI
+    pub cfi: Option<String>,
+    /// reflowable の本の読んだ割合(0.0〜1.0)
+    pub percentage: Option<f64>,
+    /// 読書位置を記録した端末の名前
+    pub device: String,
+    #[schema(value_type = String, format = DateTime)]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// `PUT /books/{book_id}/progress` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema, Default)]
+pub struct UpdateProgressRequest {
+    /// pre-paginated の本のページ番号(0始まり)
+    pub page: Option<i32>,
+    /// reflowable の本のEPUB CFI(`epubcfi(...)` の形式)
+    pub cfi: Option<String>,
+    /// reflowable の本の読んだ割合(0.0〜1.0)
+    pub percentage: Option<f64>,
+    pub device: String,
+    /// 端末で読書位置が変わった日時(省略した場合はサーバーの現在日時)
+    ///
+    /// 記録されている日時より古い場合は更新しない
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// 読みかけの本
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ContinueReading {
+    pub book_id: String,
+    pub name: String,
+    pub creator: String,
+    pub cover_image: String,
+    #[schema(inline)]
+    pub layout: Option<BookLayout>,
+    /// 本のページ数(pre-paginated の本のみ)
+    pub page_count: i32,
+    #[schema(inline)]
+    pub progress: ReadingProgress,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, IntoParams)]
+#[into_params(style = Form, parameter_in = Query)]
+pub struct ContinueReadingQuery {
+    /// 取得する件数(デフォルト: 24、最大: 100)
+    pub limit: Option<u32>,
+}
+
+/// 端末名の最大文字数
+const MAX_DEVICE_LENGTH: usize = 100;
+
+/// EPUB CFIの最大文字数
+const MAX_CFI_LENGTH: usize = 1024;
+
+/// 読みかけの本の一覧のデフォルトの件数
+const DEFAULT_CONTINUE_READING_LIMIT: u32 = 24;
+
+/// 読みかけの本の一覧の最大件数
+const MAX_CONTINUE_READING_LIMIT: u32 = 100;
+
+impl UpdateProgressRequest {
+    /// 本のレイアウトに合わせて値を検証する
+    fn validate(mut self, layout: Option<BookLayout>, page_count: usize) -> Result<Self, String> {
+        self.device = self.device.trim().to_string();
+        if self.device.is_empty() {
+            return Err("端末名を指定してください".to_string());
+        }
+        if self.device.chars().count() > MAX_DEVICE_LENGTH {
+            return Err(format!(
+                "端末名は{}文字以内で指定してください",
+                MAX_DEVICE_LENGTH
+            ));
+        }
+        match layout {
+            Some(BookLayout::PrePaginated) => {
+                let Some(page) = self.page else {
+                    return Err("ページ番号を指定してください".to_string());
+                };
+                if page < 0 || (page_count > 0 && page as usize >= page_count) {
+                    return Err(format!(
+                        "ページ番号は0から{}の範囲で指定してください",
+                        page_count.saturating_sub(1)
+                    ));
+                }
+                self.cfi = None;
+                self.percentage = None;
+            }
+            _ => {
+                if self.cfi.is_none() && self.percentage.is_none() {
+                    return Err("EPUB CFIまたは読んだ割合を指定してください".to_string());
+                }
+                if let Some(cfi) = &self.cfi {
+                    if !(cfi.starts_with("epubcfi(") && cfi.ends_with(')'))
+                        || cfi.chars().count() > MAX_CFI_LENGTH
+                    {
+                        return Err("EPUB CFIの形式が正しくありません".to_string());
+                    }
+                }
+                if self
+                    .percentage
+                    .is_some_and(|percentage| !(0.0..=1.0).contains(&percentage))
+                {
+                    return Err("読んだ割合は0.0から1.0の範囲で指定してください".to_string());
+                }
+                self.page = None;
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// 本の読書位置を取得する
+///
+/// 閲覧できない本や記録がない場合は `RowNotFound` を返す
+pub async fn get_progress(
+    book_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<ReadingProgress, sqlx::Error> {
+    sqlx::query_as!(
+        ReadingProgress,
+        r#"
+            SELECT p.book_id, p.page, p.cfi, p.percentage, p.device, p.updated_at
+            FROM reading_progress p
+            JOIN books b ON b.id = p.book_id
+            WHERE p.book_id = $1
+            AND p.user_id = $2
+            AND ($3 OR b.owner_id = $2 OR b.visibility = 'public')
+        "#,
+        book_id,
+        user_id,
+        is_admin
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// 本の読書位置を記録する
+///
+/// 記録されている読書位置より新しい場合のみ更新し(後勝ち)、更新後に記録されている読書位置を返す。
+/// 未来の日時はサーバーの現在日時として扱う
+pub async fn update_progress(
+    book_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    req: UpdateProgressRequest,
+    db: &PgPool,
+) -> Result<ReadingProgress, String> {
+    let book = sqlx::query!(
+        r#"
+            SELECT layout as "layout: BookLayout", cardinality(images) as "page_count!"
+            FROM books
+            WHERE id = $1
+            AND ($3 OR owner_id = $2 OR visibility = 'public')
+        "#,
+        book_id,
+        user_id,
+        is_admin
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("本が見つかりません")?;
+    let req = req.validate(book.layout, book.page_count as usize)?;
+    let now = Utc::now();
+    let updated_at = req.updated_at.map_or(now, |updated_at| updated_at.min(now));
+
+    sqlx::query!(
+        r#"
+            INSERT INTO reading_progress (user_id, book_id, page, cfi, percentage, device, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (user_id, book_id) DO UPDATE
+            SET
+                page = excluded.page,
+                cfi = excluded.cfi,
+                percentage = excluded.percentage,
+                device = excluded.device,
+                updated_at = excluded.updated_at,
+                kosync_progress = NULL,
+                device_id = NULL
+            WHERE reading_progress.updated_at <= excluded.updated_at
+        "#,
+        user_id,
+        book_id,
+        req.page,
+        req.cfi,
+        req.percentage,
+        req.device,
+        updated_at
+    )
+    .execute(db)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    get_progress(book_id, user_id, is_admin, db)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// 本の読書位置の記録を削除する
+pub async fn delete_progress(book_id: &str, user_id: &str, db: &PgPool) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM reading_progress
+            WHERE book_id = $1
+            AND user_id = $2
+        "#,
+        book_id,
+        user_id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// 読みかけの本を読書位置の新しい順に取得する
+///
+/// 読み終えた本と閲覧できなくなった本は含まない
+pub async fn get_continue_reading(
+    user_id: &str,
+    is_admin: bool,
+    query: ContinueReadingQuery,
+    db: &PgPool,
+) -> Result<Vec<ContinueReading>, sqlx::Error> {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_CONTINUE_READING_LIMIT)
+        .clamp(1, MAX_CONTINUE_READING_LIMIT);
+    let rows = sqlx::query!(
+        r#"
+            SELECT
+                b.id,
+                b.name,
+                b.creator,
+                b.cover_image,
+                b.layout as "layout: BookLayout",
+                cardinality(b.images) as "page_count!",
+                p.page,
+                p.cfi,
+                p.percentage,
+                p.device,
+                p.updated_at
+            FROM reading_progress p
+            JOIN books b ON b.id = p.book_id
+            WHERE p.user_id = $1
+            AND ($2 OR b.owner_id = $1 OR b.visibility = 'public')
+            AND NOT EXISTS (
+                SELECT 1
+                FROM finished_books f
+                WHERE f.user_id = $1
+                AND f.book_id = p.book_id
+            )
+            ORDER BY p.updated_at DESC, b.id
+            LIMIT $3
+        "#,
+        user_id,
+        is_admin,
+        limit as i64
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| ContinueReading {
+            progress: ReadingProgress {
+                book_id: row.id.clone(),
+                page: row.page,
+                cfi: row.cfi,
+                percentage: row.percentage,
+                device: row.device,
+                updated_at: row.updated_at,
+            },
+            book_id: row.id,
+            name: row.name,
+            creator: row.creator,
+            cover_image: row.cover_image,
+            layout: row.layout,
+            page_count: row.page_count,
+        })
+        .collect())
+}
@@ -0,0 +1,281 @@
+use super::model;
+use crate::service::user::{auth::AuthUser, model::UserError};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use sqlx::PgPool;
+
+/// 読みかけの本を読書位置の新しい順に取得する
+///
+/// 読み終えた本は含まない
+#[utoipa::path(
+    get,
+    path = "/progress",
+    params(model::ContinueReadingQuery),
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::ContinueReading>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_continue_reading(
+    user: AuthUser,
+    Query(query): Query<model::ContinueReadingQuery>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_continue_reading(&user.id, user.is_admin(), query, &db).await {
+        Ok(books) => (StatusCode::OK, Json(books)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// 本の読書位置を取得する
+///
+/// book_id: 本のID
+#[utoipa::path(
+    get,
+    path = "/books/{book_id}/progress",
+    responses(
+        (status = 200, description = "OK", body = inline(model::ReadingProgress)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn get_progress(
+    Path(book_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_progress(&book_id, &user.id, user.is_admin(), &db).await {
+        Ok(progress) => (StatusCode::OK, Json(progress)).into_response(),
+        Err(sqlx::Error::RowNotFound) => (StatusCode::NOT_FOUND).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// 本の読書位置を記録する
+///
+/// 記録されている読書位置より `updated_at` が新しい場合のみ更新する(後勝ち)。
+/// レスポンスは更新後に記録されている読書位置
+#[utoipa::path(
+    put,
+    path = "/books/{book_id}/progress",
+    request_body = inline(model::UpdateProgressRequest),
+    responses(
+        (status = 200, description = "OK", body = inline(model::ReadingProgress)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidProgressRequest(String::from("ページ番号を指定してください")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn update_progress(
+    Path(book_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::UpdateProgressRequest>,
+) -> impl IntoResponse {
+    match model::update_progress(&book_id, &user.id, user.is_admin(), body, &db).await {
+        Ok(progress) => (StatusCode::OK, Json(progress)).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidProgressRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// 本の読書位置の記録を削除する
+#[utoipa::path(
+    delete,
+    path = "/books/{book_id}/progress",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn delete_progress(
+    Path(book_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::delete_progress(&book_id, &user.id, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
+
+    fn request(method: &str, uri: &str, cookie: &str, body: Option<Value>) -> Request<Body> {
+        let builder = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header(header::COOKIE, cookie);
+        match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(Body::empty()).unwrap(),
+        }
+    }
+
+    async fn send(router: &axum::Router, req: Request<Body>) -> (u16, Option<Value>) {
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status().as_u16();
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice::<Value>(&bytes).ok())
+    }
+
+    /// 読書位置の記録と取得のテスト
+    #[sqlx::test(fixtures("users", "progress"))]
+    async fn test_progress(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+
+        // GET /books/{book_id}/progress (記録がない)
+        let (status, _) = send(
+            &router,
+            request("GET", "/books/comic_book_id/progress", &user_cookie, None),
+        )
+        .await;
+        assert_eq!(status, 404);
+
+        // PUT /books/{book_id}/progress (pre-paginated)
+        let (status, progress) = send(
+            &router,
+            request(
+                "PUT",
+                "/books/comic_book_id/progress",
+                &user_cookie,
+                Some(json!({"page": 1, "device": "phone", "updated_at": "2024-01-02T00:00:00Z"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let progress = progress.unwrap();
+        assert_eq!(progress["page"], 1);
+        assert_eq!(progress["device"], "phone");
+
+        // PUT /books/{book_id}/progress (古い記録は反映しない)
+        let (status, progress) = send(
+            &router,
+            request(
+                "PUT",
+                "/books/comic_book_id/progress",
+                &user_cookie,
+                Some(json!({"page": 0, "device": "tablet", "updated_at": "2024-01-01T00:00:00Z"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert_eq!(progress.unwrap()["device"], "phone");
+
+        // PUT /books/{book_id}/progress (ページ数を超える)
+        let (status, _) = send(
+            &router,
+            request(
+                "PUT",
+                "/books/comic_book_id/progress",
+                &user_cookie,
+                Some(json!({"page": 3, "device": "phone"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // PUT /books/{book_id}/progress (reflowable)
+        let (status, progress) = send(
+            &router,
+            request(
+                "PUT",
+                "/books/novel_book_id/progress",
+                &user_cookie,
+                Some(json!({"cfi": "epubcfi(/6/4!/4/2/1:0)", "percentage": 0.25, "device": "desktop"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let progress = progress.unwrap();
+        assert_eq!(progress["cfi"], "epubcfi(/6/4!/4/2/1:0)");
+        assert_eq!(progress["page"], Value::Null);
+        let (status, _) = send(
+            &router,
+            request(
+                "PUT",
+                "/books/novel_book_id/progress",
+                &user_cookie,
+                Some(json!({"percentage": 1.5, "device": "desktop"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // PUT /books/{book_id}/progress (閲覧できない本)
+        let (status, _) = send(
+            &router,
+            request(
+                "PUT",
+                "/books/private_book_id/progress",
+                &user_cookie,
+                Some(json!({"percentage": 0.5, "device": "desktop"})),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // GET /progress
+        let (status, books) = send(&router, request("GET", "/progress", &user_cookie, None)).await;
+        assert_eq!(status, 200);
+        let books = books.unwrap();
+        let ids = books
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| b["book_id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["novel_book_id", "comic_book_id"]);
+        assert_eq!(books[1]["page_count"], 3);
+        assert_eq!(books[1]["progress"]["page"], 1);
+
+        // GET /progress (読み終えた本は含まない)
+        let (status, _) = send(
+            &router,
+            request("PUT", "/books/novel_book_id/finished", &user_cookie, None),
+        )
+        .await;
+        assert_eq!(status, 204);
+        let (_, books) = send(&router, request("GET", "/progress", &user_cookie, None)).await;
+        assert_eq!(books.unwrap().as_array().unwrap().len(), 1);
+
+        // DELETE /books/{book_id}/progress
+        let (status, _) = send(
+            &router,
+            request(
+                "DELETE",
+                "/books/comic_book_id/progress",
+                &user_cookie,
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+        let (status, _) = send(
+            &router,
+            request("GET", "/books/comic_book_id/progress", &user_cookie, None),
+        )
+        .await;
+        assert_eq!(status, 404);
+    }
+}
@@ -0,0 +1,87 @@
+insert into
+    series(id, "name")
+values
+    ('manga_series_id', 'ある漫画');
+
+insert into
+    books(
+        id,
+        "key",
+        owner_id,
+        "name",
+        creator,
+        publisher,
+        "date",
+        cover_image,
+        visibility,
+        layout,
+        images,
+        series_id,
+        volume
+    )
+values
+    (
+        'manga_2_id',
+        'manga_2_key',
+        'user_id',
+        'ある漫画 2',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'pre-paginated',
+        '{}',
+        'manga_series_id',
+        2
+    ),
+    (
+        'manga_1_id',
+        'manga_1_key',
+        'user_id',
+        'ある漫画 1',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'pre-paginated',
+        '{}',
+        'manga_series_id',
+        1
+    ),
+    (
+        'manga_3_id',
+        'manga_3_key',
+        'admin_id',
+        'ある漫画 3',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'private',
+        'pre-paginated',
+        '{}',
+        'manga_series_id',
+        3
+    ),
+    (
+        'manga_extra_id',
+        'manga_extra_key',
+        'user_id',
+        'ある漫画 番外編',
+        'book_creator',
+        'book_publisher',
+        'book_date',
+        'book_cover_image',
+        'public',
+        'pre-paginated',
+        '{}',
+        'manga_series_id',
+        null
+    );
+
+insert into
+    finished_books(user_id, book_id)
+values
+    ('user_id', 'manga_1_id');
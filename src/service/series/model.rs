@@ -0,0 +1,142 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{PgConnection, PgPool};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct Series {
+    pub id: String,
+    pub name: String,
+    /// 閲覧可能な巻の数
+    pub volume_count: i64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SeriesVolume {
+    pub book_id: String,
+    pub name: String,
+    pub creator: String,
+    pub cover_image: String,
+    /// 巻数(未設定の場合は `null`)
+    pub volume: Option<f64>,
+    /// 読み終えたかどうか
+    pub finished: bool,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UpdateSeriesRequest {
+    pub name: String,
+}
+
+/// シリーズ名からシリーズのIDを取得し、なければ作成する
+pub async fn find_or_create_series(
+    name: &str,
+    conn: &mut PgConnection,
+) -> Result<String, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            INSERT INTO series (id, name)
+            VALUES ($1, $2)
+            ON CONFLICT (name) DO UPDATE SET name = excluded.name
+            RETURNING id
+        "#,
+        Uuid::new_v4().to_string(),
+        name
+    )
+    .fetch_one(conn)
+    .await
+}
+
+/// 閲覧可能な巻があるシリーズの一覧を取得する
+pub async fn get_series(
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Vec<Series>, sqlx::Error> {
+    sqlx::query_as!(
+        Series,
+        r#"
+            SELECT s.id, s.name, count(*) as "volume_count!"
+            FROM series s
+            JOIN books b ON b.series_id = s.id
+            WHERE $2 OR b.owner_id = $1 OR b.visibility = 'public'
+            GROUP BY s.id
+            ORDER BY s.name
+        "#,
+        user_id,
+        is_admin
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// シリーズの閲覧可能な巻を巻数の順に取得する
+///
+/// 巻数のない本は最後にタイトル順で並べる
+pub async fn get_series_volumes(
+    series_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Vec<SeriesVolume>, sqlx::Error> {
+    sqlx::query_as!(
+        SeriesVolume,
+        r#"
+            SELECT
+                b.id as book_id,
+                b.name,
+                b.creator,
+                b.cover_image,
+                b.volume,
+                EXISTS (
+                    SELECT 1
+                    FROM finished_books f
+                    WHERE f.user_id = $2
+                    AND f.book_id = b.id
+                ) as "finished!"
+            FROM books b
+            WHERE b.series_id = $1
+            AND ($3 OR b.owner_id = $2 OR b.visibility = 'public')
+            ORDER BY b.volume NULLS LAST, b.name, b.id
+        "#,
+        series_id,
+        user_id,
+        is_admin
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// シリーズの中で最初の読み終えていない巻を取得する
+pub async fn get_next_volume(
+    series_id: &str,
+    user_id: &str,
+    is_admin: bool,
+    db: &PgPool,
+) -> Result<Option<SeriesVolume>, sqlx::Error> {
+    let volumes = get_series_volumes(series_id, user_id, is_admin, db).await?;
+    Ok(volumes.into_iter().find(|volume| !volume.finished))
+}
+
+/// シリーズ名を変更する
+pub async fn update_series(series_id: &str, name: &str, db: &PgPool) -> Result<(), String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("シリーズ名を指定してください".to_string());
+    }
+    sqlx::query!(
+        r#"
+            UPDATE series
+            SET name = $1
+            WHERE id = $2
+            RETURNING id
+        "#,
+        name,
+        series_id
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|_| "同じ名前のシリーズがあります")?
+    .ok_or("シリーズが見つかりません")?;
+    Ok(())
+}
@@ -0,0 +1,285 @@
+use super::model;
+use crate::service::user::{
+    auth::{AdminUser, AuthUser},
+    model::UserError,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use sqlx::PgPool;
+
+/// 閲覧可能な巻があるシリーズの一覧を取得する
+#[utoipa::path(
+    get,
+    path = "/series",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::Series>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_series(user: AuthUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::get_series(&user.id, user.is_admin(), &db).await {
+        Ok(series) => (StatusCode::OK, Json(series)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// シリーズの閲覧可能な巻を巻数の順に取得する
+///
+/// series_id: シリーズのID
+#[utoipa::path(
+    get,
+    path = "/series/{series_id}/books",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::SeriesVolume>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_series_volumes(
+    Path(series_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_series_volumes(&series_id, &user.id, user.is_admin(), &db).await {
+        Ok(volumes) => (StatusCode::OK, Json(volumes)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// シリーズの中で最初の読み終えていない巻を取得する
+///
+/// すべて読み終えている場合は204を返す
+#[utoipa::path(
+    get,
+    path = "/series/{series_id}/next",
+    responses(
+        (status = 200, description = "OK", body = inline(model::SeriesVolume)),
+        (status = 204, description = "No unread volume"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_next_volume(
+    Path(series_id): Path<String>,
+    user: AuthUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_next_volume(&series_id, &user.id, user.is_admin(), &db).await {
+        Ok(Some(volume)) => (StatusCode::OK, Json(volume)).into_response(),
+        Ok(None) => (StatusCode::NO_CONTENT).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// シリーズ名を変更する
+///
+/// シリーズはすべてのユーザーで共有されるため、管理者のみ実行可能
+#[utoipa::path(
+    patch,
+    path = "/series/{series_id}",
+    request_body = inline(model::UpdateSeriesRequest),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidBookRequest(String::from("シリーズが見つかりません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn update_series(
+    _: AdminUser,
+    Path(series_id): Path<String>,
+    State(db): State<PgPool>,
+    Json(body): Json<model::UpdateSeriesRequest>,
+) -> impl IntoResponse {
+    match model::update_series(&series_id, &body.name, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidBookRequest(e)),
+        )
+            .into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Request},
+    };
+    use serde_json::{json, Value};
+    use sqlx::PgPool;
+    use tower::ServiceExt;
+
+    use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
+
+    /// シリーズの一覧と巻の取得のテスト
+    #[sqlx::test(fixtures("users", "series"))]
+    async fn test_get_series(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let get = |uri: &str| {
+            let router = router.clone();
+            let req = Request::builder()
+                .uri(uri)
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap();
+            async move {
+                let res = router.oneshot(req).await.unwrap();
+                let status = res.status();
+                let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+                (status, serde_json::from_slice::<Value>(&bytes).ok())
+            }
+        };
+
+        // GET /series
+        let (status, series) = get("/series").await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            series.unwrap(),
+            json!([{"id": "manga_series_id", "name": "ある漫画", "volume_count": 3}])
+        );
+
+        // GET /series/{series_id}/books (他のユーザーの非公開の巻は含まない)
+        let (status, volumes) = get("/series/manga_series_id/books").await;
+        assert_eq!(status, 200);
+        let volumes = volumes.unwrap();
+        let ids = volumes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v["book_id"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["manga_1_id", "manga_2_id", "manga_extra_id"]);
+        assert_eq!(volumes[0]["finished"], true);
+        assert_eq!(volumes[0]["volume"], 1.0);
+
+        // GET /series/{series_id}/next
+        let (status, next) = get("/series/manga_series_id/next").await;
+        assert_eq!(status, 200);
+        assert_eq!(next.unwrap()["book_id"], "manga_2_id");
+
+        // PUT /books/{book_id}/finished
+        for book_id in ["manga_2_id", "manga_extra_id"] {
+            let req = Request::builder()
+                .uri(format!("/books/{}/finished", book_id))
+                .method("PUT")
+                .header(header::COOKIE, &user_cookie)
+                .body(Body::empty())
+                .unwrap();
+            let res = router.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), 204);
+        }
+        let (status, _) = get("/series/manga_series_id/next").await;
+        assert_eq!(status, 204);
+
+        // DELETE /books/{book_id}/finished
+        let req = Request::builder()
+            .uri("/books/manga_1_id/finished")
+            .method("DELETE")
+            .header(header::COOKIE, &user_cookie)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let (_, next) = get("/series/manga_series_id/next").await;
+        assert_eq!(next.unwrap()["book_id"], "manga_1_id");
+
+        // PUT /books/{book_id}/finished (閲覧できない本)
+        let req = Request::builder()
+            .uri("/books/manga_3_id/finished")
+            .method("PUT")
+            .header(header::COOKIE, &user_cookie)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+    }
+
+    /// シリーズの編集のテスト
+    #[sqlx::test(fixtures("users", "series"))]
+    async fn test_update_series(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+        let patch = |uri: &str, cookie: &str, body: Value| {
+            Request::builder()
+                .uri(uri)
+                .method("PATCH")
+                .header(header::COOKIE, cookie)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        // PATCH /books/{book_id} でシリーズと巻数を設定
+        let req = patch(
+            "/books/manga_extra_id",
+            &user_cookie,
+            json!({"series": "別の漫画", "volume": 1}),
+        );
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let book = sqlx::query!(
+            r#"SELECT s.name, b.volume FROM books b JOIN series s ON s.id = b.series_id WHERE b.id = 'manga_extra_id'"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(book.name, "別の漫画");
+        assert_eq!(book.volume, Some(1.0));
+
+        // 既存のシリーズに戻す
+        let req = patch(
+            "/books/manga_extra_id",
+            &user_cookie,
+            json!({"series": "ある漫画"}),
+        );
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let book =
+            sqlx::query!(r#"SELECT series_id, volume FROM books WHERE id = 'manga_extra_id'"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(book.series_id.as_deref(), Some("manga_series_id"));
+        assert_eq!(book.volume, None);
+
+        // シリーズから外す
+        let req = patch("/books/manga_extra_id", &user_cookie, json!({"series": ""}));
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let book = sqlx::query!(r#"SELECT series_id FROM books WHERE id = 'manga_extra_id'"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(book.series_id, None);
+
+        // PATCH /series/{series_id} (管理者以外)
+        let req = patch(
+            "/series/manga_series_id",
+            &user_cookie,
+            json!({"name": "ある漫画(完全版)"}),
+        );
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // PATCH /series/{series_id}
+        let req = patch(
+            "/series/manga_series_id",
+            &admin_cookie,
+            json!({"name": "ある漫画(完全版)"}),
+        );
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let req = patch(
+            "/series/manga_series_id",
+            &admin_cookie,
+            json!({"name": "別の漫画"}),
+        );
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+    }
+}
@@ -1,15 +1,43 @@
 insert into
-    users(id, password, role, api_key)
+    users(id, password, role)
 values
     (
         'user_id',
         'user_password',
-        'user',
-        'user_api_key'
+        'user'
     ),
     (
         'admin_id',
         'admin_password',
-        'admin',
-        'admin_api_key'
-    );
\ No newline at end of file
+        'admin'
+    ),
+    (
+        'some_user',
+        'some_user_password',
+        'user'
+    );
+
+insert into
+    api_keys(user_id, "name", key_hash, prefix, scopes)
+values
+    (
+        'user_id',
+        'default',
+        encode(sha256(convert_to('user_api_key', 'UTF8')), 'hex'),
+        left('user_api_key', 8),
+        '{admin}'
+    ),
+    (
+        'admin_id',
+        'default',
+        encode(sha256(convert_to('admin_api_key', 'UTF8')), 'hex'),
+        left('admin_api_key', 8),
+        '{admin}'
+    ),
+    (
+        'some_user',
+        'default',
+        encode(sha256(convert_to('some_user_api_key', 'UTF8')), 'hex'),
+        left('some_user_api_key', 8),
+        '{admin}'
+    );
@@ -1,4 +1,3 @@
-use crate::service::user::model::is_admin;
 use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use utoipa::ToSchema;
@@ -59,8 +58,8 @@ pub async fn delete_tag(name: &str, db: &PgPool) -> Result<(), sqlx::Error> {
 }
 
 /// タグとそのbook件数の一覧を取得する
-pub async fn get_tags(db: &PgPool, user_id: &str) -> Result<Vec<Tag>, sqlx::Error> {
-    if is_admin(db, user_id).await {
+pub async fn get_tags(db: &PgPool, user_id: &str, is_admin: bool) -> Result<Vec<Tag>, sqlx::Error> {
+    if is_admin {
         sqlx::query!(
             r#"
                 SELECT name,
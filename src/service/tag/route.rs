@@ -1,8 +1,8 @@
 use super::model;
-use crate::service::user::model::{is_admin, user_id_from_header};
+use crate::service::user::auth::{AdminUser, AuthUser};
 use axum::{
     extract::{Path, State},
-    http::{HeaderMap, StatusCode},
+    http::StatusCode,
     response::IntoResponse,
     Json,
 };
@@ -19,13 +19,10 @@ use sqlx::PgPool;
     )
 )]
 pub async fn new_tag(
-    headers: HeaderMap,
+    _: AuthUser,
     State(db): State<PgPool>,
     Json(body): Json<model::NewTagRequest>,
 ) -> impl IntoResponse {
-    if user_id_from_header(&headers, &db).await.is_none() {
-        return (StatusCode::UNAUTHORIZED).into_response();
-    }
     match model::create_tag(&body.name, &db).await {
         Ok(_) => (StatusCode::NO_CONTENT).into_response(),
         Err(_) => (StatusCode::BAD_REQUEST).into_response(),
@@ -43,19 +40,11 @@ pub async fn new_tag(
     )
 )]
 pub async fn update_tag(
-    headers: HeaderMap,
+    _: AdminUser,
     Path(old): Path<String>,
     State(db): State<PgPool>,
     Json(body): Json<model::NewTagRequest>,
 ) -> impl IntoResponse {
-    match user_id_from_header(&headers, &db).await {
-        Some(id) => {
-            if !is_admin(&db, &id).await {
-                return (StatusCode::UNAUTHORIZED).into_response();
-            }
-        }
-        None => return (StatusCode::UNAUTHORIZED).into_response(),
-    }
     match model::update_tag(&old, &body.name, &db).await {
         Ok(_) => (StatusCode::NO_CONTENT).into_response(),
         Err(_) => (StatusCode::BAD_REQUEST).into_response(),
@@ -72,18 +61,10 @@ pub async fn update_tag(
     )
 )]
 pub async fn delete_tag(
-    headers: HeaderMap,
+    _: AdminUser,
     State(db): State<PgPool>,
     Path(name): Path<String>,
 ) -> impl IntoResponse {
-    match user_id_from_header(&headers, &db).await {
-        Some(id) => {
-            if !is_admin(&db, &id).await {
-                return (StatusCode::UNAUTHORIZED).into_response();
-            }
-        }
-        None => return (StatusCode::UNAUTHORIZED).into_response(),
-    }
     match model::delete_tag(&name, &db).await {
         Ok(_) => (StatusCode::NO_CONTENT).into_response(),
         Err(_) => (StatusCode::BAD_REQUEST).into_response(),
@@ -99,13 +80,8 @@ pub async fn delete_tag(
         (status = 401, description = "Unauthorized"),
     )
 )]
-pub async fn get_tags(headers: HeaderMap, State(db): State<PgPool>) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => return (StatusCode::UNAUTHORIZED).into_response(),
-    };
-
-    match model::get_tags(&db, &user_id).await {
+pub async fn get_tags(user: AuthUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::get_tags(&db, &user.id, user.is_admin()).await {
         Ok(tags) => (StatusCode::OK, Json(tags)).into_response(),
         Err(_) => (StatusCode::BAD_REQUEST).into_response(),
     }
@@ -128,7 +104,7 @@ mod tests {
     #[sqlx::test(fixtures("users", "tags"))]
     async fn test_new_tag(pool: PgPool) {
         let router = init_app(&pool);
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
 
         // POST /tags (unauthorized)
         let req: Request<Body> = Request::builder()
@@ -188,7 +164,7 @@ mod tests {
         assert_eq!(res.status(), 401);
 
         // POST /tags/test_tag (with token but not admin)
-        let user_cookie = token_cookie_from_user_id("user_id");
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
         let req = Request::builder()
             .uri("/tags/test_tag")
             .method(Method::PUT)
@@ -207,7 +183,7 @@ mod tests {
         assert_eq!(res.status(), 401);
 
         // POST /tags/test (authorized)
-        let cookie = token_cookie_from_user_id("admin_id");
+        let cookie = token_cookie_from_user_id("admin_id", &pool).await;
         let req = Request::builder()
             .uri("/tags/test_tag")
             .method(Method::PUT)
@@ -240,7 +216,7 @@ mod tests {
         assert_eq!(res.status(), 401);
 
         // DELETE /tags/test_tag (with token but not admin)
-        let cookie = token_cookie_from_user_id("user_id");
+        let cookie = token_cookie_from_user_id("user_id", &pool).await;
         let req = Request::builder()
             .uri("/tags/test_tag")
             .method(Method::DELETE)
@@ -251,7 +227,7 @@ mod tests {
         assert_eq!(res.status(), 401);
 
         // DELETE /tags/test_tag (authorized)
-        let cookie = token_cookie_from_user_id("admin_id");
+        let cookie = token_cookie_from_user_id("admin_id", &pool).await;
         let req = Request::builder()
             .uri("/tags/test_tag")
             .method(Method::DELETE)
@@ -276,7 +252,7 @@ mod tests {
         assert_eq!(res.status(), 401);
 
         // GET /tags (with random token)
-        let some_user_token = token_cookie_from_user_id("some_user");
+        let some_user_token = token_cookie_from_user_id("some_user", &pool).await;
         let req = Request::builder()
             .uri("/tags")
             .method(Method::GET)
@@ -290,7 +266,7 @@ mod tests {
         assert_eq!(text, r#"[{"name":"test_tag","book_count":2}]"#);
 
         // GET /tags (with user token)
-        let user_token = token_cookie_from_user_id("user_id");
+        let user_token = token_cookie_from_user_id("user_id", &pool).await;
         let req = Request::builder()
             .uri("/tags")
             .method(Method::GET)
@@ -304,7 +280,7 @@ mod tests {
         assert_eq!(text, r#"[{"name":"test_tag","book_count":3}]"#);
 
         // GET /tags (with admin token)
-        let admin_token = token_cookie_from_user_id("admin_id");
+        let admin_token = token_cookie_from_user_id("admin_id", &pool).await;
         let req = Request::builder()
             .uri("/tags")
             .method(Method::GET)
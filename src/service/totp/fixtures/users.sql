@@ -0,0 +1,13 @@
+insert into
+    users(id, "password", role)
+values
+    (
+        'user_id',
+        'user_password',
+        'user'
+    ),
+    (
+        'admin_id',
+        'admin_password',
+        'admin'
+    );
@@ -0,0 +1,535 @@
+use std::env;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::service::user::model::UserRole;
+
+/// TOTPの1ステップの秒数
+const STEP: u64 = 30;
+/// 発行するリカバリーコードの数
+const RECOVERY_CODE_COUNT: usize = 10;
+/// ログインチャレンジの有効期限(分)
+const CHALLENGE_TTL_MINUTES: i32 = 5;
+/// ログインチャレンジで許可する失敗回数
+const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// `POST /users/totp` のレスポンス
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TotpEnrollmentResponse {
+    /// Base32でエンコードされた秘密鍵
+    pub secret: String,
+    /// 認証アプリに登録するためのURI(otpauth://)
+    pub provisioning_uri: String,
+}
+
+/// TOTPのコードを送るリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TotpCodeRequest {
+    /// TOTPのコードもしくはリカバリーコード
+    pub code: String,
+}
+
+/// リカバリーコードのレスポンス
+///
+/// リカバリーコードはこのレスポンスでのみ返される
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+/// 二段階目の認証が必要な場合の `POST /login` のレスポンス
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LoginChallengeResponse {
+    /// `POST /login/totp` に送るトークン
+    pub challenge_token: String,
+    /// TOTPの登録が必要かどうか
+    ///
+    /// trueの場合は `POST /login/totp/enroll` で登録してから `POST /login/totp` を実行する
+    pub enrollment_required: bool,
+}
+
+/// `POST /login/totp/enroll` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LoginTotpEnrollRequest {
+    pub challenge_token: String,
+}
+
+/// `POST /login/totp` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct LoginTotpRequest {
+    pub challenge_token: String,
+    /// TOTPのコードもしくはリカバリーコード
+    pub code: String,
+}
+
+/// ロールごとのポリシー
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RolePolicy {
+    #[schema(inline)]
+    pub role: UserRole,
+    pub require_totp: bool,
+}
+
+/// `PUT /role_policies/{role}` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct UpdateRolePolicyRequest {
+    pub require_totp: bool,
+}
+
+/// ログイン時に必要な二段階目の認証
+#[derive(Debug, PartialEq)]
+pub enum SecondFactor {
+    /// 不要
+    None,
+    /// TOTPの確認が必要
+    Verify,
+    /// ロールのポリシーによりTOTPの登録が必要
+    Enroll,
+}
+
+/// ログインチャレンジの検証結果
+pub struct CompletedChallenge {
+    pub user_id: String,
+    pub user_agent: Option<String>,
+    /// ログイン中にTOTPを登録した場合に発行したリカバリーコード
+    pub recovery_codes: Option<Vec<String>>,
+}
+
+/// トークンをハッシュ化する
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// 秘密鍵からTOTPを作成する
+fn totp(secret: &str, user_id: &str) -> Result<TOTP, String> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| e.to_string())?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        0,
+        STEP,
+        secret,
+        Some(env::var("TOTP_ISSUER").unwrap_or("epubapi".to_string())),
+        user_id.to_string(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// コードが一致するステップを返す
+///
+/// 時計のずれを考慮して前後1ステップまで許容する
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let current = Utc::now().timestamp() / STEP as i64;
+    (current - 1..=current + 1).find(|step| {
+        totp.generate(*step as u64 * STEP)
+            .as_bytes()
+            .ct_eq(code.as_bytes())
+            .into()
+    })
+}
+
+/// TOTPの登録を開始する
+///
+/// 登録済みの場合はエラーを返す。登録中の秘密鍵は新しいものに置き換える
+pub async fn begin_enrollment(
+    user_id: &str,
+    db: &PgPool,
+) -> Result<TotpEnrollmentResponse, String> {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+    let secret = Secret::Raw(bytes.to_vec()).to_encoded().to_string();
+    let totp = totp(&secret, user_id)?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO user_totp (user_id, secret)
+            VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = EXCLUDED.secret, created_at = now(), last_used_step = NULL
+            WHERE user_totp.enabled_at IS NULL
+            RETURNING user_id
+        "#,
+        user_id,
+        secret
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("TOTPは登録済みです")?;
+
+    Ok(TotpEnrollmentResponse {
+        provisioning_uri: totp.get_url(),
+        secret,
+    })
+}
+
+/// TOTPのコードもしくはリカバリーコードを検証する
+///
+/// 登録中の秘密鍵は `pending` がtrueの場合のみ使用する。使用したコードは再利用できない
+async fn verify_code(
+    user_id: &str,
+    code: &str,
+    pending: bool,
+    conn: &mut PgConnection,
+) -> Result<(), String> {
+    let code = code.trim();
+    let secret = sqlx::query!(
+        r#"SELECT secret, last_used_step, enabled_at IS NOT NULL as "enabled!" FROM user_totp WHERE user_id = $1"#,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?
+    .filter(|row| row.enabled != pending)
+    .ok_or("TOTPが登録されていません")?;
+
+    if let Some(step) = matching_step(&totp(&secret.secret, user_id)?, code) {
+        // 同じステップのコードは再利用できない
+        let updated = sqlx::query!(
+            r#"
+                UPDATE user_totp
+                SET last_used_step = $1
+                WHERE user_id = $2 AND (last_used_step IS NULL OR last_used_step < $1)
+                RETURNING user_id
+            "#,
+            step,
+            user_id
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+        return updated
+            .map(|_| ())
+            .ok_or("使用済みのコードです".to_string());
+    }
+
+    // リカバリーコードは登録済みの場合のみ使用できる
+    if !pending {
+        let used = sqlx::query!(
+            r#"
+                UPDATE recovery_codes
+                SET used_at = now()
+                WHERE code_hash = $1 AND user_id = $2 AND used_at IS NULL
+                RETURNING code_hash
+            "#,
+            hash_token(code),
+            user_id
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+        if used.is_some() {
+            return Ok(());
+        }
+    }
+
+    Err("コードが一致しません".to_string())
+}
+
+/// リカバリーコードを発行する
+///
+/// それまでのリカバリーコードは使用できなくなる
+async fn issue_recovery_codes(
+    user_id: &str,
+    conn: &mut PgConnection,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query!(r#"DELETE FROM recovery_codes WHERE user_id = $1"#, user_id)
+        .execute(&mut *conn)
+        .await?;
+    let codes = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &code[..5], &code[5..10])
+        })
+        .collect::<Vec<_>>();
+    let hashes = codes.iter().map(|c| hash_token(c)).collect::<Vec<_>>();
+    sqlx::query!(
+        r#"
+            INSERT INTO recovery_codes (code_hash, user_id)
+            SELECT unnest($1::text[]), $2
+        "#,
+        &hashes,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(codes)
+}
+
+/// 登録中のTOTPをコードで確認して有効にし、リカバリーコードを返す
+pub async fn confirm_enrollment(
+    user_id: &str,
+    code: &str,
+    db: &PgPool,
+) -> Result<Vec<String>, String> {
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    let codes = confirm_enrollment_in(user_id, code, &mut transaction).await?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+    Ok(codes)
+}
+
+async fn confirm_enrollment_in(
+    user_id: &str,
+    code: &str,
+    conn: &mut PgConnection,
+) -> Result<Vec<String>, String> {
+    verify_code(user_id, code, true, conn).await?;
+    sqlx::query!(
+        r#"UPDATE user_totp SET enabled_at = now() WHERE user_id = $1"#,
+        user_id
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|e| e.to_string())?;
+    issue_recovery_codes(user_id, conn)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// TOTPを無効にする
+///
+/// ロールのポリシーでTOTPが必須の場合は無効にできない
+pub async fn disable(user_id: &str, code: &str, db: &PgPool) -> Result<(), String> {
+    if totp_required_for_user(user_id, db)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        return Err("このロールではTOTPを無効にできません".to_string());
+    }
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    verify_code(user_id, code, false, &mut transaction).await?;
+    sqlx::query!(r#"DELETE FROM user_totp WHERE user_id = $1"#, user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    sqlx::query!(r#"DELETE FROM recovery_codes WHERE user_id = $1"#, user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())
+}
+
+/// リカバリーコードを再発行する
+pub async fn regenerate_recovery_codes(
+    user_id: &str,
+    code: &str,
+    db: &PgPool,
+) -> Result<Vec<String>, String> {
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    verify_code(user_id, code, false, &mut transaction).await?;
+    let codes = issue_recovery_codes(user_id, &mut transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+    Ok(codes)
+}
+
+/// ユーザーのロールでTOTPが必須かどうかを返す
+async fn totp_required_for_user(user_id: &str, db: &PgPool) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT COALESCE(p.require_totp, false) as "require_totp!"
+            FROM users u
+            LEFT JOIN role_policies p ON p.role = u.role
+            WHERE u.id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// パスワード認証後に必要な二段階目の認証を返す
+pub async fn second_factor(user_id: &str, db: &PgPool) -> Result<SecondFactor, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+            SELECT
+                EXISTS (
+                    SELECT 1 FROM user_totp WHERE user_id = u.id AND enabled_at IS NOT NULL
+                ) as "enabled!",
+                COALESCE(p.require_totp, false) as "required!"
+            FROM users u
+            LEFT JOIN role_policies p ON p.role = u.role
+            WHERE u.id = $1
+        "#,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(match (row.enabled, row.required) {
+        (true, _) => SecondFactor::Verify,
+        (false, true) => SecondFactor::Enroll,
+        (false, false) => SecondFactor::None,
+    })
+}
+
+/// ログインチャレンジを作成してトークンを返す
+pub async fn create_challenge(
+    user_id: &str,
+    user_agent: Option<&str>,
+    db: &PgPool,
+) -> Result<String, sqlx::Error> {
+    let token = Uuid::new_v4().simple().to_string();
+    sqlx::query!(
+        r#"
+            INSERT INTO login_challenges (token_hash, user_id, user_agent, expires_at)
+            VALUES ($1, $2, $3, now() + make_interval(mins => $4))
+        "#,
+        hash_token(&token),
+        user_id,
+        user_agent,
+        CHALLENGE_TTL_MINUTES
+    )
+    .execute(db)
+    .await?;
+    Ok(token)
+}
+
+/// 有効なログインチャレンジのユーザーIDを返す
+pub async fn challenge_user_id(token: &str, db: &PgPool) -> Result<String, String> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT user_id
+            FROM login_challenges
+            WHERE token_hash = $1 AND expires_at > now() AND attempts < $2
+        "#,
+        hash_token(token),
+        MAX_CHALLENGE_ATTEMPTS
+    )
+    .fetch_optional(db)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("無効なトークンです".to_string())
+}
+
+/// 有効なログインチャレンジの試行回数を増やして、ユーザーIDを返す
+///
+/// 行をロックしてから検証前に数えるため、同時に送られたコードも上限を超えて試行できない
+async fn claim_challenge_attempt(token: &str, conn: &mut PgConnection) -> Result<String, String> {
+    sqlx::query_scalar!(
+        r#"
+            UPDATE login_challenges
+            SET attempts = attempts + 1
+            WHERE token_hash = $1 AND expires_at > now() AND attempts < $2
+            RETURNING user_id
+        "#,
+        hash_token(token),
+        MAX_CHALLENGE_ATTEMPTS
+    )
+    .fetch_optional(conn)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("無効なトークンです".to_string())
+}
+
+/// ログインチャレンジ中にTOTPの登録を開始する
+///
+/// ロールのポリシーでTOTPが必須で、未登録のユーザーのみ実行できる
+pub async fn begin_enrollment_for_challenge(
+    token: &str,
+    db: &PgPool,
+) -> Result<TotpEnrollmentResponse, String> {
+    let user_id = challenge_user_id(token, db).await?;
+    if second_factor(&user_id, db)
+        .await
+        .map_err(|e| e.to_string())?
+        != SecondFactor::Enroll
+    {
+        return Err("TOTPの登録は不要です".to_string());
+    }
+    begin_enrollment(&user_id, db).await
+}
+
+/// ログインチャレンジのコードを検証する
+///
+/// 成功するとチャレンジは削除される。失敗した回数が上限に達したチャレンジは使用できない
+pub async fn complete_challenge(
+    token: &str,
+    code: &str,
+    db: &PgPool,
+) -> Result<CompletedChallenge, String> {
+    // 試行回数の記録は検証に失敗しても残す
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    let user_id = claim_challenge_attempt(token, &mut transaction).await?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+
+    let result = match second_factor(&user_id, db)
+        .await
+        .map_err(|e| e.to_string())?
+    {
+        SecondFactor::Enroll => confirm_enrollment_in(&user_id, code, &mut transaction)
+            .await
+            .map(Some),
+        _ => verify_code(&user_id, code, false, &mut transaction)
+            .await
+            .map(|_| None),
+    };
+    let recovery_codes = result?;
+
+    // 同じチャレンジで同時に成功した場合は、先に削除した方のみログインできる
+    let user_agent = sqlx::query_scalar!(
+        r#"DELETE FROM login_challenges WHERE token_hash = $1 RETURNING user_agent"#,
+        hash_token(token)
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("無効なトークンです")?;
+    transaction.commit().await.map_err(|e| e.to_string())?;
+
+    Ok(CompletedChallenge {
+        user_id,
+        user_agent,
+        recovery_codes,
+    })
+}
+
+/// ロールごとのポリシーの一覧を取得する
+pub async fn get_role_policies(db: &PgPool) -> Result<Vec<RolePolicy>, sqlx::Error> {
+    sqlx::query_as!(
+        RolePolicy,
+        r#"
+            SELECT
+                r.role as "role!: UserRole",
+                COALESCE(p.require_totp, false) as "require_totp!"
+            FROM unnest(enum_range(NULL::user_role)) as r(role)
+            LEFT JOIN role_policies p ON p.role = r.role
+            ORDER BY r.role
+        "#
+    )
+    .fetch_all(db)
+    .await
+}
+
+/// ロールのポリシーを更新する
+pub async fn update_role_policy(
+    role: UserRole,
+    req: UpdateRolePolicyRequest,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO role_policies (role, require_totp)
+            VALUES ($1, $2)
+            ON CONFLICT (role) DO UPDATE SET require_totp = EXCLUDED.require_totp
+        "#,
+        role as UserRole,
+        req.require_totp
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
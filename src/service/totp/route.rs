@@ -0,0 +1,653 @@
+use axum::{
+    extract::{Path, State},
+    http::{header::SET_COOKIE, StatusCode},
+    response::{AppendHeaders, IntoResponse},
+    Json,
+};
+use sqlx::PgPool;
+
+use super::model;
+use crate::{
+    service::user::{
+        auth::{AccountUser, AdminUser},
+        model::{create_session, UserError, UserRole},
+        route::token_cookie,
+    },
+    throttle::{self, too_many_requests, ClientIp, ThrottleKey},
+};
+
+/// TOTPの登録を開始する
+///
+/// `POST /users/totp/verify` でコードを確認するまでは有効にならない
+#[utoipa::path(
+    post,
+    path = "/users/totp",
+    responses(
+        (status = 200, description = "OK", body = inline(model::TotpEnrollmentResponse)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidTotp(String::from("TOTPは登録済みです")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn enroll_totp(
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::begin_enrollment(&user.id, &db).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(UserError::InvalidTotp(e))).into_response(),
+    }
+}
+
+/// TOTPのコードを確認して登録を完了する
+///
+/// リカバリーコードはこのレスポンスでのみ返される
+#[utoipa::path(
+    post,
+    path = "/users/totp/verify",
+    request_body = inline(model::TotpCodeRequest),
+    responses(
+        (status = 200, description = "OK", body = inline(model::RecoveryCodesResponse)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidTotp(String::from("コードが一致しません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn verify_totp(
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::TotpCodeRequest>,
+) -> impl IntoResponse {
+    match model::confirm_enrollment(&user.id, &body.code, &db).await {
+        Ok(recovery_codes) => (
+            StatusCode::OK,
+            Json(model::RecoveryCodesResponse { recovery_codes }),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(UserError::InvalidTotp(e))).into_response(),
+    }
+}
+
+/// TOTPを無効にする
+///
+/// TOTPのコードもしくはリカバリーコードが必要
+#[utoipa::path(
+    delete,
+    path = "/users/totp",
+    request_body = inline(model::TotpCodeRequest),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidTotp(String::from("このロールではTOTPを無効にできません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn disable_totp(
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::TotpCodeRequest>,
+) -> impl IntoResponse {
+    match model::disable(&user.id, &body.code, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(UserError::InvalidTotp(e))).into_response(),
+    }
+}
+
+/// リカバリーコードを再発行する
+///
+/// それまでのリカバリーコードは使用できなくなる
+#[utoipa::path(
+    post,
+    path = "/users/totp/recovery_codes",
+    request_body = inline(model::TotpCodeRequest),
+    responses(
+        (status = 200, description = "OK", body = inline(model::RecoveryCodesResponse)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidTotp(String::from("コードが一致しません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("missing credentials")))),
+    )
+)]
+pub async fn regenerate_recovery_codes(
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::TotpCodeRequest>,
+) -> impl IntoResponse {
+    match model::regenerate_recovery_codes(&user.id, &body.code, &db).await {
+        Ok(recovery_codes) => (
+            StatusCode::OK,
+            Json(model::RecoveryCodesResponse { recovery_codes }),
+        )
+            .into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(UserError::InvalidTotp(e))).into_response(),
+    }
+}
+
+/// ログイン中にTOTPの登録を開始する
+///
+/// ロールのポリシーでTOTPが必須で、未登録のユーザーのみ実行できる
+#[utoipa::path(
+    post,
+    path = "/login/totp/enroll",
+    request_body = inline(model::LoginTotpEnrollRequest),
+    responses(
+        (status = 200, description = "OK", body = inline(model::TotpEnrollmentResponse)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidTotp(String::from("無効なトークンです")))),
+    )
+)]
+pub async fn login_totp_enroll(
+    State(db): State<PgPool>,
+    Json(body): Json<model::LoginTotpEnrollRequest>,
+) -> impl IntoResponse {
+    match model::begin_enrollment_for_challenge(&body.challenge_token, &db).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(UserError::InvalidTotp(e))).into_response(),
+    }
+}
+
+/// TOTPのコードもしくはリカバリーコードでログインを完了する
+///
+/// ログイン中にTOTPを登録した場合は、リカバリーコードを返す。
+/// 失敗が続いているIPアドレス・アカウントは `POST /login` と同じく一定時間実行できない
+#[utoipa::path(
+    post,
+    path = "/login/totp",
+    request_body = inline(model::LoginTotpRequest),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 200, description = "TOTPを登録した場合", body = inline(model::RecoveryCodesResponse)),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(UserError::InvalidTotp(String::from("コードが一致しません")))),
+        (status = 429, description = "Too Many Requests", body = inline(UserError), example = json!(UserError::TooManyRequests(String::from("60秒後に再試行してください")))),
+    )
+)]
+pub async fn login_totp(
+    ClientIp(ip): ClientIp,
+    State(db): State<PgPool>,
+    Json(body): Json<model::LoginTotpRequest>,
+) -> impl IntoResponse {
+    let user_id = model::challenge_user_id(&body.challenge_token, &db)
+        .await
+        .ok();
+    let mut keys = vec![ThrottleKey::TotpIp(&ip)];
+    if let Some(user_id) = user_id.as_deref() {
+        keys.push(ThrottleKey::TotpAccount(user_id));
+    }
+    match throttle::retry_after(&keys, &db).await {
+        Ok(Some(retry_after)) => return too_many_requests(retry_after),
+        Ok(None) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+
+    let challenge = match model::complete_challenge(&body.challenge_token, &body.code, &db).await {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            if let Err(e) = throttle::record_failure(&keys, &db).await {
+                log::error!("Failed to record totp failure: {}", e);
+            }
+            return (StatusCode::BAD_REQUEST, Json(UserError::InvalidTotp(e))).into_response();
+        }
+    };
+    if let Err(e) = throttle::reset(&[ThrottleKey::TotpAccount(&challenge.user_id)], &db).await {
+        log::error!("Failed to reset totp failures: {}", e);
+    }
+
+    let jwt = match create_session(&challenge.user_id, challenge.user_agent.as_deref(), &db).await {
+        Ok(jwt) => jwt,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    let cookie = AppendHeaders([(SET_COOKIE, token_cookie(&jwt, 3600 * 24 * 30))]);
+
+    match challenge.recovery_codes {
+        Some(recovery_codes) => (
+            StatusCode::OK,
+            cookie,
+            Json(model::RecoveryCodesResponse { recovery_codes }),
+        )
+            .into_response(),
+        None => (StatusCode::NO_CONTENT, cookie).into_response(),
+    }
+}
+
+/// ロールごとのポリシーを取得する
+///
+/// 管理者のみ実行可能
+#[utoipa::path(
+    get,
+    path = "/role_policies",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::RolePolicy>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn get_role_policies(_: AdminUser, State(db): State<PgPool>) -> impl IntoResponse {
+    match model::get_role_policies(&db).await {
+        Ok(policies) => (StatusCode::OK, Json(policies)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// ロールのポリシーを更新する
+///
+/// require_totpをtrueにすると、そのロールのユーザーはログイン時にTOTPが必須になる。
+/// 管理者のみ実行可能
+#[utoipa::path(
+    put,
+    path = "/role_policies/{role}",
+    request_body = inline(model::UpdateRolePolicyRequest),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(UserError::Unauthorized(String::from("admin only")))),
+    )
+)]
+pub async fn update_role_policy(
+    _: AdminUser,
+    Path(role): Path<UserRole>,
+    State(db): State<PgPool>,
+    Json(body): Json<model::UpdateRolePolicyRequest>,
+) -> impl IntoResponse {
+    match model::update_role_policy(role, body, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Method, Request},
+        Router,
+    };
+    use chrono::Utc;
+    use serde_json::{json, to_string, Value};
+    use sqlx::PgPool;
+    use totp_rs::{Algorithm, Secret, TOTP};
+    use tower::ServiceExt;
+
+    use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
+
+    /// 秘密鍵から `step` ステップ後のコードを生成する
+    fn code(secret: &str, step: u64) -> String {
+        let totp = TOTP::new_unchecked(
+            Algorithm::SHA1,
+            6,
+            0,
+            30,
+            Secret::Encoded(secret.to_string()).to_bytes().unwrap(),
+            None,
+            String::new(),
+        );
+        totp.generate(Utc::now().timestamp() as u64 + step * 30)
+    }
+
+    async fn send(router: &Router, req: Request<Body>) -> (u16, Option<String>, Value) {
+        let res = router.clone().oneshot(req).await.unwrap();
+        let status = res.status().as_u16();
+        let cookie = res
+            .headers()
+            .get(header::SET_COOKIE)
+            .map(|c| c.to_str().unwrap().to_string());
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, cookie, body)
+    }
+
+    fn json_request(method: Method, uri: &str, cookie: Option<&str>, body: Value) -> Request<Body> {
+        let mut req = Request::builder()
+            .uri(uri)
+            .method(method)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref());
+        if let Some(cookie) = cookie {
+            req = req.header(header::COOKIE, cookie);
+        }
+        req.body(Body::from(to_string(&body).unwrap())).unwrap()
+    }
+
+    fn login_request() -> Request<Body> {
+        json_request(
+            Method::POST,
+            "/login",
+            None,
+            json!({"id": "user_id", "password": "user_password"}),
+        )
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_totp(pool: PgPool) {
+        let router = init_app(&pool);
+        let cookie = token_cookie_from_user_id("user_id", &pool).await;
+
+        // 登録開始
+        let (status, _, body) = send(
+            &router,
+            json_request(Method::POST, "/users/totp", Some(&cookie), json!({})),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(body["provisioning_uri"]
+            .as_str()
+            .unwrap()
+            .starts_with("otpauth://totp/"));
+        let secret = body["secret"].as_str().unwrap().to_string();
+
+        // 登録前はパスワードのみでログインできる
+        let (status, cookie_header, _) = send(&router, login_request()).await;
+        assert_eq!(status, 204);
+        assert!(cookie_header.is_some());
+
+        // 登録完了 コードが違う場合
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/users/totp/verify",
+                Some(&cookie),
+                json!({"code": "000000x"}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // 登録完了
+        let (status, _, body) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/users/totp/verify",
+                Some(&cookie),
+                json!({"code": code(&secret, 0)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let recovery_codes = body["recovery_codes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|c| c.as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(recovery_codes.len(), 10);
+
+        // 登録済みの場合は登録を開始できない
+        let (status, _, _) = send(
+            &router,
+            json_request(Method::POST, "/users/totp", Some(&cookie), json!({})),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // 登録後はパスワードだけではセッションが作成されない
+        let (status, cookie_header, body) = send(&router, login_request()).await;
+        assert_eq!(status, 202);
+        assert!(cookie_header.is_none());
+        assert_eq!(body["enrollment_required"], false);
+        let challenge_token = body["challenge_token"].as_str().unwrap().to_string();
+
+        // 使用済みのコードは使用できない
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": code(&secret, 0)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // TOTPのコードでログイン
+        let (status, cookie_header, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": code(&secret, 1)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+        assert!(cookie_header.unwrap().contains("token="));
+
+        // 完了したチャレンジは再利用できない
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": recovery_codes[0]}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // リカバリーコードでログイン
+        let (_, _, body) = send(&router, login_request()).await;
+        let challenge_token = body["challenge_token"].as_str().unwrap().to_string();
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": recovery_codes[0]}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+
+        // 使用済みのリカバリーコードは使用できない
+        let (_, _, body) = send(&router, login_request()).await;
+        let challenge_token = body["challenge_token"].as_str().unwrap().to_string();
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": recovery_codes[0]}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // TOTPを無効にする
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::DELETE,
+                "/users/totp",
+                Some(&cookie),
+                json!({"code": recovery_codes[1]}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+        let (status, _, _) = send(&router, login_request()).await;
+        assert_eq!(status, 204);
+    }
+
+    /// 同時に送られたコードも試行回数の上限を超えて検証されないことのテスト
+    #[sqlx::test(fixtures("users"))]
+    async fn test_login_totp_attempts(pool: PgPool) {
+        let router = init_app(&pool);
+        let cookie = token_cookie_from_user_id("user_id", &pool).await;
+        let (_, _, body) = send(
+            &router,
+            json_request(Method::POST, "/users/totp", Some(&cookie), json!({})),
+        )
+        .await;
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/users/totp/verify",
+                Some(&cookie),
+                json!({"code": code(&secret, 0)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+
+        let (_, _, body) = send(&router, login_request()).await;
+        let challenge_token = body["challenge_token"].as_str().unwrap().to_string();
+        let results = futures::future::join_all((0..10).map(|i| {
+            send(
+                &router,
+                json_request(
+                    Method::POST,
+                    "/login/totp",
+                    None,
+                    json!({"challenge_token": challenge_token, "code": format!("{:06}", i)}),
+                ),
+            )
+        }))
+        .await;
+        assert!(results
+            .iter()
+            .all(|(status, _, _)| *status == 400 || *status == 429));
+        let attempts = sqlx::query_scalar!("SELECT attempts FROM login_challenges")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(attempts <= 5);
+
+        // 失敗が続いたため制限される
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": code(&secret, 1)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 429);
+
+        // 制限が解除されても、上限に達したチャレンジは使用できない
+        sqlx::query!("UPDATE throttles SET locked_until = NULL")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query!("UPDATE login_challenges SET attempts = 5")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": code(&secret, 1)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_role_policy(pool: PgPool) {
+        let router = init_app(&pool);
+        let admin_cookie = token_cookie_from_user_id("admin_id", &pool).await;
+        let user_cookie = token_cookie_from_user_id("user_id", &pool).await;
+
+        // 管理者以外は更新できない
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::PUT,
+                "/role_policies/User",
+                Some(&user_cookie),
+                json!({"require_totp": true}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 401);
+
+        // userロールでTOTPを必須にする
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::PUT,
+                "/role_policies/User",
+                Some(&admin_cookie),
+                json!({"require_totp": true}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+        let req = Request::builder()
+            .uri("/role_policies")
+            .method(Method::GET)
+            .header(header::COOKIE, &admin_cookie)
+            .body(Body::empty())
+            .unwrap();
+        let (status, _, body) = send(&router, req).await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            body,
+            json!([
+                {"role": "Admin", "require_totp": false},
+                {"role": "User", "require_totp": true}
+            ])
+        );
+
+        // 未登録の場合はログイン中に登録する
+        let (status, cookie_header, body) = send(&router, login_request()).await;
+        assert_eq!(status, 202);
+        assert!(cookie_header.is_none());
+        assert_eq!(body["enrollment_required"], true);
+        let challenge_token = body["challenge_token"].as_str().unwrap().to_string();
+        let (status, _, body) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp/enroll",
+                None,
+                json!({"challenge_token": challenge_token}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        let secret = body["secret"].as_str().unwrap().to_string();
+        let (status, cookie_header, body) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login/totp",
+                None,
+                json!({"challenge_token": challenge_token, "code": code(&secret, 0)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 200);
+        assert!(cookie_header.unwrap().contains("token="));
+        assert_eq!(body["recovery_codes"].as_array().unwrap().len(), 10);
+
+        // 必須のロールではTOTPを無効にできない
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::DELETE,
+                "/users/totp",
+                Some(&user_cookie),
+                json!({"code": code(&secret, 1)}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 400);
+
+        // 管理者ロールは必須ではない
+        let (status, _, _) = send(
+            &router,
+            json_request(
+                Method::POST,
+                "/login",
+                None,
+                json!({"id": "admin_id", "password": "admin_password"}),
+            ),
+        )
+        .await;
+        assert_eq!(status, 204);
+    }
+}
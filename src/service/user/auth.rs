@@ -0,0 +1,225 @@
+use axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{
+        header::{AUTHORIZATION, COOKIE},
+        request::Parts,
+        Method, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use cookie::Cookie;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::model::{decode_token, varify_token, UserError, UserRole};
+use crate::service::api_key::model::{find_api_key_owner, ApiKeyScope};
+
+/// 認証済みのユーザー
+///
+/// 以下の順に最初に見つかった認証情報のみを使用する
+///
+/// 1. Cookieの `token`
+/// 2. `Authorization: Bearer` (JWTもしくはAPIキー)
+/// 3. `X-Api-Key`
+///
+/// APIキーの場合、GET・HEADは `read`、それ以外は `write` スコープが必要
+pub struct AuthUser {
+    pub id: String,
+    pub role: UserRole,
+    /// 使用したセッションのID(APIキーの場合は `None`)
+    pub session_id: Option<Uuid>,
+    /// 使用したAPIキーのスコープ(セッションの場合は `None`)
+    pub scopes: Option<Vec<ApiKeyScope>>,
+}
+
+impl AuthUser {
+    /// `scope` の操作が許可されているかを返す
+    ///
+    /// セッションはすべての操作が許可される
+    pub fn allows(&self, scope: ApiKeyScope) -> bool {
+        match &self.scopes {
+            Some(scopes) => scopes.iter().any(|s| s.allows(scope)),
+            None => true,
+        }
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == UserRole::Admin
+    }
+}
+
+/// 管理者ロールのユーザー
+///
+/// APIキーの場合は `admin` スコープが必要
+pub struct AdminUser(pub AuthUser);
+
+/// bookをアップロードできるユーザー
+///
+/// APIキーの場合は `upload` スコープが必要
+pub struct UploadUser(pub AuthUser);
+
+/// パスワード・セッション・APIキーなどのアカウント情報を管理できるユーザー
+///
+/// APIキーの場合は `admin` スコープが必要
+pub struct AccountUser(pub AuthUser);
+
+/// 認証に失敗した場合のエラー
+///
+/// 常に401と `UserError::Unauthorized` を返す
+#[derive(Debug)]
+pub struct AuthError(&'static str);
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNAUTHORIZED,
+            Json(UserError::Unauthorized(self.0.to_string())),
+        )
+            .into_response()
+    }
+}
+
+/// リクエストに含まれる認証情報
+enum Credential {
+    Token(String),
+    ApiKey(String),
+}
+
+/// ヘッダーから認証情報を取り出す
+fn credential_from_parts(parts: &Parts) -> Result<Credential, AuthError> {
+    for cookie in parts.headers.get_all(COOKIE) {
+        let cookie = cookie
+            .to_str()
+            .map_err(|_| AuthError("invalid cookie header"))?;
+        if let Some(token) = Cookie::split_parse(cookie)
+            .filter_map(|c| c.ok())
+            .find(|c| c.name() == "token")
+        {
+            return Ok(Credential::Token(token.value().to_string()));
+        }
+    }
+
+    if let Some(authorization) = parts.headers.get(AUTHORIZATION) {
+        let token = authorization
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(AuthError("invalid authorization header"))?;
+        return match decode_token(token) {
+            Some(_) => Ok(Credential::Token(token.to_string())),
+            None => Ok(Credential::ApiKey(token.to_string())),
+        };
+    }
+
+    if let Some(api_key) = parts.headers.get("X-Api-Key") {
+        let api_key = api_key
+            .to_str()
+            .map_err(|_| AuthError("invalid api key header"))?;
+        return Ok(Credential::ApiKey(api_key.to_string()));
+    }
+
+    Err(AuthError("missing credentials"))
+}
+
+/// 認証情報を検証して、`scope` の操作が許可されていればユーザーを返す
+async fn authenticate(
+    parts: &Parts,
+    db: &PgPool,
+    scope: ApiKeyScope,
+) -> Result<AuthUser, AuthError> {
+    let user = match credential_from_parts(parts)? {
+        Credential::Token(token) => {
+            let owner = varify_token(&token, db)
+                .await
+                .ok_or(AuthError("invalid token"))?;
+            AuthUser {
+                id: owner.user_id,
+                role: owner.role,
+                session_id: Some(owner.session_id),
+                scopes: None,
+            }
+        }
+        Credential::ApiKey(key) => {
+            let owner = find_api_key_owner(&key, db)
+                .await
+                .ok_or(AuthError("invalid api key"))?;
+            AuthUser {
+                id: owner.user_id,
+                role: owner.role,
+                session_id: None,
+                scopes: Some(owner.scopes),
+            }
+        }
+    };
+    if !user.allows(scope) {
+        return Err(AuthError("insufficient scope"));
+    }
+    Ok(user)
+}
+
+/// HTTPメソッドから必要なスコープを返す
+fn scope_for_method(method: &Method) -> ApiKeyScope {
+    match *method {
+        Method::GET | Method::HEAD => ApiKeyScope::Read,
+        _ => ApiKeyScope::Write,
+    }
+}
+
+impl<S> FromRequestParts<S> for AuthUser
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let scope = scope_for_method(&parts.method);
+        authenticate(parts, &PgPool::from_ref(state), scope).await
+    }
+}
+
+impl<S> FromRequestParts<S> for AdminUser
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let user = authenticate(parts, &PgPool::from_ref(state), ApiKeyScope::Admin).await?;
+        if !user.is_admin() {
+            return Err(AuthError("admin only"));
+        }
+        Ok(Self(user))
+    }
+}
+
+impl<S> FromRequestParts<S> for UploadUser
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        authenticate(parts, &PgPool::from_ref(state), ApiKeyScope::Upload)
+            .await
+            .map(Self)
+    }
+}
+
+impl<S> FromRequestParts<S> for AccountUser
+where
+    PgPool: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        authenticate(parts, &PgPool::from_ref(state), ApiKeyScope::Admin)
+            .await
+            .map(Self)
+    }
+}
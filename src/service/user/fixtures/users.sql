@@ -1,9 +1,31 @@
 insert into
-    users(id, "password", role, api_key)
+    users(id, "password", role)
 values
     (
         'used_id',
         'Test1234',
-        'user',
-        'used_test_api_key'
-    );
\ No newline at end of file
+        'user'
+    ),
+    (
+        'admin_id',
+        'admin_password',
+        'admin'
+    );
+
+insert into
+    api_keys(user_id, "name", key_hash, prefix, scopes)
+values
+    (
+        'used_id',
+        'default',
+        encode(sha256(convert_to('used_test_api_key', 'UTF8')), 'hex'),
+        left('used_test_api_key', 8),
+        '{admin}'
+    ),
+    (
+        'admin_id',
+        'default',
+        encode(sha256(convert_to('admin_api_key', 'UTF8')), 'hex'),
+        left('admin_api_key', 8),
+        '{admin}'
+    );
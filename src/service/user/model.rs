@@ -1,17 +1,23 @@
 use std::env;
 
-use chrono::Duration;
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use chrono::{DateTime, Duration, Utc};
 use cookie::Cookie;
 use jsonwebtoken::{encode, DecodingKey, EncodingKey, Validation};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use subtle::ConstantTimeEq;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::service::invitation::model::InvitationState;
+use crate::service::invitation::model::{add_user_to_group, redeem_reservation};
 
-#[derive(Serialize, Debug, sqlx::Type, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, sqlx::Type, ToSchema, PartialEq, Clone, Copy)]
 #[sqlx(type_name = "user_role", rename_all = "lowercase")]
 pub enum UserRole {
     Admin,
@@ -23,14 +29,37 @@ pub struct User {
     pub id: String,
     #[schema(inline)]
     pub role: UserRole,
-    pub api_key: String,
     pub invitations: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct Claims {
+pub struct Claims {
     id: String,
     exp: i64,
+    jti: Uuid,
+}
+
+/// 有効なセッションの所有者
+pub struct SessionOwner {
+    pub session_id: Uuid,
+    pub user_id: String,
+    pub role: UserRole,
+}
+
+/// ログイン中のセッション
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Session {
+    #[schema(value_type = String)]
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    #[schema(value_type = String, format = DateTime)]
+    pub created_at: DateTime<Utc>,
+    #[schema(value_type = String, format = DateTime)]
+    pub last_used_at: DateTime<Utc>,
+    #[schema(value_type = String, format = DateTime)]
+    pub expires_at: DateTime<Utc>,
+    /// リクエストに使用しているセッションかどうか
+    pub current: bool,
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -41,6 +70,30 @@ pub enum UserError {
     InvalidIdOrPassword(String),
     #[serde(rename = "invalid invitation code")]
     InvalidInvitationCode(String),
+    #[serde(rename = "invalid password")]
+    InvalidPassword(String),
+    #[serde(rename = "invalid reset token")]
+    InvalidResetToken(String),
+    #[serde(rename = "invalid api key")]
+    InvalidApiKey(String),
+    #[serde(rename = "oidc login failed")]
+    OidcLoginFailed(String),
+    #[serde(rename = "invalid totp")]
+    InvalidTotp(String),
+    #[serde(rename = "too many requests")]
+    TooManyRequests(String),
+    #[serde(rename = "invalid user operation")]
+    InvalidUserOperation(String),
+    #[serde(rename = "invalid invitation request")]
+    InvalidInvitationRequest(String),
+    #[serde(rename = "invalid book query")]
+    InvalidBookQuery(String),
+    #[serde(rename = "invalid book request")]
+    InvalidBookRequest(String),
+    #[serde(rename = "invalid collection request")]
+    InvalidCollectionRequest(String),
+    #[serde(rename = "invalid progress request")]
+    InvalidProgressRequest(String),
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -53,6 +106,8 @@ pub struct NewUserRequest {
     pub id: String,
     pub password: String,
     pub invitation_code: String,
+    /// `POST /check_invitation` で取得した予約トークン
+    pub reservation_token: String,
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -61,14 +116,36 @@ pub struct LoginRequest {
     pub password: String,
 }
 
-/// idとpasswordを受け取って、認証に成功したらJWTを返す
-pub async fn varify_password(db: PgPool, id: &str, password: &str) -> Result<String, String> {
+/// `PUT /users/password` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// `POST /users/{user_id}/password_reset` のレスポンス
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct PasswordResetTokenResponse {
+    pub token: String,
+    #[schema(value_type = String, format = DateTime)]
+    pub expires_at: DateTime<Utc>,
+}
+
+/// `POST /password_reset` のリクエストボディ
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// idとpasswordを受け取って、認証に成功したかを返す
+pub async fn varify_password(db: &PgPool, id: &str, password: &str) -> Result<(), String> {
     // ログイン処理
     let user = match sqlx::query!(
-        r#"SELECT id, password, role as "role: UserRole" FROM users WHERE id = $1"#,
+        r#"SELECT id, password, role as "role: UserRole", suspended_at IS NOT NULL as "suspended!" FROM users WHERE id = $1"#,
         id,
     )
-    .fetch_one(&db)
+    .fetch_one(db)
     .await
     {
         Ok(user) => user,
@@ -76,15 +153,71 @@ pub async fn varify_password(db: PgPool, id: &str, password: &str) -> Result<Str
             return Err(String::from("invalid id"));
         }
     };
-    if user.password != password {
+    if !password_matches(password, &user.password) {
         return Err(String::from("invalid password"));
     }
+    if user.suspended {
+        return Err(String::from("suspended account"));
+    }
+
+    // 平文で保存されているパスワードはログイン時にハッシュへ移行する
+    if PasswordHash::new(&user.password).is_err() {
+        if let Err(e) = sqlx::query!(
+            r#"UPDATE users SET password = $1 WHERE id = $2"#,
+            hash_password(password),
+            id,
+        )
+        .execute(db)
+        .await
+        {
+            log::error!("Failed to rehash password: {}", e);
+        }
+    }
+
+    Ok(())
+}
 
-    Ok(id_to_jwt(id))
+/// パスワードをArgon2idでハッシュ化する
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+/// パスワードが保存されている値と一致するかを返す
+///
+/// Argon2のハッシュとして読めない値は移行前の平文とみなし、定数時間で比較する
+fn password_matches(password: &str, stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => password.as_bytes().ct_eq(stored.as_bytes()).into(),
+    }
 }
 
-/// IDを受け取って、JWTを返す
-pub fn id_to_jwt(id: &str) -> String {
+/// セッションを作成して、そのセッションのJWTを返す
+pub async fn create_session(
+    user_id: &str,
+    user_agent: Option<&str>,
+    db: &PgPool,
+) -> Result<String, sqlx::Error> {
+    let expires_at = Utc::now() + Duration::days(30);
+    let session_id = sqlx::query_scalar!(
+        r#"
+            INSERT INTO sessions (user_id, user_agent, expires_at)
+            VALUES ($1, $2, $3)
+            RETURNING id
+        "#,
+        user_id,
+        user_agent,
+        expires_at
+    )
+    .fetch_one(db)
+    .await?;
+
     // JWTの生成
     let key = EncodingKey::from_secret(
         env::var("JWT_SECRET")
@@ -93,29 +226,48 @@ pub fn id_to_jwt(id: &str) -> String {
     );
     let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256);
     let claim = Claims {
-        id: id.to_string(),
-        exp: (chrono::Utc::now() + Duration::days(30)).timestamp(),
+        id: user_id.to_string(),
+        exp: expires_at.timestamp(),
+        jti: session_id,
     };
-    encode(&header, &claim, &key).unwrap()
+    Ok(encode(&header, &claim, &key).unwrap())
 }
 
-/// APIキーを受け取って、ユーザーIDを返す
-pub async fn get_user_id_by_api_key(api_key: &str, db: &PgPool) -> Result<String, sqlx::Error> {
-    sqlx::query!(
+/// JWTを受け取って、有効なセッションであればその所有者を返す
+pub async fn varify_token(token: &str, db: &PgPool) -> Option<SessionOwner> {
+    let claims = decode_token(token)?;
+    sqlx::query_as!(
+        SessionOwner,
         r#"
-            SELECT id
-            FROM users
-            WHERE api_key = $1
+            WITH s AS (
+                UPDATE sessions
+                SET last_used_at = now()
+                WHERE
+                    id = $1
+                    AND user_id = $2
+                    AND revoked_at IS NULL
+                    AND expires_at > now()
+                RETURNING id, user_id
+            )
+            SELECT
+                s.id as "session_id!",
+                u.id as "user_id!",
+                u.role as "role!: UserRole"
+            FROM s
+            JOIN users u ON u.id = s.user_id
+            WHERE u.suspended_at IS NULL
         "#,
-        api_key
+        claims.jti,
+        claims.id
     )
-    .fetch_one(db)
+    .fetch_optional(db)
     .await
-    .map(|row| row.id)
+    .ok()
+    .flatten()
 }
 
-/// JWTを受け取って、認証に成功したらユーザーIDを返す
-pub fn varify_token(token: &str) -> Option<String> {
+/// JWTを検証してClaimsを返す
+pub fn decode_token(token: &str) -> Option<Claims> {
     let validation = Validation::default();
     let key = DecodingKey::from_secret(
         env::var("JWT_SECRET")
@@ -123,99 +275,38 @@ pub fn varify_token(token: &str) -> Option<String> {
             .as_bytes(),
     );
     match jsonwebtoken::decode::<Claims>(token, &key, &validation) {
-        Ok(claim) => Some(claim.claims.id),
+        Ok(claim) => Some(claim.claims),
         Err(_) => None,
     }
 }
 
-/// HeaderMapからJWTを取り出して、認証に成功したらユーザーIDを返す
-pub async fn user_id_from_header(headers: &axum::http::HeaderMap, db: &PgPool) -> Option<String> {
-    if let Some(cookie) = headers.get("Cookie") {
-        let cookie = cookie.to_str().unwrap();
-        return Cookie::split_parse(cookie)
-            .find(|c| c.clone().unwrap().name() == "token")
-            .map(|c| c.unwrap().value().to_string())
-            .and_then(|token| varify_token(&token));
-    }
-    if let Some(api_key) = headers.get("X-Api-Key") {
-        let user_id = get_user_id_by_api_key(api_key.to_str().unwrap(), db).await;
-        return match user_id {
-            Ok(id) => Some(id),
-            Err(_) => None,
-        };
-    }
-    None
-}
-
 /// user_idを受け取ってCookieを返す
 /// テスト用
-pub fn token_cookie_from_user_id(user_id: &str) -> String {
-    let token = id_to_jwt(user_id);
+pub async fn token_cookie_from_user_id(user_id: &str, db: &PgPool) -> String {
+    let token = create_session(user_id, None, db)
+        .await
+        .expect("Failed to create session");
     Cookie::build(("token", &token))
         .secure(true)
         .http_only(true)
         .to_string()
 }
 
-/// user_idを受け取って、そのユーザーが管理者かどうかを返す
-pub async fn is_admin(db: &PgPool, user_id: &str) -> bool {
-    match sqlx::query!(
-        r#"
-                SELECT role as "role: UserRole"
-                FROM users
-                WHERE id = $1
-            "#,
-        user_id
-    )
-    .fetch_one(db)
-    .await
-    {
-        Ok(user) => matches!(user.role, UserRole::Admin),
-        Err(_) => false,
-    }
-}
-
-/// 招待コードを確認して、ユーザーを作成する
+/// 予約済みの招待コードを使用して、ユーザーを作成する
+///
+/// 招待コードの使用とユーザーの作成は同じトランザクションで行い、
+/// 招待コードは予約トークンが一致し予約の期限内の場合のみ使用できる。
+/// ユーザーには招待コードのロールを付与し、グループが指定されていれば追加する
 pub async fn create_user(
     id: &str,
     password: &str,
     invitation_code: &str,
+    reservation_token: &str,
     db: &PgPool,
 ) -> Result<(), String> {
-    // 招待コードの確認
-    let invitation = match sqlx::query!(
-        r#"
-            SELECT
-                state as "state: InvitationState",
-                used_at
-            FROM invitations
-            WHERE
-                code = $1
-                AND
-                state != 'used'
-        "#,
-        invitation_code
-    )
-    .fetch_one(db)
-    .await
-    {
-        Ok(invitation) => invitation,
-        Err(_) => {
-            return Err("招待コードが見つかりませんでした".to_string());
-        }
-    };
-    if invitation.state == InvitationState::Used {
-        return Err("使用済みの招待コードです".to_string());
-    }
-
     // ID(英数字と-と_で2文字以上), パスワード(英数字と記号で8文字以上)の確認
-    let id_regex = Regex::new(r#"^[a-zA-Z0-9_-]{2,40}$"#).unwrap();
-    if !id_regex.is_match(id) {
-        return Err("IDは2文字以上で英数字,-,_のみを使用してください".to_string());
-    }
-    if password.chars().count() < 8 {
-        return Err("パスワードは8文字以上で入力してください".to_string());
-    }
+    validate_user_id(id)?;
+    validate_password(password)?;
 
     // トランザクションの開始
     let mut transaction = match db.begin().await {
@@ -223,37 +314,36 @@ pub async fn create_user(
         Err(e) => return Err(e.to_string()),
     };
 
+    // 招待コードの使用
+    // 予約は1回しか使用できないため、同じ予約で同時に登録しても成功するのは1つのみ
+    let redemption = match redeem_reservation(&mut transaction, invitation_code, reservation_token)
+        .await
+    {
+        Ok(Some(redemption)) => redemption,
+        Ok(None) => return Err("招待コードが見つからないか、予約の期限が切れています".to_string()),
+        Err(e) => return Err(e.to_string()),
+    };
+
     // ユーザーの作成
     if let Err(e) = sqlx::query!(
         r#"
-            INSERT INTO users (id, password, role, api_key)
+            INSERT INTO users (id, password, role, invitation_code)
             VALUES ($1, $2, $3, $4)
         "#,
         id,
-        password,
-        UserRole::User as UserRole,
-        Uuid::new_v4().to_string(),
+        hash_password(password),
+        redemption.role as UserRole,
+        invitation_code,
     )
     .execute(&mut *transaction)
     .await
     {
         return Err(e.to_string());
     }
-
-    // 招待コードの使用済み化
-    if let Err(e) = sqlx::query!(
-        r#"
-            UPDATE invitations
-            SET state = $1
-            WHERE code = $2
-        "#,
-        InvitationState::Used as InvitationState,
-        invitation_code
-    )
-    .execute(&mut *transaction)
-    .await
-    {
-        return Err(e.to_string());
+    if let Some(group_name) = &redemption.group_name {
+        if let Err(e) = add_user_to_group(&mut transaction, id, group_name).await {
+            return Err(e.to_string());
+        }
     }
 
     // トランザクションのコミット
@@ -268,7 +358,7 @@ pub async fn create_user(
 pub async fn show_user(id: &str, db: &PgPool) -> Result<User, sqlx::Error> {
     let user = sqlx::query!(
         r#"
-            SELECT id, password, role as "role: UserRole", api_key
+            SELECT id, role as "role: UserRole"
             FROM users
             WHERE id = $1
         "#,
@@ -289,7 +379,216 @@ pub async fn show_user(id: &str, db: &PgPool) -> Result<User, sqlx::Error> {
     Ok(User {
         id: user.id,
         role: user.role,
-        api_key: user.api_key,
         invitations: invitations.iter().map(|i| i.code.clone()).collect(),
     })
 }
+
+/// ID(英数字と-と_で2文字以上40文字以下)の確認
+pub fn validate_user_id(id: &str) -> Result<(), String> {
+    let id_regex = Regex::new(r#"^[a-zA-Z0-9_-]{2,40}$"#).unwrap();
+    if !id_regex.is_match(id) {
+        return Err("IDは2文字以上で英数字,-,_のみを使用してください".to_string());
+    }
+    Ok(())
+}
+
+/// パスワード(8文字以上)の確認
+fn validate_password(password: &str) -> Result<(), String> {
+    if password.chars().count() < 8 {
+        return Err("パスワードは8文字以上で入力してください".to_string());
+    }
+    Ok(())
+}
+
+/// パスワードを更新して、そのユーザーのセッションをすべて無効にする
+async fn set_password(
+    user_id: &str,
+    password: &str,
+    conn: &mut PgConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            UPDATE users
+            SET password = $1
+            WHERE id = $2
+        "#,
+        hash_password(password),
+        user_id
+    )
+    .execute(&mut *conn)
+    .await?;
+    revoke_all_sessions(user_id, &mut *conn).await
+}
+
+/// 現在のパスワードを確認して、パスワードを変更する
+pub async fn change_password(
+    user_id: &str,
+    current_password: &str,
+    new_password: &str,
+    db: &PgPool,
+) -> Result<(), String> {
+    let user = sqlx::query!(r#"SELECT password FROM users WHERE id = $1"#, user_id)
+        .fetch_one(db)
+        .await
+        .map_err(|_| "ユーザーが見つかりませんでした".to_string())?;
+    if !password_matches(current_password, &user.password) {
+        return Err("現在のパスワードが一致しません".to_string());
+    }
+    validate_password(new_password)?;
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+    set_password(user_id, new_password, &mut transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+    transaction.commit().await.map_err(|e| e.to_string())
+}
+
+/// パスワードリセット用トークンをハッシュ化する
+fn hash_reset_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// パスワードリセット用トークンを発行する
+///
+/// 発行済みの未使用トークンは無効になる
+pub async fn issue_password_reset_token(
+    user_id: &str,
+    db: &PgPool,
+) -> Result<PasswordResetTokenResponse, sqlx::Error> {
+    let ttl_minutes = env::var("PASSWORD_RESET_TTL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(60);
+    let token = Uuid::new_v4().simple().to_string();
+    let expires_at = Utc::now() + Duration::minutes(ttl_minutes);
+
+    let mut transaction = db.begin().await?;
+    sqlx::query!(r#"SELECT id FROM users WHERE id = $1"#, user_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+    sqlx::query!(
+        r#"
+            DELETE FROM password_reset_tokens
+            WHERE user_id = $1 AND used_at IS NULL
+        "#,
+        user_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+            INSERT INTO password_reset_tokens (token_hash, user_id, expires_at)
+            VALUES ($1, $2, $3)
+        "#,
+        hash_reset_token(&token),
+        user_id,
+        expires_at
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(PasswordResetTokenResponse { token, expires_at })
+}
+
+/// パスワードリセット用トークンを使用して、パスワードを再設定する
+pub async fn reset_password(token: &str, new_password: &str, db: &PgPool) -> Result<(), String> {
+    validate_password(new_password)?;
+
+    let mut transaction = db.begin().await.map_err(|e| e.to_string())?;
+
+    // トークンの使用済み化(期限切れ・使用済みのトークンは更新されない)
+    let user_id = sqlx::query_scalar!(
+        r#"
+            UPDATE password_reset_tokens
+            SET used_at = now()
+            WHERE
+                token_hash = $1
+                AND used_at IS NULL
+                AND expires_at > now()
+            RETURNING user_id
+        "#,
+        hash_reset_token(token)
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(|e| e.to_string())?
+    .ok_or("無効なトークンです".to_string())?;
+
+    set_password(&user_id, new_password, &mut transaction)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    transaction.commit().await.map_err(|e| e.to_string())
+}
+
+/// ユーザーのセッションをすべて無効にする
+async fn revoke_all_sessions(user_id: &str, conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            UPDATE sessions
+            SET revoked_at = now()
+            WHERE user_id = $1 AND revoked_at IS NULL
+        "#,
+        user_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// ユーザーのセッションを無効にする
+///
+/// 該当するセッションがなければ `sqlx::Error::RowNotFound` を返す
+pub async fn revoke_session(
+    user_id: &str,
+    session_id: Uuid,
+    db: &PgPool,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            UPDATE sessions
+            SET revoked_at = now()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            RETURNING id
+        "#,
+        session_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(())
+}
+
+/// 有効なセッションの一覧を取得する
+pub async fn get_sessions(
+    user_id: &str,
+    current_session_id: Option<Uuid>,
+    db: &PgPool,
+) -> Result<Vec<Session>, sqlx::Error> {
+    let sessions = sqlx::query!(
+        r#"
+            SELECT id, user_agent, created_at, last_used_at, expires_at
+            FROM sessions
+            WHERE
+                user_id = $1
+                AND revoked_at IS NULL
+                AND expires_at > now()
+            ORDER BY last_used_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(sessions
+        .into_iter()
+        .map(|session| Session {
+            current: Some(session.id) == current_session_id,
+            id: session.id,
+            user_agent: session.user_agent,
+            created_at: session.created_at,
+            last_used_at: session.last_used_at,
+            expires_at: session.expires_at,
+        })
+        .collect())
+}
@@ -2,14 +2,42 @@ use std::env;
 
 use axum::{
     body::Body,
-    extract::State,
-    http::{header::SET_COOKIE, HeaderMap, StatusCode},
+    extract::{Path, State},
+    http::{
+        header::{SET_COOKIE, USER_AGENT},
+        HeaderMap, StatusCode,
+    },
     response::{AppendHeaders, IntoResponse},
     Json,
 };
 use sqlx::PgPool;
+use uuid::Uuid;
 
-use super::model::{self, user_id_from_header, UserError};
+use super::{
+    auth::{AccountUser, AdminUser, AuthError, AuthUser},
+    model::{self, UserError},
+};
+use crate::{
+    service::totp::model::{self as totp, LoginChallengeResponse, SecondFactor},
+    throttle::{self, too_many_requests, ClientIp, ThrottleKey},
+};
+
+/// JWTを保存するSet-Cookieヘッダーの値を返す
+///
+/// max_ageに0を指定するとCookieを削除する
+pub fn token_cookie(jwt: &str, max_age: i64) -> String {
+    format!(
+        "token={};Domain={};Max-Age={};Path=/;Secure;HttpOnly;SameSite=None",
+        jwt,
+        env::var("DOMAIN").unwrap_or("localhost".to_string()),
+        max_age
+    )
+}
+
+/// リクエストのUser-Agentを返す
+pub fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    headers.get(USER_AGENT).and_then(|v| v.to_str().ok())
+}
 
 #[utoipa::path(
     post,
@@ -21,11 +49,20 @@ use super::model::{self, user_id_from_header, UserError};
     )
 )]
 pub async fn new_user(
+    headers: HeaderMap,
     State(db): State<PgPool>,
     Json(body): Json<model::NewUserRequest>,
 ) -> impl IntoResponse {
     // ユーザー登録処理
-    if let Err(e) = model::create_user(&body.id, &body.password, &body.invitation_code, &db).await {
+    if let Err(e) = model::create_user(
+        &body.id,
+        &body.password,
+        &body.invitation_code,
+        &body.reservation_token,
+        &db,
+    )
+    .await
+    {
         return (
             StatusCode::BAD_REQUEST,
             Json(model::UserError::InvalidInvitationCode(e.to_string())),
@@ -33,20 +70,15 @@ pub async fn new_user(
             .into_response();
     }
 
-    // JWTの生成
-    let jwt = model::id_to_jwt(&body.id);
+    // セッションの作成
+    let jwt = match model::create_session(&body.id, user_agent(&headers), &db).await {
+        Ok(jwt) => jwt,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
 
     (
         StatusCode::NO_CONTENT,
-        AppendHeaders([(
-            SET_COOKIE,
-            format!(
-                "token={};Domain={};Max-Age={};Path=/;Secure;HttpOnly;SameSite=None",
-                jwt,
-                env::var("DOMAIN").unwrap_or("localhost".to_string()),
-                3600 * 24 * 30
-            ),
-        )]),
+        AppendHeaders([(SET_COOKIE, token_cookie(&jwt, 3600 * 24 * 30))]),
         Body::empty(),
     )
         .into_response()
@@ -59,35 +91,69 @@ pub async fn new_user(
     request_body = inline(model::LoginRequest),
     responses(
         (status = 204),
+        (status = 202, description = "二段階目の認証が必要", body = inline(LoginChallengeResponse)),
         (status = 400, description = "Bad Request", body = inline(UserError), example = json!(model::UserError::InvalidIdOrPassword(String::from("invalid id or password")))),
+        (status = 429, description = "Too Many Requests", body = inline(UserError), example = json!(model::UserError::TooManyRequests(String::from("60秒後に再試行してください")))),
     )
 )]
 pub async fn login(
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     State(db): State<PgPool>,
     Json(body): Json<model::LoginRequest>,
 ) -> impl IntoResponse {
-    let jwt = match model::varify_password(db, &body.id, &body.password).await {
-        Ok(jwt) => jwt,
-        Err(err) => {
-            return (
-                StatusCode::BAD_REQUEST,
-                Json(model::UserError::InvalidIdOrPassword(err)),
-            )
-                .into_response();
+    // 失敗が続いているIPアドレス・アカウントは一定時間ログインできない
+    let keys = [
+        ThrottleKey::LoginIp(&ip),
+        ThrottleKey::LoginAccount(&body.id),
+    ];
+    match throttle::retry_after(&keys, &db).await {
+        Ok(Some(retry_after)) => return too_many_requests(retry_after),
+        Ok(None) => {}
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+
+    if let Err(err) = model::varify_password(&db, &body.id, &body.password).await {
+        if let Err(e) = throttle::record_failure(&keys, &db).await {
+            log::error!("Failed to record login failure: {}", e);
         }
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(model::UserError::InvalidIdOrPassword(err)),
+        )
+            .into_response();
+    }
+    if let Err(e) = throttle::reset(&[ThrottleKey::LoginAccount(&body.id)], &db).await {
+        log::error!("Failed to reset login failures: {}", e);
+    }
+
+    // TOTPが必要な場合はセッションを作成せずにチャレンジを返す
+    let second_factor = match totp::second_factor(&body.id, &db).await {
+        Ok(second_factor) => second_factor,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+    if second_factor != SecondFactor::None {
+        return match totp::create_challenge(&body.id, user_agent(&headers), &db).await {
+            Ok(challenge_token) => (
+                StatusCode::ACCEPTED,
+                Json(LoginChallengeResponse {
+                    challenge_token,
+                    enrollment_required: second_factor == SecondFactor::Enroll,
+                }),
+            )
+                .into_response(),
+            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+        };
+    }
+
+    let jwt = match model::create_session(&body.id, user_agent(&headers), &db).await {
+        Ok(jwt) => jwt,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     };
 
     (
         StatusCode::NO_CONTENT,
-        AppendHeaders([(
-            SET_COOKIE,
-            format!(
-                "token={};Domain={};Max-Age={};Path=/;Secure;HttpOnly;SameSite=None",
-                jwt,
-                env::var("DOMAIN").unwrap_or("localhost".to_string()),
-                3600 * 24 * 30
-            ),
-        )]),
+        AppendHeaders([(SET_COOKIE, token_cookie(&jwt, 3600 * 24 * 30))]),
         Body::empty(),
     )
         .into_response()
@@ -102,19 +168,8 @@ pub async fn login(
         (status = 400, description = "Bad Request", body = inline(UserError), example = json!(model::UserError::Unauthorized(String::from("認証に失敗しました")))),
     )
 )]
-pub async fn show_user(headers: HeaderMap, State(db): State<PgPool>) -> impl IntoResponse {
-    let user_id = match user_id_from_header(&headers, &db).await {
-        Some(id) => id,
-        None => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(UserError::Unauthorized(String::from("missing user id"))),
-            )
-                .into_response()
-        }
-    };
-
-    let user = match model::show_user(&user_id, &db).await {
+pub async fn show_user(user: AuthUser, State(db): State<PgPool>) -> impl IntoResponse {
+    let user = match model::show_user(&user.id, &db).await {
         Ok(user) => user,
         Err(_) => {
             return (
@@ -128,22 +183,203 @@ pub async fn show_user(headers: HeaderMap, State(db): State<PgPool>) -> impl Int
     (StatusCode::OK, Json(user)).into_response()
 }
 
+/// パスワードを変更する
+///
+/// すべてのセッションが無効になり、リクエストしたクライアントには新しいセッションを作成する
+#[utoipa::path(
+    put,
+    path = "/users/password",
+    request_body = inline(model::ChangePasswordRequest),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(model::UserError::InvalidPassword(String::from("現在のパスワードが一致しません")))),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(model::UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn change_password(
+    headers: HeaderMap,
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+    Json(body): Json<model::ChangePasswordRequest>,
+) -> impl IntoResponse {
+    if let Err(e) =
+        model::change_password(&user.id, &body.current_password, &body.new_password, &db).await
+    {
+        return (StatusCode::BAD_REQUEST, Json(UserError::InvalidPassword(e))).into_response();
+    }
+
+    // セッションの再作成
+    let jwt = match model::create_session(&user.id, user_agent(&headers), &db).await {
+        Ok(jwt) => jwt,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    };
+
+    (
+        StatusCode::NO_CONTENT,
+        AppendHeaders([(SET_COOKIE, token_cookie(&jwt, 3600 * 24 * 30))]),
+        Body::empty(),
+    )
+        .into_response()
+}
+
+/// パスワードリセット用トークンを発行する
+///
+/// 管理者のみ実行可能
+#[utoipa::path(
+    post,
+    path = "/users/{user_id}/password_reset",
+    responses(
+        (status = 200, description = "OK", body = inline(model::PasswordResetTokenResponse)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(model::UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn issue_password_reset_token(
+    _: AdminUser,
+    Path(user_id): Path<String>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::issue_password_reset_token(&user_id, &db).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
+/// パスワードリセット用トークンを使用してパスワードを再設定する
+#[utoipa::path(
+    post,
+    path = "/password_reset",
+    request_body = inline(model::ResetPasswordRequest),
+    responses(
+        (status = 204, description = "OK"),
+        (status = 400, description = "Bad Request", body = inline(UserError), example = json!(model::UserError::InvalidResetToken(String::from("無効なトークンです")))),
+    )
+)]
+pub async fn reset_password(
+    State(db): State<PgPool>,
+    Json(body): Json<model::ResetPasswordRequest>,
+) -> impl IntoResponse {
+    match model::reset_password(&body.token, &body.new_password, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(UserError::InvalidResetToken(e)),
+        )
+            .into_response(),
+    }
+}
+
+/// ログアウトする
+///
+/// リクエストに使用したセッションを無効にして、Cookieを削除する
+#[utoipa::path(
+    post,
+    path = "/logout",
+    responses(
+        (status = 204, description = "OK"),
+    )
+)]
+pub async fn logout(
+    user: Result<AuthUser, AuthError>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    if let Ok(AuthUser {
+        id,
+        session_id: Some(session_id),
+        ..
+    }) = user
+    {
+        if let Err(e) = model::revoke_session(&id, session_id, &db).await {
+            log::error!("Failed to revoke session: {}", e);
+        }
+    }
+
+    (
+        StatusCode::NO_CONTENT,
+        AppendHeaders([(SET_COOKIE, token_cookie("", 0))]),
+        Body::empty(),
+    )
+        .into_response()
+}
+
+/// 有効なセッションの一覧を取得する
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    responses(
+        (status = 200, description = "OK", body = inline(Vec<model::Session>)),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(model::UserError::Unauthorized(String::from("missing user id")))),
+    )
+)]
+pub async fn get_sessions(
+    AccountUser(user): AccountUser,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::get_sessions(&user.id, user.session_id, &db).await {
+        Ok(sessions) => (StatusCode::OK, Json(sessions)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+/// セッションを無効にする
+#[utoipa::path(
+    delete,
+    path = "/sessions/{session_id}",
+    responses(
+        (status = 204, description = "OK"),
+        (status = 401, description = "Unauthorized", body = inline(UserError), example = json!(model::UserError::Unauthorized(String::from("missing user id")))),
+        (status = 404, description = "Not Found"),
+    )
+)]
+pub async fn delete_session(
+    AccountUser(user): AccountUser,
+    Path(session_id): Path<Uuid>,
+    State(db): State<PgPool>,
+) -> impl IntoResponse {
+    match model::revoke_session(&user.id, session_id, &db).await {
+        Ok(_) => (StatusCode::NO_CONTENT).into_response(),
+        Err(_) => (StatusCode::NOT_FOUND).into_response(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
     use axum::{
-        body::Body,
-        http::{header, Method, Request},
+        body::{to_bytes, Body},
+        http::{header, HeaderValue, Method, Request},
+        Router,
     };
-    use serde_json::{json, to_string};
+    use futures::future::join_all;
+    use serde_json::{json, to_string, Value};
     use sqlx::PgPool;
     use tower::ServiceExt;
 
     use crate::{routes::init_app, service::user::model::token_cookie_from_user_id};
 
+    /// 招待コードを予約して予約トークンを返す
+    async fn reserve_invitation(router: &Router, code: &str) -> Option<String> {
+        let req = Request::builder()
+            .uri("/check_invitation")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!({ "invitation_code": code })).unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        let body: Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap())
+                .unwrap_or(Value::Null);
+        body["reservation_token"].as_str().map(str::to_string)
+    }
+
     #[sqlx::test(fixtures("users", "invitations"))]
     async fn test_new_user(pool: PgPool) {
         let router = init_app(&pool);
+        let token = reserve_invitation(&router, "unused_test_code")
+            .await
+            .unwrap();
 
         // ユーザー登録 無効なidもしくはpasswordの場合
         let req = Request::builder()
@@ -155,7 +391,8 @@ mod tests {
                     {
                         "id": "test",
                         "password": "test",
-                        "invitation_code": "unused_test_code"
+                        "invitation_code": "unused_test_code",
+                        "reservation_token": token
                     }
                 ))
                 .unwrap(),
@@ -174,7 +411,28 @@ mod tests {
                     {
                         "id": "used_id",
                         "password": "Test1234",
-                        "invitation_code": "unused_test_code"
+                        "invitation_code": "unused_test_code",
+                        "reservation_token": token
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+
+        // 予約トークンが一致しない場合
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "id": "unused_id",
+                        "password": "Test1234",
+                        "invitation_code": "unused_test_code",
+                        "reservation_token": "invalid_token"
                     }
                 ))
                 .unwrap(),
@@ -193,7 +451,8 @@ mod tests {
                     {
                         "id": "unused_id",
                         "password": "Test1234",
-                        "invitation_code": "unused_test_code"
+                        "invitation_code": "unused_test_code",
+                        "reservation_token": token
                     }
                 ))
                 .unwrap(),
@@ -204,6 +463,11 @@ mod tests {
         let headers = res.headers();
         let cookie = headers.get(header::SET_COOKIE).unwrap();
         assert!(cookie.to_str().unwrap().contains("token="));
+        let password = sqlx::query_scalar!(r#"SELECT password FROM users WHERE id = 'unused_id'"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(password.starts_with("$argon2id$"));
 
         // ユーザー登録 招待コードが無効な場合
         let req = Request::builder()
@@ -215,7 +479,8 @@ mod tests {
                     {
                         "id": "test2",
                         "password": "Test1234",
-                        "invitation_code": "used_test_code"
+                        "invitation_code": "used_test_code",
+                        "reservation_token": token
                     }
                 ))
                 .unwrap(),
@@ -267,6 +532,49 @@ mod tests {
         let headers = res.headers();
         let cookie = headers.get(header::SET_COOKIE).unwrap();
         assert!(cookie.to_str().unwrap().contains("token="));
+
+        // 平文のパスワードはログイン時にハッシュへ移行される
+        let password = sqlx::query_scalar!(r#"SELECT password FROM users WHERE id = 'used_id'"#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(password.starts_with("$argon2id$"));
+
+        // ログイン 移行後のハッシュで成功した場合
+        let req = Request::builder()
+            .uri("/login")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "id": "used_id",
+                        "password": "Test1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+
+        // ログイン 移行後のハッシュで失敗した場合
+        let req = Request::builder()
+            .uri("/login")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "id": "used_id",
+                        "password": password
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 400);
     }
 
     #[sqlx::test(fixtures("users", "invitations"))]
@@ -274,7 +582,17 @@ mod tests {
         let router = init_app(&pool);
 
         // ユーザー情報取得 認証に失敗した場合
-        let user_token = token_cookie_from_user_id("invalid_user_id");
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, "token=invalid_token")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // ユーザー情報取得 成功した場合
+        let user_token = token_cookie_from_user_id("used_id", &pool).await;
         let req = Request::builder()
             .uri("/users")
             .method(Method::GET)
@@ -282,17 +600,561 @@ mod tests {
             .body(Body::empty())
             .unwrap();
         let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+    }
+
+    #[sqlx::test(fixtures("users", "invitations"))]
+    async fn test_change_password(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_token = token_cookie_from_user_id("used_id", &pool).await;
+
+        // パスワード変更 認証されていない場合
+        let req = Request::builder()
+            .uri("/users/password")
+            .method(Method::PUT)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "current_password": "Test1234",
+                        "new_password": "NewTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // パスワード変更 現在のパスワードが一致しない場合
+        let req = Request::builder()
+            .uri("/users/password")
+            .method(Method::PUT)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::COOKIE, &user_token)
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "current_password": "invalid_password",
+                        "new_password": "NewTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
         assert_eq!(res.status(), 400);
 
-        // ユーザー情報取得 成功した場合
-        let user_token = token_cookie_from_user_id("used_id");
+        // パスワード変更 新しいパスワードが短い場合
+        let req = Request::builder()
+            .uri("/users/password")
+            .method(Method::PUT)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::COOKIE, &user_token)
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "current_password": "Test1234",
+                        "new_password": "short"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+
+        // パスワード変更 成功した場合
+        let req = Request::builder()
+            .uri("/users/password")
+            .method(Method::PUT)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::COOKIE, &user_token)
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "current_password": "Test1234",
+                        "new_password": "NewTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let new_token = res
+            .headers()
+            .get(header::SET_COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap()
+            .to_string();
+
+        // 変更前のセッションは無効になる
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // 再作成されたセッションは有効
         let req = Request::builder()
             .uri("/users")
             .method(Method::GET)
+            .header(header::COOKIE, &new_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        // 新しいパスワードでログインできる
+        let req = Request::builder()
+            .uri("/login")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "id": "used_id",
+                        "password": "NewTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+    }
+
+    #[sqlx::test(fixtures("users", "invitations"))]
+    async fn test_reset_password(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_token = token_cookie_from_user_id("used_id", &pool).await;
+        let admin_token = token_cookie_from_user_id("admin_id", &pool).await;
+
+        // リセットトークン発行 管理者でない場合
+        let req = Request::builder()
+            .uri("/users/used_id/password_reset")
+            .method(Method::POST)
             .header(header::COOKIE, &user_token)
             .body(Body::empty())
             .unwrap();
         let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // リセットトークン発行 ユーザーが存在しない場合
+        let req = Request::builder()
+            .uri("/users/invalid_user_id/password_reset")
+            .method(Method::POST)
+            .header(header::COOKIE, &admin_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        // リセットトークン発行 成功した場合
+        let req = Request::builder()
+            .uri("/users/used_id/password_reset")
+            .method(Method::POST)
+            .header(header::COOKIE, &admin_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
         assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        let reset_token = body["token"].as_str().unwrap().to_string();
+
+        // パスワード再設定 トークンが無効な場合
+        let req = Request::builder()
+            .uri("/password_reset")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "token": "invalid_token",
+                        "new_password": "NewTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+
+        // パスワード再設定 成功した場合
+        let req = Request::builder()
+            .uri("/password_reset")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "token": reset_token,
+                        "new_password": "NewTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+
+        // パスワード再設定 使用済みのトークンの場合
+        let req = Request::builder()
+            .uri("/password_reset")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "token": reset_token,
+                        "new_password": "OtherTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 400);
+
+        // 再設定前のセッションは無効になる
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // 新しいパスワードでログインできる
+        let req = Request::builder()
+            .uri("/login")
+            .method(Method::POST)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(
+                to_string(&json!(
+                    {
+                        "id": "used_id",
+                        "password": "NewTest1234"
+                    }
+                ))
+                .unwrap(),
+            ))
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+    }
+
+    #[sqlx::test(fixtures("users", "invitations"))]
+    async fn test_sessions(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_token = token_cookie_from_user_id("used_id", &pool).await;
+        let other_token = token_cookie_from_user_id("used_id", &pool).await;
+        let admin_token = token_cookie_from_user_id("admin_id", &pool).await;
+
+        // セッション一覧 認証されていない場合
+        let req = Request::builder()
+            .uri("/sessions")
+            .method(Method::GET)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // セッション一覧 成功した場合
+        let req = Request::builder()
+            .uri("/sessions")
+            .method(Method::GET)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let sessions: Vec<Value> = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions.iter().filter(|s| s["current"] == true).count(), 1);
+        let other_session_id = sessions.iter().find(|s| s["current"] == false).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        // セッション削除 他のユーザーのセッションの場合
+        let req = Request::builder()
+            .uri(format!("/sessions/{}", other_session_id))
+            .method(Method::DELETE)
+            .header(header::COOKIE, &admin_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 404);
+
+        // セッション削除 成功した場合
+        let req = Request::builder()
+            .uri(format!("/sessions/{}", other_session_id))
+            .method(Method::DELETE)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+
+        // 削除されたセッションは使用できない
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, &other_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // ログアウト
+        let req = Request::builder()
+            .uri("/logout")
+            .method(Method::POST)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let cookie = res.headers().get(header::SET_COOKIE).unwrap();
+        assert!(cookie.to_str().unwrap().contains("Max-Age=0"));
+
+        // ログアウトしたセッションは使用できない
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // 他のユーザーのセッションは影響を受けない
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, &admin_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_authentication(pool: PgPool) {
+        let router = init_app(&pool);
+        let user_token = token_cookie_from_user_id("used_id", &pool).await;
+        let jwt = user_token
+            .split(';')
+            .next()
+            .unwrap()
+            .trim_start_matches("token=")
+            .to_string();
+
+        // tokenを含まないCookieがあってもAPIキーで認証できる
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, "theme=dark")
+            .header("X-Api-Key", "used_test_api_key")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        // BearerトークンにJWTを指定した場合
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::AUTHORIZATION, format!("Bearer {}", jwt))
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        // BearerトークンにAPIキーを指定した場合
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::AUTHORIZATION, "Bearer used_test_api_key")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        // Cookieのtokenが優先される
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header(header::COOKIE, "token=invalid_token")
+            .header("X-Api-Key", "used_test_api_key")
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+
+        // UTF-8でないヘッダーの場合
+        let req = Request::builder()
+            .uri("/users")
+            .method(Method::GET)
+            .header("X-Api-Key", HeaderValue::from_bytes(b"\xff").unwrap())
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert!(body["unauthorized"].is_string());
+
+        // 管理者でない場合
+        let req = Request::builder()
+            .uri("/users/used_id/password_reset")
+            .method(Method::POST)
+            .header(header::COOKIE, &user_token)
+            .body(Body::empty())
+            .unwrap();
+        let res = router.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 401);
+        let bytes = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body["unauthorized"], "admin only");
+    }
+
+    #[sqlx::test(fixtures("users"))]
+    async fn test_login_throttle(pool: PgPool) {
+        let router = init_app(&pool);
+        let login = |password: &'static str| {
+            Request::builder()
+                .uri("/login")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    to_string(&json!(
+                        {
+                            "id": "used_id",
+                            "password": password
+                        }
+                    ))
+                    .unwrap(),
+                ))
+                .unwrap()
+        };
+
+        // 待ち時間なしで失敗できる回数まではパスワードが確認される
+        for _ in 0..5 {
+            let res = router.clone().oneshot(login("wrong")).await.unwrap();
+            assert_eq!(res.status(), 400);
+        }
+
+        // 制限中は正しいパスワードでもログインできない
+        let res = router.clone().oneshot(login("Test1234")).await.unwrap();
+        assert_eq!(res.status(), 429);
+        let retry_after: i64 = res.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(retry_after >= 1);
+        let body: Value =
+            serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert!(body["too many requests"].is_string());
+
+        // 制限が解除されればログインでき、アカウントの失敗回数はリセットされる
+        sqlx::query!("UPDATE throttles SET locked_until = NULL")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let res = router.clone().oneshot(login("Test1234")).await.unwrap();
+        assert_eq!(res.status(), 204);
+        let failures = sqlx::query_scalar!(
+            r#"SELECT failures FROM throttles WHERE key = 'login:account:used_id'"#
+        )
+        .fetch_optional(&pool)
+        .await
+        .unwrap();
+        assert_eq!(failures, None);
+
+        // 失敗回数が上限に達したアカウントはロックされる
+        for _ in 0..10 {
+            sqlx::query!("UPDATE throttles SET locked_until = NULL")
+                .execute(&pool)
+                .await
+                .unwrap();
+            let res = router.clone().oneshot(login("wrong")).await.unwrap();
+            assert_eq!(res.status(), 400);
+        }
+        let locked_seconds = sqlx::query_scalar!(
+            r#"SELECT extract(epoch FROM locked_until - now())::bigint as "seconds!" FROM throttles WHERE key = 'login:account:used_id'"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert!(locked_seconds > 800);
+    }
+
+    #[sqlx::test(fixtures("users", "invitations"))]
+    async fn test_concurrent_invitation_redemption(pool: PgPool) {
+        let router = init_app(&pool);
+
+        // 同時に予約しても予約できるのは1つのみ
+        let tokens = join_all((0..10).map(|_| reserve_invitation(&router, "unused_test_code")))
+            .await
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+        assert_eq!(tokens.len(), 1);
+
+        // 同時に登録しても成功するのは1つのみ
+        let statuses = join_all((0..10).map(|i| {
+            let req = Request::builder()
+                .uri("/users")
+                .method(Method::POST)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    to_string(&json!(
+                        {
+                            "id": format!("concurrent_user_{}", i),
+                            "password": "Test1234",
+                            "invitation_code": "unused_test_code",
+                            "reservation_token": tokens[0]
+                        }
+                    ))
+                    .unwrap(),
+                ))
+                .unwrap();
+            let router = router.clone();
+            async move {
+                tokio::spawn(router.oneshot(req))
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .status()
+            }
+        }))
+        .await;
+        assert_eq!(statuses.iter().filter(|s| s.as_u16() == 204).count(), 1);
+        let count = sqlx::query_scalar!(
+            r#"SELECT count(*) as "count!" FROM users WHERE invitation_code = 'unused_test_code'"#
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(count, 1);
+
+        // 使用済みの招待コードは予約できない
+        assert!(reserve_invitation(&router, "unused_test_code")
+            .await
+            .is_none());
     }
 }
@@ -0,0 +1,248 @@
+use std::{convert::Infallible, env, net::SocketAddr};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::RETRY_AFTER, request::Parts, StatusCode},
+    response::{AppendHeaders, IntoResponse, Response},
+    Json,
+};
+use sqlx::PgPool;
+
+use crate::service::user::model::UserError;
+
+/// 失敗回数の制限の設定
+///
+/// 各値は環境変数で変更できる
+pub struct ThrottleConfig {
+    /// 待ち時間なしで失敗できる回数
+    pub free_attempts: i32,
+    /// 最初の待ち時間(秒)。以降は失敗するごとに2倍になる
+    pub base_delay_seconds: i64,
+    /// 待ち時間の上限(秒)
+    pub max_delay_seconds: i64,
+    /// 最後の失敗からこの秒数が経過すると失敗回数をリセットする
+    pub window_seconds: i64,
+    /// アカウントをロックする失敗回数
+    pub lockout_threshold: i32,
+    /// アカウントをロックする秒数
+    pub lockout_seconds: i64,
+}
+
+impl ThrottleConfig {
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        }
+        Self {
+            free_attempts: var("THROTTLE_FREE_ATTEMPTS", 5),
+            base_delay_seconds: var("THROTTLE_BASE_DELAY_SECONDS", 1),
+            max_delay_seconds: var("THROTTLE_MAX_DELAY_SECONDS", 900),
+            window_seconds: var("THROTTLE_WINDOW_SECONDS", 900),
+            lockout_threshold: var("ACCOUNT_LOCKOUT_THRESHOLD", 10),
+            lockout_seconds: var("ACCOUNT_LOCKOUT_SECONDS", 900),
+        }
+    }
+
+    /// 失敗回数に応じた待ち時間(秒)を返す
+    fn delay_seconds(&self, failures: i32, account: bool) -> Option<i64> {
+        if account && failures >= self.lockout_threshold {
+            return Some(self.lockout_seconds);
+        }
+        if failures < self.free_attempts {
+            return None;
+        }
+        let exponent = (failures - self.free_attempts).min(32) as u32;
+        Some(
+            self.base_delay_seconds
+                .saturating_mul(2_i64.saturating_pow(exponent))
+                .min(self.max_delay_seconds),
+        )
+    }
+}
+
+/// 制限の対象
+pub enum ThrottleKey<'a> {
+    /// IPアドレスごとのログイン
+    LoginIp(&'a str),
+    /// アカウントごとのログイン
+    LoginAccount(&'a str),
+    /// IPアドレスごとの招待コードの確認
+    InvitationIp(&'a str),
+    /// IPアドレスごとのログイン時のTOTPの確認
+    TotpIp(&'a str),
+    /// アカウントごとのログイン時のTOTPの確認
+    TotpAccount(&'a str),
+}
+
+impl ThrottleKey<'_> {
+    fn key(&self) -> String {
+        match self {
+            Self::LoginIp(ip) => format!("login:ip:{}", ip),
+            Self::LoginAccount(id) => format!("login:account:{}", id),
+            Self::InvitationIp(ip) => format!("invitation:ip:{}", ip),
+            Self::TotpIp(ip) => format!("totp:ip:{}", ip),
+            Self::TotpAccount(id) => format!("totp:account:{}", id),
+        }
+    }
+
+    fn is_account(&self) -> bool {
+        matches!(self, Self::LoginAccount(_) | Self::TotpAccount(_))
+    }
+}
+
+/// 制限中であれば、再試行できるまでの秒数を返す
+pub async fn retry_after(
+    keys: &[ThrottleKey<'_>],
+    db: &PgPool,
+) -> Result<Option<i64>, sqlx::Error> {
+    let keys = keys.iter().map(|k| k.key()).collect::<Vec<_>>();
+    sqlx::query_scalar!(
+        r#"
+            SELECT ceil(extract(epoch FROM max(locked_until) - now()))::bigint
+            FROM throttles
+            WHERE key = ANY($1) AND locked_until > now()
+        "#,
+        &keys
+    )
+    .fetch_one(db)
+    .await
+    .map(|seconds| seconds.map(|s| s.max(1)))
+}
+
+/// 失敗を記録して、失敗回数に応じて制限する
+pub async fn record_failure(keys: &[ThrottleKey<'_>], db: &PgPool) -> Result<(), sqlx::Error> {
+    let config = ThrottleConfig::from_env();
+    for key in keys {
+        let failures = sqlx::query_scalar!(
+            r#"
+                INSERT INTO throttles (key, failures)
+                VALUES ($1, 1)
+                ON CONFLICT (key) DO UPDATE
+                SET
+                    failures = CASE
+                        WHEN throttles.last_failure_at < now() - make_interval(secs => $2) THEN 1
+                        ELSE throttles.failures + 1
+                    END,
+                    last_failure_at = now()
+                RETURNING failures
+            "#,
+            key.key(),
+            config.window_seconds as f64
+        )
+        .fetch_one(db)
+        .await?;
+
+        if let Some(delay) = config.delay_seconds(failures, key.is_account()) {
+            sqlx::query!(
+                r#"
+                    UPDATE throttles
+                    SET locked_until = now() + make_interval(secs => $1)
+                    WHERE key = $2
+                "#,
+                delay as f64,
+                key.key()
+            )
+            .execute(db)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// 失敗回数をリセットする
+pub async fn reset(keys: &[ThrottleKey<'_>], db: &PgPool) -> Result<(), sqlx::Error> {
+    let keys = keys.iter().map(|k| k.key()).collect::<Vec<_>>();
+    sqlx::query!(r#"DELETE FROM throttles WHERE key = ANY($1)"#, &keys)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// 429 Too Many Requests のレスポンスを返す
+pub fn too_many_requests(retry_after: i64) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        AppendHeaders([(RETRY_AFTER, retry_after.to_string())]),
+        Json(UserError::TooManyRequests(format!(
+            "{}秒後に再試行してください",
+            retry_after
+        ))),
+    )
+        .into_response()
+}
+
+/// X-Forwarded-Forからクライアントのエントリーを返す
+///
+/// クライアントは任意の値を先頭に追加できるため、信頼できるプロキシが追加した
+/// 末尾から `trusted_hops` 番目のエントリーを使用する。
+/// エントリーがそれより少ない場合は、すべて信頼できるプロキシが追加したものとして先頭を使用する
+fn forwarded_client_ip(forwarded_for: &str, trusted_hops: usize) -> Option<String> {
+    let entries = forwarded_for
+        .split(',')
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .collect::<Vec<_>>();
+    let index = entries.len().saturating_sub(trusted_hops.max(1));
+    entries.get(index).map(|ip| ip.to_string())
+}
+
+/// クライアントのIPアドレス
+///
+/// `TRUST_FORWARDED_FOR` がtrueの場合は、X-Forwarded-Forの末尾から
+/// `TRUSTED_PROXY_HOPS` 番目(デフォルト: 1)のエントリーを使用する
+pub struct ClientIp(pub String);
+
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let trust_forwarded_for = env::var("TRUST_FORWARDED_FOR").is_ok_and(|v| v == "true");
+        let trusted_hops = env::var("TRUSTED_PROXY_HOPS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        let forwarded_for = parts
+            .headers
+            .get("X-Forwarded-For")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| forwarded_client_ip(v, trusted_hops));
+        let ip = match forwarded_for {
+            Some(ip) if trust_forwarded_for => ip,
+            _ => parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip().to_string())
+                .unwrap_or("unknown".to_string()),
+        };
+        Ok(Self(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forwarded_client_ip() {
+        // クライアントが追加したエントリーは使用しない
+        assert_eq!(
+            forwarded_client_ip("1.1.1.1, 2.2.2.2, 3.3.3.3", 1),
+            Some("3.3.3.3".to_string())
+        );
+        assert_eq!(
+            forwarded_client_ip("1.1.1.1, 2.2.2.2, 3.3.3.3", 2),
+            Some("2.2.2.2".to_string())
+        );
+        assert_eq!(
+            forwarded_client_ip("3.3.3.3", 2),
+            Some("3.3.3.3".to_string())
+        );
+        assert_eq!(forwarded_client_ip(" , ", 1), None);
+    }
+}